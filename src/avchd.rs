@@ -0,0 +1,56 @@
+//! AVCHD（.mts/.m2ts）の撮影日時を読むためのモジュール
+//! H.264のSEI（user data unregistered）に埋め込まれたMDPMから撮影日時を取り出す
+
+/// MDPMを格納しているSEIのUUID
+const MDPM_UUID: [u8; 16] = [
+    0x17, 0xEE, 0x8C, 0x60, 0xF8, 0x4D, 0x11, 0xD9,
+    0x8C, 0xD6, 0x08, 0x00, 0x20, 0x0C, 0x9A, 0x66,
+];
+
+// MDPMのタグ番号
+const TAG_YEAR_MONTH: u8 = 0x18;  // [タイムゾーン, 年(上2桁), 年(下2桁), 月]
+const TAG_DAY_TIME: u8 = 0x19;    // [日, 時, 分, 秒]
+
+/// BCDで表現された1byteを2桁の数字の文字に変換する．
+fn bcd_to_ascii(bcd: u8) -> Option<[u8; 2]> {
+    let (hi, lo) = (bcd >> 4, bcd & 0x0F);
+    if hi > 9 || lo > 9 {
+        return None;
+    }
+    Some([b'0' + hi, b'0' + lo])
+}
+
+/// MDPMに記録された撮影日時（撮影地の現地時刻）をExifの日時形式で返す．
+///
+/// トランスポートストリームのパケット境界は気にせず，ファイル先頭部分のバイト列からMDPMを探す．
+pub fn get_date_time(binary: &[u8]) -> Option<[u8; 19]> {
+    let start = binary.windows(MDPM_UUID.len() + 4)
+        .position(|w| w[..16] == MDPM_UUID && &w[16..] == b"MDPM")?;
+
+    // "MDPM" の後ろにはエントリ数（1byte）と，タグ番号(1byte) + データ(4byte)のエントリが並ぶ
+    let mdpm = &binary[(start + 20)..];
+    let num_entries = *mdpm.first()? as usize;
+
+    let mut year_month = None;
+    let mut day_time = None;
+    for entry in mdpm.get(1..)?.chunks_exact(5).take(num_entries) {
+        match entry[0] {
+            TAG_YEAR_MONTH => year_month = Some([entry[2], entry[3], entry[4]]),
+            TAG_DAY_TIME   => day_time = Some([entry[1], entry[2], entry[3], entry[4]]),
+            _ => (),
+        }
+    }
+    let [year_hi, year_lo, month] = year_month?;
+    let [day, hour, minute, second] = day_time?;
+
+    // YYYY:MM:DD HH:MM:SS
+    let mut date_time = *b"0000:00:00 00:00:00";
+    date_time[0..2].copy_from_slice(&bcd_to_ascii(year_hi)?);
+    date_time[2..4].copy_from_slice(&bcd_to_ascii(year_lo)?);
+    date_time[5..7].copy_from_slice(&bcd_to_ascii(month)?);
+    date_time[8..10].copy_from_slice(&bcd_to_ascii(day)?);
+    date_time[11..13].copy_from_slice(&bcd_to_ascii(hour)?);
+    date_time[14..16].copy_from_slice(&bcd_to_ascii(minute)?);
+    date_time[17..19].copy_from_slice(&bcd_to_ascii(second)?);
+    Some(date_time)
+}
@@ -0,0 +1,427 @@
+//! ISO-BMFFのボックスを読むためのモジュール
+//! HEIC/AVIFはmetaボックス内のExifアイテムにTIFF構造を格納している
+//! Canon CR3はmoovボックス内のCanon独自のuuidボックスにTIFF構造（CMT1〜CMT4）を格納している
+//! MP4/MOVはmoovボックス内のメタデータやmvhdボックスに作成日時を格納している
+//!
+//! 必要なところだけを読む最小限の実装．
+
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use crate::datetime;
+
+/// Canon CR3のメタデータを格納しているuuidボックスのUUID
+const CANON_UUID: [u8; 16] = [
+    0x85, 0xC0, 0xB6, 0x87, 0x82, 0x0F, 0x11, 0xE0,
+    0x81, 0x11, 0xF4, 0xCE, 0x46, 0x2B, 0x6A, 0x48,
+];
+
+/// mvhdの時刻の起点（1904-01-01 00:00:00 UTC）からUNIX時間の起点までの秒数
+const SECS_1904_TO_1970: u64 = 2_082_844_800;
+
+/// ファイルから読み込むボックスの最大サイズ（壊れたファイルで巨大なメモリを確保しないように）
+const MAX_BOX_LEN: u64 = 256 * 1024 * 1024;
+
+/// ビッグエンディアンの整数を先頭から順に読み出すためのカーソル
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn bytes(&mut self, n: usize) -> Option<&'a [u8]> {
+        let end = self.pos.checked_add(n)?;
+        let slice = self.data.get(self.pos..end)?;
+        self.pos = end;
+        Some(slice)
+    }
+
+    /// nバイト（0, 1, 2, 4, 8のいずれか）の符号無し整数を読む．n == 0の場合は0を返す．
+    fn uint(&mut self, n: usize) -> Option<u64> {
+        let mut val = 0u64;
+        for b in self.bytes(n)? {
+            val = (val << 8) | *b as u64;
+        }
+        Some(val)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some( self.uint(1)? as u8 )
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        Some( self.uint(2)? as u16 )
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some( self.uint(4)? as u32 )
+    }
+
+    /// FullBoxのversionとflagsを読む
+    fn version_flags(&mut self) -> Option<(u8, u32)> {
+        let version = self.u8()?;
+        let flags = self.uint(3)? as u32;
+        Some((version, flags))
+    }
+
+    fn rest(&self) -> &'a [u8] {
+        &self.data[self.pos.min(self.data.len())..]
+    }
+}
+
+/// data[i..]から始まるボックスのヘッダを読み，(ボックスタイプ, 中身, 次のボックスの先頭) を返す．
+fn read_box(data: &[u8], i: usize) -> Option<(&[u8], &[u8], usize)> {
+    let mut r = Reader::new(data.get(i..)?);
+    let size = r.u32()? as u64;
+    let box_type = r.bytes(4)?;
+    let size = match size {
+        0 => (data.len() - i) as u64,  // ファイル末尾まで
+        1 => r.uint(8)?,               // 64bitのlargesize
+        _ => size,
+    };
+    let header_len = r.pos;
+
+    let end = i.checked_add(usize::try_from(size).ok()?)?;
+    if (size as usize) < header_len || end > data.len() {
+        return None;
+    }
+    Some((box_type, &data[(i + header_len)..end], end))
+}
+
+/// 指定したタイプのボックスを並びの中から探して，その中身を返す．
+fn find_box<'a>(data: &'a [u8], box_type: &[u8]) -> Option<&'a [u8]> {
+    let mut i = 0;
+    while i < data.len() {
+        let (t, payload, next) = read_box(data, i)?;
+        if t == box_type {
+            return Some(payload);
+        }
+        i = next;
+    }
+    None
+}
+
+/// iinfボックスからExifアイテムのアイテムIDを探す．
+fn find_exif_item_id(iinf: &[u8]) -> Option<u32> {
+    let mut r = Reader::new(iinf);
+    let (version, _) = r.version_flags()?;
+    let _entry_count = if version == 0 { r.u16()? as u32 } else { r.u32()? };
+
+    let entries = r.rest();
+    let mut i = 0;
+    while i < entries.len() {
+        let (box_type, payload, next) = read_box(entries, i)?;
+        i = next;
+        if box_type != b"infe" {
+            continue;
+        }
+
+        let mut r = Reader::new(payload);
+        let (version, _) = r.version_flags()?;
+        if version < 2 {
+            continue;  // version 0, 1 はアイテムタイプを持たない
+        }
+        let item_id = if version == 2 { r.u16()? as u32 } else { r.u32()? };
+        let _item_protection_index = r.u16()?;
+        if r.bytes(4)? == b"Exif" {
+            return Some(item_id);
+        }
+    }
+    None
+}
+
+/// ilocボックスから指定したアイテムの (construction_method, オフセット, 長さ) を返す．
+/// エクステントが複数ある場合は最初のものだけを見る．
+fn find_item_location(iloc: &[u8], target_id: u32) -> Option<(u16, u64, u64)> {
+    let mut r = Reader::new(iloc);
+    let (version, _) = r.version_flags()?;
+    let tmp = r.u8()?;
+    let offset_size = (tmp >> 4) as usize;
+    let length_size = (tmp & 0x0F) as usize;
+    let tmp = r.u8()?;
+    let base_offset_size = (tmp >> 4) as usize;
+    let index_size = if version == 1 || version == 2 { (tmp & 0x0F) as usize } else { 0 };
+    let item_count = if version < 2 { r.u16()? as u32 } else { r.u32()? };
+
+    for _ in 0..item_count {
+        let item_id = if version < 2 { r.u16()? as u32 } else { r.u32()? };
+        let construction_method = if version == 1 || version == 2 { r.u16()? & 0x000F } else { 0 };
+        let _data_reference_index = r.u16()?;
+        let base_offset = r.uint(base_offset_size)?;
+        let extent_count = r.u16()?;
+
+        let mut first_extent = None;
+        for _ in 0..extent_count {
+            let _extent_index = r.uint(index_size)?;
+            let extent_offset = r.uint(offset_size)?;
+            let extent_length = r.uint(length_size)?;
+            if first_extent.is_none() {
+                first_extent = Some((extent_offset, extent_length));
+            }
+        }
+
+        if item_id == target_id {
+            let (extent_offset, extent_length) = first_extent?;
+            return Some((construction_method, base_offset.checked_add(extent_offset)?, extent_length));
+        }
+    }
+    None
+}
+
+/// HEIC/AVIFのExifアイテムの中身（TIFFヘッダから始まるExifデータ）を返す．
+pub fn get_exif(binary: &[u8]) -> Option<&[u8]> {
+    let meta = find_box(binary, b"meta")?;
+    let meta = meta.get(4..)?;  // metaはFullBoxなのでversionとflagsを飛ばす
+
+    let item_id = find_exif_item_id(find_box(meta, b"iinf")?)?;
+    let (construction_method, offset, length) = find_item_location(find_box(meta, b"iloc")?, item_id)?;
+
+    // construction_method == 0 ならファイル先頭からのオフセット，1 ならidatボックス内のオフセット
+    let container = match construction_method {
+        0 => binary,
+        1 => find_box(meta, b"idat")?,
+        _ => return None,
+    };
+    let offset = usize::try_from(offset).ok()?;
+    let end = offset.checked_add(usize::try_from(length).ok()?)?;
+    let item = container.get(offset..end)?;
+
+    // Exifアイテムの先頭4byteはTIFFヘッダまでのオフセット（通常は "Exif\0\0" の6byte）
+    let mut r = Reader::new(item);
+    let tiff_header_offset = r.u32()? as usize;
+    r.bytes(tiff_header_offset)?;
+    Some(r.rest())
+}
+
+/// Canon CR3のCMTボックスの中身（TIFFヘッダから始まるデータ）を返す．
+/// 
+/// * CMT1: 0th IFD（Make, Model, DateTimeなど）
+/// * CMT2: Exif IFD（DateTimeOriginalなど）
+/// * CMT3: MakerNote
+/// * CMT4: GPS IFD
+pub fn get_canon_cmt<'a>(binary: &'a [u8], box_type: &[u8]) -> Option<&'a [u8]> {
+    let moov = find_box(binary, b"moov")?;
+
+    // moovの中には複数のuuidボックスがあり得るので，UUIDがCanonのものを探す
+    let mut i = 0;
+    while i < moov.len() {
+        let (t, payload, next) = read_box(moov, i)?;
+        if t == b"uuid" && payload.get(..16)? == CANON_UUID {
+            return find_box(&payload[16..], box_type);
+        }
+        i = next;
+    }
+    None
+}
+
+/// ファイルの最上位のボックスをシークしながら辿り，指定したタイプのボックスの中身を読み込む．
+/// 
+/// 動画ファイルはmdatボックスが巨大で，moovボックスがファイルの末尾にあることも多いので，
+/// ファイル全体を読み込まずにボックスのヘッダだけを読んで読み飛ばす．
+/// ボックスが見つからない場合やファイルが壊れている場合はNoneを返す．
+pub fn read_top_level_box(path: &Path, box_type: &[u8]) -> io::Result<Option<Vec<u8>>> {
+    let mut file = fs::File::open(path)?;
+    let file_len = file.metadata()?.len();
+
+    let mut pos = 0u64;
+    while pos + 8 <= file_len {
+        file.seek(SeekFrom::Start(pos))?;
+        let mut header = [0u8; 16];
+        file.read_exact(&mut header[..8])?;
+
+        let mut tmp = [0u8; 8];
+        tmp[4..].copy_from_slice(&header[..4]);
+        let (size, header_len) = match u64::from_be_bytes(tmp) {
+            0 => (file_len - pos, 8),  // ファイル末尾まで
+            1 => {  // 64bitのlargesize
+                if pos + 16 > file_len {
+                    return Ok(None);
+                }
+                file.read_exact(&mut header[8..16])?;
+                tmp.copy_from_slice(&header[8..16]);
+                (u64::from_be_bytes(tmp), 16)
+            },
+            size => (size, 8),
+        };
+        if size < header_len || size > file_len - pos {
+            return Ok(None);  // ファイルが壊れている
+        }
+
+        if &header[4..8] == box_type {
+            if size - header_len > MAX_BOX_LEN {
+                return Ok(None);
+            }
+            let mut payload = vec![0u8; (size - header_len) as usize];
+            file.read_exact(&mut payload)?;
+            return Ok(Some(payload));
+        }
+        pos += size;
+    }
+    Ok(None)
+}
+
+/// metaボックスの子ボックスの並びを返す．
+/// 
+/// MP4のmetaはFullBoxだが，QuickTimeのmetaはversionとflagsを持たないので両方に対応する．
+fn meta_children(meta: &[u8]) -> &[u8] {
+    if meta.get(4..8) == Some(b"hdlr") {
+        meta
+    } else {
+        meta.get(4..).unwrap_or(&[])
+    }
+}
+
+/// QuickTimeのメタデータ（moov/meta のkeysとilst）から指定したキーの値を文字列で返す．
+fn read_quicktime_meta(moov: &[u8], key: &[u8]) -> Option<String> {
+    let meta = meta_children(find_box(moov, b"meta")?);
+
+    // keysボックスからキーのインデックス（1始まり）を探す
+    let mut r = Reader::new(find_box(meta, b"keys")?);
+    r.version_flags()?;
+    let entry_count = r.u32()?;
+    let mut index = None;
+    for i in 1..=entry_count {
+        let key_size = r.u32()? as usize;
+        let _namespace = r.bytes(4)?;
+        if r.bytes(key_size.checked_sub(8)?)? == key {
+            index = Some(i);
+            break;
+        }
+    }
+    let index = index?.to_be_bytes();
+
+    // ilstボックスの中ではインデックスがボックスタイプになっている
+    let item = find_box(find_box(meta, b"ilst")?, &index)?;
+    let mut r = Reader::new(find_box(item, b"data")?);
+    let _type_indicator = r.u32()?;
+    let _locale = r.u32()?;
+    String::from_utf8(r.rest().to_vec()).ok()
+}
+
+/// QuickTimeのユーザーデータ（moov/udta の©day）を文字列で返す．
+fn read_udta_day(moov: &[u8]) -> Option<String> {
+    let day = find_box(find_box(moov, b"udta")?, b"\xA9day")?;
+    let mut r = Reader::new(day);
+    let text_len = r.u16()? as usize;
+    let _language = r.u16()?;
+    String::from_utf8(r.bytes(text_len)?.to_vec()).ok()
+}
+
+/// mvhdボックスの作成日時（UTC）をUNIX時間で返す．
+/// 
+/// 0や1904-01-01のような日時は設定されていないものとみなす．
+fn read_mvhd_creation_time(moov: &[u8]) -> Option<i64> {
+    let mut r = Reader::new(find_box(moov, b"mvhd")?);
+    let (version, _) = r.version_flags()?;
+    let creation_time = if version == 1 { r.uint(8)? } else { r.u32()? as u64 };
+    if creation_time <= SECS_1904_TO_1970 {
+        return None;  // 1970年以前の動画は無いはず
+    }
+    Some( (creation_time - SECS_1904_TO_1970) as i64 )
+}
+
+/// moovボックスの中身から作成日時を読み，Exifの日時形式で返す．
+/// 
+/// 写真と同じく撮影地の現地時刻で名前を付けたいので，
+/// 現地時刻が書かれているQuickTimeのメタデータ（com.apple.quicktime.creationdate，©day）を優先し，
+/// 無ければmvhdの作成日時（UTC）を使う．
+pub fn get_creation_date(moov: &[u8]) -> Option<[u8; 19]> {
+    read_quicktime_meta(moov, b"com.apple.quicktime.creationdate")
+        .and_then(|s| datetime::from_iso8601(&s))
+        .or_else(|| read_udta_day(moov).and_then(|s| datetime::from_iso8601(&s)))
+        .or_else(|| datetime::from_unix(read_mvhd_creation_time(moov)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// TIFFヘッダだけのExifデータ
+    const TIFF: &[u8] = b"II*\0\x08\0\0\0";
+
+    /// ボックスを作る（sizeは32bit）．
+    fn boxed(box_type: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+        [&(8 + payload.len() as u32).to_be_bytes()[..], box_type, payload].concat()
+    }
+
+    /// largesizeを使うボックスを作る．
+    fn large_boxed(box_type: &[u8; 4], payload: &[u8], largesize: u64) -> Vec<u8> {
+        [&1u32.to_be_bytes()[..], box_type, &largesize.to_be_bytes(), payload].concat()
+    }
+
+    /// ftyp，meta，Exifアイテムの順に並べたHEICを作る．ilocに書くオフセットはitem_offset(本当のオフセット)．
+    fn heic(item_offset: impl Fn(u32) -> u32) -> Vec<u8> {
+        let item = [&[0, 0, 0, 6][..], b"Exif\0\0", TIFF].concat();
+        let infe = boxed(b"infe", &[&[2, 0, 0, 0, 0, 1, 0, 0][..], b"Exif"].concat());
+        let iinf = boxed(b"iinf", &[&[0, 0, 0, 0, 0, 1][..], &infe].concat());
+        let meta = |offset: u32| {
+            let iloc = boxed(b"iloc", &[
+                &[0, 0, 0, 0, 0x44, 0x00, 0, 1, 0, 1, 0, 0, 0, 1][..], &offset.to_be_bytes(), &(item.len() as u32).to_be_bytes(),
+            ].concat());
+            boxed(b"meta", &[&[0, 0, 0, 0][..], &iinf, &iloc].concat())
+        };
+        let ftyp = boxed(b"ftyp", b"heic\0\0\0\0");
+        let offset = item_offset((ftyp.len() + meta(0).len()) as u32);
+        [ftyp, meta(offset), item].concat()
+    }
+
+    /// テスト用のファイルを書き，read_top_level_boxでmoovを読む．
+    fn read_moov(name: &str, contents: &[u8]) -> Option<Vec<u8>> {
+        let path = std::env::temp_dir().join(format!("photo-organizer-test-{}-bmff-{}.mp4", std::process::id(), name));
+        fs::write(&path, contents).unwrap();
+        let moov = read_top_level_box(&path, b"moov").unwrap();
+        fs::remove_file(&path).unwrap();
+        moov
+    }
+
+    #[test]
+    fn exif_item_is_found() {
+        assert_eq!(get_exif(&heic(|offset| offset)), Some(TIFF));
+    }
+
+    #[test]
+    fn truncated_boxes_are_rejected() {
+        let binary = heic(|offset| offset);
+        for len in 0..binary.len() {
+            assert_eq!(get_exif(&binary[..len]), None, "{}", len);
+        }
+        assert_eq!(read_moov("truncated", &[boxed(b"ftyp", b"isom"), boxed(b"moov", b"mvhd")].concat()[..20]), None);
+    }
+
+    #[test]
+    fn item_location_outside_the_file_is_rejected() {
+        assert_eq!(get_exif(&heic(|offset| offset + 1)), None);
+        assert_eq!(get_exif(&heic(|_| u32::MAX)), None);
+    }
+
+    #[test]
+    fn size_zero_box_extends_to_the_end() {
+        let mut binary = heic(|offset| offset);
+        binary[12..16].copy_from_slice(&0u32.to_be_bytes());  // metaのsize
+        assert_eq!(get_exif(&binary), Some(TIFF));
+        assert_eq!(read_box(&[0, 0, 0, 0, b'f', b'r', b'e'], 0), None);
+
+        let moov = [&[0, 0, 0, 0][..], b"moov", b"payload"].concat();
+        assert_eq!(read_moov("size-zero", &[boxed(b"ftyp", b"isom"), moov].concat()), Some(b"payload".to_vec()));
+    }
+
+    #[test]
+    fn largesize_is_read_and_checked() {
+        let ftyp = boxed(b"ftyp", b"isom");
+        let mdat = large_boxed(b"mdat", b"video", 16 + 5);
+        let moov = boxed(b"moov", b"payload");
+        assert_eq!(read_moov("largesize", &[&ftyp[..], &mdat, &moov].concat()), Some(b"payload".to_vec()));
+        assert_eq!(find_box(&[&mdat[..], &moov].concat(), b"moov"), Some(&b"payload"[..]));
+
+        // ヘッダより小さいもの，ファイルより大きいもの，largesizeの途中で終わっているもの
+        for mdat in [large_boxed(b"mdat", b"video", 8), large_boxed(b"mdat", b"video", 1 << 40), large_boxed(b"mdat", b"", 0)[..12].to_vec()] {
+            assert_eq!(read_moov("bad-largesize", &[&ftyp[..], &mdat, &moov].concat()), None);
+            assert_eq!(find_box(&[&mdat[..], &moov].concat(), b"moov"), None);
+        }
+    }
+}
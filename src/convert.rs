@@ -0,0 +1,178 @@
+//! 整理の際にHEIC・PNG画像をJPEG画像に変換するためのモジュール（--convert-heic，--convert-png）
+//! 回転情報に従ってJPEG画像自体を回転させる処理や，ハッシュ値を計算するために画像を展開する処理もここに置く
+//! HEICのデコードはimageクレートでは出来ないので，libheifに付属するheif-convertコマンドを使う
+
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process::{self, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use image::DynamicImage;
+use image::codecs::jpeg::JpegEncoder;
+
+use crate::exif;
+
+/// HEICのデコードに使う外部コマンド
+const HEIF_CONVERT: &str = "heif-convert";
+
+/// heif-convertの出力先の一時ファイルに付ける番号（同じ実行の中で名前が被らないように）
+static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// HEIC画像をJPEG画像に変換したバイナリを返す．
+/// 
+/// heif-convertの出力先は一時フォルダの一時ファイルで，読み込んだら削除する（元画像のフォルダは読み取り専用でもよい）．
+/// コマンドが見つからない場合やデコードに失敗した場合はエラーを返す．
+pub fn heic_to_jpeg(heic_path: &Path, quality: u8) -> io::Result<Vec<u8>> {
+    let n = TEMP_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let tmp_path = std::env::temp_dir().join(format!("photo-organizer-{}-{}.converting.jpg", process::id(), n));
+
+    let status = Command::new(HEIF_CONVERT)
+        .arg("-q").arg(quality.to_string())
+        .arg(heic_path)
+        .arg(&tmp_path)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status();
+    let jpeg = match status {
+        Ok(status) if status.success() => fs::read(&tmp_path),
+        Ok(status) => Err(io::Error::other(format!("{} exited with {}", HEIF_CONVERT, status))),
+        Err(e) => Err(io::Error::new(e.kind(), format!("could not run {} ({})", HEIF_CONVERT, e))),
+    };
+    let _ = fs::remove_file(&tmp_path);  // 途中まで書き出された場合も含めて消しておく
+    jpeg
+}
+
+/// PNG画像をJPEG画像に変換したバイナリを返す．
+/// 
+/// JPEGは透明度を持てないので，不透明でないピクセルがある場合はNoneを返す．
+/// ただし，flatten_alphaがtrueの場合は白い背景に合成して変換する．
+pub fn png_to_jpeg(png: &[u8], quality: u8, flatten_alpha: bool) -> image::ImageResult<Option<Vec<u8>>> {
+    let img = image::load_from_memory_with_format(png, image::ImageFormat::Png)?;
+    let rgba = img.to_rgba8();
+    if !flatten_alpha && rgba.pixels().any(|p| p[3] != 255) {
+        return Ok(None);
+    }
+
+    // 白い背景に合成（不透明な画像はそのまま）
+    let rgb = image::RgbImage::from_fn(rgba.width(), rgba.height(), |x, y| {
+        let [r, g, b, a] = rgba.get_pixel(x, y).0;
+        let blend = |c: u8| ((c as u32 * a as u32 + 255 * (255 - a as u32) + 127) / 255) as u8;
+        image::Rgb([blend(r), blend(g), blend(b)])
+    });
+
+    let mut jpeg = Vec::new();
+    JpegEncoder::new_with_quality(&mut jpeg, quality)
+        .encode(&rgb, rgb.width(), rgb.height(), image::ColorType::Rgb8)?;
+    Ok(Some(jpeg))
+}
+
+/// 変換後のJPEG画像にExifが無ければ，元画像のExif（TIFF構造）をAPP1セグメントとして挿入する．
+/// 
+/// 変換時に画像自体を回転させている場合（HEIC）は，Orientationは1（回転なし）にする．
+pub fn carry_over_exif(jpeg: Vec<u8>, tiff: Option<&[u8]>, rotated: bool) -> Vec<u8> {
+    if exif::get_app1(&jpeg).is_some() {
+        return jpeg;
+    }
+    let app1 = match tiff.filter(|tiff| exif::has_tiff_header(tiff)).and_then(exif::app1_from_tiff) {
+        Some(app1) if rotated => exif::clear_app1_orientation(app1),
+        Some(app1) => app1,
+        None => return jpeg,
+    };
+    exif::insert_after_app0(&jpeg, &app1).unwrap_or(jpeg)
+}
+
+/// ExifのOrientationに従って画像を回転・反転させる．
+/// 
+/// 2，4，5，7は鏡像（インカメラで撮った自撮りやスキャナの出力など）．
+pub fn rotate(img: DynamicImage, orientation: Option<u16>) -> DynamicImage {
+    match orientation {
+        Some(2) => img.fliph(),
+        Some(3) => img.rotate180(),
+        Some(4) => img.flipv(),
+        Some(5) => img.rotate90().fliph(),   // 左上と右下を結ぶ対角線で反転
+        Some(6) => img.rotate90(),
+        Some(7) => img.rotate270().fliph(),  // 右上と左下を結ぶ対角線で反転
+        Some(8) => img.rotate270(),
+        _ => img,
+    }
+}
+
+/// JPEG画像をExifのOrientationに従って回転させ，再エンコードしたバイナリを返す（メタデータは持たない）．
+pub fn apply_orientation(jpeg: &[u8], quality: u8) -> image::ImageResult<Vec<u8>> {
+    let img = image::load_from_memory_with_format(jpeg, image::ImageFormat::Jpeg)?;
+    let img = rotate(img, exif::get_orientation(jpeg));
+
+    let mut rotated = Vec::new();
+    JpegEncoder::new_with_quality(&mut rotated, quality).encode_image(&img)?;
+    Ok(rotated)
+}
+
+/// 画像を展開してOrientationに従って回転させ，RGBの画素データを返す（--hash-source pixels）．
+/// 
+/// 画素の並びが同じで縦横が入れ替わった画像を区別するために，先頭に幅と高さ（ビッグエンディアン）を付ける．
+pub fn upright_pixels(binary: &[u8], format: image::ImageFormat, orientation: Option<u16>) -> image::ImageResult<Vec<u8>> {
+    let img = rotate(image::load_from_memory_with_format(binary, format)?, orientation).to_rgb8();
+    let mut pixels = Vec::with_capacity(8 + img.as_raw().len());
+    pixels.extend_from_slice(&img.width().to_be_bytes());
+    pixels.extend_from_slice(&img.height().to_be_bytes());
+    pixels.extend_from_slice(img.as_raw());
+    Ok(pixels)
+}
+
+/// 画像を長辺がlong_edgeピクセルになるように縮小して，JPEGにしたバイナリを返す（Exifのサムネイル用）．
+pub fn thumbnail(img: &DynamicImage, long_edge: u32, quality: u8) -> image::ImageResult<Vec<u8>> {
+    let thumbnail = img.thumbnail(long_edge, long_edge).to_rgb8();
+
+    let mut jpeg = Vec::new();
+    JpegEncoder::new_with_quality(&mut jpeg, quality).encode_image(&thumbnail)?;
+    Ok(jpeg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage};
+
+    /// 正しい向きの3×2の画像（画素ごとに色が違う）
+    fn upright() -> RgbImage {
+        RgbImage::from_fn(3, 2, |x, y| Rgb([x as u8 * 80, y as u8 * 200, 10 + x as u8 + 3 * y as u8]))
+    }
+
+    /// Orientationがorientationのカメラが記録する画素の並び（Exifの仕様の定義から直接作る）
+    fn stored(orientation: u16) -> RgbImage {
+        let img = upright();
+        let (w, h) = img.dimensions();
+        let at = |x: u32, y: u32| *img.get_pixel(x, y);
+        match orientation {
+            1 => img.clone(),
+            2 => RgbImage::from_fn(w, h, |x, y| at(w - 1 - x, y)),
+            3 => RgbImage::from_fn(w, h, |x, y| at(w - 1 - x, h - 1 - y)),
+            4 => RgbImage::from_fn(w, h, |x, y| at(x, h - 1 - y)),
+            5 => RgbImage::from_fn(h, w, |x, y| at(y, x)),
+            6 => RgbImage::from_fn(h, w, |x, y| at(w - 1 - y, x)),
+            7 => RgbImage::from_fn(h, w, |x, y| at(w - 1 - y, h - 1 - x)),
+            8 => RgbImage::from_fn(h, w, |x, y| at(y, h - 1 - x)),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn every_orientation_is_made_upright() {
+        let expected = upright();
+        for orientation in 1..=8 {
+            let img = rotate(DynamicImage::ImageRgb8(stored(orientation)), Some(orientation)).to_rgb8();
+            assert_eq!(img, expected, "Orientation {}", orientation);
+
+            // 可逆なPNGにして，ハッシュ値の計算と同じ経路でも確かめる
+            let mut png = Vec::new();
+            DynamicImage::ImageRgb8(stored(orientation)).write_to(&mut io::Cursor::new(&mut png), image::ImageOutputFormat::Png).unwrap();
+            let pixels = upright_pixels(&png, image::ImageFormat::Png, Some(orientation)).unwrap();
+            assert_eq!(&pixels[..8], &[0, 0, 0, 3, 0, 0, 0, 2], "Orientation {}", orientation);
+            assert_eq!(&pixels[8..], expected.as_raw().as_slice(), "Orientation {}", orientation);
+        }
+        // 知らない値や無い場合は回転させない
+        assert_eq!(rotate(DynamicImage::ImageRgb8(upright()), Some(9)).to_rgb8(), expected);
+        assert_eq!(rotate(DynamicImage::ImageRgb8(upright()), None).to_rgb8(), expected);
+    }
+}
@@ -0,0 +1,591 @@
+//! 日時の変換を行うためのモジュール
+//! 日時は基本的にExifのDateTimeOriginalと同じ形式（YYYY:MM:DD HH:MM:SS）のバイト列で扱う
+
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// 1970-01-01からの経過日数を年月日に変換する．
+///
+/// 参考: http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let y = yoe + era * 400 + if m <= 2 { 1 } else { 0 };
+    (y, m, d)
+}
+
+/// 年月日を1970-01-01からの経過日数に変換する（civil_from_daysの逆）．
+///
+/// 参考: http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+fn days_from_civil(y: i64, m: u32, d: u32) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y.rem_euclid(400);
+    let mp = if m > 2 { m - 3 } else { m + 9 } as i64;
+    let doy = (153 * mp + 2) / 5 + d as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// Exifの日時形式（YYYY:MM:DD HH:MM:SS）として正しいかどうかを返す．
+/// 
+/// Exifが壊れている場合や，日時が未設定のカメラが空白や0で埋めている場合（"    :  :     :  :  "，"0000:00:00 00:00:00"），
+/// 途中にNULL文字が入っている場合はファイル名に使えないのでfalseを返す．
+/// 各値の範囲は，年が1以上，月が1～12，日が1～31，時が0～23，分が0～59，秒が0～60（うるう秒）．
+pub fn is_valid(date_time: &[u8; 19]) -> bool {
+    let is_format = date_time.iter().enumerate().all(|(i, c)| match i {
+        4 | 7 | 13 | 16 => *c == b':',
+        10 => *c == b' ',
+        _  => c.is_ascii_digit(),
+    });
+    if !is_format {
+        return false;
+    }
+    let num = |range: std::ops::Range<usize>| {
+        date_time[range].iter().fold(0, |acc, c| acc * 10 + (c - b'0') as u32)
+    };
+    num(0..4) >= 1 && (1..=12).contains(&num(5..7)) && (1..=31).contains(&num(8..10))
+        && num(11..13) < 24 && num(14..16) < 60 && num(17..19) <= 60
+}
+
+/// UNIX時間（1970-01-01 00:00:00からの経過秒数）をExifの日時形式に変換する．
+///
+/// 西暦が4桁に収まらない場合はNoneを返す．
+pub fn from_unix(secs: i64) -> Option<[u8; 19]> {
+    let (y, mo, d) = civil_from_days(secs.div_euclid(86400));
+    let sec_of_day = secs.rem_euclid(86400);
+    if !(0..=9999).contains(&y) {
+        return None;
+    }
+
+    let s = format!("{:04}:{:02}:{:02} {:02}:{:02}:{:02}",
+        y, mo, d, sec_of_day / 3600, sec_of_day % 3600 / 60, sec_of_day % 60);
+    let mut date_time = [0u8; 19];
+    date_time.copy_from_slice(s.as_bytes());
+    Some(date_time)
+}
+
+/// Exifの日時形式をUTCとみなしてUNIX時間に変換する．
+pub fn to_unix(date_time: &[u8; 19]) -> Option<i64> {
+    if !is_valid(date_time) {
+        return None;
+    }
+    let num = |range: std::ops::Range<usize>| -> i64 {
+        date_time[range].iter().fold(0, |acc, c| acc * 10 + (c - b'0') as i64)
+    };
+    let days = days_from_civil(num(0..4), num(5..7) as u32, num(8..10) as u32);
+    Some(days * 86400 + num(11..13) * 3600 + num(14..16) * 60 + num(17..19))
+}
+
+/// Exifの日時形式の日時をsecs秒ずらす（月末や年末の繰り上がりも正しく扱う）．
+pub fn shift(date_time: &[u8; 19], secs: i64) -> Option<[u8; 19]> {
+    from_unix(to_unix(date_time)? + secs)
+}
+
+/// UTCからのずれを表す文字列（+09:00，-05:30など．ExifのOffsetTimeOriginalと同じ形式）を秒に変換する．
+pub fn parse_offset(s: &str) -> Option<i64> {
+    let s = s.trim().as_bytes();
+    if s.len() != 6 || s[3] != b':' || !s[1..3].iter().chain(&s[4..6]).all(u8::is_ascii_digit) {
+        return None;
+    }
+    let sign = match s[0] {
+        b'+' => 1,
+        b'-' => -1,
+        _ => return None,
+    };
+    let hours = ((s[1] - b'0') * 10 + (s[2] - b'0')) as i64;
+    let minutes = ((s[4] - b'0') * 10 + (s[5] - b'0')) as i64;
+    if hours > 14 || minutes >= 60 {
+        return None;
+    }
+    Some(sign * (hours * 3600 + minutes * 60))
+}
+
+/// UNIX時間をこのコンピュータのタイムゾーンの現地時刻に直す場合の，UTCからのずれ（秒）を返す．
+#[cfg(unix)]
+pub fn local_offset(secs: i64) -> i64 {
+    let t = secs as libc::time_t;
+    // SAFETY: tmはlocaltime_rが書き込むための領域で，ゼロ初期化しても問題ない
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    let res = unsafe { libc::localtime_r(&t, &mut tm) };
+    if res.is_null() {
+        0
+    } else {
+        tm.tm_gmtoff as i64
+    }
+}
+
+/// UNIX時間をこのコンピュータのタイムゾーンの現地時刻に直す場合の，UTCからのずれ（秒）を返す．
+///
+/// WindowsにはUNIX時間を直接変換する関数が無いので，SYSTEMTIMEにしてから現地時刻に変換し，その差を求める．
+#[cfg(windows)]
+pub fn local_offset(secs: i64) -> i64 {
+    use windows_sys::Win32::Foundation::SYSTEMTIME;
+    use windows_sys::Win32::System::Time::SystemTimeToTzSpecificLocalTime;
+
+    let (y, m, d) = civil_from_days(secs.div_euclid(86400));
+    // SYSTEMTIMEで表せるのは1601年から30827年まで
+    if !(1601..=30827).contains(&y) {
+        return 0;
+    }
+    let sec_of_day = secs.rem_euclid(86400);
+    let utc = SYSTEMTIME {
+        wYear: y as u16,
+        wMonth: m as u16,
+        wDayOfWeek: 0,
+        wDay: d as u16,
+        wHour: (sec_of_day / 3600) as u16,
+        wMinute: (sec_of_day % 3600 / 60) as u16,
+        wSecond: (sec_of_day % 60) as u16,
+        wMilliseconds: 0,
+    };
+    let mut local = SYSTEMTIME::default();
+    // タイムゾーンにNULLを渡すと，このコンピュータのタイムゾーン（夏時間を含む）で変換する
+    // SAFETY: utcとlocalはどちらも有効なSYSTEMTIMEを指している
+    if unsafe { SystemTimeToTzSpecificLocalTime(std::ptr::null(), &utc, &mut local) } == 0 {
+        return 0;
+    }
+    let local_secs = days_from_civil(local.wYear as i64, local.wMonth as u32, local.wDay as u32) * 86400
+        + local.wHour as i64 * 3600 + local.wMinute as i64 * 60 + local.wSecond as i64;
+    local_secs - secs
+}
+
+/// Unix系とWindows以外のOSではタイムゾーンを取得できないので，UTCとして扱う．
+#[cfg(not(any(unix, windows)))]
+pub fn local_offset(_secs: i64) -> i64 {
+    0
+}
+
+/// UNIX時間を，このコンピュータのタイムゾーンの現地時刻のExifの日時形式に変換する．
+pub fn from_unix_local(secs: i64) -> Option<[u8; 19]> {
+    from_unix(secs + local_offset(secs))
+}
+
+/// ファイルの更新日時などのSystemTimeを，現地時刻のExifの日時形式に変換する．
+pub fn from_system_time(time: SystemTime) -> Option<[u8; 19]> {
+    let secs = match time.duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs() as i64,
+        Err(e) => -(e.duration().as_secs() as i64),  // 1970年より前
+    };
+    from_unix_local(secs)
+}
+
+/// ISO 8601形式の日時文字列（YYYY-MM-DDTHH:MM:SS，タイムゾーン付きでも良い）を
+/// Exifの日時形式に変換する．タイムゾーンは無視して，書かれている時刻をそのまま使う．
+pub fn from_iso8601(s: &str) -> Option<[u8; 19]> {
+    let s = s.trim().as_bytes();
+    let s = s.get(..19)?;
+
+    let mut date_time = [0u8; 19];
+    for (i, c) in s.iter().enumerate() {
+        date_time[i] = match i {
+            4 | 7   => if *c == b'-' { b':' } else { return None },
+            10      => if *c == b'T' || *c == b' ' { b' ' } else { return None },
+            13 | 16 => if *c == b':' { b':' } else { return None },
+            _       => if c.is_ascii_digit() { *c } else { return None },
+        };
+    }
+    Some(date_time)
+}
+
+/// ファイル名から読んだ日時として受け付ける年の範囲（連番などの数字を日付と間違えないようにする）
+const FILE_NAME_YEARS: std::ops::RangeInclusive<u32> = 1900..=2099;
+
+/// ファイル名（拡張子を除く）に含まれる日時を読む（--fallback filename）．
+/// 
+/// "IMG_20230123_143027"，"PXL_20230123_053027123"，"Screenshot 2023-01-23 at 14.30.27"，"2023-01-23 14.30.27" のように，
+/// 8桁か "-"・"_"・"." で区切った年月日と，その後ろの6桁（4桁）か "."・":"・"-" で区切った時刻を探す．
+/// 時刻が無い場合（"IMG-20230123-WA0001" など）は0時0分0秒にして，2つ目の値をfalseにする．
+/// 前後に数字が続く数字の並びや，存在しない日付（2月30日など）は日時とみなさない．
+pub fn from_file_name(name: &str) -> Option<([u8; 19], bool)> {
+    let s = name.as_bytes();
+    (0..s.len())
+        .filter(|&i| s[i].is_ascii_digit() && (i == 0 || !s[i - 1].is_ascii_digit()))
+        .find_map(|i| parse_file_name_date(&s[i..]))
+}
+
+/// 先頭から数字がちょうどn桁続く場合に，その値と残りを返す．
+fn take_digits(s: &[u8], n: usize) -> Option<(u32, &[u8])> {
+    let digits = s.get(..n)?;
+    if !digits.iter().all(u8::is_ascii_digit) || s.get(n).is_some_and(u8::is_ascii_digit) {
+        return None;
+    }
+    Some((digits.iter().fold(0, |acc, c| acc * 10 + (c - b'0') as u32), &s[n..]))
+}
+
+/// 先頭から始まる年月日（と時刻）を読む．
+fn parse_file_name_date(s: &[u8]) -> Option<([u8; 19], bool)> {
+    let run = s.iter().take_while(|c| c.is_ascii_digit()).count();
+    let (year, month, day, rest) = match run {
+        // YYYYMMDD，またはYYYYMMDDHHMMSS（時刻は後で読む）
+        8 | 14 => {
+            let num = |range: std::ops::Range<usize>| s[range].iter().fold(0, |acc, c| acc * 10 + (c - b'0') as u32);
+            (num(0..4), num(4..6), num(6..8), &s[8..])
+        },
+        // YYYY-MM-DD（区切りは揃っている必要がある）
+        4 => {
+            let (year, rest) = take_digits(s, 4)?;
+            let sep = *rest.first().filter(|c| b"-_.".contains(c))?;
+            let (month, rest) = take_digits(&rest[1..], 2)?;
+            let rest = rest.strip_prefix(&[sep])?;
+            let (day, rest) = take_digits(rest, 2)?;
+            (year, month, day, rest)
+        },
+        _ => return None,
+    };
+    let time = parse_file_name_time(rest);
+    let (hour, minute, second) = time.unwrap_or((0, 0, 0));
+
+    let date_time = format!("{:04}:{:02}:{:02} {:02}:{:02}:{:02}", year, month, day, hour, minute, second);
+    let date_time: [u8; 19] = date_time.as_bytes().try_into().ok()?;
+    // 存在しない日付は，UNIX時間を経由すると別の日付になる
+    if !FILE_NAME_YEARS.contains(&year) || !is_valid(&date_time) || shift(&date_time, 0) != Some(date_time) {
+        return None;
+    }
+    Some((date_time, time.is_some()))
+}
+
+/// 年月日の直後から時刻を読む（区切りの " at " や "_" などは読み飛ばす）．
+fn parse_file_name_time(s: &[u8]) -> Option<(u32, u32, u32)> {
+    let separators: [&[u8]; 6] = [b" at ", b"_", b"-", b" ", b"T", b""];
+    separators.iter().filter_map(|sep| s.strip_prefix(*sep)).find_map(|s| {
+        let run = s.iter().take_while(|c| c.is_ascii_digit()).count();
+        let (hour, minute, second) = match run {
+            // HHMM
+            4 => (num2(s, 0), num2(s, 2), 0),
+            // HHMMSS（後ろにミリ秒などが続いても良い）
+            6.. => (num2(s, 0), num2(s, 2), num2(s, 4)),
+            // HH.MM.SS（区切りは揃っている必要がある）
+            2 => {
+                let sep = *s.get(2).filter(|c| b".:-".contains(c))?;
+                let (minute, rest) = take_digits(&s[3..], 2)?;
+                let (second, _) = take_digits(rest.strip_prefix(&[sep])?, 2)?;
+                (num2(s, 0), minute, second)
+            },
+            _ => return None,
+        };
+        (hour < 24 && minute < 60 && second < 60).then_some((hour, minute, second))
+    })
+}
+
+/// i桁目から2桁の数字を読む．
+fn num2(s: &[u8], i: usize) -> u32 {
+    ((s[i] - b'0') * 10 + (s[i + 1] - b'0')) as u32
+}
+
+/// --date-formatを指定しなかった場合の形式（--precision minuteの場合は秒を除く）
+const DEFAULT_DATE_FORMAT: &str = "%Y-%m-%d_%H%M%S";
+
+/// --date-formatで使える指定子（エラーの表示用）
+const SPECIFIERS: &str = "%Y, %y, %m, %d, %H, %M, %S";
+
+/// ファイル名の撮影日時の形式の要素（--date-format）
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum FormatItem {
+    /// %Y（4桁の年）
+    Year,
+    /// %y（年の下2桁）
+    ShortYear,
+    /// %m（2桁の月）
+    Month,
+    /// %d（2桁の日）
+    Day,
+    /// %H（2桁の時）
+    Hour,
+    /// %M（2桁の分）
+    Minute,
+    /// %S（2桁の秒）
+    Second,
+}
+
+impl FormatItem {
+    fn is_date(self) -> bool {
+        matches!(self, FormatItem::Year | FormatItem::ShortYear | FormatItem::Month | FormatItem::Day)
+    }
+
+    /// Exifの日時形式（YYYY:MM:DD HH:MM:SS）の中の位置
+    fn range(self) -> std::ops::Range<usize> {
+        match self {
+            FormatItem::Year      => 0..4,
+            FormatItem::ShortYear => 2..4,
+            FormatItem::Month     => 5..7,
+            FormatItem::Day       => 8..10,
+            FormatItem::Hour      => 11..13,
+            FormatItem::Minute    => 14..16,
+            FormatItem::Second    => 17..19,
+        }
+    }
+}
+
+/// 形式の要素か，そのまま使う文字列（"%%" は "%" にしたもの）
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Piece {
+    Item(FormatItem),
+    Text(String),
+}
+
+/// ファイル名の撮影日時の形式（--date-format，"%Y%m%d-%H%M%S" のようなstrftimeに似た書き方）
+///
+/// 日付の指定子（%Y，%y，%m，%d）までをテンプレートの{date}に，時刻の指定子（%H，%M，%S）からを{time}にし，
+/// 間の文字列を既定の名前での区切りにする．どの指定子も桁数が決まっているので，付けた名前から読み戻せる．
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DateFormat {
+    date: Vec<Piece>,
+    separator: String,
+    /// 時刻の指定子が無い場合は空
+    time: Vec<Piece>,
+}
+
+impl Default for DateFormat {
+    fn default() -> DateFormat {
+        DateFormat::parse(DEFAULT_DATE_FORMAT).unwrap()
+    }
+}
+
+impl DateFormat {
+    /// 形式の文字列を読む（clapのvalue_parserとして使い，ファイルを処理する前に間違いを知らせる）．
+    ///
+    /// 知らない指定子や同じ指定子の繰り返し，日付の指定子が揃っていない場合，時刻の指定子が日付の指定子より前にある場合，
+    /// ファイル名に使えない文字がある場合は，どこが悪いかを書いたエラーを返す．
+    pub fn parse(s: &str) -> Result<DateFormat, String> {
+        let mut pieces = parse_pieces(s, "date format")?;
+        let is_year = |item: &FormatItem| matches!(item, FormatItem::Year | FormatItem::ShortYear);
+        let items: Vec<FormatItem> = pieces.iter().filter_map(|piece| match piece {
+            Piece::Item(item) => Some(*item),
+            Piece::Text(_) => None,
+        }).collect();
+        for (i, item) in items.iter().enumerate() {
+            if items[..i].contains(item) || (is_year(item) && items[..i].iter().any(is_year)) {
+                return Err(format!("The date format has \"%{}\" more than once (%Y and %y count as the same).", specifier(*item)));
+            }
+        }
+
+        let has = |item: FormatItem| pieces.contains(&Piece::Item(item));
+        if !(has(FormatItem::Year) || has(FormatItem::ShortYear)) || !has(FormatItem::Month) || !has(FormatItem::Day) {
+            return Err("The date format needs %Y (or %y), %m and %d.".to_string());
+        }
+        let has_time = has(FormatItem::Hour) || has(FormatItem::Minute) || has(FormatItem::Second);
+        if has_time && !(has(FormatItem::Hour) && has(FormatItem::Minute)) {
+            return Err("The date format needs both %H and %M if it has the time.".to_string());
+        }
+        let is_date = |piece: &Piece| matches!(piece, Piece::Item(item) if item.is_date());
+        let is_time = |piece: &Piece| matches!(piece, Piece::Item(item) if !item.is_date());
+        let last_date = pieces.iter().rposition(is_date).unwrap();
+        let first_time = match pieces.iter().position(is_time) {
+            Some(first_time) => first_time,
+            None => return Ok(DateFormat { date: pieces, separator: String::new(), time: Vec::new() }),
+        };
+        if first_time < last_date {
+            return Err(format!("The date specifiers (%Y, %y, %m, %d) must come before the time specifiers (%H, %M, %S): {}", s));
+        }
+        let time = pieces.split_off(first_time);
+        let separator = match pieces.len() - 1 > last_date {
+            true => match pieces.pop() {
+                Some(Piece::Text(text)) => text,
+                _ => unreachable!(),
+            },
+            false => String::new(),
+        };
+        Ok(DateFormat { date: pieces, separator, time })
+    }
+
+    /// Exifの日時形式の日時を，撮影日と時刻（時刻の指定子が無い形式の場合はNone）の文字列にする．
+    ///
+    /// secondsがfalseの場合は%Sを除く（--precision minuteの既定の形式）．
+    pub fn render(&self, date_time: &[u8; 19], seconds: bool) -> (String, Option<String>) {
+        let time = (!self.time.is_empty()).then(|| render_pieces(&self.time, date_time, seconds));
+        (render_pieces(&self.date, date_time, seconds), time)
+    }
+
+    /// 撮影日と時刻をつないで，既定の名前の先頭に入れる文字列にする．
+    pub fn join(&self, date: &str, time: Option<&str>) -> String {
+        match time {
+            Some(time) => format!("{}{}{}", date, self.separator, time),
+            None => date.to_string(),
+        }
+    }
+
+    /// 撮影日（{date}）の形をしているかどうかを返す．
+    pub fn is_date(&self, value: &str) -> bool {
+        match_pieces(&self.date, value) == Some(value.len())
+    }
+
+    /// 時刻（{time}）の形をしているかどうかを返す．
+    ///
+    /// --precisionを変える前に付けた名前も一致するように，末尾の%Sが無いものや，秒未満（".42"）が付いたものも受け付ける．
+    pub fn is_time(&self, value: &str) -> bool {
+        self.match_time(value) == Some(value.len())
+    }
+
+    /// 名前の先頭の撮影日時を読み，撮影日，時刻，残り（区切りの "_" の後，無ければ空）に分ける．
+    /// 
+    /// 先頭がこの形式の撮影日時でない場合や，撮影日時の後に "_" 以外が続く場合はNoneを返す．
+    pub fn split<'a>(&self, s: &'a str) -> Option<(&'a str, Option<&'a str>, &'a str)> {
+        let date_len = match_pieces(&self.date, s)?;
+        let (time, end) = if self.time.is_empty() {
+            (None, date_len)
+        } else {
+            let rest = s[date_len..].strip_prefix(self.separator.as_str())?;
+            let time_len = self.match_time(rest)?;
+            (Some(&rest[..time_len]), s.len() - rest.len() + time_len)
+        };
+        let rest = match &s[end..] {
+            "" => "",
+            rest => rest.strip_prefix('_')?,
+        };
+        Some((&s[..date_len], time, rest))
+    }
+
+    /// 先頭の時刻の長さを返す（is_timeを参照）．
+    fn match_time(&self, s: &str) -> Option<usize> {
+        if self.time.is_empty() {
+            return None;
+        }
+        let without_second = match self.time.last() {
+            Some(Piece::Item(FormatItem::Second)) => Some(&self.time[..self.time.len() - 1]),
+            _ => None,
+        };
+        let len = match_pieces(&self.time, s).or_else(|| match_pieces(without_second?, s))?;
+        let sub_sec = s[len..].strip_prefix('.')
+            .filter(|rest| rest.len() >= 2 && rest.as_bytes()[..2].iter().all(u8::is_ascii_digit) && !rest.as_bytes().get(2).is_some_and(u8::is_ascii_digit));
+        Some(len + sub_sec.map_or(0, |_| 3))
+    }
+}
+
+/// 撮影日時で分けるフォルダの形式（--layout，"%Y/%Y-%m" のように "/" で区切った，strftimeに似た書き方）
+///
+/// --date-formatと違い，同じ指定子を何度使ってもよく，使わない指定子があってもよい．
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Layout {
+    /// フォルダごとの形式の要素の列
+    dirs: Vec<Vec<Piece>>,
+}
+
+impl Layout {
+    /// 形式の文字列を読む（clapのvalue_parserとして使い，ファイルを処理する前に間違いを知らせる）．
+    ///
+    /// 知らない指定子やフォルダ名に使えない文字がある場合，空や "."，".." のフォルダ名がある場合，指定子が一つも無い場合はエラーを返す．
+    pub fn parse(s: &str) -> Result<Layout, String> {
+        let mut dirs = Vec::new();
+        for dir in s.split('/') {
+            if dir.is_empty() || dir == "." || dir == ".." {
+                return Err(format!("The layout has an empty, \".\" or \"..\" folder name: {}", s));
+            }
+            dirs.push(parse_pieces(dir, "layout")?);
+        }
+        if !dirs.iter().flatten().any(|piece| matches!(piece, Piece::Item(_))) {
+            return Err(format!("The layout needs at least one specifier ({}).", SPECIFIERS));
+        }
+        Ok(Layout { dirs })
+    }
+
+    /// Exifの日時形式の日時を入れるフォルダ（基準のフォルダからの相対パス）を返す．
+    pub fn render(&self, date_time: &[u8; 19]) -> PathBuf {
+        self.dirs.iter().map(|pieces| render_pieces(pieces, date_time, true)).collect()
+    }
+}
+
+/// 形式の文字列を要素の列にする（whatはエラーの表示用の名前）．
+fn parse_pieces(s: &str, what: &str) -> Result<Vec<Piece>, String> {
+    let mut pieces = Vec::new();
+    let mut text = String::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            crate::template::check_char(c)?;
+            text.push(c);
+            continue;
+        }
+        let item = match chars.next() {
+            Some('%') => {
+                text.push('%');
+                continue;
+            },
+            Some('Y') => FormatItem::Year,
+            Some('y') => FormatItem::ShortYear,
+            Some('m') => FormatItem::Month,
+            Some('d') => FormatItem::Day,
+            Some('H') => FormatItem::Hour,
+            Some('M') => FormatItem::Minute,
+            Some('S') => FormatItem::Second,
+            Some(c) => return Err(format!("Unknown specifier \"%{}\" in the {}. Available specifiers: {} (\"%%\" for \"%\").", c, what, SPECIFIERS)),
+            None => return Err(format!("\"%\" at the end of the {} is not a specifier (write \"%%\" for the character itself).", what)),
+        };
+        if !text.is_empty() {
+            pieces.push(Piece::Text(std::mem::take(&mut text)));
+        }
+        pieces.push(Piece::Item(item));
+    }
+    if !text.is_empty() {
+        pieces.push(Piece::Text(text));
+    }
+    Ok(pieces)
+}
+
+/// 要素の列に日時を入れた文字列を返す（secondsがfalseの場合は%Sを除く）．
+fn render_pieces(pieces: &[Piece], date_time: &[u8; 19], seconds: bool) -> String {
+    pieces.iter().map(|piece| match piece {
+        Piece::Text(text) => text.as_str(),
+        Piece::Item(FormatItem::Second) if !seconds => "",
+        Piece::Item(item) => std::str::from_utf8(&date_time[item.range()]).unwrap(),
+    }).collect()
+}
+
+/// 指定子の書き方（エラーの表示用）
+fn specifier(item: FormatItem) -> &'static str {
+    match item {
+        FormatItem::Year      => "Y",
+        FormatItem::ShortYear => "y",
+        FormatItem::Month     => "m",
+        FormatItem::Day       => "d",
+        FormatItem::Hour      => "H",
+        FormatItem::Minute    => "M",
+        FormatItem::Second    => "S",
+    }
+}
+
+/// 形式の要素の列が先頭に一致する場合は，その長さを返す（指定子は決まった桁数の数字に一致する）．
+fn match_pieces(pieces: &[Piece], s: &str) -> Option<usize> {
+    let mut len = 0;
+    for piece in pieces {
+        let rest = &s[len..];
+        len += match piece {
+            Piece::Text(text) => rest.strip_prefix(text.as_str()).map(|_| text.len())?,
+            Piece::Item(item) => {
+                let digits = item.range().len();
+                rest.as_bytes().get(..digits).filter(|digits| digits.iter().all(u8::is_ascii_digit))?;
+                digits
+            },
+        };
+    }
+    Some(len)
+}
+
+#[cfg(all(test, windows))]
+mod tests {
+    use super::*;
+
+    /// 夏時間の無いタイムゾーン（CIではTokyo Standard Time）では，どの日時でもUTCからのずれがBiasの符号を変えたものになる．
+    #[test]
+    fn local_offset_matches_the_time_zone_bias() {
+        use windows_sys::Win32::System::Time::{GetTimeZoneInformation, TIME_ZONE_INFORMATION};
+        /// GetTimeZoneInformationが，夏時間の無いタイムゾーンの場合に返す値
+        const TIME_ZONE_ID_UNKNOWN: u32 = 0;
+
+        // SAFETY: tzはGetTimeZoneInformationが書き込むための領域で，ゼロ初期化しても問題ない
+        let mut tz: TIME_ZONE_INFORMATION = unsafe { std::mem::zeroed() };
+        if unsafe { GetTimeZoneInformation(&mut tz) } != TIME_ZONE_ID_UNKNOWN {
+            return;
+        }
+        // 2023-01-23 14:30:27と2023-07-01 00:00:00（UTC）
+        for secs in [1674484227, 1688169600] {
+            assert_eq!(local_offset(secs), -(tz.Bias as i64) * 60);
+        }
+    }
+}
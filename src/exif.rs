@@ -1,19 +1,192 @@
 //! Exifデータの読み出し・修正を行うためのモジュール
-//! JPEGのみ
-//! 規格としてはPNGも補助チャンク内にExifメタデータを保持できる
+//! JPEGはAPP1セグメント，PNGはeXIfチャンクに格納されたTIFF構造を読む
+//! TIFFファイルやDNGはファイル自体がTIFF構造なので先頭から読む
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::datetime;
+use crate::jpeg;
 
 enum ByteOrder {
     BigEndian,
     LittleEndian,
 }
 
+/// TIFFヘッダのマジックナンバー（バイトオーダーの直後の2byte）
+/// 
+/// 標準のTIFFは42だが，RAWファイルの中には独自の値を使っているものがある．
+const TIFF_MAGICS: [u16; 4] = [
+    0x002A,  // 標準のTIFF
+    0x4F52,  // Olympus ORF（"IIRO", "MMOR"）
+    0x5352,  // Olympus ORF（"IIRS"）
+    0x0055,  // Panasonic RW2（"IIU\0"）
+];
+
 /// APP1セグメント内におけるTIFFヘッダの開始オフセット
 const OFFSET_TIFF_HEADER: usize = 10;
 
 // タグ番号
+const IMAGE_WIDTH: u16 = 0x0100;
+const IMAGE_LENGTH: u16 = 0x0101;
 const ORIENTATION: u16 = 0x0112;
+const MAKE: u16 = 0x010F;
+const MODEL: u16 = 0x0110;
+const SOFTWARE: u16 = 0x0131;
+const DATE_TIME: u16 = 0x0132;
+const ARTIST: u16 = 0x013B;
+const COPYRIGHT: u16 = 0x8298;
 const EXIF_IFD_POINTER: u16 = 0x8769;
+const GPS_INFO_IFD_POINTER: u16 = 0x8825;
 const DATE_TIME_ORIGINAL: u16 = 0x9003;
+const DATE_TIME_DIGITIZED: u16 = 0x9004;
+const OFFSET_TIME_ORIGINAL: u16 = 0x9011;
+const MAKER_NOTE: u16 = 0x927C;       // メーカー独自の形式のデータ（Exif IFD）
+const USER_COMMENT: u16 = 0x9286;     // 先頭8byteの文字コードに続くコメント（Exif IFD）
+const SUB_SEC_TIME_ORIGINAL: u16 = 0x9291;
+const PIXEL_X_DIMENSION: u16 = 0xA002;  // 画像の幅（Exif IFD）
+const PIXEL_Y_DIMENSION: u16 = 0xA003;  // 画像の高さ（Exif IFD）
+const IMAGE_UNIQUE_ID: u16 = 0xA420;   // カメラが画像ごとに付ける固有のID（Exif IFD）
+const LENS_MODEL: u16 = 0xA434;
+const JPEG_INTERCHANGE_FORMAT: u16 = 0x0201;         // 1st IFDのサムネイル（JPEG）の開始オフセット
+const JPEG_INTERCHANGE_FORMAT_LENGTH: u16 = 0x0202;  // 1st IFDのサムネイル（JPEG）のバイト数
+
+// GPS IFDのタグ番号
+const GPS_LATITUDE_REF: u16 = 0x0001;   // "N" or "S"
+const GPS_LATITUDE: u16 = 0x0002;       // 度，分，秒のRATIONAL 3つ
+const GPS_LONGITUDE_REF: u16 = 0x0003;  // "E" or "W"
+const GPS_LONGITUDE: u16 = 0x0004;      // 度，分，秒のRATIONAL 3つ
+const GPS_ALTITUDE_REF: u16 = 0x0005;   // 0: 海抜，1: 海面下
+const GPS_ALTITUDE: u16 = 0x0006;       // 高度（m）
+const GPS_TIME_STAMP: u16 = 0x0007;     // 時，分，秒のRATIONAL 3つ（UTC）
+const GPS_DATE_STAMP: u16 = 0x001D;     // "YYYY:MM:DD"（UTC）
+
+// valueのタイプ番号
+const BYTE: u16 = 1;       // 8bit符号無し整数
+const ASCII: u16 = 2;      // ASCII文字列（NULL終端）
+const SHORT: u16 = 3;      // 16bit符号無し整数
+const LONG: u16 = 4;       // 32bit符号無し整数
+const RATIONAL: u16 = 5;   // LONG 2つ（分子，分母）
+const SBYTE: u16 = 6;      // 8bit符号付き整数
+const UNDEFINED: u16 = 7;  // 任意の1byteデータ
+const SSHORT: u16 = 8;     // 16bit符号付き整数
+const SLONG: u16 = 9;      // 32bit符号付き整数
+const SRATIONAL: u16 = 10; // SLONG 2つ（分子，分母）
+const FLOAT: u16 = 11;     // 単精度浮動小数点数
+const DOUBLE: u16 = 12;    // 倍精度浮動小数点数
+
+/// タイプごとの1要素あたりのバイト数を返す．知らないタイプの場合はNone．
+fn type_size(value_type: u16) -> Option<usize> {
+    match value_type {
+        BYTE | ASCII | SBYTE | UNDEFINED => Some(1),
+        SHORT | SSHORT => Some(2),
+        LONG | SLONG | FLOAT => Some(4),
+        RATIONAL | SRATIONAL | DOUBLE => Some(8),
+        _ => None,
+    }
+}
+
+/// GPS IFDから読み出した位置情報．
+/// 
+/// GPS IFDはあっても一部のタグが無い場合があるので，それぞれ無ければNone．
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Gps {
+    /// 緯度（度，北緯が正，南緯が負）
+    pub latitude: Option<f64>,
+    /// 経度（度，東経が正，西経が負）
+    pub longitude: Option<f64>,
+    /// 高度（m，海面下は負）
+    pub altitude: Option<f64>,
+}
+
+/// 0th IFDとExif IFDから読み出した撮影機材の情報．
+/// 
+/// 前後の空白と終端のNULL文字は取り除いてあり，タグが無い場合や空文字列の場合はNone．
+#[allow(dead_code)]
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Camera {
+    /// メーカー名（Make，例: "Canon"）
+    pub make: Option<String>,
+    /// 機種名（Model，例: "Canon EOS R5"）
+    pub model: Option<String>,
+    /// レンズの名前（LensModel，例: "RF24-105mm F4 L IS USM"）
+    pub lens_model: Option<String>,
+    /// 撮影・編集に使ったソフトウェア（Software，例: "17.1"）
+    pub software: Option<String>,
+}
+
+/// Exifを持たない画像に新しく作るExifのタグ（build_app1）．
+/// 
+/// どのIFDに書くかはタグごとに決まっている．
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NewTag<'a> {
+    /// 画像の回転情報（0th IFD）
+    Orientation(u16),
+    /// ファイル変更日時（0th IFD）
+    DateTime(&'a [u8; 19]),
+    /// 撮影日時（Exif IFD）
+    DateTimeOriginal(&'a [u8; 19]),
+    /// デジタル化した日時（Exif IFD）
+    DateTimeDigitized(&'a [u8; 19]),
+    /// 撮影者（0th IFD，ASCII文字だけにすること）
+    Artist(&'a str),
+    /// 著作権表示（0th IFD，ASCII文字だけにすること）
+    Copyright(&'a str),
+    /// コメント（Exif IFD，ASCII以外の文字を含む場合はUnicodeで書く）
+    UserComment(&'a str),
+}
+
+/// Exifの中のIFDの種類
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Ifd {
+    /// 0th IFD（主画像の情報）
+    Ifd0,
+    /// Exif IFD（撮影条件など）
+    Exif,
+    /// GPS IFD（位置情報）
+    Gps,
+    /// 1st IFD（サムネイルの情報）
+    Ifd1,
+}
+
+/// タイプに従って復号したタグのvalue（要素ごとに並べたもの）
+#[derive(Clone, Debug, PartialEq)]
+pub enum TagValue {
+    Byte(Vec<u8>),
+    /// 終端のNULL文字や詰め物も含めたバイト列
+    Ascii(Vec<u8>),
+    Short(Vec<u16>),
+    Long(Vec<u32>),
+    /// （分子，分母）
+    Rational(Vec<(u32, u32)>),
+    SByte(Vec<i8>),
+    Undefined(Vec<u8>),
+    SShort(Vec<i16>),
+    SLong(Vec<i32>),
+    /// （分子，分母）
+    SRational(Vec<(i32, i32)>),
+    Float(Vec<f32>),
+    Double(Vec<f64>),
+}
+
+/// 0th IFD，Exif IFD，GPS IFD，1st IFDの全てのタグを読み出したExifデータ．
+/// 
+/// 壊れたIFDやスライスの範囲外を指すタグは読み飛ばすので，読めた分だけを持つ．
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Exif {
+    entries: BTreeMap<(Ifd, u16), TagValue>,
+}
+
+/// read_tagで読み出したタグの情報
+struct Tag<'a> {
+    /// valueのタイプ番号
+    value_type: u16,
+    /// valueの要素数（ASCIIの場合はNULL文字を含む文字数）
+    count: usize,
+    /// valueが書かれた領域（長さは1要素あたりのバイト数 × 要素数）
+    value: &'a [u8],
+}
 
 /// 2byteのスライスをu16として復号する．
 /// 
@@ -41,172 +214,694 @@ fn decode_u32(slice: &[u8], byte_order: &ByteOrder) -> u32 {
     }
 }
 
-/// 回転情報を消した（回転なしの状態にした）APP1セグメントを返す．
-pub fn clear_orientation(jpeg_binary: &[u8]) -> Vec<u8> {
-    let ref_app1 = get_app1(jpeg_binary).unwrap();
-    let mut app1 = vec![0; ref_app1.len()];
-    app1.copy_from_slice(ref_app1);
+/// u16をバイトオーダーに従って2byteにする．
+fn encode_u16(value: u16, byte_order: &ByteOrder) -> [u8; 2] {
+    match byte_order {
+        ByteOrder::BigEndian    => value.to_be_bytes(),
+        ByteOrder::LittleEndian => value.to_le_bytes(),
+    }
+}
 
-    let byte_order = if app1[OFFSET_TIFF_HEADER..(OFFSET_TIFF_HEADER + 2)] == [0x4D, 0x4D] {
-        ByteOrder::BigEndian
-    } else {
-        ByteOrder::LittleEndian
-    };
+/// u32をバイトオーダーに従って4byteにする．
+fn encode_u32(value: u32, byte_order: &ByteOrder) -> [u8; 4] {
+    match byte_order {
+        ByteOrder::BigEndian    => value.to_be_bytes(),
+        ByteOrder::LittleEndian => value.to_le_bytes(),
+    }
+}
 
-    // 0th IFDのオフセットを読む．起点はTIFFヘッダの先頭（Exif識別子の直後）．
-    let offset_0th_ifd = decode_u32(&app1[(OFFSET_TIFF_HEADER + 4)..(OFFSET_TIFF_HEADER + 8)], &byte_order) as usize;
+/// APP1セグメント（マーカを含む）がExifを格納したものかどうかを返す．
+/// 
+/// Exif識別子の後ろのTIFFヘッダが壊れている（バイトオーダーがII・MMでない，マジックナンバーが違う）ものは，
+/// IFDのオフセットを読み違えないようにExifとして扱わない（read_tiff_headerで確かめる）．
+pub fn is_exif_app1(app1: &[u8]) -> bool {
+    app1.get(4..9) == Some(b"Exif\0") && app1.get(OFFSET_TIFF_HEADER..).is_some_and(has_tiff_header)
+}
 
-    // Orientationを読む
-    let orientation_slice = read_tag(&app1, offset_0th_ifd, ORIENTATION, &byte_order);
-    if orientation_slice.is_some() {
-        // スライスが元の配列のどこの部分であるかを逆算して，orientationタグのvalueを書き直す．
-        let app1_ptr = app1.as_ptr();
-        let orientation_ptr = orientation_slice.unwrap().as_ptr();
-
-        // APP1セグメント内におけるOrientationタグのvalueの開始オフセット
-        let orientation_offset = orientation_ptr as usize - app1_ptr as usize;
-        let tmp = match byte_order {  // 1（回転なし）を書き込む
-            ByteOrder::BigEndian => 1_u16.to_be_bytes(),
-            ByteOrder::LittleEndian => 1_u16.to_le_bytes(),
+/// JPEG画像のExifを格納したAPP1セグメントの位置を返す．
+/// 
+/// SOI直後からSOSまでのセグメントを辿るので，圧縮データ中の偶然の "FF E1" は拾わない．
+/// XMPなどの他のAPP1セグメントは読み飛ばす．
+fn find_exif_segment(jpeg_binary: &[u8]) -> Option<jpeg::Segment> {
+    jpeg::segments(jpeg_binary)
+        .take_while(|s| s.marker != jpeg::SOS)
+        .find(|s| s.marker == jpeg::APP1 && is_exif_app1(&jpeg_binary[s.start..s.end]))
+}
+
+/// APP1セグメント（マーカを含む）のOrientationタグを1（回転なし）に書き換えたものを返す．
+/// 
+/// 0th IFDに続く1st IFD（サムネイル）にもOrientationタグがあれば，そちらも書き換える．
+/// TIFFヘッダが壊れている場合やOrientationタグが無い場合はそのまま返す．
+pub fn clear_app1_orientation(mut app1: Vec<u8>) -> Vec<u8> {
+    let (orientation_offsets, byte_order) = {
+        let tiff = match app1.get(OFFSET_TIFF_HEADER..) {
+            Some(tiff) => tiff,
+            None => return app1,
         };
+        let (byte_order, offset_0th_ifd) = match read_tiff_header(tiff) {
+            Some(header) => header,
+            None => return app1,
+        };
+
+        // 0th IFDと1st IFDのOrientationを読む
+        let ifd_offsets = [Some(offset_0th_ifd), next_ifd_offset(tiff, offset_0th_ifd, &byte_order)];
+        let orientation_offsets: Vec<usize> = ifd_offsets.into_iter().flatten()
+            .filter_map(|ifd_offset| match read_tag(tiff, ifd_offset, ORIENTATION, &byte_order) {
+                // スライスが元の配列のどこの部分であるかを逆算する（APP1セグメント内におけるOrientationタグのvalueの開始オフセット）
+                Some(tag) if tag.value_type == SHORT && tag.count >= 1 => {
+                    Some(tag.value.as_ptr() as usize - app1.as_ptr() as usize)
+                },
+                _ => None,
+            })
+            .collect();
+        (orientation_offsets, byte_order)
+    };
+
+    // orientationタグのvalueを書き直す．
+    let tmp = encode_u16(1, &byte_order);  // 1（回転なし）を書き込む
+    for orientation_offset in orientation_offsets {
         app1[orientation_offset] = tmp[0];
         app1[orientation_offset + 1] = tmp[1];
     }
     app1
 }
 
-/// APP0セグメントの次のセグメントの先頭のインデックスを返す．
+/// APP1セグメント（マーカを含む）に書かれた画像の幅と高さを，width，heightに書き換えたものを返す．
+/// 
+/// 画像を回転させた場合に，Exif IFDのPixelXDimension・PixelYDimensionと
+/// 0th IFDのImageWidth・ImageLengthが回転前の縦横のままにならないようにする．
+/// SHORTとLONGのどちらで書かれていてもその場で書き換える（SHORTに収まらない場合はそのままにする）．
+pub fn set_app1_dimensions(mut app1: Vec<u8>, width: u32, height: u32) -> Vec<u8> {
+    let (dimension_fields, byte_order) = {
+        let tiff = match app1.get(OFFSET_TIFF_HEADER..) {
+            Some(tiff) => tiff,
+            None => return app1,
+        };
+        let (byte_order, offset_0th_ifd) = match read_tiff_header(tiff) {
+            Some(header) => header,
+            None => return app1,
+        };
+
+        let offset_exif_ifd = read_tag(tiff, offset_0th_ifd, EXIF_IFD_POINTER, &byte_order)
+            .filter(|tag| tag.value.len() == 4)
+            .map(|tag| decode_u32(tag.value, &byte_order) as usize);
+        let dimension_fields: Vec<(usize, u16, u32)> = [
+            offset_exif_ifd.map(|ifd| (ifd, PIXEL_X_DIMENSION, width)),
+            offset_exif_ifd.map(|ifd| (ifd, PIXEL_Y_DIMENSION, height)),
+            Some((offset_0th_ifd, IMAGE_WIDTH, width)),
+            Some((offset_0th_ifd, IMAGE_LENGTH, height)),
+        ].into_iter().flatten()
+            .filter_map(|(ifd_offset, tag, value)| match read_tag(tiff, ifd_offset, tag, &byte_order) {
+                // スライスが元の配列のどこの部分であるかを逆算する（APP1セグメント内におけるvalueの開始オフセット）
+                Some(tag) if (tag.value_type == SHORT || tag.value_type == LONG) && tag.count >= 1 => {
+                    Some((tag.value.as_ptr() as usize - app1.as_ptr() as usize, tag.value_type, value))
+                },
+                _ => None,
+            })
+            .collect();
+        (dimension_fields, byte_order)
+    };
+
+    for (value_offset, value_type, value) in dimension_fields {
+        if value_type == LONG {
+            app1[value_offset..(value_offset + 4)].copy_from_slice(&encode_u32(value, &byte_order));
+        } else if let Ok(value) = u16::try_from(value) {
+            app1[value_offset..(value_offset + 2)].copy_from_slice(&encode_u16(value, &byte_order));
+        }
+    }
+    app1
+}
+
+/// APP1セグメント（マーカを含む）の1st IFDにサムネイル（JPEG）があるかどうかを返す．
+pub fn has_app1_thumbnail(app1: &[u8]) -> bool {
+    get_thumbnail_fields(app1.get(OFFSET_TIFF_HEADER..).unwrap_or_default()).is_some()
+}
+
+/// APP1セグメント（マーカを含む）の1st IFDのサムネイル（JPEG）をthumbnailに差し替えたものを返す．
+/// 
+/// 元のサムネイルの領域は0で塗り潰し，新しいサムネイルはTIFFデータの末尾に追加する
+/// （元のサムネイルが末尾にある場合は，その分を切り詰めてから追加する）．
+/// thumbnailがNoneの場合は，0th IFDから1st IFDへのオフセットを0にしてサムネイルを削除する．
+/// サムネイルが無い場合やAPP1セグメントに収まらない場合はNoneを返す．
+pub fn replace_app1_thumbnail(app1: &[u8], thumbnail: Option<&[u8]>) -> Option<Vec<u8>> {
+    let tiff = app1.get(OFFSET_TIFF_HEADER..)?;
+    let (byte_order, offset_0th_ifd) = read_tiff_header(tiff)?;
+    let (offset_field, length_field) = get_thumbnail_fields(tiff)?;
+    let old_offset = decode_u32(&tiff[(offset_field + 8)..(offset_field + 12)], &byte_order) as usize;
+    let old_length = decode_u32(&tiff[(length_field + 8)..(length_field + 12)], &byte_order) as usize;
+
+    let mut tiff = tiff.to_vec();
+    if let Some(old_end) = old_offset.checked_add(old_length).filter(|end| *end <= tiff.len()) {
+        tiff[old_offset..old_end].fill(0);
+        if old_end == tiff.len() {
+            tiff.truncate(old_offset);
+        }
+    }
+
+    match thumbnail {
+        Some(thumbnail) => {
+            let new_offset = u32::try_from(tiff.len()).ok()?;
+            let new_length = u32::try_from(thumbnail.len()).ok()?;
+            tiff.extend_from_slice(thumbnail);
+            tiff[(offset_field + 8)..(offset_field + 12)].copy_from_slice(&encode_u32(new_offset, &byte_order));
+            tiff[(length_field + 8)..(length_field + 12)].copy_from_slice(&encode_u32(new_length, &byte_order));
+        },
+        None => {
+            let tag_num = decode_u16(&tiff[offset_0th_ifd..(offset_0th_ifd + 2)], &byte_order) as usize;
+            let pointer_offset = offset_0th_ifd + 2 + 12 * tag_num;
+            tiff[pointer_offset..(pointer_offset + 4)].fill(0);
+        },
+    }
+    app1_from_tiff(&tiff)
+}
+
+/// 1st IFDのサムネイルの開始オフセットとバイト数のタグフィールドの位置（起点はTIFFヘッダの先頭）を返す．
+/// 
+/// どちらもLONGで書かれている必要がある．1st IFDやタグが無い場合はNoneを返す．
+fn get_thumbnail_fields(tiff: &[u8]) -> Option<(usize, usize)> {
+    let (byte_order, offset_0th_ifd) = read_tiff_header(tiff)?;
+    let offset_1st_ifd = next_ifd_offset(tiff, offset_0th_ifd, &byte_order)?;
+    let field = |tag: u16| -> Option<usize> {
+        let field = find_tag_field(tiff, offset_1st_ifd, tag, &byte_order)?;
+        let is_long = decode_u16(&tiff[(field + 2)..(field + 4)], &byte_order) == LONG
+            && decode_u32(&tiff[(field + 4)..(field + 8)], &byte_order) == 1;
+        if is_long { Some(field) } else { None }
+    };
+    Some((field(JPEG_INTERCHANGE_FORMAT)?, field(JPEG_INTERCHANGE_FORMAT_LENGTH)?))
+}
+
+/// TIFF構造のExifデータ（TIFFヘッダから始まる）をJPEGのAPP1セグメント（マーカを含む）に包む．
+/// 
+/// APP1セグメントに収まらない大きさの場合はNoneを返す．
+pub fn app1_from_tiff(tiff: &[u8]) -> Option<Vec<u8>> {
+    // セグメント長は長さ自身の2byteとExif識別子の6byteを含む
+    let segment_len = u16::try_from(tiff.len() + 8).ok()?;
+    let mut app1 = Vec::with_capacity(segment_len as usize + 2);
+    app1.extend_from_slice(&[0xFF, 0xE1]);
+    app1.extend_from_slice(&segment_len.to_be_bytes());
+    app1.extend_from_slice(b"Exif\0\0");
+    app1.extend_from_slice(tiff);
+    Some(app1)
+}
+
+/// Exifを持たないJPEG画像のAPP0セグメントの後ろにAPP1セグメントなど（複数のセグメントを連結したものでも良い）を挿入したものを返す．
+pub fn insert_after_app0(jpeg_binary: &[u8], segments: &[u8]) -> Result<Vec<u8>, &'static str> {
+    let next_app0 = next_app0_index(jpeg_binary)?;
+    let mut inserted = Vec::with_capacity(jpeg_binary.len() + segments.len());
+    inserted.extend_from_slice(&jpeg_binary[..next_app0]);
+    inserted.extend_from_slice(segments);
+    inserted.extend_from_slice(&jpeg_binary[next_app0..]);
+    Ok(inserted)
+}
+
+/// JPEG画像のExifのAPP1セグメントをapp1（マーカを含む）に置き換えたものを返す．画像データは再エンコードしない．
+/// 
+/// Exifが無い場合はAPP0セグメントの後ろに挿入する．
+pub fn replace_app1(jpeg_binary: &[u8], app1: &[u8]) -> Result<Vec<u8>, &'static str> {
+    let segment = find_exif_segment(jpeg_binary);
+    let segment = match segment {
+        Some(segment) => segment,
+        None => return insert_after_app0(jpeg_binary, app1),
+    };
+
+    let mut replaced = Vec::with_capacity(jpeg_binary.len() + app1.len());
+    replaced.extend_from_slice(&jpeg_binary[..segment.start]);
+    replaced.extend_from_slice(app1);
+    replaced.extend_from_slice(&jpeg_binary[segment.end..]);
+    Ok(replaced)
+}
+
+/// APP0セグメントの次のセグメントの先頭（APP1セグメントを挿入する位置）のインデックスを返す．
+/// 
+/// SOIの直後に続くAPP0セグメント（JFIF，JFXXなど．複数あっても良い）を全て読み飛ばした位置を返す．
+/// APP0セグメントが無い場合はSOIマーカの直後を返す．
 pub fn next_app0_index(without_app1_binary: &[u8]) -> Result<usize, &'static str> {
     // JPEG画像先頭のSOIマーカを確認
-    if without_app1_binary[..2] != [0xFF, 0xD8] {
-        return Err("SOI marker does not exist.");
+    if without_app1_binary.get(..2) != Some(&[0xFF, 0xD8]) {
+        return Err("Not a JPEG file (SOI marker does not exist).");
     }
 
-    // APP0セグメントの終端を探す
-    // APP0セグメントがない場合はSOIマーカの次のインデックスを返す．
     let mut next_app0 = 2;  // APP0の次のセグメント先頭を指すインデックス
-    for i in 2..(without_app1_binary.len() - 1) {
-        if without_app1_binary[i..(i + 2)] == [0xFF, 0xE0] {  // APP0のマーカを探す
-            // セグメント長は必ずビッグエンディアン
-            let segment_len = decode_u16(&without_app1_binary[(i+2)..(i+4)], &ByteOrder::BigEndian) as usize;
-            // ASCII文字も一応確認
-            if &without_app1_binary[(i+4)..(i+9)] == b"JFIF\0" {
-                next_app0 = i + segment_len + 2;
-                break;
-            }
+    for segment in jpeg::segments(without_app1_binary) {
+        if segment.marker != jpeg::APP0 {
+            return Ok(next_app0);
         }
+        next_app0 = segment.end;
     }
 
-    Ok(next_app0)
+    // APP0以外のセグメントに辿り着く前に終わった場合は，セグメントが壊れているか途中で切れている
+    Err("JPEG segments are broken or truncated before the image data.")
 }
 
 /// JPEG画像のバイナリデータのうちExifを格納した
 /// APP1セグメント（マーカを含む）のスライスを返す
+/// 
+/// RAFの埋め込みJPEGのように途中で切れたデータの場合もあるので，範囲外を指す場合はNoneを返す．
 pub fn get_app1(jpeg_binary: &[u8]) -> Option<&[u8]> {
-    for i in 0..(jpeg_binary.len() - 1) {
-        if jpeg_binary[i..(i + 2)] == [0xFF, 0xE1] {  // APP1のマーカを探す
-            // セグメント長は必ずビッグエンディアン
-            let segment_len = decode_u16(&jpeg_binary[(i+2)..(i+4)], &ByteOrder::BigEndian) as usize;
-            // Exif識別子を確認（XMPの可能性があるため）
-            if &jpeg_binary[(i+4)..(i+9)] == b"Exif\0" {
-                return Some(&jpeg_binary[i..(i + segment_len + 2)]);
-            }
-        }
+    find_exif_segment(jpeg_binary).map(|s| &jpeg_binary[s.start..s.end])
+}
+
+/// JPEG画像のExifデータ（APP1セグメントのTIFFヘッダから始まる部分）を返す．
+pub fn get_tiff(jpeg_binary: &[u8]) -> Option<&[u8]> {
+    get_app1(jpeg_binary)?.get(OFFSET_TIFF_HEADER..)
+}
+
+/// TIFFヘッダとして認識できるかどうかを返す．
+pub fn has_tiff_header(tiff: &[u8]) -> bool {
+    read_tiff_header(tiff).is_some()
+}
+
+/// TIFFヘッダのバイトオーダーがビッグエンディアン（MM）かどうかを返す．TIFFヘッダが壊れている場合はNone．
+pub fn is_big_endian(tiff: &[u8]) -> Option<bool> {
+    match read_tiff_header(tiff)?.0 {
+        ByteOrder::BigEndian    => Some(true),
+        ByteOrder::LittleEndian => Some(false),
     }
-    None
 }
 
-/// 指定したタグのvalueが書かれた領域をスライスで返す．
+/// TIFFヘッダを読んで，バイトオーダーと0th IFDのオフセット（起点はTIFFヘッダの先頭）を返す．
+/// 
+/// JPEGの場合はAPP1セグメントのExif識別子の直後，TIFFファイルの場合はファイルの先頭がTIFFヘッダ．
+/// マジックナンバーはORFやRW2の独自の値も受け付ける．
+fn read_tiff_header(tiff: &[u8]) -> Option<(ByteOrder, usize)> {
+    let byte_order = match tiff.get(..2)? {
+        [0x4D, 0x4D] => ByteOrder::BigEndian,
+        [0x49, 0x49] => ByteOrder::LittleEndian,
+        _ => return None
+    };
+    if !TIFF_MAGICS.contains(&decode_u16(tiff.get(2..4)?, &byte_order)) {
+        return None;
+    }
+    let offset_0th_ifd = decode_u32(tiff.get(4..8)?, &byte_order) as usize;
+
+    Some((byte_order, offset_0th_ifd))
+}
+
+/// 指定したタグのタグフィールド（12byte）の開始オフセット（起点はTIFFヘッダの先頭）を返す．
+/// 
+/// IFDやタグフィールドがスライスの範囲外の場合はNoneを返す．
+fn find_tag_field(tiff: &[u8], ifd_offset: usize, tag: u16, byte_order: &ByteOrder) -> Option<usize> {
+    let tag_num = decode_u16(tiff.get(ifd_offset..(ifd_offset + 2))?, byte_order) as usize;
+    (0..tag_num)
+        .map(|i| ifd_offset + 2 + 12 * i)
+        .take_while(|field| field + 12 <= tiff.len())
+        .find(|&field| decode_u16(&tiff[field..(field + 2)], byte_order) == tag)
+}
+
+/// IFDの末尾に書かれた次のIFDの開始オフセット（起点はTIFFヘッダの先頭）を返す．
 /// 
+/// 0th IFDの次は1st IFD（サムネイル）．次のIFDが無い場合（オフセットが0）や範囲外の場合はNoneを返す．
+fn next_ifd_offset(tiff: &[u8], ifd_offset: usize, byte_order: &ByteOrder) -> Option<usize> {
+    let tag_num = decode_u16(tiff.get(ifd_offset..(ifd_offset + 2))?, byte_order) as usize;
+    let pointer_offset = ifd_offset + 2 + 12 * tag_num;
+    let next = decode_u32(tiff.get(pointer_offset..(pointer_offset + 4))?, byte_order) as usize;
+    if next == 0 || next == ifd_offset || next >= tiff.len() {
+        None
+    } else {
+        Some(next)
+    }
+}
+
+/// 指定したタグのタイプ，要素数と，valueが書かれた領域のスライスを返す．
+/// 
+/// * tiff: TIFFヘッダの先頭から始まるスライス（JPEGの場合はAPP1セグメントのExif識別子より後ろ）
 /// * ifd_offset: タグを読み出したいIFDの開始オフセット（起点はTIFFヘッダの先頭）
 /// * tag: タグ番号
 /// * byte_order: TIFFヘッダに書かれているバイトオーダー
-fn read_tag<'a>(app1: &'a [u8], ifd_offset: usize, tag: u16, byte_order: &ByteOrder) -> Option<&'a [u8]> {
+/// 
+/// RAWファイルなどで先頭部分しか読み込んでいない場合もあるので，
+/// IFDやvalueがスライスの範囲外を指している場合はNoneを返す．
+/// 知らないタイプのタグは大きさが分からないので読み飛ばす．
+/// 要素数が0の場合は空のスライスを返すので，呼び出し側で長さを確認すること．
+fn read_tag<'a>(tiff: &'a [u8], ifd_offset: usize, tag: u16, byte_order: &ByteOrder) -> Option<Tag<'a>> {
     // タグ数を読む
-    let tmp = OFFSET_TIFF_HEADER + ifd_offset;
-    let tag_num = decode_u16(&app1[tmp..(tmp + 2)], byte_order) as usize;
+    let tag_num = decode_u16(tiff.get(ifd_offset..(ifd_offset + 2))?, byte_order) as usize;
     
     let tag_bytes = match byte_order {
         ByteOrder::BigEndian    => tag.to_be_bytes(),
         ByteOrder::LittleEndian => tag.to_le_bytes(),
     };
 
-    let mut tag_field_offset = tmp + 2;  // タグフィールドの開始オフセット
+    let mut tag_field_offset = ifd_offset + 2;  // タグフィールドの開始オフセット
     for _ in 0..tag_num {
-        if app1[tag_field_offset..(tag_field_offset + 2)] == tag_bytes {  // タグをチェック
-            // valueのタイプを確認（SHORTかASCIIか...とか）
-            let value_type = decode_u16(&app1[(tag_field_offset + 2)..(tag_field_offset + 4)], byte_order);
-            
-            // valueのカウントを確認
-            let count = decode_u32(&app1[(tag_field_offset + 4)..(tag_field_offset + 8)], byte_order) as usize;
-
-            // valueを表現するのに必要なデータ長を計算する
-            let value_bytes = match value_type {
-                2 => 1,  // ASCII（1文字1byte）
-                3 => 2,  // SHORT (16bit符号無し整数)
-                4 => 4,  // LONG （32bit符号無し整数）
-                _ => return None
-            } * count;
-
-            if value_bytes <= 4 {
-                // 4byte以下のデータはオフセット領域に直書きされている（左詰め）
-                return Some( &app1[(tag_field_offset + 8)..(tag_field_offset + 8 + value_bytes)] );
-            } else {
-                // valueのオフセットを調べる（起点はTIFFヘッダの先頭）
-                let value_offset = decode_u32(&app1[(tag_field_offset + 8)..(tag_field_offset + 12)], byte_order) as usize;
+        let tag_field = tiff.get(tag_field_offset..(tag_field_offset + 12))?;
+        tag_field_offset += 12;  // 次のタグフィールドへ
+        if tag_field[..2] != tag_bytes {  // タグをチェック
+            continue;
+        }
+        match read_field(tiff, tag_field, byte_order) {
+            Some(tag) => return Some(tag),
+            None => continue,
+        }
+    }
 
-                return Some( &app1[(OFFSET_TIFF_HEADER + value_offset)..(OFFSET_TIFF_HEADER + value_offset + value_bytes)] );
+    None
+}
+
+/// タグフィールド（12byte）から，タグのタイプ，要素数と，valueが書かれた領域のスライスを読む．
+/// 
+/// 知らないタイプの場合や，valueがスライスの範囲外を指している場合はNoneを返す．
+fn read_field<'a>(tiff: &'a [u8], tag_field: &'a [u8], byte_order: &ByteOrder) -> Option<Tag<'a>> {
+    // valueのタイプを確認（SHORTかASCIIか...とか）
+    let value_type = decode_u16(&tag_field[2..4], byte_order);
+    
+    // valueのカウントを確認
+    let count = decode_u32(&tag_field[4..8], byte_order) as usize;
+
+    // valueを表現するのに必要なデータ長を計算する
+    let value_bytes = type_size(value_type)?.checked_mul(count)?;
+
+    let value = if value_bytes <= 4 {
+        // 4byte以下のデータはオフセット領域に直書きされている（左詰め）
+        &tag_field[8..(8 + value_bytes)]
+    } else {
+        // valueのオフセットを調べる（起点はTIFFヘッダの先頭）
+        let value_offset = decode_u32(&tag_field[8..12], byte_order) as usize;
+        tiff.get(value_offset..value_offset.checked_add(value_bytes)?)?
+    };
+    Some(Tag { value_type, count, value })
+}
+
+/// Exif::from_tiffで読み出すvalueの合計バイト数の上限．
+/// 
+/// 壊れたファイルで多数のタグが同じ大きな領域を指している場合に，メモリを使い果たさないようにする．
+const MAX_EXIF_VALUE_BYTES: usize = 16 * 1024 * 1024;
+
+impl Exif {
+    /// TIFFヘッダから始まるExifデータ（TIFFファイルやRAWファイルの先頭部分など）を読み出す．
+    /// 
+    /// TIFFヘッダが壊れている場合はNoneを返す．
+    pub fn from_tiff(tiff: &[u8]) -> Option<Exif> {
+        let (byte_order, offset_0th_ifd) = read_tiff_header(tiff)?;
+        let mut exif = Exif::default();
+        let mut budget = MAX_EXIF_VALUE_BYTES;
+        exif.read_ifd(tiff, Ifd::Ifd0, offset_0th_ifd, &byte_order, &mut budget);
+
+        // Exif IFDとGPS IFDは0th IFDのポインタから辿る
+        for (ifd, pointer) in [(Ifd::Exif, EXIF_IFD_POINTER), (Ifd::Gps, GPS_INFO_IFD_POINTER)] {
+            if let Some(offset) = exif.get(Ifd::Ifd0, pointer).and_then(TagValue::to_u32) {
+                exif.read_ifd(tiff, ifd, offset as usize, &byte_order, &mut budget);
             }
         }
-        tag_field_offset += 12;  // 次のタグフィールドへ
+        if let Some(offset_1st_ifd) = next_ifd_offset(tiff, offset_0th_ifd, &byte_order) {
+            exif.read_ifd(tiff, Ifd::Ifd1, offset_1st_ifd, &byte_order, &mut budget);
+        }
+        Some(exif)
     }
 
-    None
+    /// IFDのタグを全て読み出して追加する（同じタグが複数ある場合は最初のものを使う）．
+    /// 
+    /// タグフィールドが範囲外になった場合はそこで打ち切り，valueを読めないタグは読み飛ばす．
+    /// MakerNoteはメーカーごとに独自のIFDもどきや，APP1セグメントの外を指すオフセットを含むことがあるので，
+    /// タイプに関わらず中身を解釈しないバイト列（UNDEFINED）として持ち，中のオフセットは辿らない．
+    /// MakerNoteが壊れていて読めない場合も，同じIFDの後ろのタグはそのまま読む．
+    fn read_ifd(&mut self, tiff: &[u8], ifd: Ifd, ifd_offset: usize, byte_order: &ByteOrder, budget: &mut usize) {
+        let tag_num = match tiff.get(ifd_offset..(ifd_offset + 2)) {
+            Some(slice) => decode_u16(slice, byte_order) as usize,
+            None => return,
+        };
+        for i in 0..tag_num {
+            let field_offset = ifd_offset + 2 + 12 * i;
+            let tag_field = match tiff.get(field_offset..(field_offset + 12)) {
+                Some(tag_field) => tag_field,
+                None => return,
+            };
+            let tag = match read_field(tiff, tag_field, byte_order) {
+                Some(tag) if tag.value.len() <= *budget => tag,
+                _ => continue,
+            };
+            *budget -= tag.value.len();
+            let number = decode_u16(&tag_field[..2], byte_order);
+            self.entries.entry((ifd, number)).or_insert_with(|| {
+                if number == MAKER_NOTE {
+                    TagValue::Undefined(tag.value.to_vec())
+                } else {
+                    TagValue::decode(&tag, byte_order)
+                }
+            });
+        }
+    }
+
+    /// 指定したIFDのタグのvalueを返す．
+    pub fn get(&self, ifd: Ifd, tag: u16) -> Option<&TagValue> {
+        self.entries.get(&(ifd, tag))
+    }
+
+    /// 全てのタグを（IFD，タグ番号）の順に返す．
+    pub fn iter(&self) -> impl Iterator<Item = (Ifd, u16, &TagValue)> {
+        self.entries.iter().map(|((ifd, tag), value)| (*ifd, *tag, value))
+    }
+
+    /// ASCII文字列のタグのvalueを，前後の空白と終端のNULL文字を取り除いた文字列として返す．
+    /// 
+    /// ASCIIの代わりにUNDEFINEDなどの1byteのタイプで書くカメラもあるので，それも受け付ける．
+    /// タグが無い場合や空文字列の場合はNoneを返す．
+    pub fn ascii(&self, ifd: Ifd, tag: u16) -> Option<String> {
+        let value = self.get(ifd, tag)?.as_bytes()?;
+        // NULL文字より後ろは詰め物なので捨てる
+        let value = value.split(|c| *c == 0).next().unwrap_or_default();
+        let value = String::from_utf8_lossy(value).trim().to_string();
+        if value.is_empty() {
+            None
+        } else {
+            Some(value)
+        }
+    }
+
+    /// 日時のタグのvalueをExifの日時形式の19byteで返す（終端のNULL文字は除く）．
+    fn date_time(&self, ifd: Ifd, tag: u16) -> Option<[u8; 19]> {
+        self.get(ifd, tag)?.as_bytes()?.get(..19)?.try_into().ok()
+    }
+}
+
+impl Ifd {
+    /// IFDの名前（exifサブコマンドの表示用）
+    pub fn name(self) -> &'static str {
+        match self {
+            Ifd::Ifd0 => "IFD0",
+            Ifd::Exif => "Exif",
+            Ifd::Gps  => "GPS",
+            Ifd::Ifd1 => "IFD1",
+        }
+    }
+}
+
+/// このモジュールで扱うタグの名前を返す（exifサブコマンドの表示用）．知らないタグの場合はNone．
+pub fn tag_name(ifd: Ifd, tag: u16) -> Option<&'static str> {
+    let name = match (ifd, tag) {
+        (Ifd::Gps, GPS_LATITUDE_REF)  => "GPSLatitudeRef",
+        (Ifd::Gps, GPS_LATITUDE)      => "GPSLatitude",
+        (Ifd::Gps, GPS_LONGITUDE_REF) => "GPSLongitudeRef",
+        (Ifd::Gps, GPS_LONGITUDE)     => "GPSLongitude",
+        (Ifd::Gps, GPS_ALTITUDE_REF)  => "GPSAltitudeRef",
+        (Ifd::Gps, GPS_ALTITUDE)      => "GPSAltitude",
+        (Ifd::Gps, GPS_TIME_STAMP)    => "GPSTimeStamp",
+        (Ifd::Gps, GPS_DATE_STAMP)    => "GPSDateStamp",
+        (Ifd::Gps, _) => return None,
+        (_, IMAGE_WIDTH)  => "ImageWidth",
+        (_, IMAGE_LENGTH) => "ImageLength",
+        (_, ORIENTATION)  => "Orientation",
+        (_, MAKE)         => "Make",
+        (_, MODEL)        => "Model",
+        (_, SOFTWARE)     => "Software",
+        (_, DATE_TIME)    => "DateTime",
+        (_, ARTIST)       => "Artist",
+        (_, COPYRIGHT)    => "Copyright",
+        (_, EXIF_IFD_POINTER)     => "ExifIFDPointer",
+        (_, GPS_INFO_IFD_POINTER) => "GPSInfoIFDPointer",
+        (_, DATE_TIME_ORIGINAL)   => "DateTimeOriginal",
+        (_, DATE_TIME_DIGITIZED)  => "DateTimeDigitized",
+        (_, OFFSET_TIME_ORIGINAL) => "OffsetTimeOriginal",
+        (_, MAKER_NOTE)           => "MakerNote",
+        (_, USER_COMMENT)         => "UserComment",
+        (_, SUB_SEC_TIME_ORIGINAL) => "SubSecTimeOriginal",
+        (_, PIXEL_X_DIMENSION)    => "PixelXDimension",
+        (_, PIXEL_Y_DIMENSION)    => "PixelYDimension",
+        (_, IMAGE_UNIQUE_ID)      => "ImageUniqueID",
+        (_, LENS_MODEL)           => "LensModel",
+        (_, JPEG_INTERCHANGE_FORMAT)        => "JPEGInterchangeFormat",
+        (_, JPEG_INTERCHANGE_FORMAT_LENGTH) => "JPEGInterchangeFormatLength",
+        _ => return None,
+    };
+    Some(name)
+}
+
+/// 表示するvalueの要素数の上限（exifサブコマンド）．これより多い場合は要素数だけを表示する．
+const MAX_DISPLAY_ELEMENTS: usize = 16;
+
+impl fmt::Display for TagValue {
+    /// タイプ名に続けてvalueを表示する（例: SHORT 6，ASCII "Canon"，RATIONAL 35/1, 41/1, 1234/100）．
+    /// 
+    /// ASCIIは終端のNULL文字を除いてエスケープし，UNDEFINEDは16進数で表示する．
+    /// 要素数が多いもの（MakerNoteなど）は要素数だけを表示する．
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fn list<T>(f: &mut fmt::Formatter, name: &str, values: &[T], item: impl Fn(&T) -> String) -> fmt::Result {
+            if values.len() > MAX_DISPLAY_ELEMENTS {
+                return write!(f, "{} ({} values)", name, values.len());
+            }
+            let items: Vec<String> = values.iter().map(item).collect();
+            write!(f, "{} {}", name, items.join(", "))
+        }
+        match self {
+            TagValue::Ascii(value) => {
+                let value = value.strip_suffix(&[0]).unwrap_or(value);
+                write!(f, "ASCII \"{}\"", String::from_utf8_lossy(value).escape_debug())
+            },
+            TagValue::Undefined(value) if value.len() > MAX_DISPLAY_ELEMENTS => write!(f, "UNDEFINED ({} bytes)", value.len()),
+            TagValue::Undefined(value) => {
+                let hex: Vec<String> = value.iter().map(|c| format!("{:02x}", c)).collect();
+                write!(f, "UNDEFINED {}", hex.join(" "))
+            },
+            TagValue::Byte(value)      => list(f, "BYTE", value, |v| v.to_string()),
+            TagValue::Short(value)     => list(f, "SHORT", value, |v| v.to_string()),
+            TagValue::Long(value)      => list(f, "LONG", value, |v| v.to_string()),
+            TagValue::Rational(value)  => list(f, "RATIONAL", value, |(n, d)| format!("{}/{}", n, d)),
+            TagValue::SByte(value)     => list(f, "SBYTE", value, |v| v.to_string()),
+            TagValue::SShort(value)    => list(f, "SSHORT", value, |v| v.to_string()),
+            TagValue::SLong(value)     => list(f, "SLONG", value, |v| v.to_string()),
+            TagValue::SRational(value) => list(f, "SRATIONAL", value, |(n, d)| format!("{}/{}", n, d)),
+            TagValue::Float(value)     => list(f, "FLOAT", value, |v| v.to_string()),
+            TagValue::Double(value)    => list(f, "DOUBLE", value, |v| v.to_string()),
+        }
+    }
+}
+
+impl TagValue {
+    /// read_fieldで読み出したタグをタイプに従って復号する．
+    fn decode(tag: &Tag, byte_order: &ByteOrder) -> TagValue {
+        let value = tag.value;
+        let u32s = || value.chunks_exact(4).map(|slice| decode_u32(slice, byte_order));
+        let pairs = || value.chunks_exact(8).map(|slice| (decode_u32(&slice[..4], byte_order), decode_u32(&slice[4..], byte_order)));
+        match tag.value_type {
+            BYTE      => TagValue::Byte(value.to_vec()),
+            ASCII     => TagValue::Ascii(value.to_vec()),
+            SHORT     => TagValue::Short(value.chunks_exact(2).map(|slice| decode_u16(slice, byte_order)).collect()),
+            LONG      => TagValue::Long(u32s().collect()),
+            RATIONAL  => TagValue::Rational(pairs().collect()),
+            SBYTE     => TagValue::SByte(value.iter().map(|c| *c as i8).collect()),
+            SSHORT    => TagValue::SShort(value.chunks_exact(2).map(|slice| decode_u16(slice, byte_order) as i16).collect()),
+            SLONG     => TagValue::SLong(u32s().map(|n| n as i32).collect()),
+            SRATIONAL => TagValue::SRational(pairs().map(|(n, d)| (n as i32, d as i32)).collect()),
+            FLOAT     => TagValue::Float(u32s().map(f32::from_bits).collect()),
+            DOUBLE    => TagValue::Double(value.chunks_exact(8).map(|slice| {
+                let bytes: [u8; 8] = slice.try_into().unwrap();
+                match byte_order {
+                    ByteOrder::BigEndian    => f64::from_be_bytes(bytes),
+                    ByteOrder::LittleEndian => f64::from_le_bytes(bytes),
+                }
+            }).collect()),
+            // read_fieldは知らないタイプを返さないので，ここに来るのはUNDEFINEDだけ
+            _ => TagValue::Undefined(value.to_vec()),
+        }
+    }
+
+    /// 1byteのタイプ（BYTE，ASCII，UNDEFINED）のvalueをバイト列として返す．
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            TagValue::Byte(value) | TagValue::Ascii(value) | TagValue::Undefined(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// SHORTまたはLONGのvalueの最初の要素を返す．
+    pub fn to_u32(&self) -> Option<u32> {
+        match self {
+            TagValue::Short(value) => value.first().map(|v| *v as u32),
+            TagValue::Long(value) => value.first().copied(),
+            _ => None,
+        }
+    }
+
+    /// RATIONALまたはSRATIONALの全要素を分子/分母の値にして返す（GPSの緯度経度などで使う）．
+    /// 
+    /// タイプが違う場合や，分母が0の要素がある場合はNoneを返す．
+    pub fn to_f64s(&self) -> Option<Vec<f64>> {
+        match self {
+            TagValue::Rational(value) => value.iter()
+                .map(|(n, d)| if *d == 0 { None } else { Some(*n as f64 / *d as f64) })
+                .collect(),
+            TagValue::SRational(value) => value.iter()
+                .map(|(n, d)| if *d == 0 { None } else { Some(*n as f64 / *d as f64) })
+                .collect(),
+            _ => None,
+        }
+    }
 }
 
 /// DateTimeOriginalタグのvalueを返す（ASCII文字列で，終端のNULL文字は除く）．
 /// 
 /// Format: YYYY:MM:DD HH:MM:SS (Example: 2015:09:27 11:43:11)
 pub fn get_date_time_original(jpeg_binary: &[u8]) -> Option<[u8; 19]> {
-    let app1 = get_app1(jpeg_binary)?;
-    
-    let byte_order = match app1[OFFSET_TIFF_HEADER..(OFFSET_TIFF_HEADER + 2)] {
-        [0x4D, 0x4D] => ByteOrder::BigEndian,
-        [0x49, 0x49] => ByteOrder::LittleEndian,
-        _ => return None
-    };
+    get_date_time_original_from_tiff(get_tiff(jpeg_binary)?)
+}
+
+/// TIFFヘッダから始まるExifデータ（PNGのeXIfチャンクの中身など）から
+/// DateTimeOriginalタグのvalueを返す．
+/// 
+/// Format: YYYY:MM:DD HH:MM:SS (Example: 2015:09:27 11:43:11)
+pub fn get_date_time_original_from_tiff(tiff: &[u8]) -> Option<[u8; 19]> {
+    get_exif_date_time_from_tiff(tiff, DATE_TIME_ORIGINAL)
+}
+
+/// TIFFヘッダから始まるExifデータからDateTimeDigitizedタグ（デジタル化した日時．スキャナはこちらを書くことが多い）のvalueを返す．
+/// 
+/// Format: YYYY:MM:DD HH:MM:SS (Example: 2015:09:27 11:43:11)
+pub fn get_date_time_digitized_from_tiff(tiff: &[u8]) -> Option<[u8; 19]> {
+    get_exif_date_time_from_tiff(tiff, DATE_TIME_DIGITIZED)
+}
 
-    // 0th IFDのオフセットを読む．起点はTIFFヘッダの先頭（Exif識別子の直後）．
-    let offset_0th_ifd = decode_u32(&app1[(OFFSET_TIFF_HEADER + 4)..(OFFSET_TIFF_HEADER + 8)], &byte_order) as usize;
+/// Exif IFDに書かれた日時のタグのvalueを返す．
+fn get_exif_date_time_from_tiff(tiff: &[u8], tag: u16) -> Option<[u8; 19]> {
+    // DNGなどのTIFF/EP系のファイルは0th IFDに直接DateTimeOriginalを書くこともあるので，そちらも探す．
+    let exif = Exif::from_tiff(tiff)?;
+    exif.date_time(Ifd::Exif, tag).or_else(|| exif.date_time(Ifd::Ifd0, tag))
+}
 
-    // Exif IFDの開始オフセットを読む．起点はTIFFヘッダの先頭．
-    let tmp = read_tag(app1, offset_0th_ifd, EXIF_IFD_POINTER, &byte_order)?;
-    let offset_exif_ifd = decode_u32(tmp, &byte_order);
+/// TIFFヘッダから始まるExifデータからSubSecTimeOriginalタグ（DateTimeOriginalの秒未満の部分）の数字を返す．
+/// 
+/// Format: 秒の小数部分の数字列 (Example: "42" は0.42秒)
+/// DateTimeOriginalと同じくExif IFDと0th IFDを探す．数字以外の文字（詰め物の空白など）より後ろは捨てる．
+pub fn get_sub_sec_time_original_from_tiff(tiff: &[u8]) -> Option<String> {
+    let exif = Exif::from_tiff(tiff)?;
+    let sub_sec = exif.ascii(Ifd::Exif, SUB_SEC_TIME_ORIGINAL)
+        .or_else(|| exif.ascii(Ifd::Ifd0, SUB_SEC_TIME_ORIGINAL))?;
+    let digits: String = sub_sec.chars().take_while(|c| c.is_ascii_digit()).collect();
+    if digits.is_empty() {
+        None
+    } else {
+        Some(digits)
+    }
+}
 
-    // Exif IFDのDateTimeOriginalタグを読む
-    let tmp = read_tag(app1, offset_exif_ifd as usize, DATE_TIME_ORIGINAL, &byte_order)?;
-    let mut date_time_original = [0u8; 19];
-    date_time_original.copy_from_slice(&tmp[..19]);  // ASCIIの場合にはバイトオーダーは気にしなくていいっぽい
+/// TIFFヘッダから始まるExifデータからOffsetTimeOriginalタグ（DateTimeOriginalのUTCからのずれ）のvalueを返す．
+/// 
+/// Format: ±HH:MM (Example: +09:00)
+pub fn get_offset_time_original_from_tiff(tiff: &[u8]) -> Option<String> {
+    let exif = Exif::from_tiff(tiff)?;
+    exif.ascii(Ifd::Exif, OFFSET_TIME_ORIGINAL)
+        .or_else(|| exif.ascii(Ifd::Ifd0, OFFSET_TIME_ORIGINAL))
+}
 
-    Some(date_time_original)
+/// TIFFヘッダから始まるデータの0th IFDに書かれたDateTimeタグ（ファイル変更日時）のvalueを返す．
+/// TIFFファイルはExif IFDを持たないことが多いので，DateTimeOriginalが無い場合の代わりに使う．
+/// 
+/// Format: YYYY:MM:DD HH:MM:SS (Example: 2015:09:27 11:43:11)
+pub fn get_date_time_from_tiff(tiff: &[u8]) -> Option<[u8; 19]> {
+    Exif::from_tiff(tiff)?.date_time(Ifd::Ifd0, DATE_TIME)
 }
 
 /// 画像の回転情報を読み込んで返す
 pub fn get_orientation(jpeg_binary: &[u8]) -> Option<u16> {
-    let app1 = get_app1(jpeg_binary)?;
-
-    let byte_order = match app1[OFFSET_TIFF_HEADER..(OFFSET_TIFF_HEADER + 2)] {
-        [0x4D, 0x4D] => ByteOrder::BigEndian,
-        [0x49, 0x49] => ByteOrder::LittleEndian,
-        _ => return None
-    };
-
-    // 0th IFDのオフセットを読む．起点はTIFFヘッダの先頭（Exif識別子の直後）．
-    let offset_0th_ifd = decode_u32(&app1[(OFFSET_TIFF_HEADER + 4)..(OFFSET_TIFF_HEADER + 8)], &byte_order) as usize;
+    get_orientation_from_tiff(get_tiff(jpeg_binary)?)
+}
 
+/// TIFFヘッダから始まるExifデータから回転情報（1〜8）を返す．
+pub fn get_orientation_from_tiff(tiff: &[u8]) -> Option<u16> {
     // Orientationを読む
-    let tmp = read_tag(app1, offset_0th_ifd, ORIENTATION, &byte_order)?;
-    let orientation = decode_u16(tmp, &byte_order);
+    let orientation = match Exif::from_tiff(tiff)?.get(Ifd::Ifd0, ORIENTATION)? {
+        TagValue::Short(value) => *value.first()?,
+        _ => return None,
+    };
 
     // orientationは1〜8の値をとる
     if orientation == 0 || orientation > 8 {
@@ -215,3 +910,991 @@ pub fn get_orientation(jpeg_binary: &[u8]) -> Option<u16> {
         Some(orientation)
     }
 }
+
+/// 位置情報（GPS IFD）を返す．GPS IFDが無い場合はNoneを返す．
+pub fn get_gps(jpeg_binary: &[u8]) -> Option<Gps> {
+    get_gps_from_tiff(get_tiff(jpeg_binary)?)
+}
+
+/// TIFFヘッダから始まるExifデータのGPS IFDに書かれた測位日時（GPSDateStampとGPSTimeStamp，UTC）を
+/// Exifの日時形式で返す．
+/// 
+/// 測位できていないカメラは0を書くことがあるので，日付が0の場合や時刻が全て0の場合はNoneを返す．
+/// 秒の小数部分は切り捨てる．
+pub fn get_gps_date_time_from_tiff(tiff: &[u8]) -> Option<[u8; 19]> {
+    let exif = Exif::from_tiff(tiff)?;
+    let date = exif.ascii(Ifd::Gps, GPS_DATE_STAMP)?;
+    let time = exif.get(Ifd::Gps, GPS_TIME_STAMP)?.to_f64s()?;
+    if date.len() != 10 || date.starts_with("0000") || time.len() != 3 || time.iter().all(|v| *v == 0.0) {
+        return None;
+    }
+    if !(0.0..24.0).contains(&time[0]) || !(0.0..60.0).contains(&time[1]) || !(0.0..61.0).contains(&time[2]) {
+        return None;
+    }
+
+    let s = format!("{} {:02}:{:02}:{:02}", date, time[0] as u32, time[1] as u32, time[2] as u32);
+    let mut date_time = [0u8; 19];
+    date_time.copy_from_slice(s.as_bytes().get(..19)?);
+    Some(date_time)
+}
+
+/// TIFFヘッダから始まるExifデータから位置情報（GPS IFD）を返す．GPS IFDが無い場合はNoneを返す．
+pub fn get_gps_from_tiff(tiff: &[u8]) -> Option<Gps> {
+    let exif = Exif::from_tiff(tiff)?;
+    exif.get(Ifd::Ifd0, GPS_INFO_IFD_POINTER)?;
+    let reference = |ref_tag: u16| exif.get(Ifd::Gps, ref_tag).and_then(TagValue::as_bytes).and_then(|value| value.first().copied());
+
+    // 度，分，秒を度に変換して，南緯・西経の場合は負にする（参照のタグが無い場合は北緯・東経とする）
+    let coordinate = |tag: u16, ref_tag: u16, negative_ref: u8| -> Option<f64> {
+        let dms = exif.get(Ifd::Gps, tag)?.to_f64s()?;
+        let degrees = dms.first()? + dms.get(1).unwrap_or(&0.0) / 60.0 + dms.get(2).unwrap_or(&0.0) / 3600.0;
+        if reference(ref_tag) == Some(negative_ref) {
+            Some(-degrees)
+        } else {
+            Some(degrees)
+        }
+    };
+
+    let altitude = exif.get(Ifd::Gps, GPS_ALTITUDE)
+        .and_then(|value| value.to_f64s()?.first().copied())
+        .map(|altitude| if reference(GPS_ALTITUDE_REF) == Some(1) { -altitude } else { altitude });
+
+    Some(Gps {
+        latitude: coordinate(GPS_LATITUDE, GPS_LATITUDE_REF, b'S'),
+        longitude: coordinate(GPS_LONGITUDE, GPS_LONGITUDE_REF, b'W'),
+        altitude,
+    })
+}
+
+/// JPEG画像のExifから位置情報（GPS IFD）を削除したものを返す．画像データは再エンコードしない．
+/// 
+/// GPS IFDが無い場合やExifが壊れている場合はNoneを返す．
+pub fn strip_gps(jpeg_binary: &[u8]) -> Option<Vec<u8>> {
+    let segment = find_exif_segment(jpeg_binary)?;
+    let app1 = strip_app1_gps(&jpeg_binary[segment.start..segment.end])?;
+
+    let mut stripped = Vec::with_capacity(jpeg_binary.len());
+    stripped.extend_from_slice(&jpeg_binary[..segment.start]);
+    stripped.extend_from_slice(&app1);
+    stripped.extend_from_slice(&jpeg_binary[segment.end..]);
+    Some(stripped)
+}
+
+/// APP1セグメント（マーカを含む）から位置情報（GPS IFD）を削除したものを返す．
+/// 
+/// 他のIFDやvalueのオフセットがずれないように，0th IFDからGPS IFDへのポインタを取り除いて後ろのタグを詰め，
+/// GPS IFDとその外に書かれたvalueは0で塗り潰す．
+/// GPS IFDがTIFFデータの末尾にある場合は，その分を切り詰めてセグメント長を書き直す．
+/// GPS IFDへのポインタが無い場合やTIFFヘッダが壊れている場合はNoneを返す．
+fn strip_app1_gps(app1: &[u8]) -> Option<Vec<u8>> {
+    let tiff = app1.get(OFFSET_TIFF_HEADER..)?;
+    let (byte_order, offset_0th_ifd) = read_tiff_header(tiff)?;
+
+    // 0th IFDの中でGPS IFDへのポインタのタグフィールドを探す
+    let tag_num = decode_u16(tiff.get(offset_0th_ifd..(offset_0th_ifd + 2))?, &byte_order) as usize;
+    let end_0th_ifd = offset_0th_ifd + 2 + 12 * tag_num + 4;  // 次のIFDへのオフセットまで含む
+    tiff.get(..end_0th_ifd)?;
+    let pointer_field = (0..tag_num)
+        .map(|i| offset_0th_ifd + 2 + 12 * i)
+        .find(|&field| decode_u16(&tiff[field..(field + 2)], &byte_order) == GPS_INFO_IFD_POINTER)?;
+    let offset_gps_ifd = decode_u32(&tiff[(pointer_field + 8)..(pointer_field + 12)], &byte_order) as usize;
+
+    // 壊れたExifでTIFFヘッダや0th IFDを指している領域は塗り潰さない
+    let mut regions: Vec<(usize, usize)> = ifd_regions(tiff, offset_gps_ifd, &byte_order).into_iter()
+        .filter(|&(start, end)| start >= 8 && (end <= offset_0th_ifd || start >= end_0th_ifd))
+        .collect();
+    regions.sort();
+
+    let mut tiff = tiff.to_vec();
+    // ポインタより後ろのタグフィールドと次のIFDへのオフセットを12byte詰めて，空いた末尾は0にする
+    tiff.copy_within((pointer_field + 12)..end_0th_ifd, pointer_field);
+    tiff[(end_0th_ifd - 12)..end_0th_ifd].fill(0);
+    tiff[offset_0th_ifd..(offset_0th_ifd + 2)].copy_from_slice(&encode_u16(tag_num as u16 - 1, &byte_order));
+    for &(start, end) in &regions {
+        tiff[start..end].fill(0);
+    }
+
+    // 末尾から連続しているGPSの領域は切り詰める
+    truncate_tail_regions(&mut tiff, &regions);
+
+    app1_from_tiff(&tiff)
+}
+
+/// JPEG画像のExifからサムネイル（1st IFDとサムネイルのJPEG）を削除したものを返す．画像データは再エンコードしない．
+/// 
+/// サムネイルが無い場合やExifが壊れている場合はNoneを返す．
+pub fn strip_thumbnail(jpeg_binary: &[u8]) -> Option<Vec<u8>> {
+    let segment = find_exif_segment(jpeg_binary)?;
+    let app1 = strip_app1_thumbnail(&jpeg_binary[segment.start..segment.end])?;
+
+    let mut stripped = Vec::with_capacity(jpeg_binary.len());
+    stripped.extend_from_slice(&jpeg_binary[..segment.start]);
+    stripped.extend_from_slice(&app1);
+    stripped.extend_from_slice(&jpeg_binary[segment.end..]);
+    Some(stripped)
+}
+
+/// APP1セグメント（マーカを含む）から1st IFDとサムネイルのJPEGを削除したものを返す．
+/// 
+/// 0th IFDから1st IFDへのオフセットを0にして，1st IFDとその外に書かれたvalue，サムネイルのJPEGを0で塗り潰す．
+/// それらがTIFFデータの末尾にある場合（普通はそうなっている）は，その分を切り詰めてセグメント長を書き直す．
+/// 1st IFDが無い場合やTIFFヘッダが壊れている場合はNoneを返す．
+fn strip_app1_thumbnail(app1: &[u8]) -> Option<Vec<u8>> {
+    let tiff = app1.get(OFFSET_TIFF_HEADER..)?;
+    let (byte_order, offset_0th_ifd) = read_tiff_header(tiff)?;
+    let offset_1st_ifd = next_ifd_offset(tiff, offset_0th_ifd, &byte_order)?;
+    let tag_num = decode_u16(&tiff[offset_0th_ifd..(offset_0th_ifd + 2)], &byte_order) as usize;
+    let pointer_offset = offset_0th_ifd + 2 + 12 * tag_num;
+    let end_0th_ifd = pointer_offset + 4;
+
+    let mut regions = ifd_regions(tiff, offset_1st_ifd, &byte_order);
+    if let Some((offset_field, length_field)) = get_thumbnail_fields(tiff) {
+        let offset = decode_u32(&tiff[(offset_field + 8)..(offset_field + 12)], &byte_order) as usize;
+        let length = decode_u32(&tiff[(length_field + 8)..(length_field + 12)], &byte_order) as usize;
+        if offset.checked_add(length).is_some_and(|end| end <= tiff.len()) {
+            regions.push((offset, offset + length));
+        }
+    }
+    // 壊れたExifでTIFFヘッダや0th IFDを指している領域は塗り潰さない
+    let mut regions: Vec<(usize, usize)> = regions.into_iter()
+        .filter(|&(start, end)| start >= 8 && (end <= offset_0th_ifd || start >= end_0th_ifd))
+        .collect();
+    regions.sort();
+
+    let mut tiff = tiff.to_vec();
+    tiff[pointer_offset..end_0th_ifd].fill(0);
+    for &(start, end) in &regions {
+        tiff[start..end].fill(0);
+    }
+    truncate_tail_regions(&mut tiff, &regions);
+
+    app1_from_tiff(&tiff)
+}
+
+/// 開始位置の順に並んだ領域のうち，TIFFデータの末尾から連続しているものを切り詰める（領域の間の1byteの詰め物は含める）．
+fn truncate_tail_regions(tiff: &mut Vec<u8>, regions: &[(usize, usize)]) {
+    let mut tail_start = tiff.len();
+    for &(start, end) in regions.iter().rev() {
+        if end + 1 < tail_start || start >= tail_start {
+            break;
+        }
+        tail_start = start;
+    }
+    tiff.truncate(tail_start);
+}
+
+/// IFD（GPS IFDや1st IFD）と，IFDの外に書かれたvalueが占める領域（起点はTIFFヘッダの先頭）を返す．
+/// 
+/// スライスの範囲外を指している部分や，大きさが分からない知らないタイプのvalueは含めない．
+fn ifd_regions(tiff: &[u8], ifd_offset: usize, byte_order: &ByteOrder) -> Vec<(usize, usize)> {
+    let mut regions = Vec::new();
+    let tag_num = match tiff.get(ifd_offset..(ifd_offset + 2)) {
+        Some(slice) => decode_u16(slice, byte_order) as usize,
+        None => return regions,
+    };
+    let end_ifd = ifd_offset + 2 + 12 * tag_num + 4;
+    if end_ifd > tiff.len() {
+        return regions;
+    }
+    regions.push((ifd_offset, end_ifd));
+
+    for tag_field in tiff[(ifd_offset + 2)..(end_ifd - 4)].chunks_exact(12) {
+        let value_type = decode_u16(&tag_field[2..4], byte_order);
+        let count = decode_u32(&tag_field[4..8], byte_order) as usize;
+        let value_bytes = match type_size(value_type).and_then(|size| size.checked_mul(count)) {
+            Some(value_bytes) if value_bytes > 4 => value_bytes,
+            _ => continue,  // IFDの中に直書きされているか，大きさが分からない
+        };
+        let value_offset = decode_u32(&tag_field[8..12], byte_order) as usize;
+        if value_offset.checked_add(value_bytes).is_some_and(|end| end <= tiff.len()) {
+            regions.push((value_offset, value_offset + value_bytes));
+        }
+    }
+    regions
+}
+
+/// TIFFヘッダから始まるExifデータから撮影機材の情報を返す．TIFFヘッダが壊れている場合はNoneを返す．
+/// 
+/// LensModelはExif IFDにあるが，DNGなどのTIFF/EP系のファイルは0th IFDに書くこともあるので，そちらも探す．
+pub fn get_camera_from_tiff(tiff: &[u8]) -> Option<Camera> {
+    let exif = Exif::from_tiff(tiff)?;
+    Some(Camera {
+        make: exif.ascii(Ifd::Ifd0, MAKE),
+        model: exif.ascii(Ifd::Ifd0, MODEL),
+        lens_model: exif.ascii(Ifd::Exif, LENS_MODEL).or_else(|| exif.ascii(Ifd::Ifd0, LENS_MODEL)),
+        software: exif.ascii(Ifd::Ifd0, SOFTWARE),
+    })
+}
+
+/// TIFFヘッダから始まるExifデータからImageUniqueIDタグ（カメラが画像ごとに付ける固有のID）のvalueを返す．
+/// 
+/// Format: 16進数32文字が多い (Example: "6B8A3A1F0C4E4D2B9E5F7A6C3D2B1A09")
+/// DateTimeOriginalと同じくExif IFDと0th IFDを探す．
+pub fn get_image_unique_id_from_tiff(tiff: &[u8]) -> Option<String> {
+    let exif = Exif::from_tiff(tiff)?;
+    exif.ascii(Ifd::Exif, IMAGE_UNIQUE_ID)
+        .or_else(|| exif.ascii(Ifd::Ifd0, IMAGE_UNIQUE_ID))
+}
+
+/// TIFFヘッダから始まるExifデータからUserCommentタグのコメントを返す．タグが無い場合はOk(None)を返す．
+/// 
+/// 先頭8byteの文字コードがASCII，Unicode（UCS-2，TIFFヘッダのバイトオーダー），未定義（0埋め）の場合に読める．
+/// JISなどの読めない文字コードの場合はErrを返す．終端のNULL文字と前後の空白は取り除く．
+pub fn get_user_comment_from_tiff(tiff: &[u8]) -> Result<Option<String>, &'static str> {
+    let byte_order = read_tiff_header(tiff).ok_or("The Exif data is broken.")?.0;
+    let exif = Exif::from_tiff(tiff).ok_or("The Exif data is broken.")?;
+    let value = match exif.get(Ifd::Exif, USER_COMMENT).and_then(TagValue::as_bytes) {
+        Some(value) if value.len() >= 8 => value,
+        _ => return Ok(None),
+    };
+    let (code, comment) = value.split_at(8);
+    let comment = match code {
+        b"ASCII\0\0\0" | [0, 0, 0, 0, 0, 0, 0, 0] => String::from_utf8_lossy(comment).to_string(),
+        b"UNICODE\0" => {
+            let units: Vec<u16> = comment.chunks_exact(2).map(|slice| decode_u16(slice, &byte_order)).collect();
+            String::from_utf16_lossy(&units)
+        },
+        _ => return Err("The character code of the UserComment is not supported."),
+    };
+    Ok(Some(comment.trim_matches(|c: char| c == '\0' || c.is_whitespace()).to_string()))
+}
+
+/// JPEG画像のExifのDateTimeOriginalとDateTimeDigitizedをdate_timeに書き換えたものを返す．画像データは再エンコードしない．
+/// 
+/// 両方のタグが既にある場合はその場で書き換える．無いタグがある場合は，
+/// 他のタグのオフセットがずれないように，書き換えたExif IFDはTIFFデータの末尾に追加して，
+/// 0th IFDのExif IFDポインタを付け替える（0th IFDにポインタが無い場合は0th IFDも末尾に作り直す）．
+/// Exifが無い場合は，二つのタグだけを持つAPP1セグメントを作ってAPP0セグメントの後ろに挿入する．
+pub fn set_date_time_original(jpeg_binary: &[u8], date_time: &[u8; 19]) -> Result<Vec<u8>, &'static str> {
+    let segment = find_exif_segment(jpeg_binary);
+    let segment = match segment {
+        Some(segment) => segment,
+        None => {
+            let tags = [NewTag::DateTimeOriginal(date_time), NewTag::DateTimeDigitized(date_time)];
+            let app1 = build_app1(&tags).ok_or("Failed to build the Exif data.")?;
+            return insert_after_app0(jpeg_binary, &app1);
+        },
+    };
+
+    let tiff = jpeg_binary.get((segment.start + OFFSET_TIFF_HEADER)..segment.end).ok_or("The Exif data is truncated.")?;
+    let tiff = set_tiff_date_time(tiff, date_time).ok_or("The Exif data is broken.")?;
+    let app1 = app1_from_tiff(&tiff).ok_or("The Exif data does not fit in the APP1 segment.")?;
+
+    let mut written = Vec::with_capacity(jpeg_binary.len() + app1.len());
+    written.extend_from_slice(&jpeg_binary[..segment.start]);
+    written.extend_from_slice(&app1);
+    written.extend_from_slice(&jpeg_binary[segment.end..]);
+    Ok(written)
+}
+
+/// TIFFヘッダから始まるExifデータのDateTimeOriginalとDateTimeDigitizedをdate_timeにしたものを返す．
+/// 
+/// TIFFヘッダやIFDが壊れている場合はNoneを返す．
+fn set_tiff_date_time(tiff: &[u8], date_time: &[u8; 19]) -> Option<Vec<u8>> {
+    let (byte_order, offset_0th_ifd) = read_tiff_header(tiff)?;
+    let (mut fields_0th_ifd, next_0th_ifd) = read_ifd_fields(tiff, offset_0th_ifd, &byte_order)?;
+    let pointer_field = fields_0th_ifd.iter().position(|field| decode_u16(&field[..2], &byte_order) == EXIF_IFD_POINTER);
+    let (mut fields_exif_ifd, next_exif_ifd) = match pointer_field {
+        Some(i) => read_ifd_fields(tiff, decode_u32(&fields_0th_ifd[i][8..12], &byte_order) as usize, &byte_order)?,
+        None => (Vec::new(), 0),
+    };
+
+    let mut tiff = tiff.to_vec();
+
+    // 両方のタグが既に20byteの領域を持っている場合は，その場で書き換える
+    if let Some(i) = pointer_field {
+        let offset_exif_ifd = decode_u32(&fields_0th_ifd[i][8..12], &byte_order) as usize;
+        let value_offsets: Vec<usize> = [DATE_TIME_ORIGINAL, DATE_TIME_DIGITIZED].iter()
+            .filter_map(|tag| find_tag_field(&tiff, offset_exif_ifd, *tag, &byte_order))
+            .filter(|field| decode_u16(&tiff[(field + 2)..(field + 4)], &byte_order) == ASCII
+                && decode_u32(&tiff[(field + 4)..(field + 8)], &byte_order) == 20)
+            .map(|field| decode_u32(&tiff[(field + 8)..(field + 12)], &byte_order) as usize)
+            .filter(|offset| offset + 20 <= tiff.len())
+            .collect();
+        if value_offsets.len() == 2 {
+            for offset in value_offsets {
+                tiff[offset..(offset + 19)].copy_from_slice(date_time);
+                tiff[offset + 19] = 0;
+            }
+            return Some(tiff);
+        }
+    }
+
+    // 日時の文字列（NULL終端で20byte）を末尾に追加して，Exif IFDのタグを差し替える
+    fields_exif_ifd.retain(|field| {
+        let tag = decode_u16(&field[..2], &byte_order);
+        tag != DATE_TIME_ORIGINAL && tag != DATE_TIME_DIGITIZED
+    });
+    for tag in [DATE_TIME_ORIGINAL, DATE_TIME_DIGITIZED] {
+        fields_exif_ifd.push(append_date_time_field(&mut tiff, tag, date_time, &byte_order)?);
+    }
+    let offset_exif_ifd = append_ifd(&mut tiff, fields_exif_ifd, next_exif_ifd, &byte_order)?;
+
+    // 0th IFDのExif IFDポインタを付け替える
+    let pointer = make_field(EXIF_IFD_POINTER, LONG, 1, encode_u32(offset_exif_ifd, &byte_order), &byte_order);
+    match pointer_field {
+        Some(i) => {
+            let field_offset = offset_0th_ifd + 2 + 12 * i;
+            tiff[field_offset..(field_offset + 12)].copy_from_slice(&pointer);
+        },
+        None => {
+            fields_0th_ifd.push(pointer);
+            let new_offset_0th_ifd = append_ifd(&mut tiff, fields_0th_ifd, next_0th_ifd, &byte_order)?;
+            tiff[4..8].copy_from_slice(&encode_u32(new_offset_0th_ifd, &byte_order));
+        },
+    }
+    Some(tiff)
+}
+
+/// JPEG画像のExifのDateTimeOriginal，DateTimeDigitized，DateTimeをsecs秒ずらしたものを返す．画像データは再エンコードしない．
+/// 
+/// 日時の値は常に19文字なので，APP1セグメントの中でその場で書き換える（他のタグのオフセットはずれない）．
+/// 月末や年末の繰り上がりも正しく扱う．ずらせる日時が一つも無い場合はNoneを返す．
+pub fn shift_date_times(jpeg_binary: &[u8], secs: i64) -> Option<Vec<u8>> {
+    let segment = find_exif_segment(jpeg_binary)?;
+    let tiff_start = segment.start + OFFSET_TIFF_HEADER;
+    let tiff = jpeg_binary.get(tiff_start..segment.end)?;
+    let (byte_order, offset_0th_ifd) = read_tiff_header(tiff)?;
+
+    // Exif IFDと0th IFDの日時のタグを探す（同じ値を二度ずらさないように，valueの位置で重複を除く）
+    let offset_exif_ifd = read_tag(tiff, offset_0th_ifd, EXIF_IFD_POINTER, &byte_order)
+        .filter(|tag| tag.value.len() == 4)
+        .map(|tag| decode_u32(tag.value, &byte_order) as usize);
+    let mut value_offsets: Vec<usize> = [
+        offset_exif_ifd.map(|ifd| (ifd, DATE_TIME_ORIGINAL)),
+        offset_exif_ifd.map(|ifd| (ifd, DATE_TIME_DIGITIZED)),
+        Some((offset_0th_ifd, DATE_TIME_ORIGINAL)),
+        Some((offset_0th_ifd, DATE_TIME_DIGITIZED)),
+        Some((offset_0th_ifd, DATE_TIME)),
+    ].into_iter().flatten()
+        .filter_map(|(ifd_offset, tag)| match read_tag(tiff, ifd_offset, tag, &byte_order) {
+            // スライスが元の配列のどこの部分であるかを逆算する（TIFFヘッダの先頭からのvalueの開始オフセット）
+            Some(tag) if tag.value_type == ASCII && tag.value.len() >= 19 => {
+                Some(tag.value.as_ptr() as usize - tiff.as_ptr() as usize)
+            },
+            _ => None,
+        })
+        .collect();
+    value_offsets.sort_unstable();
+    value_offsets.dedup();
+
+    let mut shifted = jpeg_binary.to_vec();
+    let mut is_shifted = false;
+    for value_offset in value_offsets {
+        let range = (tiff_start + value_offset)..(tiff_start + value_offset + 19);
+        let date_time: [u8; 19] = shifted[range.clone()].try_into().unwrap();
+        // 日時が未設定の場合（空白や0埋め）はそのままにする
+        if let Some(date_time) = datetime::shift(&date_time, secs).filter(|_| datetime::is_valid(&date_time)) {
+            shifted[range].copy_from_slice(&date_time);
+            is_shifted = true;
+        }
+    }
+    if is_shifted {
+        Some(shifted)
+    } else {
+        None
+    }
+}
+
+/// tagsだけを持つ最小限のTIFF構造（リトルエンディアン）を作って，APP1セグメント（マーカを含む）にして返す．
+/// 
+/// Exif IFDのタグがある場合は0th IFDにExif IFDポインタを置く．
+/// 返したAPP1セグメントは，SOIの直後（APP0セグメントがあればその後ろ）にそのまま挿入できる．
+/// APP1セグメントに収まらない場合はNoneを返す．
+pub fn build_app1(tags: &[NewTag]) -> Option<Vec<u8>> {
+    let byte_order = ByteOrder::LittleEndian;
+    // 0th IFDのオフセットは最後に書き直す
+    let mut tiff = b"II\x2A\x00\x00\x00\x00\x00".to_vec();
+
+    // valueを先に末尾に追加してから，それを指すタグフィールドを作る
+    let mut fields_0th_ifd = Vec::new();
+    let mut fields_exif_ifd = Vec::new();
+    for tag in tags {
+        match append_new_tag_field(&mut tiff, tag, &byte_order)? {
+            (Ifd::Exif, field) => fields_exif_ifd.push(field),
+            (_, field) => fields_0th_ifd.push(field),
+        }
+    }
+
+    if !fields_exif_ifd.is_empty() {
+        let offset_exif_ifd = append_ifd(&mut tiff, fields_exif_ifd, 0, &byte_order)?;
+        fields_0th_ifd.push(make_field(EXIF_IFD_POINTER, LONG, 1, encode_u32(offset_exif_ifd, &byte_order), &byte_order));
+    }
+    let offset_0th_ifd = append_ifd(&mut tiff, fields_0th_ifd, 0, &byte_order)?;
+    tiff[4..8].copy_from_slice(&encode_u32(offset_0th_ifd, &byte_order));
+    app1_from_tiff(&tiff)
+}
+
+/// JPEG画像のExifにtagsを書き込んだものを返す．画像データは再エンコードしない．
+/// 
+/// 既にあるタグ（空白だけのものは除く）は，overwriteを指定しない限りそのままにする．
+/// 他のタグのオフセットがずれないように，書き換えたIFDはTIFFデータの末尾に追加して，ポインタを付け替える．
+/// Exifが無い場合は，tagsだけを持つAPP1セグメントを作ってAPP0セグメントの後ろに挿入する．
+pub fn set_tags(jpeg_binary: &[u8], tags: &[NewTag], overwrite: bool) -> Result<Vec<u8>, &'static str> {
+    let segment = find_exif_segment(jpeg_binary);
+    let segment = match segment {
+        Some(segment) => segment,
+        None => {
+            let app1 = build_app1(tags).ok_or("Failed to build the Exif data.")?;
+            return insert_after_app0(jpeg_binary, &app1);
+        },
+    };
+
+    let tiff = jpeg_binary.get((segment.start + OFFSET_TIFF_HEADER)..segment.end).ok_or("The Exif data is truncated.")?;
+    let tiff = match set_tiff_tags(tiff, tags, overwrite).ok_or("The Exif data is broken.")? {
+        Some(tiff) => tiff,
+        None => return Ok(jpeg_binary.to_vec()),  // 全てのタグが既にあった
+    };
+    let app1 = app1_from_tiff(&tiff).ok_or("The Exif data does not fit in the APP1 segment.")?;
+
+    let mut written = Vec::with_capacity(jpeg_binary.len() + app1.len());
+    written.extend_from_slice(&jpeg_binary[..segment.start]);
+    written.extend_from_slice(&app1);
+    written.extend_from_slice(&jpeg_binary[segment.end..]);
+    Ok(written)
+}
+
+/// TIFFヘッダから始まるExifデータにtagsを書き込んだものを返す．書き込むタグが無かった場合はSome(None)を返す．
+/// 
+/// TIFFヘッダやIFDが壊れている場合はNoneを返す．
+fn set_tiff_tags(tiff: &[u8], tags: &[NewTag], overwrite: bool) -> Option<Option<Vec<u8>>> {
+    let (byte_order, offset_0th_ifd) = read_tiff_header(tiff)?;
+    let existing = Exif::from_tiff(tiff)?;
+    let (mut fields_0th_ifd, next_0th_ifd) = read_ifd_fields(tiff, offset_0th_ifd, &byte_order)?;
+    let pointer_field = fields_0th_ifd.iter().position(|field| decode_u16(&field[..2], &byte_order) == EXIF_IFD_POINTER);
+    let (mut fields_exif_ifd, next_exif_ifd) = match pointer_field {
+        Some(i) => read_ifd_fields(tiff, decode_u32(&fields_0th_ifd[i][8..12], &byte_order) as usize, &byte_order)?,
+        None => (Vec::new(), 0),
+    };
+
+    let mut tiff = tiff.to_vec();
+    let mut is_changed_0th_ifd = false;
+    let mut is_changed_exif_ifd = false;
+    for tag in tags {
+        let number = tag.number();
+        let ifd = tag.ifd();
+        // 空白やNULL文字だけの文字列は，書かれていないものとして扱う
+        let has_value = existing.get(ifd, number)
+            .is_some_and(|value| value.as_bytes().is_none_or(|bytes| bytes.iter().any(|c| *c != 0 && *c != b' ')));
+        if has_value && !overwrite {
+            continue;
+        }
+        let (fields, is_changed) = match ifd {
+            Ifd::Exif => (&mut fields_exif_ifd, &mut is_changed_exif_ifd),
+            _ => (&mut fields_0th_ifd, &mut is_changed_0th_ifd),
+        };
+        fields.retain(|field| decode_u16(&field[..2], &byte_order) != number);
+        fields.push(append_new_tag_field(&mut tiff, tag, &byte_order)?.1);
+        *is_changed = true;
+    }
+    if !is_changed_0th_ifd && !is_changed_exif_ifd {
+        return Some(None);
+    }
+
+    // 書き換えたIFDを末尾に追加して，ポインタを付け替える
+    if is_changed_exif_ifd {
+        let offset_exif_ifd = append_ifd(&mut tiff, fields_exif_ifd, next_exif_ifd, &byte_order)?;
+        fields_0th_ifd.retain(|field| decode_u16(&field[..2], &byte_order) != EXIF_IFD_POINTER);
+        fields_0th_ifd.push(make_field(EXIF_IFD_POINTER, LONG, 1, encode_u32(offset_exif_ifd, &byte_order), &byte_order));
+    }
+    let new_offset_0th_ifd = append_ifd(&mut tiff, fields_0th_ifd, next_0th_ifd, &byte_order)?;
+    tiff[4..8].copy_from_slice(&encode_u32(new_offset_0th_ifd, &byte_order));
+    Some(Some(tiff))
+}
+
+impl NewTag<'_> {
+    /// タグ番号を返す．
+    fn number(&self) -> u16 {
+        match self {
+            NewTag::Orientation(_)       => ORIENTATION,
+            NewTag::DateTime(_)          => DATE_TIME,
+            NewTag::DateTimeOriginal(_)  => DATE_TIME_ORIGINAL,
+            NewTag::DateTimeDigitized(_) => DATE_TIME_DIGITIZED,
+            NewTag::Artist(_)            => ARTIST,
+            NewTag::Copyright(_)         => COPYRIGHT,
+            NewTag::UserComment(_)       => USER_COMMENT,
+        }
+    }
+
+    /// 書き込むIFDを返す．
+    fn ifd(&self) -> Ifd {
+        match self {
+            NewTag::DateTimeOriginal(_) | NewTag::DateTimeDigitized(_) | NewTag::UserComment(_) => Ifd::Exif,
+            _ => Ifd::Ifd0,
+        }
+    }
+}
+
+/// tagのvalueを（4byteより大きい場合は）TIFFデータの末尾に追加して，書き込むIFDとタグフィールドを返す．
+fn append_new_tag_field(tiff: &mut Vec<u8>, tag: &NewTag, byte_order: &ByteOrder) -> Option<(Ifd, [u8; 12])> {
+    let field = match *tag {
+        NewTag::Orientation(orientation) => {
+            let mut value = [0u8; 4];
+            value[..2].copy_from_slice(&encode_u16(orientation, byte_order));
+            make_field(ORIENTATION, SHORT, 1, value, byte_order)
+        },
+        NewTag::DateTime(date_time) | NewTag::DateTimeOriginal(date_time) | NewTag::DateTimeDigitized(date_time) => {
+            append_date_time_field(tiff, tag.number(), date_time, byte_order)?
+        },
+        NewTag::Artist(text) | NewTag::Copyright(text) => {
+            // NULL終端を付ける．4byte以下の場合はオフセット領域に直書きする
+            let mut value = text.as_bytes().to_vec();
+            value.push(0);
+            let count = u32::try_from(value.len()).ok()?;
+            if value.len() <= 4 {
+                let mut inline = [0u8; 4];
+                inline[..value.len()].copy_from_slice(&value);
+                make_field(tag.number(), ASCII, count, inline, byte_order)
+            } else {
+                let value_offset = append_aligned(tiff, &value)?;
+                make_field(tag.number(), ASCII, count, encode_u32(value_offset, byte_order), byte_order)
+            }
+        },
+        NewTag::UserComment(text) => {
+            // 先頭8byteに文字コードを書く（ASCII以外の文字を含む場合はUCS-2）
+            let value = if text.is_ascii() {
+                [b"ASCII\0\0\0".as_slice(), text.as_bytes()].concat()
+            } else {
+                let mut value = b"UNICODE\0".to_vec();
+                for unit in text.encode_utf16() {
+                    value.extend_from_slice(&encode_u16(unit, byte_order));
+                }
+                value
+            };
+            let value_offset = append_aligned(tiff, &value)?;
+            make_field(USER_COMMENT, UNDEFINED, u32::try_from(value.len()).ok()?, encode_u32(value_offset, byte_order), byte_order)
+        },
+    };
+    Some((tag.ifd(), field))
+}
+
+/// 日時の文字列（NULL終端で20byte）をTIFFデータの末尾に追加して，それを指すタグフィールドを返す．
+fn append_date_time_field(tiff: &mut Vec<u8>, tag: u16, date_time: &[u8; 19], byte_order: &ByteOrder) -> Option<[u8; 12]> {
+    let value_offset = append_aligned(tiff, date_time)?;
+    tiff.push(0);
+    Some(make_field(tag, ASCII, 20, encode_u32(value_offset, byte_order), byte_order))
+}
+
+/// IFDのタグフィールド（12byteずつ）と次のIFDの開始オフセットを読む．範囲外の場合はNoneを返す．
+fn read_ifd_fields(tiff: &[u8], ifd_offset: usize, byte_order: &ByteOrder) -> Option<(Vec<[u8; 12]>, u32)> {
+    let tag_num = decode_u16(tiff.get(ifd_offset..(ifd_offset + 2))?, byte_order) as usize;
+    let fields_end = ifd_offset + 2 + 12 * tag_num;
+    let fields = tiff.get((ifd_offset + 2)..fields_end)?
+        .chunks_exact(12)
+        .map(|field| field.try_into().unwrap())
+        .collect();
+    let next = decode_u32(tiff.get(fields_end..(fields_end + 4))?, byte_order);
+    Some((fields, next))
+}
+
+/// タグフィールドを作る．4byte以下のvalueは左詰めでvalueに，それより大きい場合はオフセットをvalueに入れること．
+fn make_field(tag: u16, value_type: u16, count: u32, value: [u8; 4], byte_order: &ByteOrder) -> [u8; 12] {
+    let mut field = [0u8; 12];
+    field[..2].copy_from_slice(&encode_u16(tag, byte_order));
+    field[2..4].copy_from_slice(&encode_u16(value_type, byte_order));
+    field[4..8].copy_from_slice(&encode_u32(count, byte_order));
+    field[8..].copy_from_slice(&value);
+    field
+}
+
+/// TIFFデータの末尾を偶数バイトに揃えてからdataを追加し，その開始オフセットを返す．
+/// 
+/// オフセットがu32に収まらない場合はNoneを返す．
+fn append_aligned(tiff: &mut Vec<u8>, data: &[u8]) -> Option<u32> {
+    if tiff.len() % 2 == 1 {
+        tiff.push(0);
+    }
+    let offset = u32::try_from(tiff.len()).ok()?;
+    tiff.extend_from_slice(data);
+    Some(offset)
+}
+
+/// タグ番号順に並べたIFDをTIFFデータの末尾に追加し，その開始オフセットを返す．
+fn append_ifd(tiff: &mut Vec<u8>, mut fields: Vec<[u8; 12]>, next: u32, byte_order: &ByteOrder) -> Option<u32> {
+    fields.sort_by_key(|field| decode_u16(&field[..2], byte_order));
+    let mut ifd = Vec::with_capacity(2 + 12 * fields.len() + 4);
+    ifd.extend_from_slice(&encode_u16(u16::try_from(fields.len()).ok()?, byte_order));
+    for field in &fields {
+        ifd.extend_from_slice(field);
+    }
+    ifd.extend_from_slice(&encode_u32(next, byte_order));
+    append_aligned(tiff, &ifd)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// テスト用のTIFFデータを作る．0th IFD（Exif IFDポインタは自動で足す）とExif IFDだけを持ち，4byteを超えるvalueはIFDの後ろに置く．
+    /// 
+    /// タグは（タグ番号，タイプ，バイトオーダーに合わせて書いたvalue）で与える．
+    fn build_tiff(big_endian: bool, ifd0: &[(u16, u16, &[u8])], exif: &[(u16, u16, &[u8])]) -> Vec<u8> {
+        let byte_order = if big_endian { ByteOrder::BigEndian } else { ByteOrder::LittleEndian };
+        let exif_offset = 8 + 2 + 12 * (ifd0.len() + 1) + 4;
+        let data_offset = exif_offset + 2 + 12 * exif.len() + 4;
+        let exif_pointer = encode_u32(exif_offset as u32, &byte_order);
+        let ifd0: Vec<(u16, u16, &[u8])> = ifd0.iter().copied().chain([(EXIF_IFD_POINTER, LONG, &exif_pointer[..])]).collect();
+
+        let mut tiff = if big_endian { b"MM\0\x2A".to_vec() } else { b"II\x2A\0".to_vec() };
+        tiff.extend_from_slice(&encode_u32(8, &byte_order));
+        let mut data = Vec::new();
+        for fields in [&ifd0[..], exif] {
+            tiff.extend_from_slice(&encode_u16(fields.len() as u16, &byte_order));
+            for &(tag, value_type, value) in fields {
+                tiff.extend_from_slice(&encode_u16(tag, &byte_order));
+                tiff.extend_from_slice(&encode_u16(value_type, &byte_order));
+                tiff.extend_from_slice(&encode_u32((value.len() / type_size(value_type).unwrap()) as u32, &byte_order));
+                if value.len() <= 4 {
+                    let mut inline = [0u8; 4];
+                    inline[..value.len()].copy_from_slice(value);
+                    tiff.extend_from_slice(&inline);
+                } else {
+                    tiff.extend_from_slice(&encode_u32((data_offset + data.len()) as u32, &byte_order));
+                    data.extend_from_slice(value);
+                }
+            }
+            tiff.extend_from_slice(&[0; 4]);
+        }
+        tiff.extend_from_slice(&data);
+        tiff
+    }
+
+    /// Canon（リトルエンディアン）．MakerNoteはAPP1セグメントの外を指すオフセットを含むIFDもどき．
+    fn canon_tiff() -> Vec<u8> {
+        let maker_note = [&[2, 0][..], &[0x01, 0x00, 0x03, 0x00, 0x31, 0x00, 0x00, 0x00, 0x00, 0x00, 0xFF, 0xFF], &[0x06, 0x00, 0x02, 0x00, 0x20, 0x00, 0x00, 0x00, 0xF0, 0xFF, 0x00, 0x00], &[0; 4]].concat();
+        build_tiff(false, &[
+            (MAKE, ASCII, b"Canon\0"),
+            (MODEL, ASCII, b"Canon EOS R5\0"),
+            (SOFTWARE, ASCII, b"Firmware Version 1.8.1\0"),
+        ], &[
+            (DATE_TIME_ORIGINAL, ASCII, b"2023:01:23 14:30:27\0"),
+            (MAKER_NOTE, UNDEFINED, &maker_note),
+            (SUB_SEC_TIME_ORIGINAL, ASCII, b"42\0"),
+            (LENS_MODEL, ASCII, b"RF24-105mm F4 L IS USM\0"),
+        ])
+    }
+
+    /// Nikon（ビッグエンディアン）．文字列は空白とNULL文字で詰めてあり，MakerNoteは独自のTIFFヘッダを持つ．
+    fn nikon_tiff() -> Vec<u8> {
+        let maker_note = [&b"Nikon\0\x02\x10\0\0"[..], b"MM\0\x2A\0\0\0\x08", &[0, 1, 0x00, 0x01, 0x00, 0x07, 0x00, 0x00, 0x00, 0x04, 0x30, 0x32, 0x31, 0x30, 0, 0, 0, 0]].concat();
+        build_tiff(true, &[
+            (MAKE, ASCII, b"NIKON CORPORATION\0"),
+            (MODEL, ASCII, b"NIKON Z 6_2\0\0\0\0"),
+            (SOFTWARE, ASCII, b"Ver.01.60               \0"),
+        ], &[
+            (DATE_TIME_ORIGINAL, ASCII, b"2022:11:05 09:12:45\0"),
+            (SUB_SEC_TIME_ORIGINAL, ASCII, b"30  \0"),
+            (MAKER_NOTE, UNDEFINED, &maker_note),
+            (LENS_MODEL, ASCII, b"NIKKOR Z 24-70mm f/4 S\0"),
+        ])
+    }
+
+    /// iPhone（ビッグエンディアン）．OffsetTimeOriginalがあり，MakerNoteは "Apple iOS" で始まる．
+    fn iphone_tiff() -> Vec<u8> {
+        let maker_note = [&b"Apple iOS\0\0\x01MM"[..], &[0, 2, 0x00, 0x01, 0x00, 0x09, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x0E]].concat();
+        build_tiff(true, &[
+            (MAKE, ASCII, b"Apple\0"),
+            (MODEL, ASCII, b"iPhone 14 Pro\0"),
+            (SOFTWARE, ASCII, b"17.1\0"),
+        ], &[
+            (DATE_TIME_ORIGINAL, ASCII, b"2023:10:01 18:04:59\0"),
+            (OFFSET_TIME_ORIGINAL, ASCII, b"+09:00\0"),
+            (SUB_SEC_TIME_ORIGINAL, ASCII, b"123\0"),
+            (MAKER_NOTE, UNDEFINED, &maker_note),
+            (LENS_MODEL, ASCII, b"iPhone 14 Pro back triple camera 6.86mm f/1.78\0"),
+        ])
+    }
+
+    /// Sonyのα（ILCE）のMakerNote．"SONY DSC " と詰め物の後にIFDが続き，そのオフセットはTIFFヘッダからの位置で，APP1セグメントの外を指す．
+    const SONY_MAKER_NOTE: &[u8] = b"SONY DSC \0\0\0\x02\0\x10\x20\x07\0\0\x01\0\0\0\xF0\x01\0\x40\x94\x07\0\x00\x02\0\0\x00\x80\x03\0\0\0\0\0";
+
+    /// Sony（リトルエンディアン）．MakerNoteの後ろにもExif IFDのタグが続く．
+    fn sony_tiff() -> Vec<u8> {
+        build_tiff(false, &[
+            (MAKE, ASCII, b"SONY\0"),
+            (MODEL, ASCII, b"ILCE-7M3\0"),
+            (SOFTWARE, ASCII, b"ILCE-7M3 v3.10\0"),
+        ], &[
+            (DATE_TIME_ORIGINAL, ASCII, b"2021:08:14 06:45:12\0"),
+            (OFFSET_TIME_ORIGINAL, ASCII, b"+02:00\0"),
+            (MAKER_NOTE, UNDEFINED, SONY_MAKER_NOTE),
+            (SUB_SEC_TIME_ORIGINAL, ASCII, b"507\0"),
+            (LENS_MODEL, ASCII, b"FE 24-70mm F2.8 GM\0"),
+        ])
+    }
+
+    fn camera(make: &str, model: &str, lens_model: &str, software: &str) -> Camera {
+        Camera {
+            make: Some(make.to_string()),
+            model: Some(model.to_string()),
+            lens_model: Some(lens_model.to_string()),
+            software: Some(software.to_string()),
+        }
+    }
+
+    #[test]
+    fn canon_sample() {
+        let tiff = canon_tiff();
+        assert_eq!(is_big_endian(&tiff), Some(false));
+        assert_eq!(get_camera_from_tiff(&tiff), Some(camera("Canon", "Canon EOS R5", "RF24-105mm F4 L IS USM", "Firmware Version 1.8.1")));
+        assert_eq!(&get_date_time_original_from_tiff(&tiff).unwrap(), b"2023:01:23 14:30:27");
+        assert_eq!(get_sub_sec_time_original_from_tiff(&tiff).as_deref(), Some("42"));
+        assert_eq!(get_offset_time_original_from_tiff(&tiff), None);
+    }
+
+    #[test]
+    fn nikon_sample() {
+        let tiff = nikon_tiff();
+        assert_eq!(is_big_endian(&tiff), Some(true));
+        assert_eq!(get_camera_from_tiff(&tiff), Some(camera("NIKON CORPORATION", "NIKON Z 6_2", "NIKKOR Z 24-70mm f/4 S", "Ver.01.60")));
+        assert_eq!(&get_date_time_original_from_tiff(&tiff).unwrap(), b"2022:11:05 09:12:45");
+        assert_eq!(get_sub_sec_time_original_from_tiff(&tiff).as_deref(), Some("30"));
+    }
+
+    #[test]
+    fn iphone_sample() {
+        let tiff = iphone_tiff();
+        assert_eq!(get_camera_from_tiff(&tiff), Some(camera("Apple", "iPhone 14 Pro", "iPhone 14 Pro back triple camera 6.86mm f/1.78", "17.1")));
+        assert_eq!(get_offset_time_original_from_tiff(&tiff).as_deref(), Some("+09:00"));
+        assert_eq!(get_sub_sec_time_original_from_tiff(&tiff).as_deref(), Some("123"));
+
+        // JPEGのAPP1セグメントから読む場合も同じ
+        let jpeg = [&[0xFF, 0xD8][..], &app1_from_tiff(&tiff).unwrap(), &[0xFF, 0xDA, 0x00, 0x02, 0xFF, 0xD9]].concat();
+        assert_eq!(&get_date_time_original(&jpeg).unwrap(), b"2023:10:01 18:04:59");
+    }
+
+    #[test]
+    fn sony_sample() {
+        let tiff = sony_tiff();
+        assert_eq!(is_big_endian(&tiff), Some(false));
+        // MakerNoteの中のオフセット（0x1F000など）は辿らずに，後ろのタグを読む
+        assert_eq!(get_camera_from_tiff(&tiff), Some(camera("SONY", "ILCE-7M3", "FE 24-70mm F2.8 GM", "ILCE-7M3 v3.10")));
+        assert_eq!(&get_date_time_original_from_tiff(&tiff).unwrap(), b"2021:08:14 06:45:12");
+        assert_eq!(get_offset_time_original_from_tiff(&tiff).as_deref(), Some("+02:00"));
+        assert_eq!(get_sub_sec_time_original_from_tiff(&tiff).as_deref(), Some("507"));
+        let exif = Exif::from_tiff(&tiff).unwrap();
+        assert_eq!(exif.get(Ifd::Exif, MAKER_NOTE), Some(&TagValue::Undefined(SONY_MAKER_NOTE.to_vec())));
+    }
+
+    #[test]
+    fn maker_note_is_kept_as_bytes() {
+        for tiff in [canon_tiff(), nikon_tiff(), iphone_tiff(), sony_tiff()] {
+            let exif = Exif::from_tiff(&tiff).unwrap();
+            assert!(matches!(exif.get(Ifd::Exif, MAKER_NOTE), Some(TagValue::Undefined(_))));
+            assert!(exif.get(Ifd::Exif, LENS_MODEL).is_some());
+        }
+    }
+
+    #[test]
+    fn missing_camera_tags() {
+        let tiff = build_tiff(false, &[(MAKE, ASCII, b"Canon\0"), (MODEL, ASCII, b"    \0")], &[]);
+        assert_eq!(get_camera_from_tiff(&tiff), Some(Camera { make: Some("Canon".to_string()), ..Camera::default() }));
+        assert_eq!(get_camera_from_tiff(b"II\x2A\0"), None);
+    }
+
+    /// 全ての読み書きの関数に通す（壊れたExifでpanicしないことだけを確かめる）．
+    fn read_everything(tiff: &[u8]) {
+        let _ = Exif::from_tiff(tiff).map(|exif| exif.iter().count());
+        let _ = get_camera_from_tiff(tiff);
+        let _ = get_date_time_original_from_tiff(tiff);
+        let _ = get_date_time_digitized_from_tiff(tiff);
+        let _ = get_sub_sec_time_original_from_tiff(tiff);
+        let _ = get_offset_time_original_from_tiff(tiff);
+        let _ = get_date_time_from_tiff(tiff);
+        let _ = get_orientation_from_tiff(tiff);
+        let _ = get_gps_from_tiff(tiff);
+        let _ = get_gps_date_time_from_tiff(tiff);
+        let _ = get_image_unique_id_from_tiff(tiff);
+        let _ = get_user_comment_from_tiff(tiff);
+        if let Some(app1) = app1_from_tiff(tiff) {
+            let _ = has_app1_thumbnail(&app1);
+            let _ = replace_app1_thumbnail(&app1, None);
+            let _ = clear_app1_orientation(app1.clone());
+            let _ = set_app1_dimensions(app1.clone(), 640, 480);
+            let jpeg = jpeg_with_app1(&app1);
+            let _ = strip_gps(&jpeg);
+            let _ = strip_thumbnail(&jpeg);
+            let _ = shift_date_times(&jpeg, 3600);
+            let _ = set_date_time_original(&jpeg, b"2024:02:29 12:00:00");
+            let _ = set_tags(&jpeg, &[NewTag::Artist("someone")], true);
+        }
+    }
+
+    fn jpeg_with_app1(app1: &[u8]) -> Vec<u8> {
+        [&[0xFF, 0xD8][..], app1, &[0xFF, 0xDA, 0x00, 0x02, 0xFF, 0xD9]].concat()
+    }
+
+    /// 書き換えたCanonのサンプル（リトルエンディアン）．
+    /// 0th IFDは8から始まり，各フィールドは10 + 12 * i（Make，Model，Software，Exif IFDポインタの順）．
+    fn corrupt_canon(patches: &[(usize, &[u8])]) -> Vec<u8> {
+        let mut tiff = canon_tiff();
+        for &(offset, bytes) in patches {
+            tiff[offset..(offset + bytes.len())].copy_from_slice(bytes);
+        }
+        tiff
+    }
+
+    #[test]
+    fn corrupt_exif_corpus() {
+        let make = |tiff: &[u8]| get_camera_from_tiff(tiff).and_then(|camera| camera.make);
+        let date = |tiff: &[u8]| get_date_time_original_from_tiff(tiff).map(|date| String::from_utf8_lossy(&date).into_owned());
+        let cases = [
+            ("0th IFD offset beyond the segment", corrupt_canon(&[(4, &[0xF0, 0xFF, 0xFF, 0xFF])]), None, None),
+            ("tag count larger than the remaining bytes", corrupt_canon(&[(8, &[0xFF, 0xFF])]), Some("Canon"), Some("2023:01:23 14:30:27")),
+            ("value offset past the end", corrupt_canon(&[(18, &[0x00, 0xFF, 0xFF, 0xFF])]), None, Some("2023:01:23 14:30:27")),
+            ("value count overflowing the offset", corrupt_canon(&[(14, &[0xFF, 0xFF, 0xFF, 0xFF])]), None, Some("2023:01:23 14:30:27")),
+            ("Exif IFD pointer past the end", corrupt_canon(&[(54, &[0x00, 0x00, 0x01, 0x00])]), Some("Canon"), None),
+            ("unknown value type", corrupt_canon(&[(12, &[0x63, 0x00])]), None, Some("2023:01:23 14:30:27")),
+            ("broken byte order", corrupt_canon(&[(0, b"IM")]), None, None),
+        ];
+        for (name, tiff, expected_make, expected_date) in cases {
+            read_everything(&tiff);
+            assert_eq!(make(&tiff).as_deref(), expected_make, "{}", name);
+            assert_eq!(date(&tiff).as_deref(), expected_date, "{}", name);
+        }
+    }
+
+    #[test]
+    fn mutated_and_truncated_exif_does_not_panic() {
+        for tiff in [canon_tiff(), nikon_tiff(), iphone_tiff(), sony_tiff()] {
+            for len in 0..tiff.len() {
+                read_everything(&tiff[..len]);
+            }
+            for i in 0..tiff.len() {
+                for value in [0x00, 0x7F, 0x80, 0xFF] {
+                    let mut mutated = tiff.clone();
+                    mutated[i] = value;
+                    read_everything(&mutated);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_app1_is_ignored() {
+        // 途中で切れたAPP1は読まずに，ハッシュ値だけで名前を付ける警告の対象にする（呼び出し側のhas_truncated_segment）
+        let app1 = app1_from_tiff(&iphone_tiff()).unwrap();
+        let jpeg = jpeg_with_app1(&app1);
+        assert!(get_app1(&jpeg).is_some());
+        assert!(!jpeg::has_truncated_segment(&jpeg));
+        for len in 3..(2 + app1.len()) {
+            let truncated = &jpeg[..len];
+            assert_eq!(get_app1(truncated), None, "truncated at {}", len);
+            assert_eq!(get_date_time_original(truncated), None, "truncated at {}", len);
+            assert!(jpeg::has_truncated_segment(truncated), "truncated at {}", len);
+            let _ = strip_gps(truncated);
+            let _ = shift_date_times(truncated, 60);
+            let _ = set_date_time_original(truncated, b"2024:02:29 12:00:00");
+        }
+    }
+
+    #[test]
+    fn bad_vendor_date_strings() {
+        // （DateTimeOriginalのvalue，ファイル名に使う日時）
+        let cases: [(&[u8], Option<&str>); 16] = [
+            (b"2023:01:23 14:30:27\0", Some("2023:01:23 14:30:27")),
+            // 詰め物の後ろは捨てる
+            (b"2023:01:23 14:30:27\0\0\0\0", Some("2023:01:23 14:30:27")),
+            // 19文字より長い場合は先頭の19文字を使う
+            (b"2023:01:23 14:30:27.123+09:00\0", Some("2023:01:23 14:30:27")),
+            (b"2016:12:31 23:59:60\0", Some("2016:12:31 23:59:60")),
+            // 未設定
+            (b"                   \0", None),
+            (b"    :  :     :  :  \0", None),
+            (b"0000:00:00 00:00:00\0", None),
+            // 途中にNULL文字
+            (b"2023:01:23\0\0\0\0\0\0\0\0\0\0", None),
+            (b"2023:01\0 14:30:27\0", None),
+            // ASCIIでもUTF-8でもない
+            (b"\xFF\xFE2\x003\x00:\x000\x001\x00:\x00\0", None),
+            (b"2023:01:23 14:30:2\xB7\0", None),
+            // パスの区切り文字など，ファイル名に入ると困る文字
+            (b"2023/01/23 14:30:27\0", None),
+            (b"2023:01:23 14\\30:27\0", None),
+            // 範囲外
+            (b"2023:13:01 00:00:00\0", None),
+            (b"2023:01:32 25:00:00\0", None),
+            // 短すぎる
+            (b"2023:01:23\0", None),
+        ];
+        for (value, expected) in cases {
+            let tiff = build_tiff(false, &[], &[(DATE_TIME_ORIGINAL, ASCII, value)]);
+            let date_time = get_date_time_original_from_tiff(&tiff).filter(datetime::is_valid);
+            assert_eq!(date_time.as_ref().map(|date_time| std::str::from_utf8(date_time).unwrap()), expected, "{:?}", value.escape_ascii().to_string());
+        }
+    }
+
+    /// build_tiffで作ったTIFFデータの末尾に，インラインのvalueだけを持つ1st IFDを足す．
+    fn with_ifd1(mut tiff: Vec<u8>, big_endian: bool, fields: &[(u16, u16, &[u8])]) -> Vec<u8> {
+        let byte_order = if big_endian { ByteOrder::BigEndian } else { ByteOrder::LittleEndian };
+        if tiff.len() % 2 == 1 {
+            tiff.push(0);
+        }
+        let ifd1_offset = tiff.len();
+        let next_offset = 8 + 2 + 12 * decode_u16(&tiff[8..10], &byte_order) as usize;
+        tiff[next_offset..(next_offset + 4)].copy_from_slice(&encode_u32(ifd1_offset as u32, &byte_order));
+        tiff.extend_from_slice(&encode_u16(fields.len() as u16, &byte_order));
+        for &(tag, value_type, value) in fields {
+            tiff.extend_from_slice(&encode_u16(tag, &byte_order));
+            tiff.extend_from_slice(&encode_u16(value_type, &byte_order));
+            tiff.extend_from_slice(&encode_u32((value.len() / type_size(value_type).unwrap()) as u32, &byte_order));
+            let mut inline = [0u8; 4];
+            inline[..value.len()].copy_from_slice(value);
+            tiff.extend_from_slice(&inline);
+        }
+        tiff.extend_from_slice(&[0; 4]);
+        tiff
+    }
+
+    #[test]
+    fn thumbnail_orientation_is_cleared() {
+        for big_endian in [false, true] {
+            let byte_order = if big_endian { ByteOrder::BigEndian } else { ByteOrder::LittleEndian };
+            let short = |value: u16| encode_u16(value, &byte_order);
+            let orientation = |app1: &[u8], ifd: Ifd| Exif::from_tiff(&app1[OFFSET_TIFF_HEADER..]).unwrap().get(ifd, ORIENTATION).cloned();
+            let (six, eight, length) = (short(6), short(8), encode_u32(0, &byte_order));
+
+            // 主画像とサムネイルが両方とも回転している
+            let tiff = with_ifd1(build_tiff(big_endian, &[(ORIENTATION, SHORT, &six)], &[]), big_endian, &[(ORIENTATION, SHORT, &eight)]);
+            let cleared = clear_app1_orientation(app1_from_tiff(&tiff).unwrap());
+            assert_eq!(orientation(&cleared, Ifd::Ifd0), Some(TagValue::Short(vec![1])));
+            assert_eq!(orientation(&cleared, Ifd::Ifd1), Some(TagValue::Short(vec![1])));
+            assert_eq!(cleared.len(), tiff.len() + OFFSET_TIFF_HEADER);
+
+            // サムネイルにOrientationが無い場合は，他のタグを書き換えない
+            let tiff = with_ifd1(build_tiff(big_endian, &[(ORIENTATION, SHORT, &six)], &[]), big_endian, &[(JPEG_INTERCHANGE_FORMAT_LENGTH, LONG, &length)]);
+            let app1 = app1_from_tiff(&tiff).unwrap();
+            let cleared = clear_app1_orientation(app1.clone());
+            assert_eq!(orientation(&cleared, Ifd::Ifd0), Some(TagValue::Short(vec![1])));
+            assert_eq!(orientation(&cleared, Ifd::Ifd1), None);
+            assert_eq!(cleared[(app1.len() - 18)..], app1[(app1.len() - 18)..]);
+
+            // 1st IFDへのオフセットが範囲外でも，0th IFDは書き換える
+            let mut tiff = with_ifd1(build_tiff(big_endian, &[(ORIENTATION, SHORT, &six)], &[]), big_endian, &[(ORIENTATION, SHORT, &eight)]);
+            let next_offset = 8 + 2 + 12 * 2;
+            tiff[next_offset..(next_offset + 4)].copy_from_slice(&encode_u32(0xFFFF_FF00, &byte_order));
+            let cleared = clear_app1_orientation(app1_from_tiff(&tiff).unwrap());
+            assert_eq!(orientation(&cleared, Ifd::Ifd0), Some(TagValue::Short(vec![1])));
+        }
+    }
+
+    #[test]
+    fn invalid_tiff_header_is_not_exif() {
+        let jpeg = jpeg_with_app1(&app1_from_tiff(&iphone_tiff()).unwrap());
+        assert!(get_app1(&jpeg).is_some());
+        // バイトオーダーが0埋め，II・MM以外，バイトオーダーとマジックナンバーが合わない
+        for header in [b"\0\0\0\0", b"IM\0\x2A", b"II\0\x2A", b"MM\x2A\0"] {
+            let mut tiff = iphone_tiff();
+            tiff[..4].copy_from_slice(header);
+            let jpeg = jpeg_with_app1(&app1_from_tiff(&tiff).unwrap());
+            assert_eq!(get_app1(&jpeg), None, "{:?}", header);
+            assert_eq!(get_orientation(&jpeg), None, "{:?}", header);
+            assert_eq!(get_date_time_original(&jpeg), None, "{:?}", header);
+            assert_eq!(strip_gps(&jpeg), None, "{:?}", header);
+            // 壊れたAPP1は置き換えずに，新しいExifを挿入する
+            let stamped = set_date_time_original(&jpeg, b"2024:02:29 12:00:00").unwrap();
+            assert_eq!(&get_date_time_original(&stamped).unwrap(), b"2024:02:29 12:00:00", "{:?}", header);
+            assert!(stamped.ends_with(&jpeg[2..]), "{:?}", header);
+        }
+    }
+}
@@ -0,0 +1,264 @@
+//! リネームや書き込みなど，ファイルを変更する操作をまとめたモジュール
+//!
+//! --dry-runの場合は実際には変更せずに，変更した後の状態（どの名前が空いていて，どの名前が使われているか）を
+//! メモリ上に記録しておく．名前の重複の確認なども同じ処理を通るので，本番と同じ結果の計画を表示できる．
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// --dry-runで変更したことにしたパスの状態
+#[derive(Clone)]
+enum Entry {
+    /// 別のパス（実際にファイルがある場所）から移動した，またはコピーした
+    Moved(PathBuf),
+    /// 新しく書き出した（中身の長さ）
+    Written(u64),
+    /// 削除した，または別のパスに移動した
+    Removed,
+}
+
+/// ファイルを変更する操作
+///
+/// 既定値（Default）は実際に変更する．
+#[derive(Default)]
+pub struct FileOps {
+    dry_run: bool,
+    /// --dry-runで変更したことにしたパス
+    overlay: RefCell<HashMap<PathBuf, Entry>>,
+}
+
+impl FileOps {
+    /// dry_runの場合は実際には変更しない．
+    pub fn new(dry_run: bool) -> FileOps {
+        FileOps { dry_run, overlay: RefCell::new(HashMap::new()) }
+    }
+
+    pub fn is_dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    /// fromの名前をtoに変える．
+    pub fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        if !self.dry_run {
+            return fs::rename(from, to);
+        }
+        let mut overlay = self.overlay.borrow_mut();
+        let entry = match overlay.get(from) {
+            Some(Entry::Removed) => return Err(io::Error::new(io::ErrorKind::NotFound, format!("{} was already moved", from.display()))),
+            Some(entry) => entry.clone(),
+            None => Entry::Moved(from.to_path_buf()),
+        };
+        overlay.insert(from.to_path_buf(), Entry::Removed);
+        overlay.insert(to.to_path_buf(), entry);
+        Ok(())
+    }
+
+    /// fromをtoに移動する（別のドライブの場合はコピーしてから削除する）．
+    pub fn move_file(&self, from: &Path, to: &Path) -> io::Result<()> {
+        match self.rename(from, to) {
+            Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+                self.copy(from, to)?;
+                self.remove_file(from)
+            },
+            result => result,
+        }
+    }
+
+    /// fromをtoにコピーする（更新日時も元のファイルに揃える）．
+    pub fn copy(&self, from: &Path, to: &Path) -> io::Result<()> {
+        if !self.dry_run {
+            fs::copy(from, to)?;
+            // 更新日時を揃えられなくてもコピー自体は済んでいるので，失敗は無視する
+            let _ = fs::metadata(from).and_then(|metadata| metadata.modified()).and_then(|mtime| set_modified(to, mtime));
+            return Ok(());
+        }
+        let mut overlay = self.overlay.borrow_mut();
+        let entry = match overlay.get(from) {
+            Some(Entry::Removed) => return Err(io::Error::new(io::ErrorKind::NotFound, format!("{} was already moved", from.display()))),
+            Some(entry) => entry.clone(),
+            None => Entry::Moved(from.to_path_buf()),
+        };
+        overlay.insert(to.to_path_buf(), entry);
+        Ok(())
+    }
+
+    /// ファイルの更新日時を変える（--dry-runの場合は何もしない）．
+    pub fn set_modified(&self, path: &Path, mtime: SystemTime) -> io::Result<()> {
+        if self.dry_run {
+            return Ok(());
+        }
+        set_modified(path, mtime)
+    }
+
+    /// ファイルの作成日時を変える（--dry-runの場合や，CAN_SET_CREATEDがfalseのOSでは何もしない）．
+    pub fn set_created(&self, path: &Path, btime: SystemTime) -> io::Result<()> {
+        if self.dry_run {
+            return Ok(());
+        }
+        set_created(path, btime)
+    }
+
+    /// ファイルを削除する．
+    pub fn remove_file(&self, path: &Path) -> io::Result<()> {
+        if !self.dry_run {
+            return fs::remove_file(path);
+        }
+        self.overlay.borrow_mut().insert(path.to_path_buf(), Entry::Removed);
+        Ok(())
+    }
+
+    /// 新しいファイルを書き出す．
+    pub fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        if !self.dry_run {
+            return fs::write(path, contents);
+        }
+        self.overlay.borrow_mut().insert(path.to_path_buf(), Entry::Written(contents.len() as u64));
+        Ok(())
+    }
+
+    /// ファイルの中身を書き換える（crate::overwrite_file）．
+    pub fn overwrite(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        if !self.dry_run {
+            return crate::overwrite_file(path, contents);
+        }
+        self.overlay.borrow_mut().insert(path.to_path_buf(), Entry::Written(contents.len() as u64));
+        Ok(())
+    }
+
+    /// フォルダを作る（--dry-runの場合は，作らなくてもその中のパスは空いているものとして扱える）．
+    pub fn create_dir_all(&self, dir: &Path) -> io::Result<()> {
+        if self.dry_run {
+            return Ok(());
+        }
+        fs::create_dir_all(dir)
+    }
+
+    /// テキストファイルの末尾に1行追記する（無ければ作る）．
+    pub fn append_line(&self, path: &Path, line: &str) -> io::Result<()> {
+        if self.dry_run {
+            return Ok(());
+        }
+        let mut file = fs::OpenOptions::new().create(true).append(true).open(path)?;
+        writeln!(file, "{}", line)
+    }
+
+    /// そのパスにファイルかフォルダがあるかどうかを返す（Unicode正規化の違いだけの名前のものも含む）．
+    pub fn exists(&self, path: &Path) -> bool {
+        match self.overlay.borrow().get(path) {
+            Some(Entry::Removed) => false,
+            Some(_) => true,
+            None => path.exists() || crate::unicode::has_equivalent_entry(path),
+        }
+    }
+
+    /// そのパスにファイルがあるかどうかを返す．
+    pub fn is_file(&self, path: &Path) -> bool {
+        match self.overlay.borrow().get(path) {
+            Some(Entry::Removed) => false,
+            Some(_) => true,
+            None => path.is_file(),
+        }
+    }
+
+    /// そのパスのファイルのサイズを返す．
+    pub fn size(&self, path: &Path) -> io::Result<u64> {
+        match self.overlay.borrow().get(path) {
+            Some(Entry::Written(len)) => Ok(*len),
+            _ => Ok(fs::metadata(self.real_path(path))?.len()),
+        }
+    }
+
+    /// 空いている（変更したことにした状態で）リネーム先のパスを返す（crate::unique_paths）．
+    pub fn unique_paths(&self, srcs: &[PathBuf], paths: Vec<PathBuf>, stem: &str) -> Vec<PathBuf> {
+        crate::unique_paths_by(srcs, paths, stem, |path| self.exists(path))
+    }
+
+    /// そのパスのファイルの中身を実際に読めるパスを返す（--dry-runで移動したことにしたファイルは移動前のパス）．
+    pub fn real_path(&self, path: &Path) -> PathBuf {
+        match self.overlay.borrow().get(path) {
+            Some(Entry::Moved(real)) => real.clone(),
+            _ => path.to_path_buf(),
+        }
+    }
+}
+
+/// ファイルの更新日時を変える．
+fn set_modified(path: &Path, mtime: SystemTime) -> io::Result<()> {
+    fs::File::options().write(true).open(path)?.set_modified(mtime)
+}
+
+/// このOSでファイルの作成日時を変えられるかどうか（Linuxなどでは作成日時を変える方法が無い）
+pub const CAN_SET_CREATED: bool = cfg!(any(windows, target_os = "macos"));
+
+/// ファイルの作成日時を変える（WindowsはSetFileTime，macOSはsetattrlistを使う）．
+#[cfg(any(windows, target_os = "macos"))]
+fn set_created(path: &Path, btime: SystemTime) -> io::Result<()> {
+    #[cfg(target_os = "macos")]
+    use std::os::macos::fs::FileTimesExt;
+    #[cfg(windows)]
+    use std::os::windows::fs::FileTimesExt;
+
+    fs::File::options().write(true).open(path)?.set_times(fs::FileTimes::new().set_created(btime))
+}
+
+/// 作成日時を変えられないOSでは何もしない．
+#[cfg(not(any(windows, target_os = "macos")))]
+fn set_created(_path: &Path, _btime: SystemTime) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// テスト用の一時ファイルを作る（名前はテストごとに変える）．
+    fn temp_file(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("photo-organizer-test-{}-{}", std::process::id(), name));
+        fs::write(&path, b"photo").unwrap();
+        path
+    }
+
+    /// 2023-01-23 14:30:27（UTC）
+    fn btime() -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(1674484227)
+    }
+
+    #[test]
+    #[cfg(any(windows, target_os = "macos"))]
+    fn set_created_changes_the_creation_time() {
+        let path = temp_file("btime");
+        let mtime = fs::metadata(&path).unwrap().modified().unwrap();
+        FileOps::default().set_created(&path, btime()).unwrap();
+        let metadata = fs::metadata(&path).unwrap();
+        assert_eq!(metadata.created().unwrap(), btime());
+        assert_eq!(metadata.modified().unwrap(), mtime);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    #[cfg(not(any(windows, target_os = "macos")))]
+    fn set_created_does_nothing_where_unsupported() {
+        const { assert!(!CAN_SET_CREATED) };
+        let path = temp_file("btime");
+        let before = fs::metadata(&path).unwrap();
+        FileOps::default().set_created(&path, btime()).unwrap();
+        let after = fs::metadata(&path).unwrap();
+        assert_eq!(after.modified().unwrap(), before.modified().unwrap());
+        assert_ne!(after.created().ok(), Some(btime()));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn set_created_is_skipped_in_dry_run() {
+        let path = temp_file("btime-dry-run");
+        let before = fs::metadata(&path).unwrap().created().ok();
+        FileOps::new(true).set_created(&path, btime()).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().created().ok(), before);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,263 @@
+//! ファイル形式を判定するためのモジュール
+//! 拡張子による判定と，ファイル先頭のマジックナンバーによる判定（--sniff）に対応
+
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+
+use crate::raf;
+
+/// マジックナンバーの判定に使う先頭のバイト数（MPEG-2 TSの2パケット目の同期バイトまで読む）
+const HEAD_LEN: u64 = 256;
+
+/// MPEG-2 TSのパケット長と同期バイト
+const TS_PACKET_LEN: usize = 188;
+const TS_SYNC_BYTE: u8 = 0x47;
+
+/// 処理対象のファイル形式
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FileType {
+    Jpeg,
+    Png,
+    Tiff,
+    WebP,
+    Heic,
+    Avif,
+    Dng,
+    Cr2,
+    Cr3,
+    Nef,
+    Arw,
+    Raf,
+    Orf,
+    Rw2,
+    Gif,
+    Bmp,
+    Mp4,
+    Mov,
+    Mts,
+    ThreeGp,
+    ThreeG2,
+    Mpo,
+}
+
+impl FileType {
+    /// 中身から形式を判定した場合に付ける拡張子（小文字）
+    pub fn extension(&self) -> &'static str {
+        match self {
+            FileType::Jpeg => "jpg",
+            FileType::Png  => "png",
+            FileType::Tiff => "tif",
+            FileType::WebP => "webp",
+            FileType::Heic => "heic",
+            FileType::Avif => "avif",
+            FileType::Dng  => "dng",
+            FileType::Cr2  => "cr2",
+            FileType::Cr3  => "cr3",
+            FileType::Nef  => "nef",
+            FileType::Arw  => "arw",
+            FileType::Raf  => "raf",
+            FileType::Orf  => "orf",
+            FileType::Rw2  => "rw2",
+            FileType::Gif  => "gif",
+            FileType::Bmp  => "bmp",
+            FileType::Mp4  => "mp4",
+            FileType::Mov  => "mov",
+            FileType::Mts  => "mts",
+            FileType::ThreeGp => "3gp",
+            FileType::ThreeG2 => "3g2",
+            FileType::Mpo  => "mpo",
+        }
+    }
+
+    /// ISO-BMFFの動画ファイルかどうか．
+    /// 
+    /// これらの動画はmoovボックスだけをファイルから読み込んで作成日時を読む．
+    pub fn is_bmff_video(&self) -> bool {
+        matches!(self, FileType::Mp4 | FileType::Mov | FileType::ThreeGp | FileType::ThreeG2)
+    }
+
+    /// 動画ファイルかどうか．
+    pub fn is_video(&self) -> bool {
+        self.is_bmff_video() || *self == FileType::Mts
+    }
+
+    /// 撮影日時を持たない形式（ハッシュ値のみでリネームする）かどうか．
+    /// これらの形式は--all-imagesを指定した場合のみ処理する．
+    pub fn is_hash_only(&self) -> bool {
+        matches!(self, FileType::Gif | FileType::Bmp)
+    }
+
+    /// RAWファイルかどうか．
+    pub fn is_raw(&self) -> bool {
+        matches!(self, FileType::Dng | FileType::Cr2 | FileType::Cr3 | FileType::Nef
+            | FileType::Arw | FileType::Raf | FileType::Orf | FileType::Rw2)
+    }
+
+    /// ファイル自体がTIFF構造になっている形式かどうか．
+    pub fn is_tiff_based(&self) -> bool {
+        matches!(self, FileType::Tiff | FileType::Dng | FileType::Cr2 | FileType::Nef
+            | FileType::Arw | FileType::Orf | FileType::Rw2)
+    }
+
+    /// 中身から判定した形式が，拡張子から判定したこの形式と矛盾しないかどうか．
+    /// 
+    /// MPOの先頭はJPEGと同じで，DNGなどのTIFFベースのRAWは中身だけではTIFFと区別できない．
+    /// 動画のmajor brandは撮影機器によってまちまちなので，ISO-BMFFの動画同士は区別しない．
+    pub fn matches_content(&self, sniffed: FileType) -> bool {
+        *self == sniffed
+            || (*self == FileType::Mpo && sniffed == FileType::Jpeg)
+            || (self.is_tiff_based() && sniffed == FileType::Tiff)
+            || (self.is_bmff_video() && sniffed.is_bmff_video())
+    }
+
+    /// ファイルサイズが大きくなりがちな形式（RAWや動画など）かどうか．
+    /// 
+    /// これらの形式はファイル全体を読み込まず，メタデータは先頭部分だけから読み出し，
+    /// ハッシュ値はファイルを少しずつ読みながら計算する．
+    pub fn is_large(&self) -> bool {
+        self.is_raw() || self.is_video()
+    }
+}
+
+/// 拡張子からファイル形式を判定する（大文字小文字は区別しない）．
+pub fn from_extension(ext: &OsStr) -> Option<FileType> {
+    match ext.to_ascii_lowercase().to_str()? {
+        "jpg" | "jpeg" => Some(FileType::Jpeg),
+        "thm" => Some(FileType::Jpeg),  // 動画のサムネイル（中身は小さいJPEG）
+        "png" => Some(FileType::Png),
+        "tif" | "tiff" => Some(FileType::Tiff),
+        "webp" => Some(FileType::WebP),
+        "heic" | "heif" => Some(FileType::Heic),
+        "avif" => Some(FileType::Avif),
+        "dng" => Some(FileType::Dng),
+        "cr2" => Some(FileType::Cr2),
+        "cr3" => Some(FileType::Cr3),
+        "nef" => Some(FileType::Nef),
+        "arw" => Some(FileType::Arw),
+        "raf" => Some(FileType::Raf),
+        "orf" => Some(FileType::Orf),
+        "rw2" => Some(FileType::Rw2),
+        "gif" => Some(FileType::Gif),
+        "bmp" => Some(FileType::Bmp),
+        "mp4" => Some(FileType::Mp4),
+        "mov" => Some(FileType::Mov),
+        "mts" | "m2ts" => Some(FileType::Mts),
+        "3gp" => Some(FileType::ThreeGp),
+        "3g2" => Some(FileType::ThreeG2),
+        "mpo" => Some(FileType::Mpo),
+        _ => None,
+    }
+}
+
+/// ファイル先頭のバイト列（マジックナンバー）からファイル形式を判定する．
+pub fn from_magic(head: &[u8]) -> Option<FileType> {
+    if head.starts_with(&[0xFF, 0xD8, 0xFF]) {  // SOIマーカ + 次のセグメントのマーカ
+        Some(FileType::Jpeg)
+    } else if head.starts_with(&[0x89, 0x50, 0x4E, 0x47, 0x0D, 0x0A, 0x1A, 0x0A]) {  // PNGシグネチャ
+        Some(FileType::Png)
+    } else if head.starts_with(b"II*\0") && head.get(8..10) == Some(b"CR") {  // TIFFヘッダ + CR2の識別子
+        Some(FileType::Cr2)
+    } else if head.starts_with(b"IIRO") || head.starts_with(b"IIRS") || head.starts_with(b"MMOR") {  // ORFの独自ヘッダ
+        Some(FileType::Orf)
+    } else if head.starts_with(b"IIU\0") {  // RW2の独自ヘッダ
+        Some(FileType::Rw2)
+    } else if head.starts_with(b"II*\0") || head.starts_with(b"MM\0*") {  // TIFFヘッダ
+        Some(FileType::Tiff)
+    } else if head.len() >= 12 && head.starts_with(b"RIFF") && &head[8..12] == b"WEBP" {  // RIFFヘッダ
+        Some(FileType::WebP)
+    } else if head.starts_with(b"GIF87a") || head.starts_with(b"GIF89a") {
+        Some(FileType::Gif)
+    } else if head.starts_with(b"BM") {
+        Some(FileType::Bmp)
+    } else if head.starts_with(raf::MAGIC) {
+        Some(FileType::Raf)
+    } else if head.len() >= 12 && &head[4..8] == b"ftyp" {  // ISO-BMFFのftypボックス
+        // compatible brandsはボックスの残り（先頭の読んだ部分に収まる分だけ見る）
+        let size = u32::from_be_bytes([head[0], head[1], head[2], head[3]]) as usize;
+        let compatible_brands = head.get(16..size.min(head.len())).unwrap_or(&[]);
+        match &head[8..12] {  // major brand
+            b"avif" | b"avis" => Some(FileType::Avif),
+            b"crx " => Some(FileType::Cr3),
+            b"isom" | b"iso2" | b"iso4" | b"iso5" | b"iso6" | b"mp41" | b"mp42" | b"avc1" | b"M4V "
+                | b"dash" | b"f4v " | b"MSNV" | b"XAVC" => Some(FileType::Mp4),
+            b"qt  " => Some(FileType::Mov),
+            brand if brand.starts_with(b"3gp") || brand.starts_with(b"3ge") || brand.starts_with(b"3gg") => Some(FileType::ThreeGp),
+            brand if brand.starts_with(b"3g2") => Some(FileType::ThreeG2),
+            b"heic" | b"heix" | b"heim" | b"heis" | b"hevc" | b"hevx" => Some(FileType::Heic),
+            // MIAF（HEIFの共通部分）のbrandだけではHEICかAVIFか分からないので，compatible brandsで決める
+            b"mif1" | b"msf1" => compatible_brands.chunks_exact(4).find_map(|brand| match brand {
+                b"avif" | b"avis" => Some(FileType::Avif),
+                b"heic" | b"heix" | b"heim" | b"heis" | b"hevc" | b"hevx" => Some(FileType::Heic),
+                _ => None,
+            }),
+            _ => None,
+        }
+    } else if head.len() >= 8 && matches!(&head[4..8], b"moov" | b"mdat" | b"wide" | b"free" | b"skip" | b"pnot") {  // ftypの無い古いQuickTime
+        Some(FileType::Mov)
+    } else if head.first() == Some(&TS_SYNC_BYTE) && head.get(TS_PACKET_LEN) == Some(&TS_SYNC_BYTE) {  // MPEG-2 TS（.mts）
+        Some(FileType::Mts)
+    } else if head.get(4) == Some(&TS_SYNC_BYTE) && head.get(4 + TS_PACKET_LEN + 4) == Some(&TS_SYNC_BYTE) {  // 4byteのタイムスタンプ付きのTS（.m2ts）
+        Some(FileType::Mts)
+    } else {
+        None
+    }
+}
+
+/// ファイルの先頭数バイトだけを読んでファイル形式を判定する．
+pub fn sniff(path: &Path) -> io::Result<Option<FileType>> {
+    let mut head = Vec::with_capacity(HEAD_LEN as usize);
+    fs::File::open(path)?.take(HEAD_LEN).read_to_end(&mut head)?;
+    Ok(from_magic(&head))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// ftypボックス（major brandとcompatible brands）で始まる先頭部分を作る．
+    fn ftyp(major: &[u8; 4], compatible: &[&[u8; 4]]) -> Vec<u8> {
+        let mut head = ((16 + 4 * compatible.len()) as u32).to_be_bytes().to_vec();
+        head.extend_from_slice(b"ftyp");
+        head.extend_from_slice(major);
+        head.extend_from_slice(&[0; 4]);
+        compatible.iter().for_each(|brand| head.extend_from_slice(*brand));
+        head
+    }
+
+    #[test]
+    fn miaf_brand_is_decided_by_compatible_brands() {
+        assert_eq!(from_magic(&ftyp(b"mif1", &[b"mif1", b"miaf", b"avif"])), Some(FileType::Avif));
+        assert_eq!(from_magic(&ftyp(b"msf1", &[b"msf1", b"avis"])), Some(FileType::Avif));
+        assert_eq!(from_magic(&ftyp(b"mif1", &[b"mif1", b"heic"])), Some(FileType::Heic));
+        assert_eq!(from_magic(&ftyp(b"heic", &[b"mif1", b"heic"])), Some(FileType::Heic));
+        assert_eq!(from_magic(&ftyp(b"avif", &[b"mif1", b"miaf"])), Some(FileType::Avif));
+        // どちらか分からない場合は判定しない（拡張子の形式のままにする）
+        assert_eq!(from_magic(&ftyp(b"mif1", &[b"mif1", b"miaf"])), None);
+    }
+
+    #[test]
+    fn compatible_brands_beyond_the_box_are_ignored() {
+        let mut head = ftyp(b"mif1", &[b"mif1"]);
+        head.extend_from_slice(&[0, 0, 0, 8]);
+        head.extend_from_slice(b"avif");  // 次のボックスの種類（compatible brandではない）
+        assert_eq!(from_magic(&head), None);
+    }
+
+    #[test]
+    fn quicktime_without_ftyp() {
+        for atom in [b"wide", b"mdat", b"moov"] {
+            let mut head = vec![0, 0, 0, 8];
+            head.extend_from_slice(atom);
+            assert_eq!(from_magic(&head), Some(FileType::Mov));
+        }
+        assert!(FileType::Mp4.matches_content(FileType::Mov));
+    }
+
+    #[test]
+    fn unknown_contents() {
+        assert_eq!(from_magic(b"not an image at all"), None);
+        assert_eq!(from_magic(&[]), None);
+    }
+}
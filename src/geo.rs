@@ -0,0 +1,61 @@
+//! 位置情報をファイル名に入れるための文字列に変換するモジュール
+//! ファイル名に使えるように，コロンや空白，マイナス記号は使わない
+
+use crate::exif::Gps;
+
+/// ジオハッシュで使う32進数の文字（a, i, l, oは使わない）
+const GEOHASH_BASE32: &[u8; 32] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+
+/// 緯度・経度を返す．どちらかが無い場合や範囲外の場合はNoneを返す．
+fn lat_lon(gps: &Gps) -> Option<(f64, f64)> {
+    let latitude = gps.latitude?;
+    let longitude = gps.longitude?;
+    if (-90.0..=90.0).contains(&latitude) && (-180.0..=180.0).contains(&longitude) {
+        Some((latitude, longitude))
+    } else {
+        None
+    }
+}
+
+/// 緯度・経度を小数点以下places桁の10進数で表した文字列（35.6581N139.7414E）にする．
+///
+/// 符号の代わりに方角（N/S, E/W）を後ろに付ける．
+pub fn to_decimal(gps: &Gps, places: usize) -> Option<String> {
+    let (latitude, longitude) = lat_lon(gps)?;
+    let latitude_ref  = if latitude  < 0.0 { 'S' } else { 'N' };
+    let longitude_ref = if longitude < 0.0 { 'W' } else { 'E' };
+    Some(format!("{:.*}{}{:.*}{}", places, latitude.abs(), latitude_ref, places, longitude.abs(), longitude_ref))
+}
+
+/// 緯度・経度をlen文字のジオハッシュ（xn76urx）にする．
+///
+/// 経度と緯度を交互に二分していき，5ビットごとに1文字にする．
+pub fn to_geohash(gps: &Gps, len: usize) -> Option<String> {
+    let (latitude, longitude) = lat_lon(gps)?;
+    let mut lat_range = (-90.0, 90.0);
+    let mut lon_range = (-180.0, 180.0);
+
+    let mut geohash = String::with_capacity(len);
+    let mut is_lon = true;
+    while geohash.len() < len {
+        let mut index = 0;
+        for _ in 0..5 {
+            let (value, range) = if is_lon {
+                (longitude, &mut lon_range)
+            } else {
+                (latitude, &mut lat_range)
+            };
+            let mid = (range.0 + range.1) / 2.0;
+            index <<= 1;
+            if value >= mid {
+                index |= 1;
+                range.0 = mid;
+            } else {
+                range.1 = mid;
+            }
+            is_lon = !is_lon;
+        }
+        geohash.push(GEOHASH_BASE32[index] as char);
+    }
+    Some(geohash)
+}
@@ -0,0 +1,214 @@
+//! 同じファイル名を持つファイルの組を扱うためのモジュール
+//! 組になったファイルは代表のファイルから撮影日時とハッシュ値を求めて，全て同じ名前にリネームする
+//! 
+//! * AppleのLive Photo：IMG_1234.HEICとIMG_1234.MOVのような静止画と動画の組
+//! * RAW+JPEG（--pair-raw）：DSC_0042.NEFとDSC_0042.JPGのような同時に記録したRAWとJPEGの組
+//! * 動画のサムネイル（--pair-raw）：GOPR0042.MP4とGOPR0042.THMのような動画とサムネイルのJPEGの組
+//! * Google TakeoutのJSON（--fallback takeout）：IMG_0042.jpg.jsonのようなTakeoutが書き出すメタデータ
+//! * XMPサイドカー：LightroomやdarktableがIMG_0042.xmpやIMG_0042.jpg.xmpとして書き出す編集情報
+
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use crate::filetype::{self, FileType};
+use crate::takeout;
+
+/// 代表のファイルのパスをキー，一緒にリネームするファイルのパスを値とするHashMap
+pub type Groups = HashMap<PathBuf, Vec<PathBuf>>;
+
+/// Live Photoの静止画として扱う拡張子（小文字）
+const STILL_EXTENSIONS: [&str; 4] = ["heic", "heif", "jpg", "jpeg"];
+
+/// Live Photoの動画として扱う拡張子（小文字）
+const VIDEO_EXTENSION: &str = "mov";
+
+/// 動画のサムネイルの拡張子（小文字）
+const THUMBNAIL_EXTENSION: &str = "thm";
+
+/// XMPサイドカーの拡張子（小文字）
+const SIDECAR_EXTENSION: &str = "xmp";
+
+/// 拡張子を除いたファイル名（大文字小文字は区別しない）と，小文字の拡張子を返す．
+fn split_name(path: &Path) -> Option<(OsString, String)> {
+    let stem = path.file_stem()?.to_ascii_lowercase();
+    let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+    Some((stem, ext))
+}
+
+/// 同じフォルダ内のファイルから，同じファイル名を持つLive Photoの静止画と動画の組を探してgroupsに追加する．
+/// 
+/// 静止画が見つからない動画は含まれない（普通の動画として扱う）．
+pub fn find_live_photos(paths: &[PathBuf], groups: &mut Groups) {
+    let mut videos = HashMap::new();
+    for path in paths {
+        if let Some((stem, ext)) = split_name(path) {
+            if ext == VIDEO_EXTENSION {
+                videos.insert(stem, path);
+            }
+        }
+    }
+
+    for path in paths {
+        if let Some((stem, ext)) = split_name(path) {
+            if STILL_EXTENSIONS.contains(&ext.as_str()) {
+                // 同じ名前の静止画が複数ある場合（IMG_1234.HEICとIMG_1234.JPGなど）は先に見つかった方と組にする
+                if let Some(video) = videos.remove(&stem) {
+                    groups.entry(path.clone()).or_default().push(video.clone());
+                }
+            }
+        }
+    }
+}
+
+/// 同じフォルダ内のファイルから，同じファイル名を持つRAWとJPEGの組を探してgroupsに追加する．
+/// 
+/// JPEGがあればJPEGを，無ければRAWのうちの一つを代表にする．
+/// JPEGと組にならない単独のRAWは含まれない（普通のRAWとして扱う）．
+pub fn find_raw_pairs(paths: &[PathBuf], groups: &mut Groups) {
+    // ファイル名ごとに (JPEG, RAW) を集める
+    let mut stems: BTreeMap<OsString, (Option<&PathBuf>, Vec<&PathBuf>)> = BTreeMap::new();
+    for path in paths {
+        let (stem, ext) = match split_name(path) {
+            Some(name) => name,
+            None => continue,
+        };
+        match filetype::from_extension(ext.as_ref()) {
+            Some(FileType::Jpeg) if ext != THUMBNAIL_EXTENSION => {
+                let entry = stems.entry(stem).or_default();
+                entry.0 = entry.0.or(Some(path));
+            },
+            Some(file_type) if file_type.is_raw() => stems.entry(stem).or_default().1.push(path),
+            _ => (),
+        }
+    }
+
+    for (_, (jpeg, mut raws)) in stems {
+        if raws.is_empty() || (jpeg.is_none() && raws.len() < 2) {
+            continue;
+        }
+        raws.sort();  // 代表にするRAWが実行の度に変わらないようにする
+        let primary = match jpeg {
+            Some(jpeg) => jpeg,
+            None => raws.remove(0),
+        };
+        groups.entry(primary.clone()).or_default().extend(raws.into_iter().cloned());
+    }
+}
+
+/// 組の代表のファイルを返す（どの組にも属していなければpath自身）．
+fn primary_of<'a>(groups: &'a Groups, path: &'a PathBuf) -> &'a PathBuf {
+    if groups.contains_key(path) {
+        return path;
+    }
+    groups.iter()
+        .find(|(_, members)| members.contains(path))
+        .map(|(primary, _)| primary)
+        .unwrap_or(path)
+}
+
+/// 同じフォルダ内の動画と，同じファイル名を持つサムネイル（.thm）の組を探してgroupsに追加する．
+/// 
+/// 動画が見つからないサムネイルは含まれない（普通のJPEGとして扱う）．
+pub fn find_thumbnails(paths: &[PathBuf], groups: &mut Groups) {
+    let mut videos = HashMap::new();
+    for path in paths {
+        if let Some((stem, ext)) = split_name(path) {
+            if filetype::from_extension(ext.as_ref()).is_some_and(|t| t.is_video()) {
+                videos.insert(stem, path);
+            }
+        }
+    }
+
+    for path in paths {
+        if let Some((stem, ext)) = split_name(path) {
+            if ext == THUMBNAIL_EXTENSION {
+                if let Some(video) = videos.get(&stem) {
+                    let primary = primary_of(groups, video).clone();
+                    groups.entry(primary).or_default().push(path.clone());
+                }
+            }
+        }
+    }
+}
+
+/// 同じフォルダ内の画像や動画に対応するGoogle TakeoutのJSONを探して，その画像の組に追加する．
+/// 
+/// 組の他のメンバーより後ろに追加されるように，Live PhotoやRAW+JPEGの組を探した後に呼び出すこと．
+pub fn find_takeout_jsons(paths: &[PathBuf], groups: &mut Groups) {
+    for path in paths {
+        if path.extension().and_then(filetype::from_extension).is_none() {
+            continue;
+        }
+        if let Some(json) = takeout::find_json(path) {
+            let primary = primary_of(groups, path).clone();
+            groups.entry(primary).or_default().push(json);
+        }
+    }
+}
+
+/// 同じフォルダ内のXMPサイドカーを探して，対応する画像の組に追加する．
+/// 
+/// IMG_0042.jpg.xmpのように画像のファイル名全体に拡張子を付けたものと，
+/// IMG_0042.xmpのように拡張子を置き換えたものの両方に対応する．
+/// 他の組を全て探した後に呼び出すこと．対応する画像が見つからなかったサイドカーのパスを返す．
+pub fn find_sidecars(paths: &[PathBuf], groups: &mut Groups) -> Vec<PathBuf> {
+    let mut by_name = HashMap::new();
+    let mut by_stem: HashMap<OsString, Vec<&PathBuf>> = HashMap::new();
+    for path in paths {
+        let (stem, ext) = match split_name(path) {
+            Some(name) => name,
+            None => continue,
+        };
+        if filetype::from_extension(ext.as_ref()).is_some() {
+            by_name.insert(path.file_name().unwrap().to_ascii_lowercase(), path);
+            by_stem.entry(stem).or_default().push(path);
+        }
+    }
+
+    let mut orphans = Vec::new();
+    for path in paths {
+        let stem = match split_name(path) {
+            Some((stem, ext)) if ext == SIDECAR_EXTENSION => stem,
+            _ => continue,
+        };
+        // 同じ名前の画像が複数ある場合は，Lightroomなどがサイドカーを書き出すRAWを優先する
+        let parent = by_name.get(&stem).copied().or_else(|| {
+            by_stem.get(&stem)?.iter().copied().min_by_key(|p| {
+                let file_type = p.extension().and_then(filetype::from_extension);
+                !file_type.is_some_and(|t| t.is_raw())
+            })
+        });
+        match parent {
+            Some(parent) => {
+                let primary = primary_of(groups, parent).clone();
+                groups.entry(primary).or_default().push(path.clone());
+            },
+            None => orphans.push(path.clone()),
+        }
+    }
+    orphans
+}
+
+/// 組のメンバーのリネーム後のファイル名を返す．
+/// 
+/// 基本的には代表のファイルと同じ名前に自分の拡張子（小文字）を付ける．
+/// IMG_0042.jpg.xmpやIMG_0042.jpg.jsonのように画像のファイル名全体に接尾辞を付けたものは，
+/// 対応する画像のリネーム後のファイル名に同じ接尾辞（小文字）を付ける．
+/// srcsとnew_pathsには，既に名前が決まった組のファイルのリネーム前後のパスを渡す．
+pub fn member_file_name(member: &Path, new_stem: &str, srcs: &[PathBuf], new_paths: &[PathBuf]) -> String {
+    let member_name = member.file_name().unwrap().to_string_lossy().to_ascii_lowercase();
+    for (src, new_path) in srcs.iter().zip(new_paths) {
+        let src_name = src.file_name().unwrap().to_string_lossy().to_ascii_lowercase();
+        if let Some(suffix) = member_name.strip_prefix(&src_name).filter(|suffix| suffix.starts_with('.')) {
+            return format!("{}{}", new_path.file_name().unwrap().to_string_lossy(), suffix);
+        }
+    }
+    let (_, ext) = split_name(member).unwrap();
+    format!("{}.{}", new_stem, ext)
+}
+
+/// 組のメンバーから動画のサムネイル（.thm）を探す．
+pub fn find_thumbnail(members: &[PathBuf]) -> Option<&PathBuf> {
+    members.iter().find(|member| split_name(member).is_some_and(|(_, ext)| ext == THUMBNAIL_EXTENSION))
+}
@@ -0,0 +1,109 @@
+//! ファイル名に入れるハッシュ値を計算するモジュール（--hash）
+//! 計算はアルゴリズムごとのクレート（crc32fast，sha2，blake3，xxhash-rust）で行い，ここでは同じように使えるようにまとめる．
+//! どれも大きいファイルを少しずつ読みながら計算できるように，update()で入力を追加してfinalize()で結果を得る．
+
+use sha2::Digest;
+
+/// 計算中のハッシュ値
+pub enum Hasher {
+    Crc32(crc32fast::Hasher),
+    Sha256(sha2::Sha256),
+    Blake3(Box<blake3::Hasher>),
+    Xxh3(Box<xxhash_rust::xxh3::Xxh3>),
+}
+
+impl Hasher {
+    /// 入力を追加する．
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Crc32(hasher)  => hasher.update(data),
+            Hasher::Sha256(hasher) => hasher.update(data),
+            Hasher::Blake3(hasher) => {
+                hasher.update(data);
+            },
+            Hasher::Xxh3(hasher)   => hasher.update(data),
+        }
+    }
+
+    /// ハッシュ値をビッグエンディアンのバイト列で返す（16進数にした時に一般的な表記と一致する）．
+    pub fn finalize(self) -> Vec<u8> {
+        match self {
+            Hasher::Crc32(hasher)  => hasher.finalize().to_be_bytes().to_vec(),
+            Hasher::Sha256(hasher) => hasher.finalize().to_vec(),
+            Hasher::Blake3(hasher) => hasher.finalize().as_bytes().to_vec(),
+            Hasher::Xxh3(hasher)   => hasher.digest().to_be_bytes().to_vec(),
+        }
+    }
+}
+
+/// バイト列（ビッグエンディアンの整数）をalphabetの文字を使ったN進数の文字列にする（widthの文字数まで先頭を0で埋める）．
+pub fn to_base(bytes: &[u8], alphabet: &[u8], width: usize) -> String {
+    let base = alphabet.len() as u32;
+    let mut num = bytes.to_vec();
+    let mut digits = Vec::with_capacity(width);
+    // 筆算と同じように上の桁から割っていき，余りを下の桁から並べる
+    while num.iter().any(|&b| b != 0) || digits.len() < width {
+        let mut rem = 0;
+        for b in num.iter_mut() {
+            let cur = (rem << 8) | *b as u32;
+            *b = (cur / base) as u8;
+            rem = cur % base;
+        }
+        digits.push(alphabet[rem as usize]);
+    }
+    digits.iter().rev().map(|&c| c as char).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// (入力の長さ, SHA-256, BLAKE3, XXH3)．入力はBLAKE3の公式のテストベクタと同じく，i番目のバイトがi % 251．
+    const VECTORS: [(usize, &str, &str, &str); 21] = [
+        (0, "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855", "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262", "2d06800538d394c2"),
+        (1, "6e340b9cffb37a989ca544e6bb780a2c78901d3fb33738768511a30617afa01d", "2d3adedff11b61f14c886e35afa036736dcd87a74d27b5c1510225d0f592e213", "c44bdff4074eecdb"),
+        (3, "ae4b3280e56e2faf83f414a6e3dabe9d5fbe18976544c05fed121accb85b53fc", "e1be4d7a8ab5560aa4199eea339849ba8e293d55ca0a81006726d184519e647f", "5f4299fc161c9cbb"),
+        (4, "054edec1d0211f624fed0cbca9d4f9400b0e491c43742af2c5b0abebf0c990d8", "f30f5ab28fe047904037f77b6da4fea1e27241c5d132638d8bedce9d40494f32", "60dab036a58211f2"),
+        (8, "8a851ff82ee7048ad09ec3847f1ddf44944104d2cbd17ef4e3db22c6785a0d45", "2351207d04fc16ade43ccab08600939c7c1fa70a5c0aaca76063d04c3228eaeb", "3a1c2d7c85af88f8"),
+        (9, "f8348e0b1df00833cbbbd08f07abdecc10c0efb78829d7828c62a7f36d0cc549", "a0fc27e5d7318b723207637bdeeba4f7dcb22f7f9ec3e8b6f3588ddcd4fdf861", "e9612598145bb9dc"),
+        (16, "be45cb2605bf36bebde684841a28f0fd43c69850a3dce5fedba69928ee3a8991", "a6a492965517a830cb75fdb713465aa465f2f098233896fea44c1d98268bf9e3", "8355e3a6f61770db"),
+        (17, "3e5718fea51a8f3f5baca61c77afab473c1810f8b9db330273b4011ce92c787e", "8462aa7be93b09fda7b93cf9f9cddb703f6dd2cc0c8edd5f9eee092edf8abf0c", "9ef341a99de37328"),
+        (64, "fdeab9acf3710362bd2658cdc9a29e8f9c757fcf9811603a8c447cd1d9151108", "4eed7141ea4a5cd4b788606bd23f46e212af9cacebacdc7d1f4c6dc7f2511b98", "6187eb9089b0ed55"),
+        (65, "4bfd2c8b6f1eec7a2afeb48b934ee4b2694182027e6d0fc075074f2fabb31781", "de1e5fa0be70df6d2be8fffd0e99ceaa8eb6e8c93a63f2d8d1c30ecb6b263dee", "6928c76ce90422d0"),
+        (128, "471fb943aa23c511f6f72f8d1652d9c880cfa392ad80503120547703e56a2be5", "f17e570564b26578c33bb7f44643f539624b05df1a76c81f30acd548c44b45ef", "85c6174c7ff4c46b"),
+        (129, "5099c6a56203f9687f7d33f4bfdf576d31dc91f6b695ecea38b2770c87631135", "683aaae9f3c5ba37eaaf072aed0f9e30bac0865137bae68b1fde4ca2aebdcb12", "ec7642b431ba3e5a"),
+        (240, "abf4bafcddb38bbf3855e47b5e61b75dedbcf42aa44ffd4bb85d0b08d97e2682", "45e1a0dc23dbe51733d7269a3c0f519c2a63b0718835b2b537677eba734db0d8", "375a384d957fe865"),
+        (241, "211882aeac8a599b0a55ec280e1a978923edef69cd86541bcbd58db864c45eac", "749b36ae651c22e8567db692a6876e0ca4fd3daeb7aa8fa3ab2f642ccc69a8f6", "02e8cd95421c6d02"),
+        (1024, "2bce1ba628720664be4b9fdd77aae0678e5f0f3f02fc6ff641ec879094f6a404", "42214739f095a406f3fc83deb889744ac00df831c10daa55189b5d121c855af7", "e5d78bafa45b2aa5"),
+        (1025, "bc0b6b10b89b9487a12fda2a8cc13194e7091c217aabf8b92846274026f4bcd0", "d00278ae47eb27b34faecf67b4fe263f82d5412916c1ffd97c8cb7fb814b8444", "e95c42288f28186e"),
+        (2048, "b2a8170614e23194ae2951423d601987f518ce2f11205d7b0b708080103b9f76", "e776b6028c7cd22a4d0ba182a8bf62205d2ef576467e838ed6f2529b85fba24a", "25339063db861586"),
+        (2049, "26e1e2808e3a6cf967ca03f6749a063c5ed55f92f5874653a1faabed78346f00", "5f4d72f40d7a5f82b15ca2b2e44b1de3c2ef86c426c95c1af0b6879522563030", "6c9600c0e506e2ae"),
+        (3072, "5f24b2f16026ec7d0450a5a08283d3cfd47302fe859f579ed79fe7d2663b73f9", "b98cb0ff3623be03326b373de6b9095218513e64f1ee2edd2525c7ad1e5cffd2", "4adb90b35034df6b"),
+        (4097, "a16560d668b843fb3be99ace41dbd18471f342bd3255a1d21204b35e43f74436", "9b4052b38f1c5fc8b1f9ff7ac7b27cd242487b3d890d15c96a1c25b8aa0fb995", "b69d29f17d48293f"),
+        (8193, "7e3691790cd64b19d4edb1a80e988214515abeb53aa0f34ffbfe4b4bf405d120", "bab6c09cb8ce8cf459261398d2e7aef35700bf488116ceb94a36d0f5f1b7bc3b", "d6735a2b792cf505"),
+    ];
+
+    fn input(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    /// Hasherで計算したハッシュ値を16進数で返す．
+    fn hash(mut hasher: Hasher, data: &[u8]) -> String {
+        hasher.update(data);
+        hex(&hasher.finalize())
+    }
+
+    #[test]
+    fn known_answers() {
+        for (len, sha256, blake3, xxh3) in VECTORS {
+            let data = input(len);
+            assert_eq!(hash(Hasher::Sha256(Default::default()), &data), sha256, "{} bytes", len);
+            assert_eq!(hash(Hasher::Blake3(Default::default()), &data), blake3, "{} bytes", len);
+            assert_eq!(hash(Hasher::Xxh3(Default::default()), &data), xxh3, "{} bytes", len);
+        }
+    }
+}
@@ -0,0 +1,441 @@
+//! リネームや移動などの操作を記録するジャーナル（.photo-organizer-journal.jsonl）と，
+//! それを逆順にたどって元に戻す処理（--undo）
+//!
+//! 1行に1つの操作をJSONで書き，間違えて実行した場合でも元の名前が分かるようにする．
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time;
+
+use crate::datetime;
+use crate::fileops::FileOps;
+use crate::json;
+
+/// 処理したフォルダに置くジャーナルのファイル名
+pub const JOURNAL_FILE_NAME: &str = ".photo-organizer-journal.jsonl";
+
+/// 元に戻し終わったジャーナルに付ける名前の接頭辞（後ろに元に戻した日時を付ける）
+const UNDONE_JOURNAL_PREFIX: &str = ".photo-organizer-journal-undone-";
+
+/// ジャーナルに記録する操作
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Op {
+    /// 名前を変えた，またはフォルダに移動した（元に戻せる）
+    Rename,
+    /// 編集後の中身に合わせて，名前のハッシュ値を付け直した（--rehash，元に戻せる）
+    Rehash,
+    /// 変換した画像を新しく書き出した，またはコピーした（元のファイルが戻っていれば，削除して元に戻せる）
+    Create,
+    /// 削除した（元に戻せない）
+    Delete,
+    /// メタデータを書き換えた（元に戻せない）
+    Overwrite,
+    /// 日付を印字した（元に戻せない）
+    Stamp,
+}
+
+impl Op {
+    /// ジャーナルに書く名前
+    fn name(self) -> &'static str {
+        match self {
+            Op::Rename    => "rename",
+            Op::Rehash    => "rehash",
+            Op::Create    => "create",
+            Op::Delete    => "delete",
+            Op::Overwrite => "overwrite",
+            Op::Stamp     => "stamp",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Op> {
+        [Op::Rename, Op::Rehash, Op::Create, Op::Delete, Op::Overwrite, Op::Stamp].into_iter().find(|op| op.name() == name)
+    }
+
+    /// --undoで元に戻せるかどうか
+    fn is_undoable(self) -> bool {
+        matches!(self, Op::Rename | Op::Rehash | Op::Create)
+    }
+}
+
+/// 操作を1行ずつ追記するジャーナル
+///
+/// 既定値（Default）は何も記録しない（サブコマンドなど，ジャーナルを開いていない場合）．
+#[derive(Default)]
+pub struct Journal {
+    /// ジャーナルを置いたフォルダ（パスはここからの相対パスで記録する）
+    root: PathBuf,
+    file: Option<fs::File>,
+}
+
+impl Journal {
+    /// rootのジャーナルを開く（無ければ作り，あれば後ろに追記する）．
+    pub fn open(root: &Path) -> io::Result<Journal> {
+        let file = fs::OpenOptions::new().create(true).append(true).open(root.join(JOURNAL_FILE_NAME))?;
+        Ok(Journal { root: root.to_path_buf(), file: Some(file) })
+    }
+
+    /// oldの名前をnewに変えた（フォルダに移動した）ことを記録する．
+    ///
+    /// crcには，呼び出し元が既に計算したnewの中身のCRC32を渡す（Noneの場合はファイルを読んで計算する）．以下も同じ．
+    pub fn rename(&self, old: &Path, new: &Path, crc: Option<u32>) -> io::Result<()> {
+        self.write(Op::Rename, Some(old), Some((new, crc)))
+    }
+
+    /// 名前のハッシュ値を付け直して，oldの名前をnewに変えたことを記録する（--rehash）．
+    pub fn rehash(&self, old: &Path, new: &Path, crc: Option<u32>) -> io::Result<()> {
+        self.write(Op::Rehash, Some(old), Some((new, crc)))
+    }
+
+    /// srcを変換した画像をnewに書き出した（--content-storeでコピーした）ことを記録する．
+    pub fn create(&self, src: &Path, new: &Path, crc: Option<u32>) -> io::Result<()> {
+        self.write(Op::Create, Some(src), Some((new, crc)))
+    }
+
+    /// pathを削除したことを記録する．
+    pub fn delete(&self, path: &Path) -> io::Result<()> {
+        self.write(Op::Delete, Some(path), None)
+    }
+
+    /// pathのメタデータを書き換えたことを記録する．
+    pub fn overwrite(&self, path: &Path, crc: Option<u32>) -> io::Result<()> {
+        self.write(Op::Overwrite, None, Some((path, crc)))
+    }
+
+    /// pathに日付を印字したことを記録する．
+    pub fn stamp(&self, path: &Path, crc: Option<u32>) -> io::Result<()> {
+        self.write(Op::Stamp, None, Some((path, crc)))
+    }
+
+    /// 操作を1行追記する．操作後のファイル（new）があれば，内容が変わっていないか確かめるためにCRC32を記録する．
+    ///
+    /// 大きな動画などを読み直さないように，CRC32が分からない場合だけファイルを読む．
+    fn write(&self, op: Op, old: Option<&Path>, new: Option<(&Path, Option<u32>)>) -> io::Result<()> {
+        let mut file = match &self.file {
+            Some(file) => file,
+            None => return Ok(()),
+        };
+        let mut fields = vec![
+            format!("\"time\":{}", json::string(&now())),
+            format!("\"op\":\"{}\"", op.name()),
+        ];
+        if let Some(old) = old {
+            fields.push(format!("\"old\":{}", json::string(&self.relative(old)?)));
+        }
+        if let Some((new, crc)) = new {
+            let crc = match crc {
+                Some(crc) => crc,
+                None => crate::crc32_file(new)?,
+            };
+            fields.push(format!("\"new\":{}", json::string(&self.relative(new)?)));
+            fields.push(format!("\"hash\":\"{:08x}\"", crc));
+        }
+        fields.push(format!("\"undoable\":{}", op.is_undoable()));
+        writeln!(file, "{{{}}}", fields.join(","))
+    }
+
+    /// pathをジャーナルに記録できるかどうか（記録しない場合は常にtrue）．
+    ///
+    /// 記録できないファイルを処理すると元に戻せなくなるので，呼び出し元は処理する前に確かめる．
+    pub fn can_record(&self, path: &Path) -> bool {
+        self.file.is_none() || self.relative(path).is_ok()
+    }
+
+    /// rootの中のパスは，rootからの相対パスを "/" 区切りにした文字列にする（フォルダごと移動しても元に戻せるように）．
+    /// rootの外のパス（--outputの元のファイルや--content-storeの保管場所など）は，絶対パスの文字列にする．
+    ///
+    /// 元に戻す時に別のファイルを指さないように，UTF-8として正しくない名前は置き換えずにエラーを返す．
+    fn relative(&self, path: &Path) -> io::Result<String> {
+        let not_utf8 = || io::Error::new(io::ErrorKind::InvalidData, format!("The path cannot be recorded in the journal, since it is not valid UTF-8: {}", path.display()));
+        match path.strip_prefix(&self.root) {
+            Ok(relative) => {
+                let components: Option<Vec<&str>> = relative.components().map(|component| component.as_os_str().to_str()).collect();
+                Ok(components.ok_or_else(not_utf8)?.join("/"))
+            },
+            Err(_) => std::path::absolute(path)?.into_os_string().into_string().map_err(|_| not_utf8()),
+        }
+    }
+}
+
+/// ジャーナルから読んだ操作
+struct Entry {
+    op: Op,
+    old: Option<String>,
+    new: Option<String>,
+    hash: Option<String>,
+}
+
+/// 元に戻した結果の集計（最後に表示する）
+#[derive(Default)]
+pub struct UndoSummary {
+    /// 元に戻した操作の数
+    pub restored: usize,
+    /// ファイルが移動・削除・変更されていたので元に戻さなかった操作の数
+    pub skipped: usize,
+    /// 元に戻せない操作（削除，メタデータの書き換え，印字）の数
+    pub not_undoable: usize,
+}
+
+/// ジャーナルを逆順にたどって，リネームや移動を元に戻す（--undo）．
+///
+/// journal_pathにフォルダを指定した場合は，その中のジャーナルを使う．
+/// 記録した後にファイルが移動・削除されていたり中身が変わっていたりする場合や，元の名前が既に使われている場合は，
+/// 上書きしないように飛ばして続ける．移動などに失敗した場合も，警告を表示して飛ばしたものとして数え，残りを続ける．
+/// 全て元に戻せた場合は，もう一度元に戻さないようにジャーナルの名前を変える．
+pub fn undo(journal_path: &Path) -> io::Result<UndoSummary> {
+    let journal_path = if journal_path.is_dir() {
+        journal_path.join(JOURNAL_FILE_NAME)
+    } else {
+        journal_path.to_path_buf()
+    };
+    let root = journal_path.parent().unwrap_or(Path::new("."));
+    let contents = fs::read_to_string(&journal_path)?;
+
+    let mut entries = Vec::new();
+    for (i, line) in contents.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        match parse_entry(line) {
+            Some(entry) => entries.push(entry),
+            None => println!("Warning: Line {} of the journal could not be read, so it is skipped: {}", i + 1, journal_path.display()),
+        }
+    }
+
+    let mut summary = UndoSummary::default();
+    // 別のドライブに移動したファイルは，名前を変えるだけでは戻せないのでコピーしてから削除する
+    let files = FileOps::default();
+    // 日付を印字したファイルは，リネームした後で中身が変わっているので，印字した後のCRC32と比べる
+    let mut stamped: HashMap<PathBuf, Option<String>> = HashMap::new();
+    for entry in entries.iter().rev() {
+        let old = entry.old.as_deref().map(|old| root.join(old));
+        let new = entry.new.as_deref().map(|new| root.join(new));
+        let hash = match &new {
+            Some(new) if entry.op.is_undoable() => stamped.remove(new).unwrap_or_else(|| entry.hash.clone()),
+            _ => entry.hash.clone(),
+        };
+        let restored = match (entry.op, old, new) {
+            (Op::Rename | Op::Rehash, Some(old), Some(new)) => report_error(restore_rename(&old, &new, hash.as_deref(), &files), &new),
+            // 変換元のファイルが戻っていない場合は，変換した画像しか残っていないので削除しない
+            (Op::Create, Some(src), Some(new)) => {
+                if !src.exists() {
+                    println!("Warning: The converted file is left as is, since the original \"{}\" was not restored: {}", src.display(), new.display());
+                    false
+                } else {
+                    report_error(check_recorded(&new, hash.as_deref()).and_then(|matches| {
+                        if matches {
+                            fs::remove_file(&new)?;
+                        }
+                        Ok(matches)
+                    }), &new)
+                }
+            },
+            (Op::Delete, Some(old), _) => {
+                println!("Note: The deleted file cannot be restored: {}", old.display());
+                summary.not_undoable += 1;
+                continue;
+            },
+            (Op::Overwrite, _, Some(new)) => {
+                println!("Note: The rewritten metadata cannot be restored: {}", new.display());
+                summary.not_undoable += 1;
+                continue;
+            },
+            (Op::Stamp, _, Some(new)) => {
+                println!("Note: The printed date cannot be removed: {}", new.display());
+                stamped.insert(new, hash);
+                summary.not_undoable += 1;
+                continue;
+            },
+            _ => {
+                println!("Warning: An entry of the journal lacks a path, so it is skipped: {}", journal_path.display());
+                false
+            },
+        };
+        if restored {
+            summary.restored += 1;
+        } else {
+            summary.skipped += 1;
+        }
+    }
+
+    if summary.skipped == 0 {
+        let stamp: String = now().chars().filter(char::is_ascii_digit).collect();
+        let undone_stem = format!("{}{}", UNDONE_JOURNAL_PREFIX, stamp);
+        let undone_path = root.join(format!("{}.jsonl", undone_stem));
+        let undone_path = crate::unique_paths(std::slice::from_ref(&journal_path), vec![undone_path], &undone_stem).pop().unwrap();
+        fs::rename(&journal_path, undone_path)?;
+    }
+    Ok(summary)
+}
+
+/// 名前を変えた（移動した）newを元の名前oldに戻す．戻さなかった場合はfalseを返す．
+fn restore_rename(old: &Path, new: &Path, hash: Option<&str>, files: &FileOps) -> io::Result<bool> {
+    if !check_recorded(new, hash)? {
+        return Ok(false);
+    }
+    if old.exists() {
+        println!("Warning: Not restored, since another file already has the original name \"{}\": {}", old.display(), new.display());
+        return Ok(false);
+    }
+    if let Some(parent) = old.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    files.move_file(new, old)?;
+    // "originals" などのフォルダに移動したファイルを戻して空になった場合は，フォルダも片付ける
+    if new.parent() != old.parent() {
+        if let Some(parent) = new.parent() {
+            let _ = fs::remove_dir(parent);
+        }
+    }
+    Ok(true)
+}
+
+/// 元に戻す途中で失敗した場合は，警告を表示して戻さなかったものとする（残りの操作は続ける）．
+fn report_error(result: io::Result<bool>, path: &Path) -> bool {
+    result.unwrap_or_else(|e| {
+        println!("Warning: Not restored, since an error occurred ({}): {}", e, path.display());
+        false
+    })
+}
+
+/// 記録した後のファイルが残っていて，中身が記録した時と同じかどうかを確かめる（違う場合は警告を表示する）．
+fn check_recorded(path: &Path, hash: Option<&str>) -> io::Result<bool> {
+    if !path.is_file() {
+        println!("Warning: Not restored, since the file was moved or deleted: {}", path.display());
+        return Ok(false);
+    }
+    let hash_matches = match hash {
+        Some(hash) => hash == format!("{:08x}", crate::crc32_file(path)?),
+        None => true,
+    };
+    if !hash_matches {
+        println!("Warning: Not restored, since the contents have changed: {}", path.display());
+        return Ok(false);
+    }
+    Ok(true)
+}
+
+/// 今の日時（このコンピュータの現地時刻，Exifの形式）
+fn now() -> String {
+    datetime::from_system_time(time::SystemTime::now())
+        .map(|date_time| String::from_utf8_lossy(&date_time).into_owned())
+        .unwrap_or_default()
+}
+
+/// ジャーナルの1行を読む．
+fn parse_entry(line: &str) -> Option<Entry> {
+    let mut fields = json::parse_object(line)?;
+    Some(Entry {
+        op: Op::from_name(&fields.remove("op")?)?,
+        old: fields.remove("old"),
+        new: fields.remove("new"),
+        hash: fields.remove("hash"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// テスト用の空のフォルダを作る（名前はテストごとに変える）．
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("photo-organizer-test-{}-journal-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// ジャーナルに記録しながら名前を変える．
+    fn rename(journal: &Journal, old: &Path, new: &Path) {
+        fs::create_dir_all(new.parent().unwrap()).unwrap();
+        fs::rename(old, new).unwrap();
+        journal.rename(old, new, None).unwrap();
+    }
+
+    #[test]
+    fn undo_restores_renamed_files() {
+        let root = temp_dir("undo");
+        let names = ["IMG_0001.jpg", "a \"quoted\" \\ 写真.jpg"];
+        for name in names {
+            fs::write(root.join(name), name).unwrap();
+        }
+        let journal = Journal::open(&root).unwrap();
+        rename(&journal, &root.join(names[0]), &root.join("2023/2023-01-23_1430_0ba50ed7.jpg"));
+        rename(&journal, &root.join(names[1]), &root.join("2023-01-23_1431_206cc7d9.jpg"));
+        drop(journal);
+
+        let summary = undo(&root).unwrap();
+        assert_eq!((summary.restored, summary.skipped), (2, 0));
+        for name in names {
+            assert_eq!(fs::read_to_string(root.join(name)).unwrap(), name);
+        }
+        // 空になったフォルダは片付け，ジャーナルはもう一度元に戻さないように名前を変える
+        assert!(!root.join("2023").exists());
+        assert!(!root.join(JOURNAL_FILE_NAME).exists());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn paths_outside_the_root_are_recorded_as_absolute_paths() {
+        let root = temp_dir("outside-root");
+        let output = temp_dir("outside-output");
+        fs::write(root.join("IMG_0001.jpg"), b"photo").unwrap();
+        let journal = Journal::open(&root).unwrap();
+        rename(&journal, &root.join("IMG_0001.jpg"), &output.join("2023-01-23_1430_0ba50ed7.jpg"));
+        drop(journal);
+
+        let line = fs::read_to_string(root.join(JOURNAL_FILE_NAME)).unwrap();
+        let entry = parse_entry(line.trim()).unwrap();
+        assert_eq!(entry.old.as_deref(), Some("IMG_0001.jpg"));
+        assert_eq!(entry.new.map(PathBuf::from), Some(output.join("2023-01-23_1430_0ba50ed7.jpg")));
+
+        let summary = undo(&root).unwrap();
+        assert_eq!((summary.restored, summary.skipped), (1, 0));
+        assert!(root.join("IMG_0001.jpg").is_file());
+        fs::remove_dir_all(&root).unwrap();
+        // 空になった出力先は元に戻す時に片付けられている
+        assert!(!output.exists());
+    }
+
+    #[test]
+    fn undo_skips_changed_and_taken_files() {
+        let root = temp_dir("skip");
+        for name in ["a.jpg", "b.jpg"] {
+            fs::write(root.join(name), name).unwrap();
+        }
+        let journal = Journal::open(&root).unwrap();
+        rename(&journal, &root.join("a.jpg"), &root.join("c.jpg"));
+        rename(&journal, &root.join("b.jpg"), &root.join("d.jpg"));
+        drop(journal);
+        // 名前を変えた後で中身が変わったファイルと，元の名前が使われているファイルは戻さない
+        fs::write(root.join("c.jpg"), b"edited").unwrap();
+        fs::write(root.join("b.jpg"), b"another").unwrap();
+
+        let summary = undo(&root).unwrap();
+        assert_eq!((summary.restored, summary.skipped), (0, 2));
+        assert_eq!(fs::read(root.join("c.jpg")).unwrap(), b"edited");
+        assert_eq!(fs::read(root.join("b.jpg")).unwrap(), b"another");
+        // 戻せなかった操作があるので，ジャーナルは残す
+        assert!(root.join(JOURNAL_FILE_NAME).is_file());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn names_that_are_not_utf8_are_not_recorded() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let root = temp_dir("not-utf8");
+        let path = root.join(std::ffi::OsStr::from_bytes(b"IMG_\xff.jpg"));
+        let journal = Journal::open(&root).unwrap();
+        assert!(!journal.can_record(&path));
+        assert!(journal.can_record(&root.join("IMG_0001.jpg")));
+        let e = journal.rename(&path, &root.join("2023-01-23_1430_0ba50ed7.jpg"), Some(0)).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(fs::read_to_string(root.join(JOURNAL_FILE_NAME)).unwrap(), "");
+        // ジャーナルを開いていない場合（--dry-runなど）は何も記録しないので，確かめない
+        assert!(Journal::default().can_record(&path));
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,286 @@
+//! JPEGのセグメント構造を読むためのモジュール
+//! マーカとセグメント長を辿るので，圧縮データ中の偶然のバイト列に惑わされない
+
+/// APP0マーカ（JFIFなど）
+pub const APP0: u8 = 0xE0;
+
+/// APP1マーカ（Exifなど）
+pub const APP1: u8 = 0xE1;
+
+/// APP2マーカ（ICCプロファイルなど）
+pub const APP2: u8 = 0xE2;
+
+/// APP13マーカ（IPTCなど）
+pub const APP13: u8 = 0xED;
+
+/// COM（コメント）マーカ
+pub const COM: u8 = 0xFE;
+
+/// SOS（Start Of Scan）マーカ．これ以降は圧縮データが続く．
+pub const SOS: u8 = 0xDA;
+
+/// EOI（End Of Image）マーカ
+pub const EOI: u8 = 0xD9;
+
+/// セグメントの位置情報
+#[derive(Clone, Copy, Debug)]
+pub struct Segment {
+    /// マーカの2byte目（APP1なら0xE1）
+    pub marker: u8,
+    /// マーカの先頭のインデックス
+    pub start: usize,
+    /// セグメントの終端（次のマーカの先頭）のインデックス
+    pub end: usize,
+}
+
+/// SOIの直後からセグメントを順に返すイテレータ．
+///
+/// SOSセグメント（ヘッダ部分のみ）を返した後は終了する．
+/// マーカやセグメント長が壊れている場合もそこで終了する．
+pub struct Segments<'a> {
+    jpeg: &'a [u8],
+    pos: usize,
+    done: bool,
+}
+
+impl<'a> Iterator for Segments<'a> {
+    type Item = Segment;
+
+    fn next(&mut self) -> Option<Segment> {
+        if self.done {
+            return None;
+        }
+
+        // マーカの前には0xFFの詰め物が入っていることがある
+        while self.jpeg.get(self.pos..(self.pos + 2)) == Some(&[0xFF, 0xFF]) {
+            self.pos += 1;
+        }
+        let marker = match self.jpeg.get(self.pos..(self.pos + 2)) {
+            Some([0xFF, marker]) => *marker,
+            _ => {
+                self.done = true;
+                return None;
+            },
+        };
+
+        let start = self.pos;
+        let end = match marker {
+            // セグメント長を持たないマーカ
+            0x01 | 0xD0..=0xD7 => start + 2,
+            EOI => {
+                self.done = true;
+                start + 2
+            },
+            _ => {
+                // セグメント長は必ずビッグエンディアンで，長さ自身の2byteを含む
+                let len = match self.jpeg.get((start + 2)..(start + 4)) {
+                    Some(len) => u16::from_be_bytes([len[0], len[1]]) as usize,
+                    None => {
+                        self.done = true;
+                        return None;
+                    },
+                };
+                if len < 2 || start + 2 + len > self.jpeg.len() {
+                    self.done = true;
+                    return None;
+                }
+                start + 2 + len
+            },
+        };
+        if marker == SOS {
+            self.done = true;
+        }
+        self.pos = end;
+
+        Some(Segment { marker, start, end })
+    }
+}
+
+/// JPEGのセグメントを辿るイテレータを返す．SOIマーカが無い場合は何も返さない．
+pub fn segments(jpeg: &[u8]) -> Segments<'_> {
+    Segments {
+        jpeg,
+        pos: 2,
+        done: !jpeg.starts_with(&[0xFF, 0xD8]),
+    }
+}
+
+/// SOSより前のセグメントが途中で切れている（宣言されたセグメント長がファイルの終端を越える）かどうかを返す．
+/// 
+/// ダウンロードの中断やSDカードの破損で末尾が欠けたファイルを見分けるために使う．
+/// SOIマーカが無い場合はfalseを返す．
+pub fn has_truncated_segment(jpeg: &[u8]) -> bool {
+    if !jpeg.starts_with(&[0xFF, 0xD8]) {
+        return false;
+    }
+    let mut pos = 2;
+    for s in segments(jpeg) {
+        if s.marker == SOS || s.marker == EOI {
+            return false;
+        }
+        pos = s.end;
+    }
+    while jpeg.get(pos..(pos + 2)) == Some(&[0xFF, 0xFF]) {
+        pos += 1;
+    }
+    match jpeg.get(pos..) {
+        Some([0xFF, 0x01 | 0xD0..=0xD7, ..]) => false,
+        Some([0xFF, _, len @ ..]) if len.len() < 2 => true,
+        Some([0xFF, _, hi, lo, ..]) => 2 + u16::from_be_bytes([*hi, *lo]) as usize > jpeg.len() - pos,
+        // マーカの1byte目だけで切れている
+        Some([0xFF]) => true,
+        _ => false,
+    }
+}
+
+/// 再エンコードの際に引き継ぐメタデータのセグメント（マーカを含む）を元の順番で返す．
+/// 
+/// APP1（Exif，XMP，拡張XMP），APP2のICCプロファイル，APP13（IPTC），COMが対象．
+/// APP2のMPF（MPOやゲインマップの画像の位置）は再エンコードすると位置がずれるので引き継がない．
+pub fn get_metadata_segments(jpeg: &[u8]) -> Vec<&[u8]> {
+    segments(jpeg)
+        .take_while(|s| s.marker != SOS)
+        .map(|s| (s.marker, &jpeg[s.start..s.end]))
+        .filter(|(marker, segment)| match *marker {
+            APP1 | APP13 | COM => true,
+            APP2 => segment.get(4..16) == Some(b"ICC_PROFILE\0"),
+            _ => false,
+        })
+        .map(|(_, segment)| segment)
+        .collect()
+}
+
+/// メタデータのセグメント（APPnとCOM）を全て取り除いたものを返す．圧縮データは再エンコードしない．
+/// 
+/// JFIFのAPP0セグメント（解像度だけのヘッダ）は残す．EOIより後ろに連結されたデータは含めない．
+/// セグメントが壊れていてEOIまで辿れない場合はNoneを返す．
+pub fn strip_metadata(jpeg: &[u8]) -> Option<Vec<u8>> {
+    let parts = collect_parts(jpeg, |s| match s.marker {
+        APP0 => jpeg.get((s.start + 4)..(s.start + 9)) == Some(b"JFIF\0"),
+        0xE1..=0xEF | COM => false,
+        _ => true,
+    })?;
+    Some([&[0xFF, 0xD8][..]].into_iter().chain(parts).flatten().copied().collect())
+}
+
+/// 画像そのもののデータ（SOI，DQT・DHT・SOF・SOSなどのセグメントと圧縮データ）を順に返す（--hash-source stripped）．
+/// 
+/// APPnとCOMのセグメント（JFIFを含む）とEOIより後ろに連結されたデータは含めないので，
+/// メタデータを書き換えても変わらない．セグメントが壊れていてEOIまで辿れない場合はNoneを返す．
+pub fn get_image_data(jpeg: &[u8]) -> Option<Vec<&[u8]>> {
+    let parts = collect_parts(jpeg, |s| !matches!(s.marker, 0xE0..=0xEF | COM))?;
+    Some([&jpeg[..2]].into_iter().chain(parts).collect())
+}
+
+/// SOIの後ろからEOIまでを辿り，keepがtrueを返したセグメントと圧縮データを順に返す．
+/// セグメントが壊れていてEOIまで辿れない場合はNoneを返す．
+fn collect_parts(jpeg: &[u8], keep: impl Fn(&Segment) -> bool) -> Option<Vec<&[u8]>> {
+    let mut parts = Vec::new();
+    let mut iter = segments(jpeg);
+    loop {
+        let mut last = None;
+        for s in iter.by_ref() {
+            if keep(&s) {
+                parts.push(&jpeg[s.start..s.end]);
+            }
+            last = Some(s);
+        }
+
+        // プログレッシブJPEGはSOSが複数回現れるので，EOIが見つかるまで圧縮データを写しながら繰り返す
+        let last = last?;
+        match last.marker {
+            EOI => return Some(parts),
+            SOS => {
+                let next = skip_scan_data(jpeg, last.end)?;
+                parts.push(&jpeg[last.end..next]);
+                iter = segments_from(jpeg, next);
+            },
+            _ => return None,
+        }
+    }
+}
+
+/// 圧縮データの先頭から次のマーカ（バイトスタッフィングとRSTマーカを除く）の先頭を探す．
+fn skip_scan_data(jpeg: &[u8], mut i: usize) -> Option<usize> {
+    loop {
+        i += jpeg.get(i..)?.iter().position(|b| *b == 0xFF)?;
+        match *jpeg.get(i + 1)? {
+            0x00 | 0xD0..=0xD7 => i += 2,  // バイトスタッフィングとRSTマーカは圧縮データの一部
+            0xFF => i += 1,                // 詰め物
+            _ => return Some(i),
+        }
+    }
+}
+
+/// 主画像の終端（EOIマーカの直後）のインデックスを返す．
+///
+/// Motion PhotoやMPOのように，EOIの後ろに別のデータが連結されている場合に使う．
+/// APPセグメント内のサムネイルのEOIは読み飛ばすので誤検出しない．
+pub fn find_eoi(jpeg: &[u8]) -> Option<usize> {
+    let mut iter = segments(jpeg);
+    loop {
+        // プログレッシブJPEGはSOSが複数回現れるので，EOIが見つかるまで繰り返す
+        let last = iter.by_ref().last()?;
+        match last.marker {
+            EOI => return Some(last.end),
+            SOS => iter = segments_from(jpeg, skip_scan_data(jpeg, last.end)?),
+            _ => return None,
+        }
+    }
+}
+
+/// 指定した位置からセグメントを辿るイテレータを返す．
+fn segments_from(jpeg: &[u8], pos: usize) -> Segments<'_> {
+    Segments { jpeg, pos, done: false }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// セグメント（マーカとセグメント長を含む）を作る．
+    fn segment(marker: u8, payload: &[u8]) -> Vec<u8> {
+        [&[0xFF, marker][..], &(payload.len() as u16 + 2).to_be_bytes(), payload].concat()
+    }
+
+    /// Exifにサムネイルを持ち，EOIの後ろに動画を連結したMotion Photoを作る．(JPEG, 主画像の長さ) を返す．
+    fn motion_photo() -> (Vec<u8>, usize) {
+        let thumbnail = [&[0xFF, 0xD8][..], &segment(0xDB, &[0; 65]), &[0xFF, 0xD9]].concat();
+        let exif = [&b"Exif\0\0"[..], &thumbnail].concat();
+        let image = [
+            &[0xFF, 0xD8][..], &segment(APP1, &exif), &segment(0xDB, &[0; 65]), &segment(0xC0, &[8, 0, 1, 0, 1, 1, 1, 0x11, 0]),
+            &segment(SOS, &[1, 1, 0, 0, 0x3F, 0]),
+            // 圧縮データ（バイトスタッフィングとRSTマーカを含む）
+            &[0x12, 0xFF, 0x00, 0x34, 0xFF, 0xD0, 0x56], &[0xFF, 0xD9],
+        ].concat();
+        // 連結した動画の中にもEOIと同じバイト列がある
+        let video = [&[0, 0, 0, 0x14][..], b"ftypmp42", &[0xFF, 0xD9, 0xFF, 0xE1, 0, 0, 0, 0]].concat();
+        let len = image.len();
+        ([image, video].concat(), len)
+    }
+
+    #[test]
+    fn segments_of_motion_photo_stop_before_the_video() {
+        let (jpeg, image_len) = motion_photo();
+        let markers: Vec<u8> = segments(&jpeg).map(|s| s.marker).collect();
+        assert_eq!(markers, [APP1, 0xDB, 0xC0, SOS]);
+        assert_eq!(find_eoi(&jpeg), Some(image_len));
+        assert!(!has_truncated_segment(&jpeg));
+
+        // メタデータを取り除いた画像やハッシュ値に使う画像データには，連結された動画を含めない
+        let stripped = strip_metadata(&jpeg).unwrap();
+        assert_eq!(stripped.len(), image_len - (jpeg[4] as usize * 256 + jpeg[5] as usize + 2));
+        assert!(stripped.ends_with(&[0x56, 0xFF, 0xD9]));
+        let image_data = get_image_data(&jpeg).unwrap().concat();
+        assert_eq!(image_data, stripped);
+    }
+
+    #[test]
+    fn truncated_segment_is_detected() {
+        let (jpeg, _) = motion_photo();
+        assert!(has_truncated_segment(&jpeg[..10]));
+        assert!(!has_truncated_segment(&jpeg[..2]));
+        assert_eq!(find_eoi(&jpeg[..10]), None);
+        assert_eq!(strip_metadata(&jpeg[..10]), None);
+    }
+}
@@ -0,0 +1,188 @@
+//! 外部のクレートを使わずに，表示や記録に使うJSONを書き出したり読んだりするためのモジュール
+//!
+//! exifサブコマンドの--json，ジャーナル（.photo-organizer-journal.jsonl），--manifestのJSONで使う．
+//! 読む方は，これらが書き出す1行のオブジェクトだけを扱う．
+
+use std::collections::HashMap;
+
+/// 文字列をJSONの文字列リテラルにする．
+pub fn string(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len() + 2);
+    escaped.push('"');
+    for c in s.chars() {
+        match c {
+            '"'  => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+/// JSONの値を1行に1つずつ並べた配列にする（exifサブコマンドの--jsonの表示用に字下げする）．
+pub fn array(items: &[String]) -> String {
+    if items.is_empty() {
+        return "[]".to_string();
+    }
+    format!("[\n    {}\n  ]", items.join(",\n    "))
+}
+
+/// ジャーナルや--manifestに書く形の1行のJSONオブジェクト（値は文字列，真偽値，数値，null，文字列の配列）を読み，キーと値の組にする．
+///
+/// 文字列以外の値はそのままの文字列にする（nullは取り除く）．途中で切れた行など，形が正しくない場合はNoneを返す．
+pub fn parse_object(s: &str) -> Option<HashMap<String, String>> {
+    let mut chars = s.trim().strip_prefix('{')?.strip_suffix('}')?.trim().chars().peekable();
+    let mut fields = HashMap::new();
+    while chars.peek().is_some() {
+        skip_whitespace(&mut chars);
+        if chars.next()? != '"' {
+            return None;
+        }
+        let key = parse_string(&mut chars)?;
+        skip_whitespace(&mut chars);
+        if chars.next()? != ':' {
+            return None;
+        }
+        skip_whitespace(&mut chars);
+        let value = if chars.peek() == Some(&'"') {
+            chars.next();
+            Some(parse_string(&mut chars)?)
+        } else {
+            // 配列の中の ',' で区切らないように，文字列の外の '[' と ']' を数える
+            let mut value = String::new();
+            let (mut depth, mut in_string, mut escaped) = (0, false, false);
+            while let Some(&c) = chars.peek().filter(|&&c| in_string || depth > 0 || c != ',') {
+                match c {
+                    _ if escaped => escaped = false,
+                    '\\' if in_string => escaped = true,
+                    '"' => in_string = !in_string,
+                    '[' if !in_string => depth += 1,
+                    ']' if !in_string => depth -= 1,
+                    _ => (),
+                }
+                value.push(c);
+                chars.next();
+            }
+            // 途中で切れた行は読まない
+            let value = value.trim();
+            let is_value = matches!(value, "true" | "false" | "null") || value.parse::<f64>().is_ok()
+                || (value.starts_with('[') && value.ends_with(']'));
+            if !is_value || depth != 0 || in_string {
+                return None;
+            }
+            (value != "null").then(|| value.to_string())
+        };
+        if let Some(value) = value {
+            fields.insert(key, value);
+        }
+        skip_whitespace(&mut chars);
+        match chars.next() {
+            Some(',') | None => (),
+            Some(_) => return None,
+        }
+    }
+    Some(fields)
+}
+
+fn skip_whitespace(chars: &mut std::iter::Peekable<std::str::Chars>) {
+    while chars.peek().is_some_and(|c| c.is_whitespace()) {
+        chars.next();
+    }
+}
+
+/// 開きの '"' の後から閉じの '"' までを読み，エスケープを戻した文字列を返す．
+fn parse_string(chars: &mut std::iter::Peekable<std::str::Chars>) -> Option<String> {
+    let mut s = String::new();
+    loop {
+        match chars.next()? {
+            '"' => return Some(s),
+            '\\' => match chars.next()? {
+                'n' => s.push('\n'),
+                'r' => s.push('\r'),
+                't' => s.push('\t'),
+                'b' => s.push('\u{8}'),
+                'f' => s.push('\u{c}'),
+                'u' => {
+                    // 基本多言語面の外の文字はサロゲートペアで書かれる
+                    let code = parse_hex4(chars)?;
+                    let code = if (0xD800..0xDC00).contains(&code) {
+                        if chars.next()? != '\\' || chars.next()? != 'u' {
+                            return None;
+                        }
+                        let low = parse_hex4(chars)?;
+                        if !(0xDC00..0xE000).contains(&low) {
+                            return None;
+                        }
+                        0x10000 + ((code - 0xD800) << 10) + (low - 0xDC00)
+                    } else {
+                        code
+                    };
+                    s.push(char::from_u32(code)?);
+                },
+                c => s.push(c),  // '"'，'\\'，'/'
+            },
+            c => s.push(c),
+        }
+    }
+}
+
+/// "\u" の後の4桁の16進数を読む．
+fn parse_hex4(chars: &mut std::iter::Peekable<std::str::Chars>) -> Option<u32> {
+    (0..4).try_fold(0, |code, _| Some(code * 16 + chars.next()?.to_digit(16)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escaped_strings_round_trip() {
+        for s in ["IMG_0001.jpg", "a \"quoted\" name", "back\\slash", "line\nbreak\r\ttab", "\u{1}\u{1f}", "写真/2023 🍣.jpg", ""] {
+            let line = format!("{{\"key\":{}}}", string(s));
+            assert_eq!(parse_object(&line).unwrap()["key"], s, "{}", line);
+        }
+        assert_eq!(string("\u{1}"), "\"\\u0001\"");
+    }
+
+    #[test]
+    fn unicode_escapes_are_decoded() {
+        let fields = parse_object(r#"{"a":"\u3042\u00e9","b":"\ud83c\udf63","c":"\/\b\f"}"#).unwrap();
+        assert_eq!(fields["a"], "あé");
+        assert_eq!(fields["b"], "🍣");
+        assert_eq!(fields["c"], "/\u{8}\u{c}");
+        // 桁が足りない，16進数でない，ペアになっていないサロゲート
+        for line in [r#"{"a":"\u12"}"#, r#"{"a":"\u+123"}"#, r#"{"a":"\ud83c"}"#, r#"{"a":"\ud83c\u0041"}"#, r#"{"a":"\udf63"}"#] {
+            assert_eq!(parse_object(line), None, "{}", line);
+        }
+    }
+
+    #[test]
+    fn other_values_are_kept_as_written() {
+        let fields = parse_object(r#"{ "op" : "rename", "warnings":["a, b", "c]"], "date":null, "undoable":true, "size":123 }"#).unwrap();
+        assert_eq!(fields["op"], "rename");
+        assert_eq!(fields["warnings"], r#"["a, b", "c]"]"#);
+        assert!(!fields.contains_key("date"));
+        assert_eq!(fields["undoable"], "true");
+        assert_eq!(fields["size"], "123");
+    }
+
+    #[test]
+    fn truncated_lines_are_rejected() {
+        let line = r#"{"op":"rename","old":"a.jpg","warnings":["x"],"undoable":true}"#;
+        assert!(parse_object(line).is_some());
+        for len in 1..line.len() - 1 {
+            // 最後の '}' だけを残して途中で切れた場合も含む
+            for truncated in [&line[..len], &format!("{}}}", &line[..len])] {
+                let fields = parse_object(truncated);
+                assert!(fields.is_none() || !fields.unwrap().contains_key("undoable"), "{}", truncated);
+            }
+        }
+        assert_eq!(parse_object(r#"{"a":["x"}"#), None);
+        assert_eq!(parse_object(r#"{"a":"x}"#), None);
+    }
+}
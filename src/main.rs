@@ -8,22 +8,77 @@
 // $ RUSTFLAGS='-C target-cpu=native -C opt-level=3' cargo build --release
 
 use std::process;
-use std::ffi::OsString;
+use std::collections::{HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
 use std::fs;
 use std::path;
-use std::io::{self, Write, BufWriter};
+use std::time;
+use std::io::{self, Read, Write};
 
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
 use crc32fast;
 use rfd::FileDialog;
 use rusttype::{Font, Scale};
 use image;
 use imageproc::drawing;
 
+mod avchd;
+mod bmff;
+mod convert;
+mod datetime;
 mod exif;
+mod fileops;
+mod filetype;
+mod geo;
+mod group;
+mod hash;
+mod journal;
+mod json;
+mod jpeg;
+mod motion_photo;
+mod png;
+mod raf;
+mod restore;
+mod riff;
+mod store;
+mod takeout;
+mod template;
+mod unicode;
+mod xattr;
+mod xmp;
+
+use filetype::FileType;
+
+/// 大きいファイル（RAWなど）でメタデータを読むためにファイル先頭から読み込むバイト数
+const LARGE_FILE_HEAD_LEN: u64 = 4 * 1024 * 1024;
+
+/// ファイルを少しずつ読みながらハッシュ値を計算する際のバッファサイズ
+const HASH_BUF_LEN: usize = 64 * 1024;
+
+/// 日付を印字した画像を保存する際のJPEGの品質（imageクレートの既定値と同じ）
+const PRINT_DATE_QUALITY: u8 = 75;
+
+/// 日付を印字した画像のExifに入れるサムネイルの長辺のピクセル数（Exifの標準は160×120）
+const THUMBNAIL_LONG_EDGE: u32 = 160;
+
+/// これより前のファイルの更新日時は，コピーの際に壊れたものとみなして使わない（--fallback mtime，1990-01-01 00:00:00 UTC）
+const MTIME_MIN_SECS: u64 = 631_152_000;
+
+/// 変換前の元ファイルを移動するフォルダ名（--move-originals）
+const ORIGINALS_DIR_NAME: &str = "originals";
+
+/// 既にあるファイルと中身が同じファイルを移動するフォルダ名（--on-duplicate move）
+const DUPLICATES_DIR_NAME: &str = "duplicates";
+
+/// 実行中に見つけた重複ファイルを移動するフォルダ名（--dedupe）
+const DEDUPE_DIR_NAME: &str = "_duplicates";
+
+/// --dedupeで重複ファイルと残したファイルの対応を書き出すファイル名（"_duplicates" フォルダ内）
+const DEDUPE_REPORT_NAME: &str = "report.tsv";
 
 // パーサはコンパイル時にclapが自動的に実装してくれる
 #[derive(Parser)]
+#[command(group(clap::ArgGroup::new("placement").args(["layout", "output"]).multiple(true)))]
 struct Args {
     /// Print the date on the image (format: YYYY-MM-DD).
     #[arg(short, long, help = "Print the date on the image (format: YYYY-MM-DD).")]
@@ -33,18 +88,972 @@ struct Args {
     #[arg(short, long, help = "Recursive processing when subdirectories exist.")]
     recursion: bool,
 
-    /// Keep Exif data when printing dates.
-    #[arg(short, long = "keep-exif", help = "Keep Exif data when printing dates.")]
+    /// Keep Exif data (and XMP, ICC profile and comments) when printing dates.
+    #[arg(short, long = "keep-exif", help = "Keep Exif data (and XMP, ICC profile and comments) when printing dates.")]
     keep_exif: bool,
 
     /// Give the path of the directory to be processed as a command line argument.
     #[arg(short, long, help = "Give the path of the directory to be processed as a command line argument.")]
     path: Option<OsString>,
+
+    /// Rewrite alternative extensions to a single spelling when renaming (jpeg to jpg, tiff to tif and m2ts to mts).
+    #[arg(long = "canonical-ext", visible_alias = "normalize-ext", help = "Rewrite alternative extensions to a single spelling when renaming (jpeg to jpg, tiff to tif and m2ts to mts).")]
+    canonical_ext: bool,
+
+    /// Additional extension rewrites like "tif=tiff,thm=jpg", between extensions of the same file type (takes precedence over --canonical-ext).
+    #[arg(long = "ext-map", value_delimiter = ',', value_parser = parse_ext_map, help = "Additional extension rewrites like \"tif=tiff,thm=jpg\", between extensions of the same file type (takes precedence over --canonical-ext).")]
+    ext_map: Vec<(String, String)>,
+
+    /// Detect the file type from its contents when the extension is not recognized.
+    #[arg(long, help = "Detect the file type from its contents when the extension is not recognized.")]
+    sniff: bool,
+
+    /// Rename RAW files and video thumbnails (.thm) to the same name as the JPEG/video file with the same basename.
+    #[arg(long = "pair-raw", help = "Rename RAW files and video thumbnails (.thm) to the same name as the JPEG/video file with the same basename.")]
+    pair_raw: bool,
+
+    /// Skip files whose names are already in the organized form (e.g. 2023-01-23_1430_206cc7d9.jpg), without reading them.
+    #[arg(long = "skip-organized", help = "Skip files whose names are already in the organized form (e.g. 2023-01-23_1430_206cc7d9.jpg), without reading them.")]
+    skip_organized: bool,
+
+    /// Keep the original extension even if it does not match the file contents.
+    #[arg(long = "keep-ext", help = "Keep the original extension even if it does not match the file contents.")]
+    keep_ext: bool,
+
+    /// Also rename GIF and BMP files (by hash only, since they have no Exif).
+    #[arg(long = "all-images", help = "Also rename GIF and BMP files (by hash only, since they have no Exif).")]
+    all_images: bool,
+
+    /// Date source used when the file has no embedded date (mtime before 1990 or in the future is ignored; filename reads names like IMG_20230123_143027).
+    #[arg(long, value_enum, help = "Date source used when the file has no embedded date (mtime before 1990 or in the future is ignored; filename reads names like IMG_20230123_143027).")]
+    fallback: Vec<Fallback>,
+
+    /// Convert HEIC files to JPEG before renaming (requires the heif-convert command of libheif).
+    #[arg(long = "convert-heic", help = "Convert HEIC files to JPEG before renaming (requires the heif-convert command of libheif).")]
+    convert_heic: bool,
+
+    /// Convert PNG files to JPEG before renaming (PNG files with transparency are not converted).
+    #[arg(long = "convert-png", help = "Convert PNG files to JPEG before renaming (PNG files with transparency are not converted).")]
+    convert_png: bool,
+
+    /// Convert PNG files with transparency too, by flattening them onto a white background.
+    #[arg(long = "flatten-alpha", help = "Convert PNG files with transparency too, by flattening them onto a white background.")]
+    flatten_alpha: bool,
+
+    /// JPEG quality (1-100) used when converting images.
+    #[arg(long, default_value_t = 90, value_parser = clap::value_parser!(u8).range(1..=100), help = "JPEG quality (1-100) used when converting images.")]
+    quality: u8,
+
+    /// Move the original files to the "originals" folder instead of deleting them after conversion.
+    #[arg(long = "move-originals", help = "Move the original files to the \"originals\" folder instead of deleting them after conversion.")]
+    move_originals: bool,
+
+    /// What to do with a file whose new name already exists with the same contents: "keep" it as is, "delete" it, or "move" it to the "duplicates" folder.
+    #[arg(long = "on-duplicate", value_enum, default_value_t = OnDuplicate::Keep, help = "What to do with a file whose new name already exists with the same contents: \"keep\" it as is, \"delete\" it, or \"move\" it to the \"duplicates\" folder.")]
+    on_duplicate: OnDuplicate,
+
+    /// Move files with the same contents as a file already processed in this run (including subdirectories) to the "_duplicates" folder, with a report of the kept files.
+    #[arg(long, help = "Move files with the same contents as a file already processed in this run (including subdirectories) to the \"_duplicates\" folder, with a report of the kept files.")]
+    dedupe: bool,
+
+    /// Delete the Google Takeout JSON files instead of renaming them (with "--fallback takeout").
+    #[arg(long = "delete-takeout-json", help = "Delete the Google Takeout JSON files instead of renaming them (with \"--fallback takeout\").")]
+    delete_takeout_json: bool,
+
+    /// Precision of the date in the name: "minute" (2023-01-23_1430), "second" (2023-01-23_143027) or "subsec" (2023-01-23_143027.42, ".00" if not recorded).
+    #[arg(long, value_enum, default_value_t = Precision::Minute, help = "Precision of the date in the name: \"minute\" (2023-01-23_1430), \"second\" (2023-01-23_143027) or \"subsec\" (2023-01-23_143027.42, \".00\" if not recorded).")]
+    precision: Precision,
+
+    /// Same as "--precision second".
+    #[arg(long, help = "Same as \"--precision second\".")]
+    seconds: bool,
+
+    /// Same as "--precision subsec".
+    #[arg(long, help = "Same as \"--precision subsec\".")]
+    subsec: bool,
+
+    /// Format of the date in the name, e.g. "%Y%m%d-%H%M%S" (specifiers: %Y, %y, %m, %d, %H, %M, %S; "%%" for "%"). The date specifiers fill {date} and the time specifiers fill {time} in templates; "--precision subsec" still appends ".42".
+    #[arg(long = "date-format", value_parser = datetime::DateFormat::parse, help = "Format of the date in the name, e.g. \"%Y%m%d-%H%M%S\" (specifiers: %Y, %y, %m, %d, %H, %M, %S; \"%%\" for \"%\"). The date specifiers fill {date} and the time specifiers fill {time} in templates; \"--precision subsec\" still appends \".42\".")]
+    date_format: Option<datetime::DateFormat>,
+
+    /// Exif tags tried in this order for the date (e.g. "digitized,original" for scanned photos).
+    #[arg(long = "date-tags", value_enum, value_delimiter = ',', default_values_t = [DateTag::Original, DateTag::Digitized, DateTag::Modified, DateTag::Gps], help = "Exif tags tried in this order for the date (e.g. \"digitized,original\" for scanned photos).")]
+    date_tags: Vec<DateTag>,
+
+    /// Show where the date of each file was read from.
+    #[arg(short, long, help = "Show where the date of each file was read from.")]
+    verbose: bool,
+
+    /// Time zone of the date in the name: "local" (as recorded), "utc" or an offset like "+09:00" (uses OffsetTimeOriginal of Exif).
+    #[arg(long = "time-base", default_value = "local", value_parser = parse_time_base, help = "Time zone of the date in the name: \"local\" (as recorded), \"utc\" or an offset like \"+09:00\" (uses OffsetTimeOriginal of Exif).")]
+    time_base: TimeBase,
+
+    /// Set the modification time of each renamed file to its date (read in the time zone of "--time-base"), so that apps sorting by the modification time agree with the names. Files without a date (or with only the date from the name) keep their modification time.
+    #[arg(long = "set-mtime", help = "Set the modification time of each renamed file to its date (read in the time zone of \"--time-base\"), so that apps sorting by the modification time agree with the names. Files without a date (or with only the date from the name) keep their modification time.")]
+    set_mtime: bool,
+
+    /// Set the creation time of each renamed file to its date like "--set-mtime", for the "Date" column of Windows Explorer (Windows and macOS only; no effect on other systems).
+    #[arg(long = "set-btime", help = "Set the creation time of each renamed file to its date like \"--set-mtime\", for the \"Date\" column of Windows Explorer (Windows and macOS only; no effect on other systems).")]
+    set_btime: bool,
+
+    /// Record the name before renaming in the extended attribute "user.photo_organizer.original_name" (an alternate data stream on NTFS), without changing the contents. The name recorded first is kept on later runs.
+    #[arg(long = "xattr-original-name", help = "Record the name before renaming in the extended attribute \"user.photo_organizer.original_name\" (an alternate data stream on NTFS), without changing the contents. The name recorded first is kept on later runs.")]
+    xattr_original_name: bool,
+
+    /// Use the ImageUniqueID of Exif (first 16 characters, "uniqueid"), the hash of the decoded pixels of JPEG/PNG files (slow, "pixels") or the hash of JPEG files without metadata segments ("stripped") instead of the hash of the file in the name, falling back to the hash of the file.
+    #[arg(long = "hash-source", value_enum, default_value_t = HashSource::Crc32, help = "Use the ImageUniqueID of Exif (first 16 characters, \"uniqueid\"), the hash of the decoded pixels of JPEG/PNG files (slow, \"pixels\") or the hash of JPEG files without metadata segments (\"stripped\") instead of the hash of the file in the name, falling back to the hash of the file.")]
+    hash_source: HashSource,
+
+    /// Hash algorithm of the file contents used in the name.
+    #[arg(long, value_enum, default_value_t = HashAlgorithm::Crc32, help = "Hash algorithm of the file contents used in the name.")]
+    hash: HashAlgorithm,
+
+    /// Number of characters (4-64) of the hash used in the name (default: all of crc32, or 64 bits of the others, e.g. 16 hex characters).
+    #[arg(long = "hash-length", visible_alias = "hash-len", value_parser = clap::value_parser!(u8).range(4..=64), help = "Number of characters (4-64) of the hash used in the name (default: all of crc32, or 64 bits of the others, e.g. 16 hex characters).")]
+    hash_length: Option<u8>,
+
+    /// Encoding of the hash in the name: "hex" (8 characters for crc32), "base36" (7) or "base62" (6, case-sensitive).
+    #[arg(long = "hash-encoding", value_enum, default_value_t = HashEncoding::Hex, help = "Encoding of the hash in the name: \"hex\" (8 characters for crc32), \"base36\" (7) or \"base62\" (6, case-sensitive).")]
+    hash_encoding: HashEncoding,
+
+    /// Name template without the extension, e.g. "IMG_{date}_{hash}" (tokens: {date}, {time}, {hash}, {hashalg}, {orig}, {model}, {location}, {prefix}, {ext}; "{{" for "{").
+    #[arg(long, value_parser = template::Template::parse, help = "Name template without the extension, e.g. \"IMG_{date}_{hash}\" (tokens: {date}, {time}, {hash}, {hashalg}, {orig}, {model}, {location}, {prefix}, {ext}; \"{{\" for \"{\").")]
+    template: Option<template::Template>,
+
+    /// Put a fixed text before the name, e.g. "JD_" for JD_2023-01-23_1430_206cc7d9.jpg (a ".photo-organizer-prefix" file in a folder overrides it there and below).
+    #[arg(long, value_parser = parse_prefix, help = "Put a fixed text before the name, e.g. \"JD_\" for JD_2023-01-23_1430_206cc7d9.jpg (a \".photo-organizer-prefix\" file in a folder overrides it there and below).")]
+    prefix: Option<String>,
+
+    /// Name the files 001.jpg, 002.jpg, ... in order of the date, e.g. for slideshows (files without a date go last, in order of the hash).
+    #[arg(long, conflicts_with_all = ["template", "keep_original_name", "skip_organized"], help = "Name the files 001.jpg, 002.jpg, ... in order of the date, e.g. for slideshows (files without a date go last, in order of the hash).")]
+    sequence: bool,
+
+    /// Minimum number of digits of --sequence (more digits are used if there are more files).
+    #[arg(long = "sequence-width", default_value_t = 3, value_parser = clap::value_parser!(u8).range(1..=9), help = "Minimum number of digits of --sequence (more digits are used if there are more files).")]
+    sequence_width: u8,
+
+    /// First number of --sequence.
+    #[arg(long = "sequence-start", default_value_t = 1, help = "First number of --sequence.")]
+    sequence_start: u64,
+
+    /// Leave the hash out of the name of files with a date, adding "-2", "-3", ... only to files taken in the same minute (e.g. 2023-01-23_1430-2.jpg).
+    #[arg(long = "no-hash", conflicts_with_all = ["template", "keep_original_name", "sequence"], help = "Leave the hash out of the name of files with a date, adding \"-2\", \"-3\", ... only to files taken in the same minute (e.g. 2023-01-23_1430-2.jpg).")]
+    no_hash: bool,
+
+    /// Name every file by the hash only, without reading the date (faster; files with the same contents are handled by --on-duplicate).
+    #[arg(long = "no-date", conflicts_with_all = ["template", "no_hash", "sequence", "model_name", "gps_name", "date", "fallback"], help = "Name every file by the hash only, without reading the date (faster; files with the same contents are handled by --on-duplicate).")]
+    no_date: bool,
+
+    /// Add the camera model to the name (e.g. 2023-01-23_1430_X-T4_206cc7d9.jpg).
+    #[arg(long = "model-name", help = "Add the camera model to the name (e.g. 2023-01-23_1430_X-T4_206cc7d9.jpg).")]
+    model_name: bool,
+
+    /// Add the location (GPS) to the name, as decimal degrees (35.6581N139.7414E) or a geohash (xn76urx).
+    #[arg(long = "gps-name", value_enum, help = "Add the location (GPS) to the name, as decimal degrees (35.6581N139.7414E) or a geohash (xn76urx).")]
+    gps_name: Option<GpsName>,
+
+    /// Number of decimal places or geohash characters of the location (default: 4 decimal places, 7 geohash characters).
+    #[arg(long = "gps-precision", value_parser = clap::value_parser!(u8).range(1..=12), help = "Number of decimal places or geohash characters of the location (default: 4 decimal places, 7 geohash characters).")]
+    gps_precision: Option<u8>,
+
+    /// Keep the original name after the hash, e.g. 2023-01-23_1430_206cc7d9_DSC_0042 (unsafe characters are replaced with "-"; not with --template, which has {orig}).
+    #[arg(long = "keep-original-name", conflicts_with = "template", help = "Keep the original name after the hash, e.g. 2023-01-23_1430_206cc7d9_DSC_0042 (unsafe characters are replaced with \"-\"; not with --template, which has {orig}).")]
+    keep_original_name: bool,
+
+    /// File system the names must be valid on: fat (FAT32/exFAT, e.g. SD cards) and ntfs replace characters Windows cannot use with "-", remove trailing dots and spaces and avoid names like CON; all of them shorten names that are too long (fat also keeps the whole path under 260 characters).
+    #[arg(long = "target-fs", value_enum, default_value_t = TargetFs::Posix, help = "File system the names must be valid on: fat (FAT32/exFAT, e.g. SD cards) and ntfs replace characters Windows cannot use with \"-\", remove trailing dots and spaces and avoid names like CON; all of them shorten names that are too long (fat also keeps the whole path under 260 characters).")]
+    target_fs: TargetFs,
+
+    /// Unicode normalization of the new names (nfc: composed, as on Linux and Windows; nfd: decomposed, as on older macOS; none: as is). Names that differ only in normalization are always treated as the same name.
+    #[arg(long, value_enum, default_value_t = Normalization::Nfc, help = "Unicode normalization of the new names (nfc: composed, as on Linux and Windows; nfd: decomposed, as on older macOS; none: as is). Names that differ only in normalization are always treated as the same name.")]
+    normalize: Normalization,
+
+    /// Remove the location (GPS) from the Exif of JPEG files (the image is not re-encoded).
+    #[arg(long = "strip-gps", help = "Remove the location (GPS) from the Exif of JPEG files (the image is not re-encoded).")]
+    strip_gps: bool,
+
+    /// Remove the embedded thumbnail from the Exif of JPEG files to save space (the image is not re-encoded).
+    #[arg(long = "strip-thumbnail", help = "Remove the embedded thumbnail from the Exif of JPEG files to save space (the image is not re-encoded).")]
+    strip_thumbnail: bool,
+
+    /// Remove all metadata (Exif, XMP, ICC profile, comments, etc.) from JPEG files without re-encoding the image.
+    #[arg(long = "strip-metadata", help = "Remove all metadata (Exif, XMP, ICC profile, comments, etc.) from JPEG files without re-encoding the image.")]
+    strip_metadata: bool,
+
+    /// Rotate the image itself according to the Exif orientation before removing the metadata (the image is re-encoded).
+    #[arg(long = "apply-orientation", help = "Rotate the image itself according to the Exif orientation before removing the metadata (the image is re-encoded).")]
+    apply_orientation: bool,
+
+    /// Shift the date in the Exif of JPEG files and in the name, e.g. "+01:03:00", "-00:30:00" or "+2:00:00:00" (days:hours:minutes:seconds).
+    #[arg(long = "shift-time", allow_hyphen_values = true, value_parser = parse_shift_time, help = "Shift the date in the Exif of JPEG files and in the name, e.g. \"+01:03:00\", \"-00:30:00\" or \"+2:00:00:00\" (days:hours:minutes:seconds).")]
+    shift_time: Option<i64>,
+
+    /// Shift only the date in the name, leaving the Exif as is, e.g. "+07:00" or "-05:30" (hours:minutes, e.g. to the time zone of the event).
+    #[arg(long = "name-offset", allow_hyphen_values = true, conflicts_with = "no_date", value_parser = parse_name_offset, help = "Shift only the date in the name, leaving the Exif as is, e.g. \"+07:00\" or \"-05:30\" (hours:minutes, e.g. to the time zone of the event).")]
+    name_offset: Option<i64>,
+
+    /// Write the Artist tag into the Exif of JPEG files (non-ASCII characters are transliterated, e.g. "é" to "e").
+    #[arg(long, help = "Write the Artist tag into the Exif of JPEG files (non-ASCII characters are transliterated, e.g. \"é\" to \"e\").")]
+    artist: Option<String>,
+
+    /// Write the Copyright tag into the Exif of JPEG files (e.g. "(C) 2024 Jane Doe"; "©" is written as "(C)").
+    #[arg(long, help = "Write the Copyright tag into the Exif of JPEG files (e.g. \"(C) 2024 Jane Doe\"; \"©\" is written as \"(C)\").")]
+    copyright: Option<String>,
+
+    /// Overwrite the Artist and Copyright tags even if they already have a value.
+    #[arg(long = "force-artist", help = "Overwrite the Artist and Copyright tags even if they already have a value.")]
+    force_artist: bool,
+
+    /// Record the original file name in the Exif UserComment of JPEG files before renaming (appended to an existing comment).
+    #[arg(long = "record-original-name", help = "Record the original file name in the Exif UserComment of JPEG files before renaming (appended to an existing comment).")]
+    record_original_name: bool,
+
+    /// Undo the renames and moves recorded in the journal (.photo-organizer-journal.jsonl, or the folder containing it), newest first.
+    #[arg(long, value_name = "JOURNAL", help = "Undo the renames and moves recorded in the journal (.photo-organizer-journal.jsonl, or the folder containing it), newest first.")]
+    undo: Option<path::PathBuf>,
+
+    /// Write the list of processed files to a CSV or JSON file (chosen by the extension) at the end, with the columns event (organize, rehash or store), original, new (empty if deleted), date, date_source (the Exif tag, XMP, thumbnail, mtime, takeout or filename), hash, size (in bytes after processing; empty if deleted) and warnings.
+    #[arg(long, value_name = "FILE", value_parser = parse_manifest_path, help = "Write the list of processed files to a CSV or JSON file (chosen by the extension) at the end, with the columns event (organize, rehash or store), original, new (empty if deleted), date, date_source (the Exif tag, XMP, thumbnail, mtime, takeout or filename), hash, size (in bytes after processing; empty if deleted) and warnings.")]
+    manifest: Option<path::PathBuf>,
+
+    /// Only fix organized names whose hash no longer matches the contents (e.g. after editing), replacing just the hash and keeping the date and the rest of the name.
+    #[arg(long, conflicts_with_all = ["skip_organized", "sequence", "no_hash", "no_date", "date", "convert_heic", "convert_png", "strip_gps", "strip_thumbnail", "strip_metadata", "shift_time", "artist", "copyright", "record_original_name", "dedupe"], help = "Only fix organized names whose hash no longer matches the contents (e.g. after editing), replacing just the hash and keeping the date and the rest of the name.")]
+    rehash: bool,
+
+    /// Move the files into a content-addressed archive at ROOT instead of renaming them, named only by the hash and sharded into folders by its first two characters like git objects (e.g. ROOT/ab/cdef0123....jpg). Uses SHA-256 instead of crc32 and 20 characters unless --hash-length is given. Files whose hash is already in the archive are skipped.
+    #[arg(long = "content-store", value_name = "ROOT", conflicts_with_all = ["rehash", "template", "sequence", "no_hash", "keep_original_name", "prefix", "skip_organized", "date", "convert_heic", "convert_png", "dedupe", "undo"], help = "Move the files into a content-addressed archive at ROOT instead of renaming them, named only by the hash and sharded into folders by its first two characters like git objects (e.g. ROOT/ab/cdef0123....jpg). Uses SHA-256 instead of crc32 and 20 characters unless --hash-length is given. Files whose hash is already in the archive are skipped.")]
+    content_store: Option<path::PathBuf>,
+
+    /// Copy the files into the content store instead of moving them.
+    #[arg(long = "store-copy", requires = "content_store", help = "Copy the files into the content store instead of moving them.")]
+    store_copy: bool,
+
+    /// Delete the files that are already in the content store (with the same contents) from the source folder.
+    #[arg(long = "delete-stored", requires = "content_store", help = "Delete the files that are already in the content store (with the same contents) from the source folder.")]
+    delete_stored: bool,
+
+    /// Move each renamed file into folders by its date under the directory, e.g. "%Y/%Y-%m" for 2023/2023-01/ (specifiers: %Y, %y, %m, %d, %H, %M, %S; "%%" for "%"). Files moved in this run are not processed again with -r.
+    #[arg(long, value_parser = datetime::Layout::parse, conflicts_with_all = ["content_store", "sequence", "undo"], help = "Move each renamed file into folders by its date under the directory, e.g. \"%Y/%Y-%m\" for 2023/2023-01/ (specifiers: %Y, %y, %m, %d, %H, %M, %S; \"%%\" for \"%\"). Files moved in this run are not processed again with -r.")]
+    layout: Option<datetime::Layout>,
+
+    /// Copy the files into --output with the new names instead of moving them, leaving the sources untouched (e.g. on a read-only drive). Each copy is written under a temporary name and checked by its size and hash before it gets the new name.
+    #[arg(long, requires = "output", conflicts_with_all = ["content_store", "sequence", "no_hash", "undo", "rehash", "dedupe", "delete_takeout_json", "move_originals", "strip_gps", "strip_thumbnail", "strip_metadata", "shift_time", "artist", "copyright", "record_original_name"], help = "Copy the files into --output with the new names instead of moving them, leaving the sources untouched (e.g. on a read-only drive). Each copy is written under a temporary name and checked by its size and hash before it gets the new name.")]
+    copy: bool,
+
+    /// Move the renamed files into this directory, keeping the folders relative to the source (e.g. "2019 Italy/IMG_1.jpg" goes to DIR/2019 Italy/), or creating the --layout folders there. Only the file names change. With --copy, the files are copied instead and the sources are left as is.
+    #[arg(long, visible_alias = "dest", value_name = "DIR", conflicts_with_all = ["content_store", "sequence", "undo", "rehash"], help = "Move the renamed files into this directory, keeping the folders relative to the source (e.g. \"2019 Italy/IMG_1.jpg\" goes to DIR/2019 Italy/), or creating the --layout folders there. Only the file names change. With --copy, the files are copied instead and the sources are left as is.")]
+    output: Option<path::PathBuf>,
+
+    /// Folder under the --layout or --output directory for the files whose date could not be found in the Exif, sidecars, file name or --fallback, so that they can be sorted by hand. It is created only when needed. With --output alone, the folders relative to the source are kept inside it.
+    #[arg(long = "undated-dir", value_name = "DIR", default_value = "_unsorted", value_parser = parse_undated_dir, requires = "placement", help = "Folder under the --layout or --output directory for the files whose date could not be found in the Exif, sidecars, file name or --fallback, so that they can be sorted by hand. It is created only when needed. With --output alone, the folders relative to the source are kept inside it.")]
+    undated_dir: path::PathBuf,
+
+    /// Show what would be done (the old and new names, the date source and the warnings of each file) without changing any file. The date is not printed even with -d.
+    #[arg(long = "dry-run", conflicts_with = "undo", help = "Show what would be done (the old and new names, the date source and the warnings of each file) without changing any file. The date is not printed even with -d.")]
+    dry_run: bool,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+// ファイル名の整理以外の処理（サブコマンド）
+// docコメントにするとclapがヘルプの説明文に使ってしまうので普通のコメントにしている
+#[derive(Subcommand)]
+enum Command {
+    /// Write the date into the Exif (DateTimeOriginal and DateTimeDigitized) of JPEG files, e.g. for scanned photos.
+    #[command(name = "set-date", about = "Write the date into the Exif (DateTimeOriginal and DateTimeDigitized) of JPEG files, e.g. for scanned photos.")]
+    SetDate(SetDateArgs),
+    /// Copy the Exif of the original JPEG files into the edited ones (Orientation is reset to 1, and the image is not re-encoded).
+    #[command(name = "copy-exif", about = "Copy the Exif of the original JPEG files into the edited ones (Orientation is reset to 1, and the image is not re-encoded).")]
+    CopyExif(CopyExifArgs),
+    /// Show the Exif tags of a file and which date would be used for the name (for debugging).
+    #[command(name = "exif", about = "Show the Exif tags of a file and which date would be used for the name (for debugging).")]
+    Exif(ExifArgs),
+    /// Rename files back to the original names recorded in a manifest written with --manifest.
+    #[command(name = "restore", about = "Rename files back to the original names recorded in a manifest written with --manifest.")]
+    Restore(RestoreArgs),
+    /// Check that the hashes and dates in organized names still match the file contents (exits with 1 if any mismatch is found).
+    #[command(name = "verify", about = "Check that the hashes and dates in organized names still match the file contents (exits with 1 if any mismatch is found).")]
+    Verify(VerifyArgs),
+}
+
+/// set-dateサブコマンドの引数
+#[derive(clap::Args)]
+struct SetDateArgs {
+    /// Date to write (format: YYYY-MM-DD or "YYYY-MM-DD HH:MM:SS").
+    #[arg(long, value_parser = parse_date, help = "Date to write (format: YYYY-MM-DD or \"YYYY-MM-DD HH:MM:SS\").")]
+    date: [u8; 19],
+
+    /// Also overwrite the date of files that already have one.
+    #[arg(long, help = "Also overwrite the date of files that already have one.")]
+    overwrite: bool,
+
+    /// Directory of the JPEG files to write the date into.
+    #[arg(help = "Directory of the JPEG files to write the date into.")]
+    path: path::PathBuf,
+}
+
+/// copy-exifサブコマンドの引数
+#[derive(clap::Args)]
+struct CopyExifArgs {
+    /// JPEG file (or directory) to copy the Exif from.
+    #[arg(long, help = "JPEG file (or directory) to copy the Exif from.")]
+    from: path::PathBuf,
+
+    /// JPEG file (or directory) to copy the Exif into. Files in directories are matched by the name without the extension.
+    #[arg(long, help = "JPEG file (or directory) to copy the Exif into. Files in directories are matched by the name without the extension.")]
+    to: path::PathBuf,
+}
+
+/// exifサブコマンドの引数
+#[derive(clap::Args)]
+struct ExifArgs {
+    /// Output as JSON (for scripts).
+    #[arg(long, help = "Output as JSON (for scripts).")]
+    json: bool,
+
+    /// Exif tags tried in this order for the date (same as the option for renaming).
+    #[arg(long = "date-tags", value_enum, value_delimiter = ',', default_values_t = [DateTag::Original, DateTag::Digitized, DateTag::Modified, DateTag::Gps], help = "Exif tags tried in this order for the date (same as the option for renaming).")]
+    date_tags: Vec<DateTag>,
+
+    /// File to show the Exif of.
+    #[arg(help = "File to show the Exif of.")]
+    file: path::PathBuf,
+}
+
+/// restoreサブコマンドの引数
+#[derive(clap::Args)]
+struct RestoreArgs {
+    /// Manifest (CSV or JSON) written with --manifest. Its relative paths are taken from the current directory, as when it was written.
+    #[arg(long, value_parser = parse_manifest_path, help = "Manifest (CSV or JSON) written with --manifest. Its relative paths are taken from the current directory, as when it was written.")]
+    manifest: path::PathBuf,
+
+    /// Only show what would be renamed, without renaming anything.
+    #[arg(long = "dry-run", help = "Only show what would be renamed, without renaming anything.")]
+    dry_run: bool,
+
+    /// Directory to search for files that are no longer at the recorded path (matched by the hash in the name and the size).
+    #[arg(help = "Directory to search for files that are no longer at the recorded path (matched by the hash in the name and the size).")]
+    dir: path::PathBuf,
+}
+
+/// verifyサブコマンドの引数（名前を付けた時と同じものを指定する）
+#[derive(clap::Args)]
+struct VerifyArgs {
+    /// Also check the files in subdirectories.
+    #[arg(short, long, help = "Also check the files in subdirectories.")]
+    recursion: bool,
+
+    /// Hash algorithm used for the names (names with {hashalg} use the one in the name).
+    #[arg(long, value_enum, default_value_t = HashAlgorithm::Crc32, help = "Hash algorithm used for the names (names with {hashalg} use the one in the name).")]
+    hash: HashAlgorithm,
+
+    /// Source of the hash used for the names (names with {hashalg} use the one in the name). Files with the date printed (-d) are reported, since they were changed after naming.
+    #[arg(long = "hash-source", value_enum, default_value_t = HashSource::Crc32, help = "Source of the hash used for the names (names with {hashalg} use the one in the name). Files with the date printed (-d) are reported, since they were changed after naming.")]
+    hash_source: HashSource,
+
+    /// Number of characters of the hash in names that only have the hash (names with the date are checked with any length).
+    #[arg(long = "hash-length", visible_alias = "hash-len", value_parser = clap::value_parser!(u8).range(4..=64), help = "Number of characters of the hash in names that only have the hash (names with the date are checked with any length).")]
+    hash_length: Option<u8>,
+
+    /// Encoding of the hash in names that only have the hash (names with the date are checked with any encoding).
+    #[arg(long = "hash-encoding", value_enum, default_value_t = HashEncoding::Hex, help = "Encoding of the hash in names that only have the hash (names with the date are checked with any encoding).")]
+    hash_encoding: HashEncoding,
+
+    /// Name template used for renaming (same as the option for renaming).
+    #[arg(long, value_parser = template::Template::parse, help = "Name template used for renaming (same as the option for renaming).")]
+    template: Option<template::Template>,
+
+    /// Format of the date used for renaming (same as the option for renaming).
+    #[arg(long = "date-format", value_parser = datetime::DateFormat::parse, help = "Format of the date used for renaming (same as the option for renaming).")]
+    date_format: Option<datetime::DateFormat>,
+
+    /// Exif tags tried in this order for the date (same as the option for renaming).
+    #[arg(long = "date-tags", value_enum, value_delimiter = ',', default_values_t = [DateTag::Original, DateTag::Digitized, DateTag::Modified, DateTag::Gps], help = "Exif tags tried in this order for the date (same as the option for renaming).")]
+    date_tags: Vec<DateTag>,
+
+    /// Directory to check.
+    #[arg(help = "Directory to check.")]
+    path: path::PathBuf,
+}
+
+/// 撮影日時が得られなかった場合の代わりの日時の取得元（指定した順に試す）
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq)]
+enum Fallback {
+    Mtime,    // ファイルの更新日時
+    Takeout,  // Google TakeoutのJSONに記録された撮影日時
+    Filename, // ファイル名に含まれる日時（IMG_20230123_143027など）
+}
+
+/// リネーム先に中身が同じファイルが既にある場合の扱い（--on-duplicate）
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq)]
+enum OnDuplicate {
+    Keep,    // 何もしない（元の名前のまま残す）
+    Delete,  // 削除する
+    Move,    // "duplicates" フォルダに移動する
+}
+
+/// ファイル名の末尾に入れる識別子の取得元（--hash-source）
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq)]
+enum HashSource {
+    Crc32,  // ファイル全体のCRC32
+    #[value(name = "uniqueid")]
+    UniqueId,  // ExifのImageUniqueID（再エンコードしても変わらない）
+    Pixels,    // 展開して向きを直した画素データのハッシュ値（メタデータを書き換えても変わらない）
+    Stripped,  // JPEGのAPPnとCOMのセグメントを除いたハッシュ値（展開しないので速いが，回転させると変わる）
+}
+
+/// 整理したファイルを置くファイルシステム（--target-fs，付けた名前がそこで使えるようにする）
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq)]
+enum TargetFs {
+    Fat,    // FAT32やexFAT（SDカードなど，Windowsで使えない文字と名前を避け，パス全体を260文字未満にする）
+    Ntfs,   // NTFS（Windowsで使えない文字と名前を避ける）
+    Posix,  // LinuxやmacOS（"/" とNUL以外は使える）
+}
+
+impl TargetFs {
+    /// 名前やパスの長さ（Windowsで使うものはUTF-16の単位，それ以外はバイト数）
+    fn len(self, s: &str) -> usize {
+        match self {
+            TargetFs::Fat | TargetFs::Ntfs => s.encode_utf16().count(),
+            TargetFs::Posix => s.len(),
+        }
+    }
+
+    /// パス全体の最大の長さ（Windowsの既定のMAX_PATHは終端のNULを含めて260文字）
+    fn max_path_len(self) -> usize {
+        match self {
+            TargetFs::Fat   => 259,
+            TargetFs::Ntfs  => 32_767,
+            TargetFs::Posix => 4_095,
+        }
+    }
+
+    /// 名前に使えない文字かどうか
+    fn is_forbidden(self, c: char) -> bool {
+        match self {
+            TargetFs::Fat | TargetFs::Ntfs => template::check_char(c).is_err(),
+            TargetFs::Posix => c == '/' || c == '\0',
+        }
+    }
+
+    /// Windowsで使えない名前（"CON" や "con.backup" など，最初の "." より前がデバイス名のもの）かどうか
+    fn is_reserved(self, stem: &str) -> bool {
+        if self == TargetFs::Posix {
+            return false;
+        }
+        let base = stem.split('.').next().unwrap().trim_end_matches(' ').to_ascii_uppercase();
+        match base.as_bytes() {
+            b"CON" | b"PRN" | b"AUX" | b"NUL" => true,
+            [b'C', b'O', b'M', n] | [b'L', b'P', b'T', n] => n.is_ascii_digit(),
+            _ => false,
+        }
+    }
+}
+
+/// 付けた名前のUnicode正規化の形（--normalize）
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq)]
+enum Normalization {
+    Nfc,   // 合成した形（LinuxやWindowsで普通の形）
+    Nfd,   // 分解した形（HFS+の頃のmacOSの形）
+    None,  // 正規化しない
+}
+
+impl Normalization {
+    /// 名前を正規化する．
+    fn apply(self, name: &str) -> String {
+        match self {
+            Normalization::Nfc  => unicode::nfc(name),
+            Normalization::Nfd  => unicode::nfd(name),
+            Normalization::None => name.to_string(),
+        }
+    }
+}
+
+/// ファイル名に入れるハッシュ値のアルゴリズム（--hash）
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq)]
+enum HashAlgorithm {
+    Crc32,   // CRC32（32ビット）
+    Sha256,  // SHA-256（256ビット）
+    Blake3,  // BLAKE3（256ビット）
+    Xxh3,    // XXH3（64ビット）
+}
+
+impl HashAlgorithm {
+    /// 計算を始める．
+    fn hasher(self) -> hash::Hasher {
+        match self {
+            HashAlgorithm::Crc32  => hash::Hasher::Crc32(crc32fast::Hasher::new()),
+            HashAlgorithm::Sha256 => hash::Hasher::Sha256(Default::default()),
+            HashAlgorithm::Blake3 => hash::Hasher::Blake3(Default::default()),
+            HashAlgorithm::Xxh3   => hash::Hasher::Xxh3(Default::default()),
+        }
+    }
+
+    /// ハッシュ値のビット数
+    fn bits(self) -> u32 {
+        match self {
+            HashAlgorithm::Crc32 => 32,
+            HashAlgorithm::Xxh3  => 64,
+            HashAlgorithm::Sha256 | HashAlgorithm::Blake3 => 256,
+        }
+    }
+
+    /// ハッシュ値を表す最大の文字数
+    fn max_len(self, encoding: HashEncoding) -> u8 {
+        encoding.width(self.bits())
+    }
+
+    /// --hash-lengthを指定しなかった場合の文字数（64ビット分，CRC32は今までと同じく全体）
+    fn default_len(self, encoding: HashEncoding) -> u8 {
+        encoding.width(self.bits().min(64))
+    }
+
+    /// アルゴリズムの名前（テンプレートの{hashalg}に入れる）
+    fn name(self) -> &'static str {
+        match self {
+            HashAlgorithm::Crc32  => "crc32",
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Blake3 => "blake3",
+            HashAlgorithm::Xxh3   => "xxh3",
+        }
+    }
+}
+
+/// ファイル名に入れるハッシュ値の表記（--hash-encoding）
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq)]
+enum HashEncoding {
+    Hex,     // 16進数（0-9a-f）
+    Base36,  // 36進数（0-9a-z）
+    Base62,  // 62進数（0-9A-Za-z，大文字と小文字を区別する）
+}
+
+impl HashEncoding {
+    /// 使う文字（ASCIIの順に並べて，ファイル名順がハッシュ値の大小順になるようにする）
+    fn alphabet(self) -> &'static [u8] {
+        match self {
+            HashEncoding::Hex    => b"0123456789abcdef",
+            HashEncoding::Base36 => b"0123456789abcdefghijklmnopqrstuvwxyz",
+            HashEncoding::Base62 => b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz",
+        }
+    }
+
+    /// bitsビットの値を表すのに必要な文字数（名前の長さが揃うように，常にこの文字数まで0で埋める）
+    fn width(self, bits: u32) -> u8 {
+        (bits as f64 / (self.alphabet().len() as f64).log2()).ceil() as u8
+    }
+
+    /// ハッシュ値（ビッグエンディアン）をこの表記の文字列にする．
+    fn encode(self, digest: &[u8]) -> String {
+        hash::to_base(digest, self.alphabet(), self.width(digest.len() as u32 * 8) as usize)
+    }
+
+    /// この表記で使う文字だけでできているかどうか
+    fn matches(self, value: &str) -> bool {
+        value.bytes().all(|c| self.alphabet().contains(&c))
+    }
+}
+
+/// ファイル名に入れるImageUniqueIDの文字数（--hash-source uniqueid）
+/// 
+/// CRC32（8文字）と長さを変えて，二つの方式の名前が衝突しないようにする．
+const UNIQUE_ID_LEN: usize = 16;
+
+/// UserCommentに元のファイル名を記録する時の見出し（--record-original-name）
+const ORIGINAL_NAME_LABEL: &str = "Original file name: ";
+
+/// リネームの結果の集計（最後に表示する）と，サブフォルダも含めた実行全体で共有する情報
+#[derive(Default)]
+struct Summary {
+    /// 既に整理済みの名前だったので飛ばしたファイルの数（--skip-organized）
+    skipped_organized: usize,
+    /// リネーム先に中身が同じファイルが既にあったファイルの数（--on-duplicate）
+    duplicates: usize,
+    /// 処理した（リネーム後の）ファイルを（サイズ, CRC32）ごとにまとめたもの（--dedupe）
+    kept_files: HashMap<(u64, u32), Vec<path::PathBuf>>,
+    /// 重複していたので "_duplicates" フォルダに移動したファイルの数（--dedupe）
+    deduped: usize,
+    /// リネームなどの操作の記録（--undoで元に戻すため）
+    journal: journal::Journal,
+    /// 処理したファイルごとの記録（--manifest，--dry-runの計画の表示）
+    manifest: Vec<ManifestRow>,
+    /// ファイルを変更する操作（--dry-runでは実際には変更しない）
+    files: fileops::FileOps,
+    /// ハッシュ値を付け直したファイルの数（--rehash）
+    rehashed: usize,
+    /// ハッシュ値の名前でファイルを置く保管場所（--content-store）
+    store: Option<store::ContentStore>,
+    /// 保管場所に置いたファイルの数（--content-store）
+    stored: usize,
+    /// 保管場所に既にあったので置かなかったファイルの数（--content-store）
+    already_stored: usize,
+    /// 拡張属性に対応していないことを警告したかどうか（--xattr-original-name，実行ごとに一度だけ警告する）
+    xattr_unsupported: bool,
+    /// 撮影日時のフォルダを作る基準のフォルダ（--layout，指定したフォルダか--outputの出力先）
+    layout_root: Option<path::PathBuf>,
+    /// この実行で撮影日時のフォルダに移動したファイルのパス（-rで移動先のフォルダをもう一度処理しないように）
+    laid_out: HashSet<path::PathBuf>,
+    /// 処理するフォルダと出力先のフォルダ（--output，どちらも絶対パス）
+    output_dirs: Option<(path::PathBuf, path::PathBuf)>,
+    /// 撮影日時が無いファイルを移動するフォルダ（--layoutか--outputの場合の--undated-dir）と，移動したファイルの数
+    undated_root: Option<path::PathBuf>,
+    undated: usize,
+    /// コピーしたファイルの数とバイト数（--copy）
+    copied: usize,
+    copied_bytes: u64,
+    /// 大きさかハッシュ値が元のファイルと一致しなかったので削除したコピーの数（--copy）
+    copy_failures: usize,
+}
+
+/// --manifestに書き出す，処理したファイル1つ分の記録．
+struct ManifestRow {
+    /// 処理の種類（通常の整理は "organize"，--rehashでハッシュ値を付け直した場合は "rehash"，--content-storeの場合は "store"，
+    /// 撮影日時が無いので--undated-dirに移動した場合は "unsorted"）
+    event: &'static str,
+    original: path::PathBuf,
+    /// 処理後のパス（削除した場合はNone，そのまま残した場合は元のパス）
+    new: Option<path::PathBuf>,
+    /// 撮影日時（YYYY-MM-DDTHH:MM:SS）
+    date: Option<String>,
+    /// 撮影日時の取得元（Exifのタグ名，XMP，thumbnail，mtime，takeout，filename）
+    date_source: Option<String>,
+    hash: Option<String>,
+    /// 処理後のファイルサイズ（削除した場合はNone）
+    size: Option<u64>,
+    warnings: Vec<String>,
+}
+
+/// ファイル名の撮影日時の細かさ（--precision）
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Precision {
+    Minute,  // 分まで（2023-01-23_1430）
+    Second,  // 秒まで（2023-01-23_143027）
+    Subsec,  // 秒未満まで（2023-01-23_143027.42）
+}
+
+/// ファイル名に入れる秒未満の桁数（--precision subsec）
+/// 
+/// 桁数を揃えないと，ファイル名順に並べた時に撮影順にならない．
+const SUBSEC_DIGITS: usize = 2;
+
+/// ファイル名に入れる機種名の最大文字数（--model-name）
+const MODEL_NAME_MAX_LEN: usize = 32;
+
+/// ファイル名の前に付ける接頭辞の最大文字数（--prefix）
+const PREFIX_MAX_LEN: usize = 32;
+
+/// そのフォルダ（とサブフォルダ）で使う接頭辞を書いておくファイルの名前（--prefixより優先する，空なら接頭辞を付けない）
+const PREFIX_FILE_NAME: &str = ".photo-organizer-prefix";
+
+/// 全てのファイルの順番が決まってから名前を付ける前に，一旦付ける仮の名前の接頭辞（--sequence，--no-hash，隠しファイルにする）
+const DEFERRED_TEMP_PREFIX: &str = ".photo-organizer-tmp-";
+
+/// 全てのファイルの順番が決まるまでリネームを待つファイル（組の場合は他のファイルも）と並べ替えに使う値（--sequence，--no-hash）
+struct DeferredRename {
+    /// 撮影日時（秒と秒未満まで，"2023-01-23_143027.42" のような形．無い場合はNone）
+    date_time: Option<String>,
+    /// ファイル名に入れるハッシュ値（撮影日時が同じものや無いものの順番を決める）
+    hash: String,
+    /// 元のファイル名（拡張子を除く，--no-hashで撮影日時が同じものの順番を決める）
+    original: String,
+    /// ハッシュ値を除いた新しい名前（拡張子を除く，--no-hash）
+    stem: String,
+    /// 今のパス（先頭が代表のファイル）
+    paths: Vec<path::PathBuf>,
+    /// 代表のファイルの中身のCRC32（分かっている場合，ジャーナルに記録する）
+    crc: Option<u32>,
+    /// リネーム先のフォルダ（--layoutの場合は撮影日時のフォルダ，それ以外は今のフォルダ）
+    dir: path::PathBuf,
+    /// 連番の後ろに付ける拡張子など（".jpg"，".jpg.xmp"）
+    suffixes: Vec<String>,
+}
+
+/// --canonical-extで書き換える拡張子（書き換える前, 後）
+const CANONICAL_EXTS: &[(&str, &str)] = &[("jpeg", "jpg"), ("tiff", "tif"), ("m2ts", "mts")];
+
+/// 保管場所に置くファイルの名前に使うハッシュ値の既定の文字数（--content-store）
+const CONTENT_STORE_HASH_LEN: u8 = 20;
+
+/// ファイル名の最大の長さ（--target-fs，どのファイルシステムも255）
+const NAME_MAX_LEN: usize = 255;
+
+/// 名前を切り詰める時に，unique_pathsで付ける番号（"-1" など）のために空けておく長さ（--target-fs）
+const UNIQUE_NUMBER_RESERVE: usize = 4;
+
+/// ファイル名に残す元のファイル名の最大文字数（--keep-original-name）
+const ORIGINAL_STEM_MAX_LEN: usize = 40;
+
+/// 撮影日時として読むExifのタグ（--date-tagsで指定した順に試す）
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq)]
+enum DateTag {
+    Original,   // DateTimeOriginal（撮影日時）
+    Digitized,  // DateTimeDigitized（デジタル化した日時）
+    Modified,   // 0th IFDのDateTime（ファイル変更日時）
+    Gps,        // GPS IFDのGPSDateStampとGPSTimeStamp（測位日時，UTC）
+}
+
+impl DateTag {
+    /// タグの名前（--verboseの表示用）
+    fn tag_name(self) -> &'static str {
+        match self {
+            DateTag::Original  => "DateTimeOriginal",
+            DateTag::Digitized => "DateTimeDigitized",
+            DateTag::Modified  => "DateTime",
+            DateTag::Gps       => "GPSDateStamp/GPSTimeStamp",
+        }
+    }
+}
+
+/// ファイル名の撮影日時の基準にするタイムゾーン（--time-base）
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum TimeBase {
+    Local,        // 記録された現地時刻のまま
+    Offset(i64),  // UTCからのずれ（秒）で指定したタイムゾーン（UTCは0）
+}
+
+/// UTCの日時を--time-baseのタイムゾーンの日時に直す（localの場合はこのコンピュータのタイムゾーン）．
+fn from_utc(date_time: &[u8; 19], time_base: TimeBase) -> Option<[u8; 19]> {
+    let secs = datetime::to_unix(date_time)?;
+    match time_base {
+        TimeBase::Local => datetime::from_unix_local(secs),
+        TimeBase::Offset(base) => datetime::from_unix(secs + base),
+    }
+}
+
+/// --time-baseのタイムゾーンの日時をSystemTimeに直す（from_utcの逆，localの場合はこのコンピュータのタイムゾーン）．
+fn to_system_time(date_time: &[u8; 19], time_base: TimeBase) -> Option<time::SystemTime> {
+    let secs = datetime::to_unix(date_time)?;
+    let secs = match time_base {
+        // 現地時刻からずれを求めると夏時間の切り替わりの前後で1時間ずれるので，一度UTCにしてから求め直す
+        TimeBase::Local => secs - datetime::local_offset(secs - datetime::local_offset(secs)),
+        TimeBase::Offset(base) => secs - base,
+    };
+    match u64::try_from(secs) {
+        Ok(secs) => time::UNIX_EPOCH.checked_add(time::Duration::from_secs(secs)),
+        Err(_) => time::UNIX_EPOCH.checked_sub(time::Duration::from_secs(secs.unsigned_abs())),
+    }
+}
+
+/// set-dateの--dateの引数を読む（時刻を省略した場合は0時0分0秒）．
+fn parse_date(s: &str) -> Result<[u8; 19], String> {
+    let s = if s.len() == 10 { format!("{} 00:00:00", s) } else { s.to_string() };
+    // 存在しない日付（2月30日など）は，UNIX時間を経由すると別の日付になるので弾く
+    datetime::from_iso8601(&s).filter(|date_time| datetime::shift(date_time, 0) == Some(*date_time))
+        .ok_or_else(|| "expected YYYY-MM-DD or \"YYYY-MM-DD HH:MM:SS\"".to_string())
+}
+
+/// --time-baseの引数を読む．
+fn parse_time_base(s: &str) -> Result<TimeBase, String> {
+    match s {
+        "local" => Ok(TimeBase::Local),
+        "utc" | "UTC" => Ok(TimeBase::Offset(0)),
+        _ => datetime::parse_offset(s).map(TimeBase::Offset)
+            .ok_or_else(|| format!("expected \"local\", \"utc\" or an offset like \"+09:00\", but got \"{}\"", s)),
+    }
+}
+
+/// --shift-timeの引数（[+-][日:]時:分:秒）を読んで秒にする．
+fn parse_shift_time(s: &str) -> Result<i64, String> {
+    let (sign, body) = match s.as_bytes().first() {
+        Some(b'-') => (-1, &s[1..]),
+        Some(b'+') => (1, &s[1..]),
+        _ => (1, s),
+    };
+    let nums: Option<Vec<i64>> = body.split(':')
+        .map(|num| if !num.is_empty() && num.bytes().all(|c| c.is_ascii_digit()) { num.parse().ok() } else { None })
+        .collect();
+    let secs = match nums.as_deref() {
+        Some(&[hours, minutes, seconds]) if minutes < 60 && seconds < 60 => hours.checked_mul(3600).and_then(|h| h.checked_add(minutes * 60 + seconds)),
+        Some(&[days, hours, minutes, seconds]) if hours < 24 && minutes < 60 && seconds < 60 => {
+            days.checked_mul(86400).and_then(|d| d.checked_add(hours * 3600 + minutes * 60 + seconds))
+        },
+        _ => None,
+    };
+    secs.map(|secs| sign * secs)
+        .ok_or_else(|| format!("expected an offset like \"+01:03:00\" or \"-2:00:00:00\", but got \"{}\"", s))
+}
+
+/// --ext-mapの引数（"tif=tiff" のような書き換える前と後の拡張子の組）を読む．
+/// 
+/// 拡張子で判定したファイル形式が変わると中身と食い違ってしまうので，同じ形式の拡張子どうしに限る．
+fn parse_ext_map(s: &str) -> Result<(String, String), String> {
+    let (from, to) = s.split_once('=')
+        .map(|(from, to)| (from.trim_start_matches('.').to_ascii_lowercase(), to.trim_start_matches('.').to_ascii_lowercase()))
+        .ok_or_else(|| format!("expected a pair of extensions like \"tif=tiff\", but got \"{}\"", s))?;
+    let from_type = filetype::from_extension(OsStr::new(&from))
+        .ok_or_else(|| format!("\"{}\" is not a supported extension", from))?;
+    if filetype::from_extension(OsStr::new(&to)) != Some(from_type) || from == to {
+        return Err(format!("\"{}\" must be another extension of the same file type as \"{}\"", to, from));
+    }
+    Ok((from, to))
+}
+
+/// --prefixの引数（フォルダの ".photo-organizer-prefix" の内容も）を確かめる．
+/// 
+/// ファイル名に使えない文字（"/" など）を含むものや，隠しファイルになってしまう "." で始まるもの，
+/// Windowsで取り除かれてしまう空白や "." で終わるもの，PREFIX_MAX_LEN文字より長いものはエラーにする．
+fn parse_prefix(s: &str) -> Result<String, String> {
+    if s.is_empty() {
+        return Err("the prefix is empty".to_string());
+    }
+    if let Some(c) = s.chars().find(|&c| template::check_char(c).is_err()) {
+        return Err(format!("\"{}\" cannot be used in file names, but the prefix \"{}\" has it", c.escape_debug(), s));
+    }
+    if s.starts_with('.') || s.ends_with(['.', ' ']) {
+        return Err(format!("the prefix cannot start with \".\" or end with \".\" or a space, but got \"{}\"", s));
+    }
+    if s.chars().count() > PREFIX_MAX_LEN {
+        return Err(format!("the prefix must be at most {} characters, but got \"{}\"", PREFIX_MAX_LEN, s));
+    }
+    Ok(s.to_string())
+}
+
+/// --manifestの引数を読む（拡張子で書き出す形式を決めるので，.csvか.jsonに限る）．
+fn parse_manifest_path(s: &str) -> Result<path::PathBuf, String> {
+    let path = path::PathBuf::from(s);
+    match path.extension().map(|ext| ext.to_ascii_lowercase()) {
+        Some(ext) if ext == "csv" || ext == "json" => Ok(path),
+        _ => Err(format!("the manifest must be a .csv or .json file, but got \"{}\"", s)),
+    }
+}
+
+/// --undated-dirの引数を読む（指定したフォルダの中に作るので，"/" で区切った相対パスで ".." を含まないものに限る）．
+fn parse_undated_dir(s: &str) -> Result<path::PathBuf, String> {
+    for name in s.split('/') {
+        if name.is_empty() || name == "." || name == ".." {
+            return Err(format!("expected a relative path without empty, \".\" or \"..\" folder names, but got \"{}\"", s));
+        }
+        name.chars().try_for_each(template::check_char)?;
+    }
+    Ok(path::PathBuf::from(s))
+}
+
+/// --name-offsetの引数（[+-]時:分）を読んで秒にする．
+/// 
+/// 二つのタイムゾーンの差にも使えるように，UTCからのずれ（最大14時間）より大きい時間も受け付ける．
+fn parse_name_offset(s: &str) -> Result<i64, String> {
+    let (sign, body) = match s.as_bytes().first() {
+        Some(b'-') => (-1, &s[1..]),
+        Some(b'+') => (1, &s[1..]),
+        _ => (1, s),
+    };
+    let secs = body.split_once(':').and_then(|(hours, minutes)| {
+        let is_digits = |num: &str| !num.is_empty() && num.len() <= 2 && num.bytes().all(|c| c.is_ascii_digit());
+        if !is_digits(hours) || minutes.len() != 2 || !is_digits(minutes) {
+            return None;
+        }
+        let (hours, minutes): (i64, i64) = (hours.parse().ok()?, minutes.parse().ok()?);
+        (minutes < 60).then_some(hours * 3600 + minutes * 60)
+    });
+    secs.map(|secs| sign * secs)
+        .ok_or_else(|| format!("expected an offset like \"+07:00\" or \"-05:30\", but got \"{}\"", s))
+}
+
+/// ファイル名に入れる位置情報の形式（--gps-name）
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq)]
+enum GpsName {
+    Decimal,  // 緯度・経度の10進数表記（35.6581N139.7414E）
+    Geohash,  // ジオハッシュ（xn76urx）
+}
+
+impl GpsName {
+    /// --gps-precisionを指定しなかった場合の精度（小数点以下の桁数，またはジオハッシュの文字数）
+    ///
+    /// どちらも数m～十数m程度の精度になる．
+    fn default_precision(self) -> u8 {
+        match self {
+            GpsName::Decimal => 4,
+            GpsName::Geohash => 7,
+        }
+    }
 }
 
 fn main() {
     // コマンドライン引数を読む
-    let args = Args::parse();
+    let mut args = Args::parse();
+    // 保管場所ではハッシュ値だけでファイルを見分けるので，CRC32（32ビット）では短すぎる
+    if args.content_store.is_some() {
+        if args.hash == HashAlgorithm::Crc32 {
+            args.hash = HashAlgorithm::Sha256;
+        }
+        args.hash_length.get_or_insert(CONTENT_STORE_HASH_LEN);
+    }
+
+    if let Some(Command::SetDate(set_date_args)) = &args.command {
+        match set_dates(set_date_args) {
+            Ok(()) => println!("Finish!"),
+            Err(e) => println!("Error: {}", e),
+        }
+        return;
+    }
+    if let Some(Command::CopyExif(copy_exif_args)) = &args.command {
+        match copy_exifs(copy_exif_args) {
+            Ok(()) => println!("Finish!"),
+            Err(e) => println!("Error: {}", e),
+        }
+        return;
+    }
+    // 出力をそのまま比較したりスクリプトで読んだりできるように，Finish!は表示しない
+    if let Some(Command::Exif(exif_args)) = &args.command {
+        if let Err(e) = dump_exif(exif_args) {
+            println!("Error: {}", e);
+        }
+        return;
+    }
+    if let Some(Command::Restore(restore_args)) = &args.command {
+        match restore::restore(&restore_args.manifest, &restore_args.dir, restore_args.dry_run) {
+            Ok(summary) => {
+                if restore_args.dry_run {
+                    println!("Would restore {} files (nothing was renamed, since --dry-run was specified).", summary.restored);
+                } else {
+                    println!("Restored {} files.", summary.restored);
+                }
+                if summary.conflicts > 0 {
+                    println!("Skipped {} files whose original name is used by another file.", summary.conflicts);
+                }
+                if summary.not_found > 0 {
+                    println!("Skipped {} files that could not be found.", summary.not_found);
+                }
+                if summary.skipped > 0 {
+                    println!("Skipped {} files that could not be moved.", summary.skipped);
+                }
+                if summary.deleted > 0 {
+                    println!("{} deleted files cannot be restored.", summary.deleted);
+                }
+                println!("Finish!");
+            },
+            Err(e) => println!("Error: {}", e),
+        }
+        return;
+    }
+    // 不一致があった場合は，cronなどから分かるように終了コードを1にする
+    if let Some(Command::Verify(verify_args)) = &args.command {
+        match verify(verify_args) {
+            Ok(0) => println!("Finish!"),
+            Ok(_) => process::exit(1),
+            Err(e) => {
+                println!("Error: {}", e);
+                process::exit(2);
+            },
+        }
+        return;
+    }
+    if let Some(journal_path) = &args.undo {
+        match journal::undo(journal_path) {
+            Ok(summary) => {
+                println!("Restored {} renames and moves.", summary.restored);
+                if summary.skipped > 0 {
+                    println!("Skipped {} that could not be restored safely (the journal is kept).", summary.skipped);
+                }
+                if summary.not_undoable > 0 {
+                    println!("{} deletions and rewrites cannot be undone.", summary.not_undoable);
+                }
+                println!("Finish!");
+            },
+            Err(e) => println!("Error: {}", e),
+        }
+        return;
+    }
     
     let dir_path: path::PathBuf = if args.path.is_some() {
         args.path.as_ref().unwrap().into()
@@ -70,13 +1079,29 @@ fn main() {
     if args.recursion {
         println!("The -r option was specified. Subdirectories are also included in the process.");
     }
+    match &args.output {
+        Some(output) if args.copy => println!("The files are copied into this directory, and the original files are left as is: {}", output.display()),
+        Some(output) => println!("The files are moved into this directory, keeping the folders relative to the source: {}", output.display()),
+        None => (),
+    }
+    for text in [&args.artist, &args.copyright].into_iter().flatten() {
+        if to_exif_ascii(text).1 {
+            println!("Warning: Characters that cannot be written in Exif (ASCII) will be replaced with \"?\": {}", text);
+        }
+    }
+    if args.hash_length.is_some_and(|len| len > args.hash.max_len(args.hash_encoding)) {
+        println!("Note: The hash of {} only has {} characters, so all of them are used.", args.hash.name(), args.hash.max_len(args.hash_encoding));
+    }
+    if args.set_btime && !fileops::CAN_SET_CREATED {
+        println!("Note: The creation time cannot be set on this system, so --set-btime has no effect.");
+    }
     println!("------------");
 
     // ダイアログで選択した場合は実行確認
     if args.path.is_none() {
         // 指定したフォルダの直下にある画像枚数を表示する（-rを指定した場合はフォルダ数も）
-        let (jpeg_files, folders) = count_file_folder(&dir_path).unwrap();
-        println!("I found {} jpeg files in this directory.", jpeg_files);
+        let (image_files, folders) = count_file_folder(&dir_path, &args).unwrap();
+        println!("I found {} image/video files in this directory.", image_files);
         if args.recursion {
             println!("And {} sub directries.", {folders});
         }
@@ -101,17 +1126,180 @@ fn main() {
     }
 
     println!("Processing...");
-    match change_names(&dir_path, &args) {
-        Ok(()) => println!("Finish!"),
-        Err(e) => println!("Error: {}", e),
+    let (dir_path, mut summary) = match prepare(dir_path, &args) {
+        Ok(prepared) => prepared,
+        Err(message) => {
+            println!("Error: {}", message);
+            process::exit(1);
+        },
+    };
+    let result = change_names(&dir_path, &args, args.prefix.as_deref(), &mut summary);
+    if args.skip_organized {
+        println!("Skipped {} files that are already organized.", summary.skipped_organized);
+    }
+    if args.dedupe {
+        println!("Moved {} duplicate files to the \"{}\" folder.", summary.deduped, DEDUPE_DIR_NAME);
+    }
+    if summary.duplicates > 0 {
+        println!("Found {} files that already exist with the same contents.", summary.duplicates);
+    }
+    if args.rehash {
+        println!("Renamed {} files whose hash no longer matched the contents.", summary.rehashed);
+    }
+    // --dry-runの場合は，実際には置いたりコピーしたり削除したりしていないので予定として表示する（コピーを確かめてもいない）
+    let dry_run = summary.files.is_dry_run();
+    if summary.undated_root.is_some() {
+        let verb = if dry_run { "Would put" } else { "Put" };
+        println!("{} {} files without a date into the \"{}\" folder.", verb, summary.undated, args.undated_dir.display());
+    }
+    if args.copy {
+        let verb = if dry_run { "Would copy" } else { "Copied" };
+        println!("{} {} files ({} bytes).", verb, summary.copied, summary.copied_bytes);
+        if !dry_run {
+            println!("Removed {} copies that did not match the original files.", summary.copy_failures);
+        }
+    }
+    if args.content_store.is_some() {
+        let verb = if dry_run { "Would store" } else { "Stored" };
+        println!("{} {} files in the content store.", verb, summary.stored);
+        if args.delete_stored {
+            let verb = if dry_run { "Would delete" } else { "Deleted" };
+            println!("{} {} files that were already in the content store.", verb, summary.already_stored);
+        } else {
+            println!("Skipped {} files that were already in the content store.", summary.already_stored);
+        }
+    }
+    // 途中で失敗した場合も，そこまでに処理したファイルを書き出す
+    if let Some(manifest_path) = &args.manifest {
+        match write_manifest(manifest_path, &summary.manifest) {
+            Ok(()) => println!("Wrote the list of {} processed files to {}", summary.manifest.len(), manifest_path.display()),
+            Err(e) => println!("Error: Could not write the manifest: {}", e),
+        }
+    }
+    if args.dry_run {
+        print_plan(&summary.manifest, summary.layout_root.as_deref());
+    }
+    match result {
+        Ok(()) => println!("Finish!"),
+        Err(e) => println!("Error: {}", e),
+    }
+}
+
+/// 処理するフォルダ（--outputの場合は絶対パスにしたもの）と，ジャーナルや保管場所などを開いた実行全体の情報を返す．
+/// 
+/// 開けなかった場合や，指定したフォルダの組み合わせが正しくない場合は，表示するエラーメッセージを返す．
+fn prepare(dir_path: path::PathBuf, args: &Args) -> Result<(path::PathBuf, Summary), String> {
+    // --outputの場合はジャーナルも出力先に作る（--copyの場合は元のフォルダを書き換えない）
+    // ジャーナルに記録する元のパスが，出力先からの相対パスにならないように絶対パスにする
+    let output_dirs = match &args.output {
+        Some(output) => Some(output_dirs(&dir_path, output, args).map_err(|e| e.to_string())?),
+        None => None,
+    };
+    let dir_path = output_dirs.as_ref().map_or(dir_path, |(dir, _)| dir.clone());
+    let journal_dir = output_dirs.as_ref().map_or(&dir_path, |(_, output)| output);
+    // 間違えて実行した場合に元に戻せるように，リネームなどの操作を処理したフォルダのジャーナルに記録する
+    // --dry-runの場合は何も変更しないので，ジャーナルも作らない
+    let journal = if args.dry_run { Ok(journal::Journal::default()) } else { journal::Journal::open(journal_dir) };
+    let journal = journal.map_err(|e| format!("Could not open the journal: {}", e))?;
+    let store = match &args.content_store {
+        Some(root) => match store::ContentStore::open(root, args.dry_run) {
+            // 保管場所のファイルをもう一度処理しないように，-rの場合は処理するフォルダの外にする
+            Ok(store) if args.recursion && fs::canonicalize(&dir_path).is_ok_and(|dir| store.root().starts_with(dir)) => {
+                return Err(format!("The content store must be outside the directory when -r is specified: {}", root.display()));
+            },
+            Ok(store) if !journal.can_record(store.root()) => {
+                return Err(format!("The content store cannot be recorded in the journal, since the path is not valid UTF-8: {}", root.display()));
+            },
+            Ok(store) => Some(store),
+            Err(e) => return Err(format!("Could not open the content store: {}", e)),
+        },
+        None => None,
+    };
+    let layout_root = args.layout.as_ref().map(|_| output_dirs.as_ref().map_or(&dir_path, |(_, output)| output).clone());
+    let undated_root = layout_root.as_ref().or(output_dirs.as_ref().map(|(_, output)| output)).map(|root| root.join(&args.undated_dir));
+    let summary = Summary { journal, files: fileops::FileOps::new(args.dry_run), store, layout_root, output_dirs, undated_root, ..Default::default() };
+    Ok((dir_path, summary))
+}
+
+/// --outputの処理するフォルダと出力先のフォルダを絶対パスにして返す（出力先が無ければ作る）．
+/// 
+/// "."や末尾の"/"で指定した場合もサブフォルダの相対パスが正しく求まるように，どちらも正規化する．
+/// 出力したファイルをもう一度処理しないように，-rの場合は出力先を処理するフォルダの外にする．
+fn output_dirs(dir_path: &path::Path, output: &path::Path, args: &Args) -> io::Result<(path::PathBuf, path::PathBuf)> {
+    let dir = fs::canonicalize(dir_path)?;
+    let output = fs::canonicalize(output).or_else(|_| canonicalize_missing(output))?;
+    if output == dir || (args.recursion && output.starts_with(&dir)) {
+        let message = format!("The output directory must be outside the directory{}: {}", if args.recursion { " when -r is specified" } else { "" }, output.display());
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+    }
+    if !args.dry_run {
+        fs::create_dir_all(&output)?;
+    }
+    Ok((dir, output))
+}
+
+/// まだ無いパスを，有る所までの親フォルダを正規化して残りを付けた絶対パスにする（"../out"などを処理するフォルダと比べられるように）．
+fn canonicalize_missing(path: &path::Path) -> io::Result<path::PathBuf> {
+    let path = path::absolute(path)?;
+    for ancestor in path.ancestors().skip(1) {
+        if let Ok(dir) = fs::canonicalize(ancestor) {
+            return Ok(dir.join(path.strip_prefix(ancestor).unwrap()));
+        }
+    }
+    Ok(path)
+}
+
+/// --dry-runで，処理する予定のファイルごとに元の名前と新しい名前，撮影日時の取得元，警告を表示する．
+fn print_plan(rows: &[ManifestRow], layout_root: Option<&path::Path>) {
+    println!("--- Plan (nothing was changed, since --dry-run was specified) ---");
+    for row in rows {
+        let new = match &row.new {
+            Some(new) => new.display().to_string(),
+            None => String::from("(deleted)"),
+        };
+        println!("{} -> {} [{}]", row.original.display(), new, row.date_source.as_deref().unwrap_or("no date"));
+        for warning in &row.warnings {
+            println!("    {}", warning);
+        }
+    }
+    if let Some(root) = layout_root {
+        print_layout_tree(rows, root);
+    }
+    println!("Would process {} files.", rows.len());
+}
+
+/// --layoutで移動する予定のフォルダを，フォルダごとのファイル数と一緒に木の形で表示する（--dry-run）．
+fn print_layout_tree(rows: &[ManifestRow], root: &path::Path) {
+    let mut counts: std::collections::BTreeMap<Vec<String>, usize> = std::collections::BTreeMap::new();
+    for row in rows {
+        let dir = match row.new.as_deref().and_then(path::Path::parent).and_then(|dir| dir.strip_prefix(root).ok()) {
+            Some(dir) if row.new.as_deref().and_then(path::Path::parent) != row.original.parent() => dir,
+            _ => continue,
+        };
+        *counts.entry(dir.components().map(|component| component.as_os_str().to_string_lossy().into_owned()).collect()).or_default() += 1;
+    }
+    if counts.is_empty() {
+        return;
+    }
+    println!("Folders under {}:", root.display());
+    let mut previous: &[String] = &[];
+    for (dir, count) in &counts {
+        // 前のフォルダと同じ親フォルダは表示しない
+        let common = dir.iter().zip(previous).take_while(|(a, b)| a == b).count();
+        for (depth, name) in dir.iter().enumerate().skip(common) {
+            let files = if depth == dir.len() - 1 { format!(" ({} files)", count) } else { String::new() };
+            println!("{}{}/{}", "  ".repeat(depth + 1), name, files);
+        }
+        previous = dir;
     }
 }
 
-/// 指定したパスに含まれるJPEGファイルとフォルダ数を返す。
+/// 指定したパスに含まれる処理対象の画像ファイルとフォルダ数を返す。
+/// --sniffを指定した場合は拡張子で判定できないファイルの中身も確認する。
 /// 
-/// (JPEGファイル数, フォルダ数)
-fn count_file_folder(path: &path::Path) -> io::Result<(usize, usize)> {
-    let mut cnt_jpg = 0;
+/// (画像ファイル数, フォルダ数)
+fn count_file_folder(path: &path::Path, args: &Args) -> io::Result<(usize, usize)> {
+    let mut cnt_img = 0;
     let mut cnt_dir = 0;
     for entry in fs::read_dir(path)? {  // ディレクトリ内要素のループ
         let file_path = entry?.path();
@@ -121,39 +1309,41 @@ fn count_file_folder(path: &path::Path) -> io::Result<(usize, usize)> {
             continue;
         }
 
-        let ext = match file_path.extension() {
-            Some(ext) => ext.to_ascii_lowercase(),  // 小文字に変換
-            None => continue,
+        let file_type = match file_path.extension().and_then(filetype::from_extension) {
+            Some(file_type) => Some(file_type),
+            None if args.sniff => filetype::sniff(&file_path)?,
+            None => None,
         };
 
-        if ext == OsString::from("jpg") || ext == OsString::from("jpeg") {
-            cnt_jpg += 1;
+        match file_type {
+            Some(file_type) if file_type.is_hash_only() && !args.all_images => (),
+            Some(_) => cnt_img += 1,
+            None => (),
         }
 
     }
 
-    Ok((cnt_jpg, cnt_dir))
+    Ok((cnt_img, cnt_dir))
 }
 
-/// 画像に撮影日時を印字する．
-fn print_date(file_path: &path::Path, jpeg_binary: &[u8], date_txt: &str, keep_exif: bool) {
-    {
+/// 画像に撮影日時を印字する．印字した場合はtrueを返す．
+/// 
+/// 途中で切れたファイルなど，画像を展開できない場合は警告を表示して印字しない（ファイルは書き換えない）．
+fn print_date(file_path: &path::Path, jpeg_binary: &[u8], date_txt: &str, keep_exif: bool, warnings: &mut Vec<String>) -> bool {
+    let (img, mut stamped) = {
         // コンパイル時にフォントファイルのバイナリを埋め込む
         let font = include_bytes!("../fonts-DSEG_v046/DSEG7-Classic-MINI/DSEG7ClassicMini-Bold.ttf");
         let font = Font::try_from_bytes(font).expect("Could not read font data.");
 
-        let mut img = image::load_from_memory(jpeg_binary).unwrap();
-
         // Exif情報を読んで画像を回す
-        let orientation = exif::get_orientation(jpeg_binary);
-        if orientation.is_some() {
-            img = match orientation.unwrap() {
-                3 => img.rotate180(),
-                6 => img.rotate90(),
-                8 => img.rotate270(),
-                _ => img,  // Exif情報としては縦軸まわりに反転とかもあるけど，写真では使わないので考慮しない
-            }
-        }
+        let img = match image::load_from_memory(jpeg_binary) {
+            Ok(img) => img,
+            Err(e) => {
+                warn(warnings, format!("Skipped printing the date, since the image could not be decoded: {} ({})", file_path.display(), e));
+                return false;
+            },
+        };
+        let mut img = convert::rotate(img, exif::get_orientation(jpeg_binary));
         
         // 文字サイズが画像短辺の1/45になるようにする．
         let font_size = (img.width().min( img.height() ) as f32 / 45.0).round();
@@ -167,88 +1357,2797 @@ fn print_date(file_path: &path::Path, jpeg_binary: &[u8], date_txt: &str, keep_e
         drawing::draw_text_mut(&mut img, color, pos_x, pos_y, scale, &font, date_txt);
     
         // 品質を指定して保存したい
-        img.save(file_path).expect("Failed to overwrite the file.");
+        // 壊れたデータで元の画像を上書きしないように，一旦メモリ上にJPEGとして書き出す
+        let mut stamped = Vec::new();
+        if let Err(e) = img.write_to(&mut io::Cursor::new(&mut stamped), image::ImageOutputFormat::Jpeg(PRINT_DATE_QUALITY)) {
+            warn(warnings, format!("Skipped printing the date, since the image could not be encoded: {} ({})", file_path.display(), e));
+            return false;
+        }
+        (img, stamped)
+    };
+
+    // Motion Photoの場合は，動画の位置（MicroVideoOffsetなど）がXMPに書かれているので，-kを指定しなくてもXMPは残す
+    let trailer = motion_photo::get_trailer(jpeg_binary);
+    if keep_exif || trailer.is_some() {
+        // メタデータを持たせるために，imageクレートで書き出した画像のAPP0セグメントの後ろに
+        // 元の画像のExif・XMP・ICCプロファイルなどのセグメントを元の順番のまま挿入する．
+        // 画像自体は回転させたので，回転情報は1（回転なし）に，画像の幅と高さは回転後のものに書き換える．
+        // Exifのサムネイルは印字前の画像のままなので，印字した画像から作り直す．
+        let segments: Vec<Vec<u8>> = jpeg::get_metadata_segments(jpeg_binary).into_iter().filter(|segment| keep_exif || xmp::is_xmp_app1(segment)).map(|segment| {
+            if exif::is_exif_app1(segment) {
+                let app1 = exif::set_app1_dimensions(exif::clear_app1_orientation(segment.to_vec()), img.width(), img.height());
+                replace_thumbnail(file_path, app1, &img, warnings)
+            } else if xmp::is_xmp_app1(segment) {
+                let mut segment = segment.to_vec();
+                xmp::clear_orientation(&mut segment);
+                segment
+            } else {
+                segment.to_vec()
+            }
+        }).collect();
+
+        // 元の画像がメタデータを持たない場合は挿入するものが無いので何もしない．
+        if !segments.is_empty() {
+            // 挿入後の画像を読み直して，挿入したセグメントが全て見つかることを確認する
+            match exif::insert_after_app0(&stamped, &segments.concat()) {
+                Ok(inserted) if jpeg::get_metadata_segments(&inserted) == segments => stamped = inserted,
+                Ok(_) => {
+                    warn(warnings, format!("Skipped printing the date, since the metadata could not be kept: {}", file_path.display()));
+                    return false;
+                },
+                Err(e) => {
+                    warn(warnings, format!("Skipped printing the date, since the metadata could not be kept: {} ({})", file_path.display(), e));
+                    return false;
+                },
+            }
+        }
+    }
+
+    // Motion Photoの場合は，主画像の後ろに連結されていた動画を付け直す
+    if let Some(trailer) = trailer {
+        stamped.extend_from_slice(trailer);
+    }
+
+    match overwrite_file(file_path, &stamped) {
+        Ok(()) => true,
+        Err(e) => {
+            warn(warnings, format!("Skipped printing the date, since the file could not be overwritten: {} ({})", file_path.display(), e));
+            false
+        },
+    }
+}
+
+/// APP1セグメントのExifのサムネイルを，日付を印字した画像から作り直したものに差し替える．
+/// 
+/// サムネイルが無い場合はそのまま返す．作り直したサムネイルがAPP1セグメントに収まらない場合は，
+/// 古いサムネイルを残すと印字前の画像が表示されてしまうので，警告を表示してサムネイルを削除する．
+fn replace_thumbnail(file_path: &path::Path, app1: Vec<u8>, img: &image::DynamicImage, warnings: &mut Vec<String>) -> Vec<u8> {
+    if !exif::has_app1_thumbnail(&app1) {
+        return app1;
+    }
+    let replaced = convert::thumbnail(img, THUMBNAIL_LONG_EDGE, PRINT_DATE_QUALITY).ok()
+        .and_then(|thumbnail| exif::replace_app1_thumbnail(&app1, Some(&thumbnail)));
+    match replaced {
+        Some(replaced) => replaced,
+        None => {
+            warn(warnings, format!("Removed the Exif thumbnail, since a new one did not fit in the APP1 segment: {}", file_path.display()));
+            exif::replace_app1_thumbnail(&app1, None).unwrap_or(app1)
+        },
+    }
+}
+
+/// 警告を表示して，ファイルごとの警告として残す（--manifestに書き出すため）．
+fn warn(warnings: &mut Vec<String>, message: String) {
+    println!("Warning: {}", message);
+    warnings.push(message);
+}
+
+/// ファイルの中身を書き換える．
+/// 
+/// 一時ファイルに書き出してから置き換える（書き込み中に失敗しても元の画像が壊れないように）．
+fn overwrite_file(file_path: &path::Path, contents: &[u8]) -> io::Result<()> {
+    let tmp_path = file_path.with_file_name(format!(".{}.printing", file_path.file_name().unwrap().to_string_lossy()));
+    fs::write(&tmp_path, contents)?;
+    fs::rename(&tmp_path, file_path)
+}
+
+/// JPEG画像のExifから位置情報を削除したものを返す（--strip-gps）．
+/// 
+/// 位置情報が無い場合はNoneを返す．削除した結果，撮影日時が読めなくなったり位置情報が残っていたりする場合は
+/// Exifの他の部分を壊した可能性があるので，警告を表示してNoneを返す．
+fn strip_gps(file_path: &path::Path, jpeg_binary: &[u8], warnings: &mut Vec<String>) -> Option<Vec<u8>> {
+    exif::get_gps(jpeg_binary)?;
+    let stripped = exif::strip_gps(jpeg_binary)
+        .filter(|stripped| exif::get_gps(stripped).is_none())
+        .filter(|stripped| exif::get_date_time_original(stripped) == exif::get_date_time_original(jpeg_binary));
+    if stripped.is_none() {
+        warn(warnings, format!("GPS data was left as is, since it could not be removed safely: {}", file_path.display()));
+    }
+    stripped
+}
+
+/// JPEG画像のExifからサムネイルを削除したものを返す（--strip-thumbnail）．
+/// 
+/// サムネイルが無い場合はNoneを返す．削除した結果，撮影日時が読めなくなった場合は
+/// Exifの他の部分を壊した可能性があるので，警告を表示してNoneを返す．
+fn strip_thumbnail(file_path: &path::Path, jpeg_binary: &[u8], warnings: &mut Vec<String>) -> Option<Vec<u8>> {
+    let stripped = exif::strip_thumbnail(jpeg_binary)?;
+    if exif::get_date_time_original(&stripped) != exif::get_date_time_original(jpeg_binary) {
+        warn(warnings, format!("The Exif thumbnail was left as is, since it could not be removed safely: {}", file_path.display()));
+        return None;
+    }
+    Some(stripped)
+}
+
+/// ファイルに埋め込まれた撮影日時をExifの日時形式（YYYY:MM:DD HH:MM:SS）で，取得元の名前と一緒に返す．
+/// 
+/// binaryは形式に応じてファイル全体，ファイルの先頭部分，またはmoovボックスの中身．
+/// Exifの場合はdate_tagsの順にタグを試し，Exifに撮影日時が無ければExif以外のメタデータを読む．
+fn read_date_time(file_path: &path::Path, binary: &[u8], file_type: FileType, date_tags: &[DateTag]) -> Option<([u8; 19], &'static str)> {
+    read_exif_tiffs(binary, file_type)
+        .and_then(|(exif_tiff, ifd0_tiff)| read_exif_date_time(file_path, exif_tiff, ifd0_tiff, date_tags))
+        .or_else(|| read_other_date_time(binary, file_type))
+}
+
+/// 撮影日時を読むための，Exif IFDと0th IFDのそれぞれのTIFFヘッダから始まるデータを返す（CR3以外は同じもの）．
+/// 
+/// MPOは複数のJPEGを連結したもので，1枚目のJPEGのExifを読む．
+/// CR3はExif IFDがCMT2，0th IFDがCMT1に分かれている．
+/// GIF・BMPや動画はExifを持たないのでNone．
+fn read_exif_tiffs(binary: &[u8], file_type: FileType) -> Option<(&[u8], &[u8])> {
+    match file_type {
+        FileType::Cr3 => Some((
+            bmff::get_canon_cmt(binary, b"CMT2").unwrap_or_default(),
+            bmff::get_canon_cmt(binary, b"CMT1").unwrap_or_default(),
+        )),
+        _ => read_exif(binary, file_type).map(|tiff| (tiff, tiff)),
+    }
+}
+
+/// Exif以外のメタデータに書かれた撮影日時を，取得元の名前と一緒に返す．
+fn read_other_date_time(binary: &[u8], file_type: FileType) -> Option<([u8; 19], &'static str)> {
+    match file_type {
+        // Exifに撮影日時が無い場合は，編集ソフトが書き出したXMPの日時を使う
+        FileType::Jpeg | FileType::Mpo => Some((xmp::get_date_time(xmp::get_xmp(binary)?)?, "XMP")),
+        // binaryはmoovボックスの中身．古い携帯電話の3GPは作成日時が0のことが多い（その場合はハッシュ値のみ）
+        FileType::Mp4 | FileType::Mov | FileType::ThreeGp | FileType::ThreeG2 => Some((bmff::get_creation_date(binary)?, "QuickTime metadata")),
+        FileType::Mts  => Some((avchd::get_date_time(binary)?, "AVCHD stream")),
+        _ => None,
+    }
+}
+
+/// Exifの日時のタグをdate_tagsの順に試して，日時として正しい最初のものをタグの名前と一緒に返す．
+/// 
+/// exif_tiffはExif IFDを，ifd0_tiffは0th IFDを読むためのTIFFヘッダから始まるデータ（CR3以外は同じもの）．
+/// 日時として正しくない値（未設定の空白や0埋め，範囲外の値など）は，その旨を表示して無視する．
+fn read_exif_date_time(file_path: &path::Path, exif_tiff: &[u8], ifd0_tiff: &[u8], date_tags: &[DateTag]) -> Option<([u8; 19], &'static str)> {
+    date_tags.iter().find_map(|date_tag| {
+        match read_date_tag(exif_tiff, ifd0_tiff, *date_tag) {
+            Some(date_time) if datetime::is_valid(&date_time) => Some((date_time, date_tag.tag_name())),
+            Some(date_time) => {
+                println!("Note: The {} \"{}\" is not a valid date, so it is ignored: {}",
+                    date_tag.tag_name(), String::from_utf8_lossy(&date_time).escape_debug(), file_path.display());
+                None
+            },
+            None => None,
+        }
+    })
+}
+
+/// Exifの日時のタグの値を返す（日時として正しいかどうかは確認しない）．
+fn read_date_tag(exif_tiff: &[u8], ifd0_tiff: &[u8], date_tag: DateTag) -> Option<[u8; 19]> {
+    match date_tag {
+        DateTag::Original  => exif::get_date_time_original_from_tiff(exif_tiff),
+        DateTag::Digitized => exif::get_date_time_digitized_from_tiff(exif_tiff),
+        DateTag::Modified  => exif::get_date_time_from_tiff(ifd0_tiff),
+        DateTag::Gps       => exif::get_gps_date_time_from_tiff(ifd0_tiff),
+    }
+}
+
+/// JPEG画像のメタデータを全て削除したものを返す（--strip-metadata）．
+/// 
+/// 回転情報も消えて横倒しで表示されてしまうので，--apply-orientationを指定した場合は先に画像自体を回転させる．
+/// 失敗した場合は警告を表示してNoneを返す．
+fn strip_metadata(file_path: &path::Path, jpeg_binary: &[u8], args: &Args, warnings: &mut Vec<String>) -> Option<Vec<u8>> {
+    let rotated;
+    let jpeg_binary = match exif::get_orientation(jpeg_binary) {
+        Some(2..=8) if args.apply_orientation => match convert::apply_orientation(jpeg_binary, args.quality) {
+            Ok(jpeg) => {
+                rotated = jpeg;
+                &rotated
+            },
+            Err(e) => {
+                warn(warnings, format!("Metadata was left as is, since the image could not be rotated: {} ({})", file_path.display(), e));
+                return None;
+            },
+        },
+        Some(2..=8) => {
+            println!("Note: The image may be displayed sideways or mirrored, since its orientation is removed with the metadata: {}", file_path.display());
+            jpeg_binary
+        },
+        _ => jpeg_binary,
+    };
+
+    let stripped = jpeg::strip_metadata(jpeg_binary);
+    if stripped.is_none() {
+        warn(warnings, format!("Metadata was left as is, since the JPEG segments could not be read: {}", file_path.display()));
+    }
+    stripped
+}
+
+/// JPEG画像のExifに撮影者と著作権表示を書き込んだものを返す（--artist，--copyright）．
+/// 
+/// 既に値がある場合は，--force-artistを指定しない限りそのままにする．書き込むものが無い場合はNoneを返す．
+/// 書き込んだ結果，撮影日時が読めなくなった場合はExifの他の部分を壊した可能性があるので，警告を表示してNoneを返す．
+fn write_artist(file_path: &path::Path, jpeg_binary: &[u8], args: &Args, warnings: &mut Vec<String>) -> Option<Vec<u8>> {
+    let artist = args.artist.as_deref().map(|artist| to_exif_ascii(artist).0);
+    let copyright = args.copyright.as_deref().map(|copyright| to_exif_ascii(copyright).0);
+    let tags: Vec<exif::NewTag> = [artist.as_deref().map(exif::NewTag::Artist), copyright.as_deref().map(exif::NewTag::Copyright)]
+        .into_iter().flatten().collect();
+
+    match exif::set_tags(jpeg_binary, &tags, args.force_artist) {
+        Ok(written) if written == jpeg_binary => None,
+        Ok(written) if exif::get_date_time_original(&written) == exif::get_date_time_original(jpeg_binary) => Some(written),
+        Ok(_) => {
+            warn(warnings, format!("The artist and copyright were not written, since the Exif could not be read back: {}", file_path.display()));
+            None
+        },
+        Err(e) => {
+            warn(warnings, format!("The artist and copyright were not written: {} ({})", file_path.display(), e));
+            None
+        },
+    }
+}
+
+/// JPEG画像のExifのUserCommentに元のファイル名を記録したものを返す（--record-original-name）．
+/// 
+/// 既にコメントがある場合は，改行して後ろに追加する．既に元のファイル名を記録してある場合は，
+/// リネーム後の名前を重ねて記録しないようにNoneを返す．書き込めなかった場合は警告を表示してNoneを返す．
+fn record_original_name(file_path: &path::Path, jpeg_binary: &[u8], warnings: &mut Vec<String>) -> Option<Vec<u8>> {
+    let file_name = file_path.file_name()?.to_string_lossy();
+    let comment = match exif::get_tiff(jpeg_binary).map(exif::get_user_comment_from_tiff) {
+        Some(Ok(comment)) => comment,
+        Some(Err(e)) => {
+            warn(warnings, format!("The original file name was not recorded: {} ({})", file_path.display(), e));
+            return None;
+        },
+        None => None,
+    };
+    if comment.as_deref().is_some_and(|comment| comment.contains(ORIGINAL_NAME_LABEL)) {
+        return None;
+    }
+    let comment = match comment.filter(|comment| !comment.is_empty()) {
+        Some(comment) => format!("{}\n{}{}", comment, ORIGINAL_NAME_LABEL, file_name),
+        None => format!("{}{}", ORIGINAL_NAME_LABEL, file_name),
+    };
+
+    match exif::set_tags(jpeg_binary, &[exif::NewTag::UserComment(&comment)], true) {
+        Ok(written) if exif::get_tiff(&written).map(exif::get_user_comment_from_tiff) == Some(Ok(Some(comment)))
+            && exif::get_date_time_original(&written) == exif::get_date_time_original(jpeg_binary) => Some(written),
+        Ok(_) => {
+            warn(warnings, format!("The original file name was not recorded, since the Exif could not be read back: {}", file_path.display()));
+            None
+        },
+        Err(e) => {
+            warn(warnings, format!("The original file name was not recorded: {} ({})", file_path.display(), e));
+            None
+        },
+    }
+}
+
+/// Exifの撮影者・著作権表示（ASCII）に書けるように，ASCII以外の文字を置き換えた文字列を返す．
+/// 
+/// ©などの記号はASCIIの表記に，アクセント付きのラテン文字はアクセントを除いた文字にする．
+/// それ以外の文字は "?" にして，"?" にした文字があったかどうかも返す．
+fn to_exif_ascii(text: &str) -> (String, bool) {
+    const ACCENTED: &str = "ÀÁÂÃÄÅàáâãäåÇçÈÉÊËèéêëÌÍÎÏìíîïÑñÒÓÔÕÖØòóôõöøÙÚÛÜùúûüÝýÿ";
+    const UNACCENTED: &str = "AAAAAAaaaaaaCcEEEEeeeeIIIIiiiiNnOOOOOOooooooUUUUuuuuYyy";
+    let mut ascii = String::with_capacity(text.len());
+    let mut is_replaced = false;
+    for c in text.chars() {
+        match c {
+            ' '..='~' => ascii.push(c),
+            '©' => ascii.push_str("(C)"),
+            '®' => ascii.push_str("(R)"),
+            '™' => ascii.push_str("(TM)"),
+            'Æ' => ascii.push_str("AE"),
+            'æ' => ascii.push_str("ae"),
+            'ß' => ascii.push_str("ss"),
+            _ => match ACCENTED.chars().position(|accented| accented == c) {
+                Some(i) => ascii.push(UNACCENTED.as_bytes()[i] as char),
+                None => {
+                    ascii.push('?');
+                    is_replaced = true;
+                },
+            },
+        }
+    }
+    (ascii, is_replaced)
+}
+
+/// ファイルに埋め込まれたExifデータ（TIFFヘッダから始まる）を返す．
+/// 
+/// binaryはread_date_timeと同じ．CR3はExif IFDを格納したCMT2ボックスを返す．
+/// 動画などExifを持たない形式はNoneを返す．
+fn read_exif(binary: &[u8], file_type: FileType) -> Option<&[u8]> {
+    match file_type {
+        FileType::Jpeg | FileType::Mpo => exif::get_tiff(binary),
+        FileType::Png  => png::get_exif(binary),
+        FileType::WebP => riff::get_exif(binary),
+        FileType::Heic | FileType::Avif => bmff::get_exif(binary),
+        // TIFFベースのRAWファイルはファイルの先頭がTIFFヘッダ
+        FileType::Tiff | FileType::Dng | FileType::Cr2 | FileType::Nef | FileType::Arw
+            | FileType::Orf | FileType::Rw2 => Some(binary),
+        FileType::Cr3  => bmff::get_canon_cmt(binary, b"CMT2"),
+        FileType::Raf  => exif::get_tiff(raf::get_jpeg(binary)?),  // 埋め込みJPEGのExifを読む
+        _ => None,
+    }
+}
+
+/// 画像を展開して，向きを直した画素データを返す（--hash-source pixels）．
+/// 
+/// 展開できない形式の画像の場合はNote，展開に失敗した場合はWarningを表示してNoneを返す（ファイルのハッシュ値を使う）．
+/// 動画の場合は何も表示せずにNoneを返す．
+fn read_pixels(file_path: &path::Path, binary: &[u8], file_type: FileType, warnings: &mut Vec<String>) -> Option<Vec<u8>> {
+    let format = match file_type {
+        FileType::Jpeg | FileType::Mpo => image::ImageFormat::Jpeg,  // MPOは1枚目の画像
+        FileType::Png => image::ImageFormat::Png,
+        _ if file_type.is_video() => return None,
+        _ => {
+            println!("Note: Only the pixels of JPEG and PNG files are hashed, so the file itself is hashed: {}", file_path.display());
+            return None;
+        },
+    };
+    let orientation = read_exif(binary, file_type).and_then(exif::get_orientation_from_tiff);
+    match convert::upright_pixels(binary, format, orientation) {
+        Ok(pixels) => Some(pixels),
+        Err(e) => {
+            warn(warnings, format!("The file itself is hashed, since the image could not be decoded: {} ({})", file_path.display(), e));
+            None
+        },
+    }
+}
+
+/// JPEGのメタデータ以外のデータを返す（--hash-source stripped）．
+/// 
+/// JPEG以外の画像の場合はNote，セグメントが壊れている場合はWarningを表示してNoneを返す（ファイルのハッシュ値を使う）．
+/// 動画の場合は何も表示せずにNoneを返す．
+fn read_image_data<'a>(file_path: &path::Path, binary: &'a [u8], file_type: FileType, warnings: &mut Vec<String>) -> Option<Vec<&'a [u8]>> {
+    match file_type {
+        FileType::Jpeg | FileType::Mpo => (),  // MPOは1枚目の画像
+        _ if file_type.is_video() => return None,
+        _ => {
+            println!("Note: Metadata is only excluded from the hash of JPEG files, so the file itself is hashed: {}", file_path.display());
+            return None;
+        },
+    }
+    let image_data = jpeg::get_image_data(binary);
+    if image_data.is_none() {
+        warn(warnings, format!("The file itself is hashed, since the end of the JPEG image could not be found: {}", file_path.display()));
+    }
+    image_data
+}
+
+/// ファイルに埋め込まれたExifの位置情報（GPS IFD）を返す．
+/// 
+/// CR3の位置情報はGPS IFDだけを格納したCMT4ボックスにあるので，ここでは読まない．
+fn read_gps(binary: &[u8], file_type: FileType) -> Option<exif::Gps> {
+    exif::get_gps_from_tiff(read_exif(binary, file_type)?)
+}
+
+/// ファイルに埋め込まれたExifのOffsetTimeOriginal（撮影日時のUTCからのずれ）を秒で返す．
+fn read_offset(binary: &[u8], file_type: FileType) -> Option<i64> {
+    datetime::parse_offset(&exif::get_offset_time_original_from_tiff(read_exif(binary, file_type)?)?)
+}
+
+/// ファイルに埋め込まれたExifのSubSecTimeOriginal（撮影日時の秒未満の部分）の数字を返す．
+fn read_sub_sec(binary: &[u8], file_type: FileType) -> Option<String> {
+    exif::get_sub_sec_time_original_from_tiff(read_exif(binary, file_type)?)
+}
+
+/// ファイルに埋め込まれたExifのImageUniqueIDをファイル名に使える形にして返す．
+/// 
+/// 英数字以外は取り除いて小文字にし，先頭のUNIQUE_ID_LEN文字にする．
+/// それより短い場合や，全て0の場合（IDを付けないカメラが書くことがある）はNoneを返す．
+fn read_unique_id(binary: &[u8], file_type: FileType) -> Option<String> {
+    let id: String = exif::get_image_unique_id_from_tiff(read_exif(binary, file_type)?)?
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .take(UNIQUE_ID_LEN)
+        .collect();
+    if id.len() < UNIQUE_ID_LEN || id.chars().all(|c| c == '0') {
+        None
+    } else {
+        Some(id)
+    }
+}
+
+/// ファイルに埋め込まれたExifの撮影機材の情報を返す．
+fn read_camera(binary: &[u8], file_type: FileType) -> Option<exif::Camera> {
+    match file_type {
+        FileType::Cr3 => exif::get_camera_from_tiff(bmff::get_canon_cmt(binary, b"CMT1")?),  // CMT1は0th IFD
+        _ => exif::get_camera_from_tiff(read_exif(binary, file_type)?),
+    }
+}
+
+/// 機種名をファイル名に使える文字列に変換する（X-T4，Canon-EOS-R5など）．
+/// 
+/// 空白やスラッシュなどは "-" にし（"_" は区切り文字なので同様），連続する "-" はまとめる．
+/// 英数字と "-"，"."，"+" 以外の文字は取り除き，MODEL_NAME_MAX_LEN文字で切り詰める．
+/// 何も残らない場合はNoneを返す．
+fn format_model(model: &str) -> Option<String> {
+    let mut name = String::with_capacity(model.len());
+    for c in model.chars() {
+        match c {
+            'A'..='Z' | 'a'..='z' | '0'..='9' | '.' | '+' => name.push(c),
+            ' ' | '_' | '-' | '/' | '\\' if !name.is_empty() && !name.ends_with('-') => name.push('-'),
+            _ => (),
+        }
+    }
+    name.truncate(MODEL_NAME_MAX_LEN);
+    let name = name.trim_end_matches(['-', '.']);
+    if name.is_empty() {
+        None
+    } else {
+        Some(name.to_string())
+    }
+}
+
+/// 小文字にした拡張子を，--canonical-ext，--ext-mapで指定した表記に書き換える．
+/// 
+/// --ext-mapを優先し，"--canonical-ext --ext-map tif=tiff" のように組み込みの書き換え先をさらに書き換える場合は
+/// tiffがtifを経由してtiffに戻るように，書き換えた後の拡張子にもう一度--ext-mapを当てる．
+fn canonical_ext(ext: OsString, args: &Args) -> OsString {
+    let ext_map = |ext: &str| args.ext_map.iter().find(|(from, _)| from == ext).map(|(_, to)| to.clone());
+    let built_in = |ext: &str| CANONICAL_EXTS.iter()
+        .find(|(from, _)| args.canonical_ext && *from == ext)
+        .map(|(_, to)| to.to_string());
+    let name = match ext.to_str() {
+        Some(name) => name,
+        None => return ext,
+    };
+    let mapped = ext_map(name).or_else(|| built_in(name)).unwrap_or_else(|| name.to_string());
+    OsString::from(ext_map(&mapped).unwrap_or(mapped))
+}
+
+/// 元のファイル名（拡張子を除く）をファイル名の後ろに残せる文字列に変換する（--keep-original-name）．
+/// 
+/// 英数字と "-"，"_"，"+" 以外の文字（"." や空白，日本語など）は "-" にし，連続する "-" はまとめる．
+/// 拡張子と紛れないように "." は残さない．ORIGINAL_STEM_MAX_LEN文字で切り詰め，
+/// 何も残らない場合はNoneを返す．
+fn format_original_stem(stem: &str) -> Option<String> {
+    let mut name = String::with_capacity(stem.len());
+    for c in stem.chars() {
+        match c {
+            'A'..='Z' | 'a'..='z' | '0'..='9' | '_' | '+' => name.push(c),
+            _ if !name.is_empty() && !name.ends_with('-') => name.push('-'),
+            _ => (),
+        }
+    }
+    name.truncate(ORIGINAL_STEM_MAX_LEN);
+    let name = name.trim_end_matches(['-', '_']).trim_start_matches('_');
+    if name.is_empty() {
+        None
+    } else {
+        Some(name.to_string())
+    }
+}
+
+/// 作った名前（拡張子を除く）を，--target-fsのファイルシステムで使える名前にする．
+/// 
+/// 使えない文字は "-" にし，Windowsでは黙って消されてしまう末尾の "." と空白を取り除き，CONなどのデバイス名には "_" を付ける．
+/// 拡張子（組のファイルの ".jpg.xmp" なども）と重複を避ける番号を付けても，名前とパス全体が長さの上限に収まるように末尾を切り詰める．
+/// 同じ名前からは常に同じ名前を作り，他のファイルと同じ名前になった場合は，いつも通りunique_pathsで番号が付く．
+fn sanitize_stem(stem: &str, ext: &str, file_path: &path::Path, members: &[path::PathBuf], dir_path: &path::Path,
+                 target: TargetFs, warnings: &mut Vec<String>) -> String {
+    let is_windows = target != TargetFs::Posix;
+    let mut name: String = stem.chars().map(|c| if target.is_forbidden(c) { '-' } else { c }).collect();
+    if is_windows {
+        name.truncate(name.trim_end_matches(['.', ' ']).len());
+    }
+    if name.is_empty() || target.is_reserved(&name) {
+        name.push('_');
     }
 
-    if keep_exif {
-        // Exifデータを持たせるために，imageクレートで保存した画像ファイルを開き直してAPP1セグメントを挿入する．
-        let app1 = exif::clear_orientation(jpeg_binary);
+    // 拡張子の長さは，一番長いもの（組のファイルの最初の "." から後ろ）に合わせる
+    let suffix_len = members.iter().map(path::PathBuf::as_path).chain([file_path])
+        .map(|path| {
+            let file_name = path.file_name().unwrap().to_string_lossy();
+            file_name.find('.').map_or(0, |i| target.len(&file_name[i..]))
+        })
+        .chain([target.len(ext) + 1])
+        .max().unwrap() + UNIQUE_NUMBER_RESERVE;
+    // パス全体の長さは，処理するフォルダのこのコンピュータ上での絶対パスで数える
+    let dir_path = fs::canonicalize(dir_path).unwrap_or_else(|_| dir_path.to_path_buf());
+    let dir_len = target.len(&dir_path.to_string_lossy()) + 1;
+    let max_len = NAME_MAX_LEN.min(target.max_path_len().saturating_sub(dir_len)).saturating_sub(suffix_len);
+    if target.len(&name) <= max_len {
+        return name;
+    }
+    if max_len == 0 {
+        warn(warnings, format!("The path would be too long on the target file system even with a one-character name: {}", file_path.display()));
+        return name;
+    }
+    while target.len(&name) > max_len {
+        name.pop();
+    }
+    if is_windows {
+        name.truncate(name.trim_end_matches(['.', ' ']).len());
+    }
+    if name.is_empty() || target.is_reserved(&name) {
+        name.push('_');
+    }
+    warn(warnings, format!("Shortened the name to \"{}\", since it would be too long on the target file system: {}", name, file_path.display()));
+    name
+}
 
-        let without_app1_binary = fs::read(&file_path).expect("Failed to load image file.");
-        let mut w = BufWriter::new(fs::File::create(file_path).unwrap());
-        let next_app0 = exif::next_app0_index(&without_app1_binary).unwrap();
-        w.write(&without_app1_binary[..next_app0]).unwrap();  // 先頭からAPP0の終わりまで書き込む
-        w.write(&app1).unwrap(); // APP1セグメント挿入
-        w.write(&without_app1_binary[next_app0..]).unwrap();  // 残りを書き込む
-        w.flush().expect("File overwrite failed.");
+/// 位置情報を--gps-nameで指定した形式のファイル名用の文字列に変換する．
+/// 
+/// 緯度・経度が無い場合はNoneを返す．
+fn format_gps(gps: &exif::Gps, gps_name: GpsName, precision: Option<u8>) -> Option<String> {
+    let precision = precision.unwrap_or(gps_name.default_precision()) as usize;
+    match gps_name {
+        GpsName::Decimal => geo::to_decimal(gps, precision),
+        GpsName::Geohash => geo::to_geohash(gps, precision),
     }
 }
 
-/// 日付と時刻データを以下の文字列形式で返す．
+/// 撮影日時が得られなかった場合に，--fallbackで指定した取得元から日時を得る．
 /// 
-/// YYYY-MM-DD_HHMM
-fn get_date_time(jpeg_binary: &[u8]) -> Option<String> {
-    let mut val = exif::get_date_time_original(jpeg_binary)?;
+/// ファイルの更新日時は編集やコピーで簡単に変わってしまうので，1990年より前や未来の日時は使わない．
+/// TakeoutのJSONの撮影日時はUTCなので，このコンピュータの現地時刻に直して使う．
+/// ファイル名に時刻が無かった場合は，2つ目の値をfalseにする（時刻は0時0分にする）．
+fn fallback_date_time(file_path: &path::Path, fallbacks: &[Fallback]) -> Option<([u8; 19], bool, Fallback)> {
+    fallbacks.iter().find_map(|fallback| match fallback {
+        // 更新日時はコピーの仕方によって変わってしまうので，埋め込まれた日時より信頼できないことを表示する
+        Fallback::Mtime => {
+            let mtime = fs::metadata(file_path).ok()?.modified().ok()?;
+            let min = time::UNIX_EPOCH + time::Duration::from_secs(MTIME_MIN_SECS);
+            if mtime < min || mtime > time::SystemTime::now() {
+                println!("Note: The modification time is not used, since it is before 1990 or in the future: {}", file_path.display());
+                return None;
+            }
+            println!("Note: No date was recorded, so the modification time is used (less reliable): {}", file_path.display());
+            datetime::from_system_time(mtime).map(|date_time| (date_time, true, *fallback))
+        },
+        Fallback::Takeout => {
+            let json = fs::read(takeout::find_json(file_path)?).ok()?;
+            datetime::from_unix_local(takeout::get_photo_taken_time(&json)?).map(|date_time| (date_time, true, *fallback))
+        },
+        Fallback::Filename => {
+            let stem = file_path.file_stem()?.to_string_lossy();
+            let (date_time, has_time) = datetime::from_file_name(&stem)?;
+            // Google Pixelのファイル名（PXL_20230123_053027123）の時刻はUTC
+            let date_time = if stem.starts_with("PXL_") && has_time {
+                datetime::from_unix_local(datetime::to_unix(&date_time)?)?
+            } else {
+                date_time
+            };
+            if has_time {
+                println!("Note: No date was recorded, so the date in the file name is used: {}", file_path.display());
+            } else {
+                println!("Note: No date was recorded, so the date in the file name is used (without the time): {}", file_path.display());
+            }
+            Some((date_time, has_time, *fallback))
+        },
+    })
+}
 
+/// 動画と組になっているサムネイル（.thm）のExifから撮影日時を読む．
+fn thumbnail_date_time(members: &[path::PathBuf]) -> Option<[u8; 19]> {
+    let thumbnail = fs::read(group::find_thumbnail(members)?).ok()?;
+    exif::get_date_time_original(&thumbnail).filter(datetime::is_valid)
+}
+
+/// Exifの日時形式を以下のファイル名用の文字列形式に変換する．
+/// 
+/// YYYY-MM-DD_HHMM（secondsの場合はYYYY-MM-DD_HHMMSS）
+fn format_date_time(mut val: [u8; 19], seconds: bool) -> String {
     // 文字列にしてしまうと弄りにくいので，バイト列の状態でフォーマットを整える
     val[4]  = b'-';
     val[7]  = b'-';
     val[10] = b'_';
     val[13] = val[14];  // 一文字ずらして時刻のコロンを消す
     val[14] = val[15];
+    val[15] = val[17];  // 二文字ずらして秒を詰める
+    val[16] = val[18];
 
-    Some( String::from_utf8(val[..15].to_vec()).unwrap() )
+    let len = if seconds { 17 } else { 15 };
+    String::from_utf8(val[..len].to_vec()).unwrap()
 }
 
-// PNGからの日付情報の読み出しにはまだ未対応（補助チャンクのExifデータを読み出せば可能）
-// 
-/// 指定されたディレクトリ内の画像ファイルのファイル名を書き換える．
-/// 拡張子は小文字に統一される．
-fn change_names(dir_path: &path::Path, args: &Args) -> io::Result<()> {
-    for entry in fs::read_dir(dir_path)? {  // ディレクトリ内要素のループ
-        let file_path = entry?.path();
+/// 秒未満の数字をSUBSEC_DIGITS桁に揃えて，ファイル名用の ".42" のような文字列にする．
+fn format_sub_sec(sub_sec: &str) -> String {
+    let digits: String = sub_sec.chars().chain(std::iter::repeat('0')).take(SUBSEC_DIGITS).collect();
+    format!(".{}", digits)
+}
+
+/// 指定されたディレクトリ内のJPEG画像のExifに撮影日時を書き込む（set-date）．
+/// 
+/// 既に撮影日時がある画像は，--overwriteを指定しない限り書き換えない．
+/// 画像データは再エンコードせず，書き込んだ後に読み直して確認してから元のファイルを置き換える．
+fn set_dates(args: &SetDateArgs) -> io::Result<()> {
+    let mut file_paths = Vec::new();
+    for entry in fs::read_dir(&args.path)? {
+        file_paths.push(entry?.path());
+    }
+    file_paths.sort();
+
+    let mut cnt = 0;
+    for file_path in file_paths {
+        if file_path.extension().and_then(filetype::from_extension) != Some(FileType::Jpeg) {
+            continue;
+        }
+        let binary = fs::read(&file_path)?;
+        if !args.overwrite && exif::get_date_time_original(&binary).filter(datetime::is_valid).is_some() {
+            println!("Note: Skipped, since the file already has a date (use --overwrite to replace it): {}", file_path.display());
+            continue;
+        }
+
+        match exif::set_date_time_original(&binary, &args.date) {
+            Ok(written) if exif::get_date_time_original(&written) == Some(args.date) => {
+                overwrite_file(&file_path, &written)?;
+                cnt += 1;
+            },
+            Ok(_) => println!("Warning: Skipped, since the written date could not be read back: {}", file_path.display()),
+            Err(e) => println!("Warning: Skipped, since the date could not be written: {} ({})", file_path.display(), e),
+        }
+    }
+    println!("Wrote the date into {} files.", cnt);
+    Ok(())
+}
+
+/// 整理済みの名前のハッシュ値と撮影日時が，ファイルの中身と今も一致しているかどうかを確かめる（verify）．
+/// 
+/// 名前のハッシュ値は先頭から付けた文字数だけを比べ，表記（16進数・36進数・62進数）は名前の文字から判断する．
+/// 不一致の数を返す（整理済みの形でない名前は数えない）．
+fn verify(args: &VerifyArgs) -> io::Result<usize> {
+    let mut summary = VerifySummary::default();
+    verify_dir(&args.path, args, &mut summary)?;
+    println!("Checked {} files: {} hash mismatches and {} date mismatches.", summary.checked, summary.hash_mismatches, summary.date_mismatches);
+    if summary.not_organized > 0 {
+        println!("Skipped {} files whose names are not organized.", summary.not_organized);
+    }
+    Ok(summary.hash_mismatches + summary.date_mismatches)
+}
+
+/// verifyの結果の集計
+#[derive(Default)]
+struct VerifySummary {
+    /// 確かめたファイルの数
+    checked: usize,
+    /// 名前のハッシュ値が中身と一致しなかったファイルの数
+    hash_mismatches: usize,
+    /// 名前の撮影日時がExifと一致しなかったファイルの数
+    date_mismatches: usize,
+    /// 整理済みの名前の形でなかったので確かめなかったファイルの数
+    not_organized: usize,
+}
+
+/// 整理済みの名前から読み取った撮影日・撮影時刻・ハッシュ値の候補・アルゴリズムの名前（{hashalg}）
+struct NameParts {
+    date: Option<String>,
+    time: Option<String>,
+    /// ハッシュ値の候補（既定の名前では機種名や元の名前と区別できないので，日時の後の英数字の部分を全て候補にする）
+    hashes: Vec<String>,
+    hash_alg: Option<String>,
+}
+
+/// フォルダ内のファイルを確かめる（--recursionを指定した場合はサブフォルダも）．
+fn verify_dir(dir_path: &path::Path, args: &VerifyArgs, summary: &mut VerifySummary) -> io::Result<()> {
+    let mut file_paths = Vec::new();
+    for entry in fs::read_dir(dir_path)? {
+        file_paths.push(entry?.path());
+    }
+    file_paths.sort();
+    let date_format = args.date_format.clone().unwrap_or_default();
+
+    for file_path in file_paths {
         if file_path.is_dir() {
-            // サブフォルダを処理する場合は再帰処理
             if args.recursion {
-                change_names(&file_path, args)?;
+                verify_dir(&file_path, args, summary)?;
             }
-            // サブフォルダを処理し終わったら次に行く（-rオプションが指定されていない場合はスキップ）
             continue;
         }
-
-        // 拡張子を確認
-        let ext = match file_path.extension() {
-            Some(ext) => ext.to_ascii_lowercase(),  // 小文字に変換
+        let ext = file_path.extension().map(|ext| ext.to_ascii_lowercase());
+        let file_type = match ext.as_deref().and_then(filetype::from_extension) {
+            Some(file_type) => file_type,
             None => continue,
         };
-        if ext != OsString::from("jpg") {
-            continue;  // jpg以外は飛ばす
-        }
+        let name = match parse_organized_name(&file_path, args, &date_format) {
+            Some(name) => name,
+            None => {
+                println!("Note: Not checked, since the name is not organized: {}", file_path.display());
+                summary.not_organized += 1;
+                continue;
+            },
+        };
+        summary.checked += 1;
 
-        // 画像データ読み込み
-        let jpeg_binary = fs::read(&file_path).expect("Failed to load image file.");
-        let date_time = get_date_time(&jpeg_binary);  // 現状JPEGしか処理できない
-        let hash_crc32 = format!("{:08x}", crc32fast::hash(&jpeg_binary));  // 先頭0埋め8桁
+        let binary = if file_type.is_bmff_video() {
+            bmff::read_top_level_box(&file_path, b"moov")?.unwrap_or_default()
+        } else if file_type.is_large() {
+            read_head(&file_path, LARGE_FILE_HEAD_LEN)?
+        } else {
+            fs::read(&file_path)?
+        };
 
-        // 新しいファイル名を決定
-        let mut new_file_name = String::with_capacity(32);
-        if date_time.is_some() {
-            new_file_name.push_str(&date_time.as_ref().unwrap());
-            new_file_name.push('_');
+        // {hashalg}がある名前は，名前を付けた時のアルゴリズムと取得元を使う
+        let (algorithm, source) = match name.hash_alg.as_deref() {
+            Some("uniqueid") => (args.hash, HashSource::UniqueId),
+            Some(hash_alg) => {
+                let (name, source) = match hash_alg.split_once('-') {
+                    Some((name, "pixels")) => (name, HashSource::Pixels),
+                    Some((name, "stripped")) => (name, HashSource::Stripped),
+                    _ => (hash_alg, HashSource::Crc32),
+                };
+                let algorithm = HashAlgorithm::value_variants().iter().find(|algorithm| algorithm.name() == name);
+                (algorithm.copied().unwrap_or(args.hash), source)
+            },
+            None => (args.hash, args.hash_source),
+        };
+        if !name.hashes.is_empty() {
+            let unique_id = match source {
+                HashSource::UniqueId => read_unique_id(&binary, file_type),
+                _ => None,
+            };
+            let digest = content_digest(&file_path, &binary, file_type, algorithm, source, &mut Vec::new())?;
+            let matches = |hash: &str| unique_id.as_deref() == Some(hash) || [HashEncoding::Hex, HashEncoding::Base36, HashEncoding::Base62].iter()
+                .any(|encoding| encoding.matches(hash) && encoding.encode(&digest).starts_with(hash));
+            if !name.hashes.iter().any(|hash| matches(hash)) {
+                let hash = &name.hashes[0];
+                let encoding = [HashEncoding::Hex, HashEncoding::Base36, HashEncoding::Base62].into_iter().find(|encoding| encoding.matches(hash)).unwrap();
+                let encoded = encoding.encode(&digest);
+                println!("Mismatch: The hash in the name is \"{}\", but the contents give \"{}\" ({}): {}",
+                    hash, &encoded[..hash.len().min(encoded.len())], algorithm.name(), file_path.display());
+                summary.hash_mismatches += 1;
+            }
+        }
 
-            // 日付を印字
-            if args.date {
-                print_date(&file_path, &jpeg_binary, &date_time.unwrap()[..10], args.keep_exif);
+        // 撮影日時はExif（などに埋め込まれた日時）がある場合だけ比べる（更新日時などから付けた名前は比べられない）
+        let date_time = read_date_time(&file_path, &binary, file_type, &args.date_tags)
+            .filter(|(date_time, _)| datetime::is_valid(date_time))
+            .and_then(|(date_time, source)| if source == DateTag::Gps.tag_name() { from_utc(&date_time, TimeBase::Local) } else { Some(date_time) });
+        if let (Some(date), Some(date_time)) = (&name.date, date_time) {
+            // 秒まで入れた形にして，名前の時刻（秒や秒未満が無いこともある）が先頭に一致するかを比べる
+            let (embedded_date, embedded_time) = date_format.render(&date_time, true);
+            let time = name.time.as_deref().map(|time| time.split('.').next().unwrap());
+            if embedded_date != **date || time.is_some_and(|time| !embedded_time.as_deref().unwrap_or_default().starts_with(time)) {
+                println!("Mismatch: The date in the name is \"{}\", but the file has \"{}\": {}",
+                    date_format.join(date, name.time.as_deref()), date_format.join(&embedded_date, embedded_time.as_deref()), file_path.display());
+                summary.date_mismatches += 1;
             }
         }
-        new_file_name.push_str(&hash_crc32);
-        new_file_name.push('.');
-        new_file_name.push_str(ext.to_str().unwrap());
+    }
+    Ok(())
+}
+
+/// 名前に入れるハッシュ値の元になる，ファイルの中身のハッシュ値を計算する（verifyと--rehash）．
+/// 
+/// 画素データなどが読めない場合は，名前を付けた時と同じくファイル全体のハッシュ値にする（ImageUniqueIDは扱わない）．
+fn content_digest(file_path: &path::Path, binary: &[u8], file_type: FileType, algorithm: HashAlgorithm, source: HashSource,
+                  warnings: &mut Vec<String>) -> io::Result<Vec<u8>> {
+    let digest = match source {
+        HashSource::Pixels => read_pixels(file_path, binary, file_type, warnings).map(|pixels| hash_bytes(&pixels, algorithm)),
+        HashSource::Stripped => read_image_data(file_path, binary, file_type, warnings).map(|image_data| {
+            let mut hasher = algorithm.hasher();
+            for part in image_data {
+                hasher.update(part);
+            }
+            hasher.finalize()
+        }),
+        _ => None,
+    };
+    match digest {
+        Some(digest) => Ok(digest),
+        None => hash_file(file_path, algorithm),
+    }
+}
 
-        // 新しいパスを作って書き換え
-        let new_file_path = file_path.parent().unwrap().join(new_file_name);
-        fs::rename(file_path, new_file_path)?;
+/// 整理済みの名前を撮影日・撮影時刻・ハッシュ値などに分ける（verify）．整理済みの名前の形でない場合はNoneを返す．
+/// 
+/// --templateを指定した場合はテンプレートの形と比べる．既定の名前は YYYY-MM-DD_HHMM[SS[.xx]]_...（--date-formatの形）か，ハッシュ値で始まるもの．
+/// ハッシュ値だけの名前は "holiday" のような名前と区別するために，--hash-lengthと--hash-encodingで付けた形のもの（またはImageUniqueID）に限る．
+fn parse_organized_name(file_path: &path::Path, args: &VerifyArgs, date_format: &datetime::DateFormat) -> Option<NameParts> {
+    let stems = name_stems(file_path);
+    match &args.template {
+        Some(template) => stems.iter().find_map(|stem| {
+            let captures = template.captures(stem, date_format)?;
+            Some(NameParts {
+                date: captures.date.map(str::to_string),
+                time: captures.time.map(str::to_string),
+                hashes: captures.hash.map(str::to_string).into_iter().collect(),
+                hash_alg: captures.hash_alg.map(str::to_string),
+            })
+        }),
+        // ハッシュ値にも時刻にも "-" は入らないので，番号が付いている場合は番号を除いた方を使う
+        None => {
+            let stem = stems.last()?;
+            let (date, time, rest) = match date_format.split(stem) {
+                Some((date, time, rest)) => (Some(date), time, rest),
+                None if stem.split('_').next().is_some_and(|hash| is_verify_hash(hash, args)) => (None, None, stem.split('_').next().unwrap()),
+                None => return None,
+            };
+            Some(NameParts {
+                date: date.map(|date| date.to_string()),
+                time: time.map(|time| time.to_string()),
+                hashes: rest.split('_').filter(|component| template::is_hash(component)).map(|hash| hash.to_string()).collect(),
+                hash_alg: None,
+            })
+        },
     }
+}
+
+/// --hash，--hash-length，--hash-encodingで付けるハッシュ値（またはImageUniqueID）と同じ長さで同じ表記の文字列かどうかを返す（verify）．
+fn is_verify_hash(value: &str, args: &VerifyArgs) -> bool {
+    let len = args.hash_length.unwrap_or(args.hash.default_len(args.hash_encoding)).min(args.hash.max_len(args.hash_encoding)) as usize;
+    template::is_hash(value) && ((value.len() == len && args.hash_encoding.matches(value))
+        || (value.len() == UNIQUE_ID_LEN && HashEncoding::Base36.matches(value)))
+}
+
+/// 元画像のExifを編集後の画像にコピーする（copy-exif）．
+/// 
+/// --fromと--toが両方ディレクトリの場合は，拡張子を除いたファイル名が同じJPEG画像どうしでコピーする．
+fn copy_exifs(args: &CopyExifArgs) -> io::Result<()> {
+    let pairs = if args.from.is_dir() && args.to.is_dir() {
+        let mut sources = Vec::new();
+        for entry in fs::read_dir(&args.from)? {
+            sources.push(entry?.path());
+        }
+        let mut file_paths = Vec::new();
+        for entry in fs::read_dir(&args.to)? {
+            file_paths.push(entry?.path());
+        }
+        file_paths.sort();
+
+        let is_jpeg = |file_path: &path::PathBuf| file_path.extension().and_then(filetype::from_extension) == Some(FileType::Jpeg);
+        let mut pairs = Vec::new();
+        for file_path in file_paths.into_iter().filter(is_jpeg) {
+            match sources.iter().filter(|source| is_jpeg(source)).find(|source| source.file_stem() == file_path.file_stem()) {
+                Some(source) => pairs.push((source.clone(), file_path)),
+                None => println!("Note: Skipped, since no original file with the same name was found: {}", file_path.display()),
+            }
+        }
+        pairs
+    } else if args.from.is_file() && args.to.is_file() {
+        vec![(args.from.clone(), args.to.clone())]
+    } else {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "--from and --to must be both files or both directories."));
+    };
 
+    let mut cnt = 0;
+    for (source, file_path) in pairs {
+        // 書き出した画像は回転済みなので，Orientationは1（回転なし）にする
+        let app1 = match exif::get_app1(&fs::read(&source)?) {
+            Some(app1) => exif::clear_app1_orientation(app1.to_vec()),
+            None => {
+                println!("Warning: Skipped, since the original file has no Exif: {}", source.display());
+                continue;
+            },
+        };
+        let binary = fs::read(&file_path)?;
+        match exif::replace_app1(&binary, &app1) {
+            Ok(written) if exif::get_app1(&written) == Some(&app1[..]) => {
+                overwrite_file(&file_path, &written)?;
+                cnt += 1;
+            },
+            Ok(_) => println!("Warning: Skipped, since the copied Exif could not be read back: {}", file_path.display()),
+            Err(e) => println!("Warning: Skipped, since the Exif could not be copied: {} ({})", file_path.display(), e),
+        }
+    }
+    println!("Copied the Exif into {} files.", cnt);
     Ok(())
-}
\ No newline at end of file
+}
+
+/// ファイルのExifの全てのタグと，ファイル名に使う撮影日時を選んだ経緯を表示する（exifサブコマンド）．
+/// 
+/// 表示の順番はIFD，タグ番号の順で固定なので，出力をそのまま比較できる．--jsonを指定した場合はJSONで出力する．
+/// 撮影日時は--time-baseや--shift-timeを適用する前のもので，--fallbackや.thmファイルの日時は考えない．
+fn dump_exif(args: &ExifArgs) -> io::Result<()> {
+    let ext = args.file.extension().map(|ext| ext.to_ascii_lowercase());
+    let file_type = match ext.as_deref().and_then(filetype::from_extension) {
+        Some(file_type) => file_type,
+        None => filetype::sniff(&args.file)?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "The file type is not supported."))?,
+    };
+    let binary = if file_type.is_bmff_video() {
+        bmff::read_top_level_box(&args.file, b"moov")?.unwrap_or_default()
+    } else if file_type.is_large() {
+        read_head(&args.file, LARGE_FILE_HEAD_LEN)?
+    } else {
+        fs::read(&args.file)?
+    };
+
+    // CR3は0th IFD（CMT1）とExif IFD（CMT2）がそれぞれ別のTIFF構造になっている
+    let tiffs = read_exif_tiffs(&binary, file_type);
+    let sources: Vec<(&str, &[u8])> = match (file_type, tiffs) {
+        (FileType::Cr3, Some((exif_tiff, ifd0_tiff))) => vec![("CMT1 ", ifd0_tiff), ("CMT2 ", exif_tiff)],
+        (_, Some((tiff, _))) => vec![("", tiff)],
+        (_, None) => Vec::new(),
+    };
+    let byte_order = sources.iter()
+        .find_map(|(_, tiff)| exif::is_big_endian(tiff))
+        .map(|big_endian| if big_endian { "MM" } else { "II" });
+    let mut tags = Vec::new();  // (IFDの名前，タグ番号，タグの名前，value)
+    for (prefix, tiff) in &sources {
+        for (ifd, tag, value) in exif::Exif::from_tiff(tiff).iter().flat_map(exif::Exif::iter) {
+            tags.push((format!("{}{}", prefix, ifd.name()), tag, exif::tag_name(ifd, tag), value.to_string()));
+        }
+    }
+
+    let orientation = tiffs.and_then(|(_, ifd0_tiff)| exif::get_orientation_from_tiff(ifd0_tiff));
+    let camera = read_camera(&binary, file_type).unwrap_or_default();
+    let gps = read_gps(&binary, file_type);
+
+    // ファイル名を付ける時と同じ順番でタグを試す
+    let mut date_time = None;
+    let mut date_tags = Vec::new();  // (タグの名前，値，状態)
+    for date_tag in &args.date_tags {
+        let value = tiffs.and_then(|(exif_tiff, ifd0_tiff)| read_date_tag(exif_tiff, ifd0_tiff, *date_tag));
+        let status = match value {
+            None => "not found",
+            Some(value) if !datetime::is_valid(&value) => "not a valid date",
+            Some(_) if date_time.is_some() => "not used, since an earlier tag was used",
+            Some(value) => {
+                date_time = Some((value, date_tag.tag_name()));
+                "used"
+            },
+        };
+        date_tags.push((date_tag.tag_name(), value.map(|value| String::from_utf8_lossy(&value).into_owned()), status));
+    }
+    let date_time = date_time.or_else(|| read_other_date_time(&binary, file_type).filter(|(value, _)| datetime::is_valid(value)));
+    // --xattr-original-nameで記録したリネーム前のファイル名（読めない場合も無いものとして表示する）
+    let original_name = xattr::get_original_name(&args.file).ok().flatten().map(|name| name.to_string_lossy().into_owned());
+
+    if args.json {
+        let opt = |value: Option<&str>| value.map_or("null".to_string(), json::string);
+        let tags: Vec<String> = tags.iter().map(|(ifd, tag, name, value)| format!(
+            "{{\"ifd\": {}, \"tag\": \"0x{:04X}\", \"name\": {}, \"value\": {}}}",
+            json::string(ifd), tag, opt(*name), json::string(value),
+        )).collect();
+        let date_tags: Vec<String> = date_tags.iter().map(|(name, value, status)| format!(
+            "{{\"tag\": {}, \"value\": {}, \"status\": {}}}",
+            json::string(name), opt(value.as_deref()), json::string(status),
+        )).collect();
+        let number = |value: Option<f64>| value.map_or("null".to_string(), |value| value.to_string());
+        println!("{{");
+        println!("  \"file\": {},", json::string(&args.file.to_string_lossy()));
+        println!("  \"file_type\": {},", json::string(file_type.extension()));
+        println!("  \"original_name\": {},", opt(original_name.as_deref()));
+        println!("  \"byte_order\": {},", opt(byte_order));
+        println!("  \"tags\": {},", json::array(&tags));
+        println!("  \"orientation\": {},", orientation.map_or("null".to_string(), |value| value.to_string()));
+        println!("  \"make\": {},", opt(camera.make.as_deref()));
+        println!("  \"model\": {},", opt(camera.model.as_deref()));
+        println!("  \"gps\": {},", gps.map_or("null".to_string(), |gps| format!(
+            "{{\"latitude\": {}, \"longitude\": {}, \"altitude\": {}}}",
+            number(gps.latitude), number(gps.longitude), number(gps.altitude),
+        )));
+        println!("  \"date_tags\": {},", json::array(&date_tags));
+        println!("  \"date\": {}", date_time.map_or("null".to_string(), |(value, source)| format!(
+            "{{\"value\": {}, \"source\": {}}}", json::string(&String::from_utf8_lossy(&value)), json::string(source),
+        )));
+        println!("}}");
+        return Ok(());
+    }
+
+    let none = || "-".to_string();
+    println!("File: {}", args.file.display());
+    println!("File type: {}", file_type.extension());
+    println!("Original name (xattr): {}", original_name.unwrap_or_else(none));
+    println!("Byte order: {}", match byte_order {
+        Some("MM") => "MM (big-endian)",
+        Some(_) => "II (little-endian)",
+        None => "- (no Exif)",
+    });
+    let mut current_ifd = None;
+    for (ifd, tag, name, value) in &tags {
+        if current_ifd != Some(ifd) {
+            println!("[{}]", ifd);
+            current_ifd = Some(ifd);
+        }
+        println!("  0x{:04X} {}: {}", tag, name.unwrap_or("-"), value);
+    }
+    println!("Orientation: {}", orientation.map_or_else(none, |value| value.to_string()));
+    println!("Make: {}", camera.make.unwrap_or_else(none));
+    println!("Model: {}", camera.model.unwrap_or_else(none));
+    println!("GPS: {}", gps.map_or_else(none, |gps| {
+        let value = |value: Option<f64>| value.map_or_else(none, |value| value.to_string());
+        format!("{}, {} (altitude: {})", value(gps.latitude), value(gps.longitude), value(gps.altitude))
+    }));
+    for (name, value, status) in &date_tags {
+        match value {
+            Some(value) => println!("{}: \"{}\" ({})", name, value.escape_debug(), status),
+            None => println!("{}: - ({})", name, status),
+        }
+    }
+    match date_time {
+        Some((value, source)) => println!("Date for the name: {} (from {})", String::from_utf8_lossy(&value), source),
+        None => println!("Date for the name: - (only the hash is used)"),
+    }
+    Ok(())
+}
+
+/// 処理したファイルの記録をCSVかJSON（拡張子で決める）で書き出す（--manifest）．
+/// 
+/// 途中で失敗しても読めないファイルが残らないように，一時ファイルに書き出してから置き換える．
+fn write_manifest(manifest_path: &path::Path, rows: &[ManifestRow]) -> io::Result<()> {
+    let path_string = |path: &path::Path| path.to_string_lossy().into_owned();
+    let is_json = manifest_path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("json"));
+    let contents = if is_json {
+        let opt = |value: Option<String>| value.as_deref().map_or_else(|| "null".to_string(), json::string);
+        let rows: Vec<String> = rows.iter().map(|row| format!(
+            "{{\"event\": {}, \"original\": {}, \"new\": {}, \"date\": {}, \"date_source\": {}, \"hash\": {}, \"size\": {}, \"warnings\": [{}]}}",
+            json::string(row.event), json::string(&path_string(&row.original)), opt(row.new.as_deref().map(path_string)), opt(row.date.clone()),
+            opt(row.date_source.clone()), opt(row.hash.clone()), row.size.map_or_else(|| "null".to_string(), |size| size.to_string()),
+            row.warnings.iter().map(|warning| json::string(warning)).collect::<Vec<_>>().join(", "),
+        )).collect();
+        if rows.is_empty() {
+            "[]\n".to_string()
+        } else {
+            format!("[\n  {}\n]\n", rows.join(",\n  "))
+        }
+    } else {
+        // カンマや引用符，改行を含む値は引用符で囲む（RFC 4180）
+        let field = |value: String| if value.contains([',', '"', '\n', '\r']) {
+            format!("\"{}\"", value.replace('"', "\"\""))
+        } else {
+            value
+        };
+        let mut csv = String::from("event,original,new,date,date_source,hash,size,warnings\n");
+        for row in rows {
+            let fields = [
+                Some(row.event.to_string()), Some(path_string(&row.original)), row.new.as_deref().map(path_string), row.date.clone(),
+                row.date_source.clone(), row.hash.clone(), row.size.map(|size| size.to_string()), Some(row.warnings.join("; ")),
+            ];
+            csv += &fields.map(|value| field(value.unwrap_or_default())).join(",");
+            csv.push('\n');
+        }
+        csv
+    };
+    overwrite_file(manifest_path, contents.as_bytes())
+}
+
+/// 指定されたディレクトリ内の画像ファイルのファイル名を書き換える．
+/// 拡張子は小文字に統一される（--canonical-extを指定した場合は jpeg も jpg にする）．
+/// prefixは親フォルダから引き継いだ接頭辞（--prefix）．
+fn change_names(dir_path: &path::Path, args: &Args, prefix: Option<&str>, summary: &mut Summary) -> io::Result<()> {
+    let mut file_paths = Vec::new();
+    for entry in fs::read_dir(dir_path)? {
+        file_paths.push(entry?.path());
+    }
+
+    // フォルダに接頭辞のファイルがあれば，このフォルダとサブフォルダではそれを使う（撮影者ごとのフォルダなど）
+    let prefix_file = read_prefix_file(dir_path)?;
+    let prefix = match &prefix_file {
+        Some(prefix_file) => prefix_file.as_deref(),
+        None => prefix,
+    };
+
+    // --secondsと--subsecは--precisionの短縮形
+    let precision = if args.subsec {
+        Precision::Subsec
+    } else if args.seconds {
+        args.precision.max(Precision::Second)
+    } else {
+        args.precision
+    };
+    // --date-formatを指定した場合は，秒を入れるかどうかも形式で決まる
+    let date_format = args.date_format.clone().unwrap_or_default();
+    let seconds = args.date_format.is_some() || precision >= Precision::Second;
+
+    // Live PhotoやRAW+JPEGの組は代表のファイルと一緒にリネームするので，組の他のファイルは単独では処理しない
+    let mut groups = group::Groups::new();
+    group::find_live_photos(&file_paths, &mut groups);
+    if args.pair_raw {
+        group::find_raw_pairs(&file_paths, &mut groups);
+        group::find_thumbnails(&file_paths, &mut groups);
+    }
+    if args.fallback.contains(&Fallback::Takeout) {
+        group::find_takeout_jsons(&file_paths, &mut groups);
+    }
+    for orphan in group::find_sidecars(&file_paths, &mut groups) {
+        println!("Warning: No image was found for the XMP sidecar, so it is left as is: {}", orphan.display());
+    }
+    let members: Vec<&path::PathBuf> = groups.values().flatten().collect();
+    // 連番にする場合は，全てのファイルを見て順番が決まってからリネームする
+    let mut deferred = Vec::new();
+
+    for file_path in &file_paths {  // ディレクトリ内要素のループ
+        if members.contains(&file_path) || summary.laid_out.contains(file_path) {
+            continue;
+        }
+        if file_path.is_dir() {
+            // サブフォルダを処理する場合は再帰処理
+            // 画像を変換する場合は，変換前の元ファイルを移動したフォルダをもう一度変換しないように除く
+            // 重複したファイルを移動する場合も，移動先のフォルダは除く
+            let is_originals_dir = file_path.file_name().unwrap() == ORIGINALS_DIR_NAME;
+            let is_duplicates_dir = file_path.file_name().unwrap() == DUPLICATES_DIR_NAME;
+            let is_dedupe_dir = file_path.file_name().unwrap() == DEDUPE_DIR_NAME;
+            if args.recursion && !((args.convert_heic || args.convert_png) && is_originals_dir)
+                && !(args.on_duplicate == OnDuplicate::Move && is_duplicates_dir) && !(args.dedupe && is_dedupe_dir) {
+                change_names(file_path, args, prefix, summary)?;
+            }
+            // サブフォルダを処理し終わったら次に行く（-rオプションが指定されていない場合はスキップ）
+            continue;
+        }
+
+        // 拡張子を確認（--sniffを指定した場合は，拡張子で判定できなければ中身を見る）
+        // 拡張子と中身が食い違っている場合は，--keep-extを指定しない限り中身に合わせて拡張子を直す
+        // 中身から判定できなかった場合は，間違った拡張子にしないように拡張子の形式のままにする
+        let ext = file_path.extension().map(|ext| ext.to_ascii_lowercase());  // 小文字に変換
+        let (file_type, ext) = match ext.as_deref().and_then(filetype::from_extension) {
+            Some(file_type) if args.keep_ext => (file_type, ext.unwrap()),
+            Some(file_type) => match filetype::sniff(file_path)? {
+                Some(sniffed) if file_type.matches_content(sniffed) => (file_type, ext.unwrap()),
+                Some(sniffed) => {
+                    println!("Note: Corrected the extension to \".{}\" to match the contents: {}", sniffed.extension(), file_path.display());
+                    (sniffed, OsString::from(sniffed.extension()))
+                },
+                None => (file_type, ext.unwrap()),
+            },
+            None if args.sniff => match filetype::sniff(file_path)? {
+                Some(file_type) => (file_type, OsString::from(file_type.extension())),  // 中身に合った拡張子を付ける
+                None => continue,
+            },
+            None => continue,  // 対応していない形式は飛ばす
+        };
+        if file_type.is_hash_only() && !args.all_images {
+            continue;
+        }
+        // 拡張子の表記揺れを統一する場合は jpeg -> jpg など
+        let ext = canonical_ext(ext, args);
+        // 整理済みの名前のファイルは読まずに飛ばす（拡張子を直す場合や変換する場合は飛ばさない）
+        let converts = (args.convert_heic && file_type == FileType::Heic) || (args.convert_png && file_type == FileType::Png);
+        if args.skip_organized && !converts && file_path.extension() == Some(&ext) && is_organized_name(file_path, args, prefix) {
+            summary.skipped_organized += 1;
+            continue;
+        }
+
+        // このファイルについて表示した警告（--manifestに書き出す）
+        let mut warnings = Vec::new();
+
+        // ジャーナルに記録できない名前（UTF-8として正しくないもの）のファイルは，処理すると元に戻せなくなるので飛ばす
+        let mut group_paths = [file_path].into_iter().chain(groups.get(file_path).into_iter().flatten());
+        if let Some(path) = group_paths.find(|path| !summary.journal.can_record(path)) {
+            warn(&mut warnings, format!("Skipped, since the name is not valid UTF-8 and cannot be recorded in the journal: {}", path.display()));
+            record_manifest(summary, args, &[(file_path, Some(file_path))], None, None, None, &warnings)?;
+            continue;
+        }
+
+        // 画像データ読み込み（大きいファイルはメタデータを読むために先頭部分だけ読む）
+        // 動画の場合はファイル内のどこにあるか分からないmoovボックスだけを読む
+        let binary = if file_type.is_bmff_video() {
+            bmff::read_top_level_box(file_path, b"moov").map(Option::unwrap_or_default)
+        } else if file_type.is_large() {
+            read_head(file_path, LARGE_FILE_HEAD_LEN)
+        } else {
+            fs::read(file_path)
+        };
+        // 読めないファイルがあっても，他のファイルの処理は続ける
+        let binary = match binary {
+            Ok(binary) => binary,
+            Err(e) => {
+                warn(&mut warnings, format!("Skipped, since the file could not be read: {} ({})", file_path.display(), e));
+                record_manifest(summary, args, &[(file_path, Some(file_path))], None, None, None, &warnings)?;
+                continue;
+            },
+        };
+        // --rehashの場合は，整理済みの名前のハッシュ値だけを今の中身に合わせて付け直し，他の処理はしない
+        if args.rehash {
+            rehash_name(file_path, &binary, file_type, groups.get(file_path).map_or(&[], Vec::as_slice), args, prefix, summary)?;
+            continue;
+        }
+
+        // HEIC・PNGをJPEGに変換する場合は，ディスク上のファイルと一致するように変換後のJPEGのハッシュ値で名前を付ける
+        // --dry-runの場合は，一時ファイルも書き出さないようにHEICは変換しない（変換前のファイルで名前の計画を立てる）
+        let converted = if args.convert_heic && file_type == FileType::Heic && summary.files.is_dry_run() {
+            warn(&mut warnings, format!("Not converted with --dry-run, so the planned name uses the HEIC file (the hash will differ after the conversion): {}", file_path.display()));
+            None
+        } else if args.convert_heic && file_type == FileType::Heic {
+            match convert::heic_to_jpeg(file_path, args.quality) {
+                Ok(jpeg) => Some(convert::carry_over_exif(jpeg, bmff::get_exif(&binary), true)),
+                Err(e) => {
+                    warn(&mut warnings, format!("Skipped, since the HEIC file could not be converted to JPEG: {} ({})", file_path.display(), e));
+                    record_manifest(summary, args, &[(file_path, Some(file_path))], None, None, None, &warnings)?;
+                    continue;
+                },
+            }
+        } else if args.convert_png && file_type == FileType::Png {
+            match convert::png_to_jpeg(&binary, args.quality, args.flatten_alpha) {
+                Ok(Some(jpeg)) => Some(convert::carry_over_exif(jpeg, png::get_exif(&binary), false)),
+                Ok(None) => {
+                    println!("Note: Kept as PNG, since the image has transparent pixels: {}", file_path.display());
+                    None
+                },
+                Err(e) => {
+                    warn(&mut warnings, format!("Skipped, since the PNG file could not be converted to JPEG: {} ({})", file_path.display(), e));
+                    record_manifest(summary, args, &[(file_path, Some(file_path))], None, None, None, &warnings)?;
+                    continue;
+                },
+            }
+        } else {
+            None
+        };
+        let ext = if converted.is_some() { OsString::from("jpg") } else { ext };
+
+        // 位置情報やサムネイルを削除する場合と撮影日時をずらす場合は，撮影日時を読む前に書き換えておく
+        let (binary, converted, is_shifted) = rewrite_metadata(file_path, binary, converted, file_type, args, summary, &mut warnings)?;
+
+        // 実行中に既に処理したファイルと中身が同じ場合は，リネームせずに "_duplicates" フォルダに移動する
+        // 位置情報の削除などで書き換えた後の中身で比べる（残したファイルも書き換えた後の中身なので）
+        let dedupe_key = if args.dedupe && converted.is_none() {
+            let crc = if file_type.is_large() { crc32_file(file_path)? } else { crc32fast::hash(&binary) };
+            let key = (fs::metadata(file_path)?.len(), crc);
+            if let Some(kept) = find_kept_file(file_path, summary.kept_files.get(&key), &summary.files)? {
+                let mut moves = Vec::new();
+                for (i, src) in std::iter::once(file_path).chain(groups.get(file_path).into_iter().flatten()).enumerate() {
+                    let dest = move_into(src, &dir_path.join(DEDUPE_DIR_NAME), (i == 0).then_some(crc), summary)?;
+                    write_dedupe_report(&dest, &kept, &summary.files)?;
+                    moves.push((src, dest));
+                }
+                let moves: Vec<_> = moves.iter().map(|(src, dest)| (src.as_path(), Some(dest.as_path()))).collect();
+                record_manifest(summary, args, &moves, None, None, None, &warnings)?;
+                println!("Note: Moved to the \"{}\" folder, since it has the same contents as {}: {}", DEDUPE_DIR_NAME, kept.display(), file_path.display());
+                summary.deduped += 1;
+                continue;
+            }
+            Some(key)
+        } else {
+            None
+        };
+
+        // --no-dateの場合は撮影日時を読まない（全てのファイルをハッシュ値だけの名前にする）
+        let embedded_date_time = if args.no_date {
+            None
+        } else {
+            read_date_time(file_path, &binary, file_type, &args.date_tags).filter(|(date_time, _)| datetime::is_valid(date_time))
+        };
+        if let (true, Some((_, source))) = (args.verbose, embedded_date_time) {
+            println!("Note: The date was read from {}: {}", source, file_path.display());
+        }
+        // UTCからのずれと秒未満はDateTimeOriginalと組になっているので，撮影日時をそこから得た場合だけ使う
+        let is_original = embedded_date_time.is_some_and(|(_, source)| source == DateTag::Original.tag_name());
+        let is_gps = embedded_date_time.is_some_and(|(_, source)| source == DateTag::Gps.tag_name());
+        // Exifを書き換えていない場合はファイル名の日時だけをずらす（GPSの日時は衛星の時刻なのでずらさない）
+        let shift_secs = args.shift_time.filter(|_| !is_gps);
+        let shift_secs = match embedded_date_time {
+            Some((_, source)) if is_shifted && source != "XMP" => None,
+            Some(_) if shift_secs.is_some() && file_type != FileType::Jpeg && converted.is_none() => {
+                println!("Note: The date is only shifted in the name, since the Exif is only rewritten in JPEG files: {}", file_path.display());
+                shift_secs
+            },
+            _ => shift_secs,
+        };
+        let embedded_date_time = embedded_date_time.map(|(date_time, source)| {
+            (shift_secs.and_then(|secs| datetime::shift(&date_time, secs)).unwrap_or(date_time), source)
+        });
+        // 日時の取得元（--manifestに書き出す）
+        let mut date_source = embedded_date_time.map(|(_, source)| source.to_string());
+        let embedded_date_time = embedded_date_time.map(|(date_time, _)| date_time);
+        // --time-baseでタイムゾーンを指定した場合は，Exifに記録されたUTCからのずれを使って変換する
+        // ずれが記録されていない場合は，指定したタイムゾーンで記録されているものとしてそのまま使う
+        let embedded_date_time = match args.time_base {
+            _ if is_gps => embedded_date_time.and_then(|date_time| from_utc(&date_time, args.time_base)),
+            TimeBase::Local => embedded_date_time,
+            TimeBase::Offset(base) => embedded_date_time.map(|date_time| match read_offset(&binary, file_type).filter(|_| is_original) {
+                Some(offset) => datetime::shift(&date_time, base - offset).unwrap_or(date_time),
+                None => {
+                    println!("Note: No time zone offset (OffsetTimeOriginal) was recorded, so the date is used as is: {}", file_path.display());
+                    date_time
+                },
+            }),
+        };
+        // 秒未満が記録されていない場合も，秒未満があるものと混ぜてファイル名順に並べた時に
+        // 撮影順になるように ".00" にする
+        let sub_sec = match precision {
+            Precision::Subsec if is_original => Some(read_sub_sec(&binary, file_type).unwrap_or_default()),
+            Precision::Subsec => Some(String::new()),
+            _ => None,
+        };
+        // ハッシュ値の代わりに番号を付ける場合は，同じ分に撮ったものの順番を秒未満まで使って決める
+        let full_sub_sec = if args.no_hash && is_original { read_sub_sec(&binary, file_type) } else { None };
+        // ファイル名から日付だけが得られた場合は，テンプレートの{time}を空にする（既定の名前では0000になる）
+        let mut has_date_time = true;
+        let full_date_time = embedded_date_time
+            .or_else(|| thumbnail_date_time(groups.get(file_path)?).map(|date_time| {
+                date_source = Some("thumbnail".to_string());
+                shift_secs.and_then(|secs| datetime::shift(&date_time, secs)).unwrap_or(date_time)
+            }))
+            .or_else(|| {
+                let (date_time, has_time, fallback) = fallback_date_time(file_path, &args.fallback)?;
+                has_date_time = has_time;
+                date_source = fallback.to_possible_value().map(|value| value.get_name().to_string());
+                Some(date_time)
+            })
+            .filter(|_| !args.no_date);
+        let detected_date_time = full_date_time;
+        // --name-offsetの場合は，Exifはそのままで名前の日時だけをずらす（月末や年末，うるう年の繰り上がりも扱う）
+        let full_date_time = full_date_time.map(|date_time| match args.name_offset {
+            Some(secs) => {
+                let shifted = datetime::shift(&date_time, secs).unwrap_or(date_time);
+                if args.verbose {
+                    println!("Note: The date {} is shifted to {} for the name: {}",
+                        String::from_utf8_lossy(&date_time), String::from_utf8_lossy(&shifted), file_path.display());
+                }
+                shifted
+            },
+            None => date_time,
+        });
+        // 撮影日と時刻（--date-formatに時刻が無い場合はNone）
+        let date_time = full_date_time.map(|date_time| {
+            let (date, time) = date_format.render(&date_time, seconds);
+            (date, time.map(|time| time + &sub_sec.as_deref().map(format_sub_sec).unwrap_or_default()))
+        });
+        if !args.no_date && date_time.is_none() && file_type.is_tiff_based() && !exif::has_tiff_header(&binary) {
+            warn(&mut warnings, format!("Unrecognized TIFF header, so only the hash is used for the name: {}", file_path.display()));
+        }
+        if !args.no_date && date_time.is_none() && matches!(file_type, FileType::Jpeg | FileType::Mpo) && jpeg::has_truncated_segment(&binary) {
+            warn(&mut warnings, format!("The JPEG file is truncated, so only the hash is used for the name: {}", file_path.display()));
+        }
+        // テンプレートに{model}や{location}がある場合は，--model-nameや--gps-nameを指定しなくても読む
+        let model = if args.model_name || args.template.as_ref().is_some_and(template::Template::has_model) {
+            read_camera(&binary, file_type).and_then(|camera| format_model(&camera.model?))
+        } else {
+            None
+        };
+        let gps_name = args.gps_name
+            .or_else(|| args.template.as_ref().filter(|template| template.has_location()).map(|_| GpsName::Decimal));
+        let location = gps_name.and_then(|gps_name| {
+            format_gps(&read_gps(&binary, file_type)?, gps_name, args.gps_precision)
+        });
+
+        // メタデータを全て削除する場合や撮影者などを書き込む場合も，撮影日時などを読んだ後で先に書き換えてからハッシュ値を計算する
+        let (binary, converted) = write_metadata(file_path, binary, converted, file_type, args, summary, &mut warnings)?;
+        // --hash-source uniqueidの場合は，ImageUniqueIDがあればハッシュ値の代わりに使う（印字や再圧縮をしても名前が変わらない）
+        let unique_id = match args.hash_source {
+            HashSource::UniqueId => read_unique_id(&binary, file_type),
+            _ => None,
+        };
+        // 画素データのハッシュ値は展開に時間がかかるので，指定した場合だけ計算する
+        let pixels = match (args.hash_source, &converted) {
+            (HashSource::Pixels, Some(converted)) => read_pixels(file_path, converted, FileType::Jpeg, &mut warnings),
+            (HashSource::Pixels, None) => read_pixels(file_path, &binary, file_type, &mut warnings),
+            _ => None,
+        };
+        let image_data = match (args.hash_source, &converted) {
+            (HashSource::Stripped, Some(converted)) => read_image_data(file_path, converted, FileType::Jpeg, &mut warnings),
+            (HashSource::Stripped, None) => read_image_data(file_path, &binary, file_type, &mut warnings),
+            _ => None,
+        };
+        let hash_alg = if unique_id.is_some() {
+            "uniqueid".to_string()
+        } else if pixels.is_some() {
+            format!("{}-pixels", args.hash.name())
+        } else if image_data.is_some() {
+            format!("{}-stripped", args.hash.name())
+        } else {
+            args.hash.name().to_string()
+        };
+        // 大きいファイルは，ジャーナルに記録するCRC32もハッシュ値と一緒に計算して読み直さないようにする
+        let mut large_crc = None;
+        let hash = if let Some(unique_id) = unique_id {
+            unique_id
+        } else {
+            let digest = if let Some(pixels) = &pixels {
+                hash_bytes(pixels, args.hash)
+            } else if let Some(image_data) = &image_data {
+                let mut hasher = args.hash.hasher();
+                for part in image_data {
+                    hasher.update(part);
+                }
+                hasher.finalize()
+            } else if let Some(converted) = &converted {
+                hash_bytes(converted, args.hash)
+            } else if file_type.is_large() {
+                let (digest, crc) = hash_file_with_crc(file_path, args.hash)?;
+                large_crc = Some(crc);
+                digest
+            } else {
+                hash_bytes(&binary, args.hash)
+            };
+            // 先頭から指定した文字数（16進数のCRC32は先頭0埋め8桁）
+            // 短くして他のファイルと同じ名前になった場合は，unique_pathsで番号が付く
+            args.hash_encoding.encode(&digest)[..hash_len(args)].to_string()
+        };
+        // ジャーナルに記録する中身のCRC32（分からない大きいファイルは，記録する時に読む）
+        let file_crc = match (&converted, large_crc) {
+            (Some(converted), _) => Some(crc32fast::hash(converted)),
+            (None, Some(crc)) => Some(crc),
+            (None, None) if file_type.is_large() => None,
+            (None, None) => Some(crc32fast::hash(&binary)),
+        };
+
+        // --content-storeの場合は，ハッシュ値の名前で保管場所に置き，他の名前の付け方はしない
+        if summary.store.is_some() {
+            let moves = store_file(file_path, ext.to_str().unwrap(), groups.get(file_path).map_or(&[], Vec::as_slice), (&hash, file_crc), args, summary, &mut warnings)?;
+            let moves: Vec<_> = moves.iter().map(|(src, new)| (src.as_path(), new.as_deref())).collect();
+            record_original_names(&moves, args, summary, &mut warnings);
+            record_manifest(summary, args, &moves, detected_date_time.as_ref(), date_source.as_deref(), Some(&hash), &warnings)?;
+            continue;
+        }
+
+        // 新しいファイル名を決定
+        let orig = file_path.file_stem().unwrap().to_string_lossy();
+        let values = template::Values {
+            date: date_time.as_ref().map(|(date, _)| date.as_str()),
+            time: date_time.as_ref().and_then(|(_, time)| time.as_deref()).filter(|_| has_date_time),
+            hash: &hash,
+            hash_alg: &hash_alg,
+            orig: &orig,
+            model: model.as_deref(),
+            location: location.as_deref(),
+            prefix,
+            ext: ext.to_str().unwrap(),
+        };
+        let new_file_name = new_file_name(file_path, &values, date_time.as_ref(), &date_format, args);
+        let new_file_name = sanitize_stem(&args.normalize.apply(&new_file_name), ext.to_str().unwrap(), file_path,
+            groups.get(file_path).map_or(&[], Vec::as_slice), dir_path, args.target_fs, &mut warnings);
+
+        // 連番やハッシュ値の代わりの番号を付ける場合は，全てのファイルを見てからリネームする
+        let defers = args.sequence || (args.no_hash && date_time.is_some());
+
+        // --layoutや--outputの場合は，別のフォルダに移動する
+        let target_dir = target_dir(dir_path, full_date_time.as_ref(), args, summary);
+
+        // 新しいパスを作る（組になっている場合は他のファイルも同じ名前にする）
+        let mut srcs = vec![file_path.clone()];
+        let mut new_file_paths = vec![target_dir.join(format!("{}.{}", new_file_name, ext.to_str().unwrap()))];
+        let mut deleted = Vec::new();
+        for member in groups.get(file_path).into_iter().flatten() {
+            // TakeoutのJSONを削除する場合はリネームしない
+            if args.delete_takeout_json && member.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("json")) {
+                deleted.push(member);
+                continue;
+            }
+            let member_file_name = group::member_file_name(member, &new_file_name, &srcs, &new_file_paths);
+            new_file_paths.push(target_dir.join(member_file_name));
+            srcs.push(member.clone());
+        }
+
+        // リネーム先に中身が同じファイルが既にある場合は，番号を付けずに重複として扱う
+        if !defers && converted.is_none() && is_duplicate(&srcs, &new_file_paths, &summary.files)? {
+            let target = new_file_paths[0].file_name().unwrap().to_string_lossy();
+            let redundants: Vec<&path::PathBuf> = srcs.iter().chain(deleted).collect();
+            let mut moves: Vec<(&path::PathBuf, Option<path::PathBuf>)> = redundants.iter().map(|&src| (src, Some(src.clone()))).collect();
+            match args.on_duplicate {
+                // --copyの場合は元のファイルを書き換えないので，コピーしないだけにする
+                _ if args.copy => {
+                    println!("Note: Not copied, since \"{}\" already exists with the same contents: {}", target, file_path.display());
+                },
+                OnDuplicate::Keep => {
+                    println!("Note: Left as is, since \"{}\" already exists with the same contents: {}", target, file_path.display());
+                },
+                OnDuplicate::Delete => {
+                    for (src, new) in &mut moves {
+                        summary.files.remove_file(src)?;
+                        summary.journal.delete(src)?;
+                        *new = None;
+                    }
+                    println!("Note: Deleted, since \"{}\" already exists with the same contents: {}", target, file_path.display());
+                },
+                OnDuplicate::Move => {
+                    for (i, (src, new)) in moves.iter_mut().enumerate() {
+                        *new = Some(move_into(src, &dir_path.join(DUPLICATES_DIR_NAME), file_crc.filter(|_| i == 0), summary)?);
+                    }
+                    println!("Note: Moved to the \"{}\" folder, since \"{}\" already exists with the same contents: {}", DUPLICATES_DIR_NAME, target, file_path.display());
+                },
+            }
+            let moves: Vec<_> = moves.iter().map(|(src, new)| (src.as_path(), new.as_deref())).collect();
+            record_manifest(summary, args, &moves, detected_date_time.as_ref(), date_source.as_deref(), Some(&hash), &warnings)?;
+            summary.duplicates += 1;
+            continue;
+        }
+
+        // 書き換え（変換した場合は変換後の画像を書き出して，元ファイルを片付ける）
+        // 別のファイルが既にその名前になっている場合は番号を付けて，上書きしたわけではないことが分かるように表示する
+        let planned_path = new_file_paths[0].clone();
+        let suffixes: Vec<String> = new_file_paths.iter()
+            .map(|path| path.file_name().unwrap().to_string_lossy()[new_file_name.len()..].to_string())
+            .collect();
+        let new_file_paths = if defers {
+            // 連番やハッシュ値の代わりの番号を付ける場合はまだリネームしない（変換した画像は，とりあえずそのままの名前で書き出す）
+            let mut paths = srcs.clone();
+            if converted.is_some() {
+                paths[0] = summary.files.unique_paths(&srcs[..1], vec![planned_path], &new_file_name).pop().unwrap();
+            }
+            paths
+        } else {
+            let new_file_paths = summary.files.unique_paths(&srcs, new_file_paths, &new_file_name);
+            if new_file_paths[0] != planned_path {
+                warn(&mut warnings, format!("Named \"{}\" instead, since another file already has the name \"{}\": {}",
+                    new_file_paths[0].file_name().unwrap().to_string_lossy(), planned_path.file_name().unwrap().to_string_lossy(), file_path.display()));
+            }
+            new_file_paths
+        };
+        // フォルダは並行して作られても失敗しないcreate_dir_allで作る（連番などの名前にする場合はrename_deferredで作るが，変換した画像は先に書き出す）
+        if target_dir != dir_path && (!defers || converted.is_some()) {
+            summary.files.create_dir_all(&target_dir)?;
+        }
+        let copy_failed = move_files(&srcs, &new_file_paths, converted.as_deref(), file_crc, args, summary, &mut warnings)?;
+        if summary.layout_root.is_some() && !defers {
+            summary.laid_out.extend(new_file_paths.iter().cloned());
+        }
+        for path in &deleted {
+            summary.files.remove_file(path)?;
+            summary.journal.delete(path)?;
+        }
+        // コピーできなかったファイルは，処理しなかったものとして扱う（代表のファイルの場合は組全体）
+        if copy_failed.contains(&0) {
+            continue;
+        }
+        let (srcs, new_file_paths): (Vec<_>, Vec<_>) = srcs.into_iter().zip(new_file_paths).enumerate()
+            .filter(|(i, _)| !copy_failed.contains(i))
+            .map(|(_, paths)| paths)
+            .unzip();
+        if let Some(key) = dedupe_key {
+            summary.kept_files.entry(key).or_default().push(new_file_paths[0].clone());
+        }
+        if full_date_time.is_none() && summary.undated_root.is_some() {
+            summary.undated += 1;
+        }
+        if defers {
+            deferred.push(DeferredRename {
+                date_time: full_date_time.map(|date_time| format_date_time(date_time, true) + &full_sub_sec.map(|sub_sec| format!(".{}", sub_sec)).unwrap_or_default()),
+                hash: hash.clone(),
+                original: file_path.file_stem().unwrap().to_string_lossy().into_owned(),
+                stem: new_file_name.clone(),
+                paths: new_file_paths.clone(),
+                crc: file_crc,
+                dir: target_dir.clone(),
+                suffixes,
+            });
+        }
+
+        // 日付を印字（現状JPEGのみ）
+        if args.date && date_time.is_some() {
+            let new_file_path = &new_file_paths[0];
+            let (file_type, binary) = match &converted {
+                Some(converted) => (FileType::Jpeg, converted),
+                None => (file_type, &binary),
+            };
+            match file_type {
+                // 動画の位置が分からないMotion Photoは，印字すると動画が消えてしまうので印字しない
+                FileType::Jpeg if motion_photo::is_motion_photo(binary) && motion_photo::get_trailer(binary).is_none() => {
+                    warn(&mut warnings, format!("Skipped printing the date, since the video of the motion photo could not be located: {}", file_path.display()));
+                },
+                // --dry-runの場合は印字しない（他の処理とは違い，印字した結果は計画に影響しない）
+                FileType::Jpeg if summary.files.is_dry_run() => (),
+                // 印字できなかった場合（警告済み）はジャーナルに記録しない
+                FileType::Jpeg if print_date(new_file_path, binary, &format_date_time(full_date_time.unwrap(), false)[..10], args.keep_exif, &mut warnings) => {
+                    summary.journal.stamp(new_file_path, None)?;
+                },
+                // MPOは再エンコードすると2枚目以降の画像が消えてしまうので印字しない
+                FileType::Mpo => warn(&mut warnings, format!("Skipped printing the date, since it would destroy the second image of the MPO file: {}", file_path.display())),
+                _ => (),
+            }
+        }
+
+        // 更新日時や作成日時を撮影日時にする（印字で書き換えた後に行う，連番などの名前にする場合もリネームでは変わらない）
+        // --name-offsetは名前だけをずらすので，ずらす前の日時を使う
+        if let Some(date_time) = detected_date_time.filter(|_| (args.set_mtime || args.set_btime) && has_date_time) {
+            match to_system_time(&date_time, args.time_base) {
+                Some(time) => for path in &new_file_paths {
+                    if let Some(Err(e)) = args.set_mtime.then(|| summary.files.set_modified(path, time)) {
+                        warn(&mut warnings, format!("Could not set the modification time ({}): {}", e, path.display()));
+                    }
+                    if let Some(Err(e)) = args.set_btime.then(|| summary.files.set_created(path, time)) {
+                        warn(&mut warnings, format!("Could not set the creation time ({}): {}", e, path.display()));
+                    }
+                },
+                None => warn(&mut warnings, format!("Could not set the modification time, since the date is out of range: {}", file_path.display())),
+            }
+        }
+
+        // 連番などの名前にする場合は，リネームした時に処理後のパスを書き換える
+        let moves: Vec<_> = srcs.iter().zip(&new_file_paths).map(|(src, new)| (src.as_path(), Some(new.as_path())))
+            .chain(deleted.iter().map(|path| (path.as_path(), None)))
+            .collect();
+        record_original_names(&moves, args, summary, &mut warnings);
+        record_manifest(summary, args, &moves, detected_date_time.as_ref(), date_source.as_deref(), Some(&hash), &warnings)?;
+    }
+
+    if args.sequence {
+        rename_sequence(deferred, args, prefix, summary)?;
+    } else if args.no_hash {
+        rename_numbered(deferred, summary)?;
+    }
+
+    Ok(())
+}
+
+/// 位置情報やサムネイルの削除（--strip-gps，--strip-thumbnail）と撮影日時をずらす書き換え（--shift-time）をして，
+/// (画像データ, 変換した画像, Exifの撮影日時をずらしたかどうか) を返す．
+/// 
+/// ハッシュ値が書き換え後のファイルと一致するように，撮影日時を読んでハッシュ値を計算する前に書き換える．
+/// 変換した画像はまだ書き出していないので変換後の画像を，それ以外のJPEGはファイルを書き換える．
+fn rewrite_metadata(file_path: &path::Path, binary: Vec<u8>, converted: Option<Vec<u8>>, file_type: FileType, args: &Args, summary: &Summary,
+                    warnings: &mut Vec<String>) -> io::Result<(Vec<u8>, Option<Vec<u8>>, bool)> {
+    // 位置情報を削除する場合は，ハッシュ値が書き換え後のファイルと一致するように先に削除しておく
+    // （変換した場合は変換後のJPEGから削除し，日付を印字する場合も削除後の画像から印字する）
+    let (binary, converted) = match converted {
+        Some(jpeg) if args.strip_gps => (binary, Some(strip_gps(file_path, &jpeg, warnings).unwrap_or(jpeg))),
+        None if args.strip_gps && file_type == FileType::Jpeg => match strip_gps(file_path, &binary, warnings) {
+            Some(stripped) => {
+                overwrite_file_in_place(file_path, &stripped, summary)?;
+                (stripped, None)
+            },
+            None => (binary, None),
+        },
+        None if args.strip_gps && read_gps(&binary, file_type).is_some() => {
+            println!("Note: GPS data is only removed from JPEG files, so it is left as is: {}", file_path.display());
+            (binary, None)
+        },
+        converted => (binary, converted),
+    };
+    // サムネイルを削除する場合も同じく，ハッシュ値を計算する前に削除しておく
+    let (binary, converted) = match converted {
+        Some(jpeg) if args.strip_thumbnail => (binary, Some(strip_thumbnail(file_path, &jpeg, warnings).unwrap_or(jpeg))),
+        None if args.strip_thumbnail && file_type == FileType::Jpeg => match strip_thumbnail(file_path, &binary, warnings) {
+            Some(stripped) => {
+                overwrite_file_in_place(file_path, &stripped, summary)?;
+                (stripped, None)
+            },
+            None => (binary, None),
+        },
+        converted => (binary, converted),
+    };
+    // 撮影日時をずらす場合も，ファイル名とExifの日時が一致するように先にExifを書き換えておく
+    // （JPEG以外はExifを書き換えられないので，ファイル名の日時だけをずらす）
+    Ok(match (args.shift_time, converted) {
+        (Some(secs), Some(jpeg)) => (binary, Some(exif::shift_date_times(&jpeg, secs).unwrap_or(jpeg)), false),
+        (Some(secs), None) if file_type == FileType::Jpeg => match exif::shift_date_times(&binary, secs) {
+            Some(shifted) => {
+                overwrite_file_in_place(file_path, &shifted, summary)?;
+                (shifted, None, true)
+            },
+            None => (binary, None, false),
+        },
+        (_, converted) => (binary, converted, false),
+    })
+}
+
+/// メタデータの削除（--strip-metadata），撮影者・著作権表示（--artist，--copyright）と元のファイル名（--record-original-name）の書き込みをして，
+/// (画像データ, 変換した画像) を返す．
+/// 
+/// 撮影日時などを読んだ後，ハッシュ値を計算する前に書き換える（書き換え方はrewrite_metadataと同じ）．
+fn write_metadata(file_path: &path::Path, binary: Vec<u8>, converted: Option<Vec<u8>>, file_type: FileType, args: &Args, summary: &Summary,
+                  warnings: &mut Vec<String>) -> io::Result<(Vec<u8>, Option<Vec<u8>>)> {
+    // メタデータを全て削除する場合も，撮影日時などを読んだ後で先に削除してからハッシュ値を計算する
+    let (binary, converted) = match converted {
+        Some(jpeg) if args.strip_metadata => (binary, Some(strip_metadata(file_path, &jpeg, args, warnings).unwrap_or(jpeg))),
+        None if args.strip_metadata && file_type == FileType::Jpeg && motion_photo::is_motion_photo(&binary) => {
+            println!("Note: Metadata was left as is, since removing it would break the motion photo: {}", file_path.display());
+            (binary, None)
+        },
+        None if args.strip_metadata && file_type == FileType::Jpeg => match strip_metadata(file_path, &binary, args, warnings) {
+            Some(stripped) if stripped != binary => {
+                overwrite_file_in_place(file_path, &stripped, summary)?;
+                (stripped, None)
+            },
+            _ => (binary, None),
+        },
+        None if args.strip_metadata => {
+            println!("Note: Metadata is only removed from JPEG files, so it is left as is: {}", file_path.display());
+            (binary, None)
+        },
+        converted => (binary, converted),
+    };
+    // 撮影者・著作権表示も，ハッシュ値が書き込み後のファイルと一致するように先に書き込む
+    let has_artist = args.artist.is_some() || args.copyright.is_some();
+    let (binary, converted) = match converted {
+        Some(jpeg) if has_artist => (binary, Some(write_artist(file_path, &jpeg, args, warnings).unwrap_or(jpeg))),
+        None if has_artist && file_type == FileType::Jpeg => match write_artist(file_path, &binary, args, warnings) {
+            Some(written) => {
+                overwrite_file_in_place(file_path, &written, summary)?;
+                (written, None)
+            },
+            None => (binary, None),
+        },
+        None if has_artist => {
+            println!("Note: The artist and copyright are only written into JPEG files, so they are not written: {}", file_path.display());
+            (binary, None)
+        },
+        converted => (binary, converted),
+    };
+    // 元のファイル名も，リネームする前に記録しておく（JPEG以外は何もしない）
+    Ok(match converted {
+        Some(jpeg) if args.record_original_name => (binary, Some(record_original_name(file_path, &jpeg, warnings).unwrap_or(jpeg))),
+        None if args.record_original_name && file_type == FileType::Jpeg => match record_original_name(file_path, &binary, warnings) {
+            Some(written) => {
+                overwrite_file_in_place(file_path, &written, summary)?;
+                (written, None)
+            },
+            None => (binary, None),
+        },
+        converted => (binary, converted),
+    })
+}
+
+/// 書き換えたファイルの中身を書き込み，ジャーナルに記録する．
+fn overwrite_file_in_place(file_path: &path::Path, contents: &[u8], summary: &Summary) -> io::Result<()> {
+    summary.files.overwrite(file_path, contents)?;
+    summary.journal.overwrite(file_path, Some(crc32fast::hash(contents)))
+}
+
+/// 新しいファイル名（拡張子を除く，使えない文字を置き換える前）を返す．
+/// 
+/// --templateを指定した場合はvaluesをテンプレートに埋め込み，それ以外は接頭辞，撮影日時，機種名，撮影場所，ハッシュ値，元の名前の順に並べる．
+/// date_timeは撮影日と時刻（ファイル名から日付だけが得られた場合も，既定の名前では時刻を0000にする）．
+fn new_file_name(file_path: &path::Path, values: &template::Values, date_time: Option<&(String, Option<String>)>, date_format: &datetime::DateFormat,
+                 args: &Args) -> String {
+    let prefix = values.prefix;
+    match &args.template {
+        // テンプレートにハッシュ値が無いと同じ名前になることがあるが，その場合はunique_pathsで番号が付く
+        Some(template) => {
+            let new_file_name = template.render(values);
+            // テンプレートに{prefix}が無い場合は前に付ける
+            match prefix {
+                Some(prefix) if !template.has_prefix() => format!("{}{}", prefix, new_file_name),
+                _ => new_file_name,
+            }
+        },
+        None => {
+            let mut new_file_name = String::with_capacity(32);
+            if let Some(prefix) = prefix {
+                new_file_name.push_str(prefix);
+            }
+            if let Some((date, time)) = date_time {
+                new_file_name.push_str(&date_format.join(date, time.as_deref()));
+                new_file_name.push('_');
+            }
+            if let Some(model) = values.model {
+                new_file_name.push_str(model);
+                new_file_name.push('_');
+            }
+            if let Some(location) = values.location {
+                new_file_name.push_str(location);
+                new_file_name.push('_');
+            }
+            // --no-hashでも，撮影日時が無いファイルは他に付けられる名前が無いのでハッシュ値を使う
+            if args.no_hash && date_time.is_some() {
+                new_file_name.pop();
+            } else {
+                new_file_name.push_str(values.hash);
+            }
+            // 整理済みの名前の場合は，前回残した元の名前を使う（何度実行しても後ろに付け足していかないように）
+            if args.keep_original_name {
+                let stem = file_path.file_stem().unwrap().to_string_lossy();
+                let stem = prefix.and_then(|prefix| stem.strip_prefix(prefix)).unwrap_or(&stem);
+                let original = split_original_name(stem, args).map_or(stem, |(_, original)| original);
+                if let Some(original) = format_original_stem(original) {
+                    new_file_name.push('_');
+                    new_file_name.push_str(&original);
+                }
+            }
+            new_file_name
+        },
+    }
+}
+
+/// 新しい名前のファイルを置くフォルダを返す．
+/// 
+/// --layoutの場合は撮影日時のフォルダ（撮影日時が無い場合は--undated-dir），
+/// --outputの場合は出力先の同じ構成のフォルダ（撮影日時が無い場合は--undated-dirの中）で，どちらでもなければ元のフォルダ．
+fn target_dir(dir_path: &path::Path, date_time: Option<&[u8; 19]>, args: &Args, summary: &Summary) -> path::PathBuf {
+    match (&args.layout, &summary.layout_root) {
+        (Some(layout), Some(root)) => match date_time {
+            Some(date_time) => root.join(layout.render(date_time)),
+            None => root.join(&args.undated_dir),
+        },
+        // --outputの場合は，出力先に同じ構成のフォルダを作る（撮影日時が無い場合は--undated-dirの中に作る）
+        _ => match &summary.output_dirs {
+            Some((dir, output)) => {
+                let relative = dir_path.strip_prefix(dir).unwrap_or(path::Path::new(""));
+                match date_time {
+                    Some(_) => output.join(relative),
+                    None => output.join(&args.undated_dir).join(relative),
+                }
+            },
+            None => dir_path.to_path_buf(),
+        },
+    }
+}
+
+/// 代表のファイルと組のファイルを新しいパスにリネームして，ジャーナルに記録する．
+/// 
+/// 変換した画像（convertedは代表のファイルの代わり）は書き出して元のファイルを片付け，
+/// --copyの場合は元のファイルを残してコピーし，確かめられなかったコピーの位置（srcsでの添字）を返す．
+/// crcはジャーナルに記録する代表のファイルの中身のCRC32．
+fn move_files(srcs: &[path::PathBuf], new_file_paths: &[path::PathBuf], converted: Option<&[u8]>, crc: Option<u32>, args: &Args, summary: &mut Summary,
+              warnings: &mut Vec<String>) -> io::Result<Vec<usize>> {
+    // 確かめられなかったコピーの位置（--copy）
+    let mut copy_failed = Vec::new();
+    for (i, (src, new_file_path)) in srcs.iter().zip(new_file_paths).enumerate() {
+        let (crc, converted) = (crc.filter(|_| i == 0), converted.filter(|_| i == 0));
+        if args.copy {
+            if !copy_verified(src, new_file_path, converted, crc, args, summary, warnings)? {
+                copy_failed.push(i);
+            }
+            continue;
+        }
+        if let Some(converted) = converted {
+            summary.files.write(new_file_path, converted)?;
+            summary.journal.create(src, new_file_path, crc)?;
+            dispose_original(src, args.move_originals, summary)?;
+            continue;
+        }
+        if new_file_path == src {
+            continue;  // 既に同じ名前になっている
+        }
+        summary.files.move_file(src, new_file_path)?;
+        summary.journal.rename(src, new_file_path, crc)?;
+    }
+    Ok(copy_failed)
+}
+
+/// 撮影日時順に並べて，001.jpg，002.jpg，... のような連番の名前にする（--sequence）．
+/// 
+/// 撮影日時が無いものは最後にし，撮影日時が同じものや無いものはハッシュ値の順にする．
+fn rename_sequence(mut entries: Vec<DeferredRename>, args: &Args, prefix: Option<&str>, summary: &mut Summary) -> io::Result<()> {
+    entries.sort_by(|a, b| (a.date_time.is_none(), &a.date_time, &a.hash).cmp(&(b.date_time.is_none(), &b.date_time, &b.hash)));
+    let last = args.sequence_start + entries.len().saturating_sub(1) as u64;
+    let width = (args.sequence_width as usize).max(last.to_string().len());
+    rename_deferred(&entries, summary, |entries, _| {
+        (args.sequence_start..).zip(entries).map(|(n, _)| format!("{}{:0width$}", args.normalize.apply(prefix.unwrap_or("")), n, width = width)).collect()
+    })
+}
+
+/// ハッシュ値を除いた名前が同じファイルに，撮影日時（秒と秒未満）と元のファイル名の順に "-2"，"-3"，... を付ける（--no-hash）．
+/// 
+/// 整理済みの名前の順番は変わらないので，再実行しても番号が入れ替わらない．
+/// 番号を付けないファイル（--skip-organizedで飛ばしたものなど）が既にその名前になっている場合は，次の番号にする．
+fn rename_numbered(mut entries: Vec<DeferredRename>, summary: &mut Summary) -> io::Result<()> {
+    entries.sort_by(|a, b| (&a.date_time, &a.original).cmp(&(&b.date_time, &b.original)));
+    rename_deferred(&entries, summary, |entries, files| {
+        // --layoutの場合は，フォルダごとに番号を付ける
+        let mut counts: HashMap<(&path::Path, &str), usize> = HashMap::new();
+        entries.iter().map(|entry| {
+            let count = counts.entry((&entry.dir, &entry.stem)).or_default();
+            loop {
+                *count += 1;
+                let stem = match *count {
+                    1 => entry.stem.clone(),
+                    n => format!("{}-{}", entry.stem, n),
+                };
+                if entry.suffixes.iter().all(|suffix| !files.exists(&entry.dir.join(format!("{}{}", stem, suffix)))) {
+                    return stem;
+                }
+            }
+        }).collect()
+    })
+}
+
+/// 順番が決まったファイルを，一旦全て仮の名前にしてから，name_stemsで決めた名前（拡張子を除く）にする．
+/// 
+/// 前回付けた名前と入れ替わる場合に上書きしないように，仮の名前にしてから名前を決める．
+/// 仮の名前は移動先のフォルダ（--layoutの場合は撮影日時のフォルダ）に付ける．
+/// それでも別のファイルが既にその名前になっている場合は，番号を付けて警告を表示する．
+fn rename_deferred<F>(entries: &[DeferredRename], summary: &mut Summary, name_stems: F) -> io::Result<()>
+where
+    F: FnOnce(&[DeferredRename], &fileops::FileOps) -> Vec<String>,
+{
+    let mut temp_paths = Vec::with_capacity(entries.len());
+    for (i, entry) in entries.iter().enumerate() {
+        let temp_stem = format!("{}{}", DEFERRED_TEMP_PREFIX, i);
+        let temps: Vec<path::PathBuf> = entry.suffixes.iter().map(|suffix| entry.dir.join(format!("{}{}", temp_stem, suffix))).collect();
+        let temps = summary.files.unique_paths(&entry.paths, temps, &temp_stem);
+        summary.files.create_dir_all(&entry.dir)?;
+        for (j, (path, temp)) in entry.paths.iter().zip(&temps).enumerate() {
+            summary.files.move_file(path, temp)?;
+            summary.journal.rename(path, temp, entry.crc.filter(|_| j == 0))?;
+        }
+        temp_paths.push(temps);
+    }
+
+    for ((entry, temps), stem) in entries.iter().zip(&temp_paths).zip(name_stems(entries, &summary.files)) {
+        let planned: Vec<path::PathBuf> = entry.suffixes.iter().map(|suffix| entry.dir.join(format!("{}{}", stem, suffix))).collect();
+        let new_paths = summary.files.unique_paths(temps, planned.clone(), &stem);
+        let mut warnings = Vec::new();
+        if new_paths[0] != planned[0] {
+            warn(&mut warnings, format!("Named \"{}\" instead, since another file already has the name \"{}\": {}",
+                new_paths[0].file_name().unwrap().to_string_lossy(), planned[0].file_name().unwrap().to_string_lossy(), entry.paths[0].display()));
+        }
+        for (j, (temp, new_path)) in temps.iter().zip(&new_paths).enumerate() {
+            summary.files.rename(temp, new_path)?;
+            summary.journal.rename(temp, new_path, entry.crc.filter(|_| j == 0))?;
+        }
+        if summary.layout_root.is_some() {
+            summary.laid_out.extend(new_paths.iter().cloned());
+        }
+        // --manifestの記録の処理後のパスも新しい名前にする
+        for row in &mut summary.manifest {
+            if let Some(i) = entry.paths.iter().position(|path| row.new.as_ref() == Some(path)) {
+                row.new = Some(new_paths[i].clone());
+                if i == 0 {
+                    row.warnings.append(&mut warnings);
+                }
+            }
+        }
+        // --dedupeで比べる時のために，残したファイルのパスを新しい名前にする
+        for kept in summary.kept_files.values_mut().flatten() {
+            if *kept == entry.paths[0] {
+                kept.clone_from(&new_paths[0]);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// ファイル名がこのツールで付けた名前の形をしているかどうかを返す（--skip-organized）．
+/// 
+/// 撮影日時の細かさ（--precision）や機種名・撮影場所の有無は問わないので，オプションを変える前に付けた名前も整理済みとみなす．
+/// --templateを指定した場合はテンプレートの形と比べる．重複を避けるために付けた "-1" などの番号は無視する．
+/// 接頭辞（--prefix）を付ける場合は，その接頭辞で始まる名前に限る．
+fn is_organized_name(file_path: &path::Path, args: &Args, prefix: Option<&str>) -> bool {
+    name_stems(file_path).into_iter().any(|stem| {
+        // テンプレートに{prefix}がある場合は，テンプレートの一部として比べる
+        let stem = match prefix {
+            Some(prefix) if !args.template.as_ref().is_some_and(template::Template::has_prefix) => match stem.strip_prefix(prefix) {
+                Some(stem) => stem,
+                None => return false,
+            },
+            _ => &stem,
+        };
+        is_organized_stem(stem, args)
+    })
+}
+
+/// 拡張子を除いたファイル名と，重複を避けるために付けた "-1" などの番号も除いたファイル名（番号が付いている場合）を返す．
+/// 
+/// unique_pathsは拡張子の前に番号を付ける（"{date}" のように数字で終わるテンプレートもあるので，番号が無いものとしても試せるように両方返す）．
+fn name_stems(file_path: &path::Path) -> Vec<String> {
+    let file_name = file_path.file_name().unwrap().to_string_lossy();
+    let stem = match file_name.rsplit_once('.') {
+        Some((stem, _)) => stem,
+        None => return Vec::new(),
+    };
+    let numbered = stem.rsplit_once('-')
+        .filter(|(_, n)| !n.is_empty() && n.bytes().all(|c| c.is_ascii_digit()))
+        .map(|(head, _)| head.to_string());
+    [Some(stem.to_string()), numbered].into_iter().flatten().collect()
+}
+
+/// 拡張子と番号，接頭辞を除いたファイル名がこのツールで付けた名前の形をしているかどうかを返す．
+fn is_organized_stem(stem: &str, args: &Args) -> bool {
+    match &args.template {
+        Some(template) => template.matches(stem, &args.date_format.clone().unwrap_or_default()),
+        // YYYY-MM-DD_HHMM[SS[.xx]]_[機種名_][撮影場所_]ハッシュ値（日時は--date-formatの形），またはハッシュ値のみ
+        // ハッシュ値のみの名前は，"holidays" や "DSC01234" のような名前を整理済みとみなさないように，
+        // 今の設定のハッシュ値（またはImageUniqueID）と同じ長さで同じ表記のものに限る
+        // --keep-original-nameで残した元の名前は取り除いてから比べる
+        None => {
+            let stem = split_original_name(stem, args).map_or(stem, |(stem, _)| stem);
+            if !stem.contains('_') && is_current_hash(stem, args) {
+                return true;
+            }
+            let rest = match args.date_format.clone().unwrap_or_default().split(stem) {
+                Some((_, _, rest)) => rest,
+                None => return false,
+            };
+            match rest.split('_').collect::<Vec<_>>().as_slice() {
+                // --no-hashの場合はハッシュ値が無い（YYYY-MM-DD_HHMM[SS[.xx]][_機種名][_撮影場所]）
+                _ if args.no_hash => rest.is_empty() || rest.split('_').all(|component| !component.is_empty()),
+                [middle @ .., hash] => middle.iter().all(|component| !component.is_empty()) && template::is_hash(hash),
+                [] => false,
+            }
+        },
+    }
+}
+
+/// 今の設定で付けるハッシュ値（またはImageUniqueID）と同じ長さで同じ表記の文字列かどうかを返す．
+fn is_current_hash(value: &str, args: &Args) -> bool {
+    template::is_hash(value) && ((value.len() == hash_len(args) && args.hash_encoding.matches(value))
+        || (value.len() == UNIQUE_ID_LEN && HashEncoding::Base36.matches(value)))
+}
+
+/// --keep-original-nameで付けた名前（拡張子を除く）を，ハッシュ値までの部分と後ろに残した元の名前に分ける．
+/// 
+/// 元の名前にも "_" が入っていることがあるので，日時の後（日時が無い名前は先頭）から
+/// 今の設定のハッシュ値の形をした最初の部分を探し，その後ろを元の名前とする（元の名前を残していない場合は空）．
+/// --keep-original-nameを指定していない場合や，そのような形でない場合はNoneを返す．
+fn split_original_name<'a>(stem: &'a str, args: &Args) -> Option<(&'a str, &'a str)> {
+    if !args.keep_original_name {
+        return None;
+    }
+    let hash_range = current_hash_range(stem, args)?;
+    Some((&stem[..hash_range.end], stem.get(hash_range.end + 1..).unwrap_or("")))
+}
+
+/// 日時の後（日時が無い名前は先頭）の，今の設定のハッシュ値の形をした最初の部分の位置を返す．
+fn current_hash_range(stem: &str, args: &Args) -> Option<std::ops::Range<usize>> {
+    // 日時が無い名前は先頭の部分だけを調べる
+    let (start, rest, first_only) = match args.date_format.clone().unwrap_or_default().split(stem) {
+        Some((_, _, rest)) => (stem.len() - rest.len(), rest, false),
+        None => (0, stem, true),
+    };
+    let components: Vec<&str> = rest.split('_').collect();
+    let candidates = if first_only { 0..1 } else { 0..components.len() };
+    let i = candidates.into_iter().find(|&i| is_current_hash(components[i], args))?;
+    let start = start + components[..i].iter().map(|component| component.len() + 1).sum::<usize>();
+    Some(start..start + components[i].len())
+}
+
+/// フォルダの接頭辞のファイル（PREFIX_FILE_NAME）を読む．
+/// 
+/// ファイルが無い場合はNone，空の場合は接頭辞を付けないのでSome(None)を返す．
+/// 前後の空白と改行は取り除き，ファイル名に使えない内容の場合は警告を表示して無いものとして扱う．
+fn read_prefix_file(dir_path: &path::Path) -> io::Result<Option<Option<String>>> {
+    let prefix_file_path = dir_path.join(PREFIX_FILE_NAME);
+    if !prefix_file_path.is_file() {
+        return Ok(None);
+    }
+    let contents = fs::read_to_string(&prefix_file_path)?;
+    let contents = contents.trim();
+    if contents.is_empty() {
+        return Ok(Some(None));
+    }
+    match parse_prefix(contents) {
+        Ok(prefix) => Ok(Some(Some(prefix))),
+        Err(e) => {
+            println!("Warning: The prefix is ignored, since {}: {}", e, prefix_file_path.display());
+            Ok(None)
+        },
+    }
+}
+
+/// 処理後のファイルに，処理前のファイル名を拡張属性として記録する（--xattr-original-name）．
+/// 
+/// 連番などの名前にする場合はまだリネームしていないが，リネームしても拡張属性は残るので今の名前のファイルに記録する．
+/// 既に記録してある場合（整理済みのファイルを処理し直した場合など）は，最初の名前を残すために書き換えない．
+/// 拡張属性に対応していないファイルシステムの場合は，ファイルごとではなく実行ごとに一度だけ警告する．
+fn record_original_names(moves: &[(&path::Path, Option<&path::Path>)], args: &Args, summary: &mut Summary, warnings: &mut Vec<String>) {
+    if !args.xattr_original_name || summary.files.is_dry_run() {
+        return;
+    }
+    for (src, new) in moves {
+        let Some(new) = new else {
+            continue;  // 削除したファイル
+        };
+        let result = match xattr::get_original_name(new) {
+            Ok(Some(_)) => Ok(()),
+            Ok(None) => xattr::set_original_name(new, src.file_name().unwrap()),
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(()) => (),
+            Err(e) if xattr::is_unsupported(&e) => {
+                if !summary.xattr_unsupported {
+                    summary.xattr_unsupported = true;
+                    warn(warnings, format!("The original names are not recorded, since the file system does not support extended attributes: {}", new.display()));
+                }
+            },
+            Err(e) => warn(warnings, format!("Could not record the original name ({}): {}", e, new.display())),
+        }
+    }
+}
+
+/// ファイル（組の場合は他のファイルも）を，ハッシュ値の名前で保管場所に移動（--store-copyの場合はコピー）する（--content-store）．
+/// 
+/// 同じハッシュ値のファイルが既に保管場所にある場合は置かずに，--delete-storedの場合は元のファイルを削除する
+/// （組の他のファイルは，保管場所に同じ中身のものがある場合だけ削除する）．
+/// 短くしたハッシュ値が偶然一致しただけで別のファイルを削除したり飛ばしたりしないように，same_identityで確かめる．
+/// 名前に使うハッシュ値と一緒に，ジャーナルに記録するファイルのCRC32（分かっている場合）を渡す．
+/// 処理前後のパスの組を返す（置かなかった場合は元のパス，削除した場合はNone）．
+fn store_file(file_path: &path::Path, ext: &str, members: &[path::PathBuf], (hash, crc): (&str, Option<u32>), args: &Args, summary: &mut Summary,
+              warnings: &mut Vec<String>) -> io::Result<Vec<(path::PathBuf, Option<path::PathBuf>)>> {
+    let srcs: Vec<path::PathBuf> = std::iter::once(file_path.to_path_buf()).chain(members.iter().cloned()).collect();
+    let left: Vec<_> = srcs.iter().map(|src| (src.clone(), Some(src.clone()))).collect();
+    let stored = summary.store.as_mut().unwrap().find(hash)?;
+    if !stored.is_empty() {
+        let mut found = None;
+        for path in &stored {
+            if same_identity(file_path, &summary.files.real_path(path), args.hash_source)? {
+                found = Some(path.clone());
+                break;
+            }
+        }
+        let found = match found {
+            Some(found) => found,
+            None => {
+                warn(warnings, format!("Skipped, since a different file with the same hash is already in the content store (use a longer --hash-length): {}", file_path.display()));
+                return Ok(left);
+            },
+        };
+        summary.already_stored += 1;
+        if !args.delete_stored {
+            println!("Note: Skipped, since it is already in the content store as \"{}\": {}", found.display(), file_path.display());
+            return Ok(left);
+        }
+        let mut moves = Vec::with_capacity(srcs.len());
+        for (i, src) in srcs.iter().enumerate() {
+            let counterpart = found.with_file_name(group::member_file_name(src, &found.file_stem().unwrap().to_string_lossy(), &srcs[..1], std::slice::from_ref(&found)));
+            let is_stored = i == 0 || (summary.files.is_file(&counterpart) && same_contents(src, &summary.files.real_path(&counterpart))?);
+            if is_stored {
+                summary.files.remove_file(src)?;
+                summary.journal.delete(src)?;
+                moves.push((src.clone(), None));
+            } else {
+                moves.push((src.clone(), Some(src.clone())));
+            }
+        }
+        println!("Note: Deleted, since it is already in the content store as \"{}\": {}", found.display(), file_path.display());
+        return Ok(moves);
+    }
+
+    let dest = summary.store.as_ref().unwrap().path(hash, ext);
+    let stem = dest.file_stem().unwrap().to_string_lossy().into_owned();
+    let mut dests = vec![dest];
+    for member in members {
+        let member_file_name = group::member_file_name(member, &stem, &srcs[..dests.len()], &dests);
+        dests.push(dests[0].with_file_name(member_file_name));
+    }
+    summary.files.create_dir_all(dests[0].parent().unwrap())?;
+    for (i, (src, dest)) in srcs.iter().zip(&dests).enumerate() {
+        let crc = crc.filter(|_| i == 0);
+        if args.store_copy {
+            summary.files.copy(src, dest)?;
+            summary.journal.create(src, dest, crc)?;
+        } else {
+            summary.files.move_file(src, dest)?;
+            summary.journal.rename(src, dest, crc)?;
+        }
+    }
+    summary.store.as_mut().unwrap().insert(hash, &dests[0]);
+    summary.stored += 1;
+    Ok(srcs.into_iter().zip(dests).map(|(src, dest)| (src, Some(dest))).collect())
+}
+
+/// 整理済みの名前のハッシュ値が今の中身と一致しない場合に，ハッシュ値の部分だけを付け直す（--rehash）．
+/// 
+/// 撮影日時などの他の部分は，編集しても変わらないことが多いのでそのまま残す．組になったファイルも同じ名前にする．
+/// 重複を避けるために付けた番号は一旦外し，付け直した名前が既に使われている場合だけ改めて付ける．
+fn rehash_name(file_path: &path::Path, binary: &[u8], file_type: FileType, members: &[path::PathBuf], args: &Args,
+               prefix: Option<&str>, summary: &mut Summary) -> io::Result<()> {
+    let ext = match file_path.extension() {
+        Some(ext) => ext.to_string_lossy().into_owned(),
+        None => return Ok(()),
+    };
+    // テンプレートに{prefix}が無い場合は，接頭辞を除いてからハッシュ値の位置を探す
+    let strips_prefix = !args.template.as_ref().is_some_and(template::Template::has_prefix);
+    let found = name_stems(file_path).into_iter().rev().find_map(|stem| {
+        let (head, rest) = match prefix {
+            Some(prefix) if strips_prefix => (prefix, stem.strip_prefix(prefix)?),
+            _ => ("", stem.as_str()),
+        };
+        let range = find_hash(rest, args)?;
+        Some((format!("{}{}", head, &rest[..range.start]), rest[range.clone()].to_string(), rest[range.end..].to_string()))
+    });
+    let (head, old_hash, tail) = match found {
+        Some(found) => found,
+        None => return Ok(()),  // 整理済みの名前でないものは何もしない
+    };
+
+    let mut warnings = Vec::new();
+    let unique_id = match args.hash_source {
+        HashSource::UniqueId => read_unique_id(binary, file_type),
+        _ => None,
+    };
+    let new_hash = match unique_id {
+        Some(unique_id) => unique_id,
+        None => {
+            let digest = content_digest(file_path, binary, file_type, args.hash, args.hash_source, &mut warnings)?;
+            args.hash_encoding.encode(&digest)[..hash_len(args)].to_string()
+        },
+    };
+    if new_hash == old_hash {
+        return Ok(());
+    }
+
+    let new_stem = format!("{}{}{}", head, new_hash, tail);
+    let dir_path = file_path.parent().unwrap();
+    let mut srcs = vec![file_path.to_path_buf()];
+    let mut new_paths = vec![dir_path.join(format!("{}.{}", new_stem, ext))];
+    for member in members {
+        new_paths.push(dir_path.join(group::member_file_name(member, &new_stem, &srcs, &new_paths)));
+        srcs.push(member.clone());
+    }
+    let new_paths = summary.files.unique_paths(&srcs, new_paths, &new_stem);
+    // 大きいファイルは先頭しか読んでいないので，CRC32はジャーナルに記録する時に計算する
+    let crc = (!file_type.is_large()).then(|| crc32fast::hash(binary));
+    for (i, (src, new_path)) in srcs.iter().zip(&new_paths).enumerate() {
+        summary.files.rename(src, new_path)?;
+        summary.journal.rehash(src, new_path, crc.filter(|_| i == 0))?;
+    }
+    println!("Note: Renamed to \"{}\", since the hash in the name no longer matches the contents: {}",
+        new_paths[0].file_name().unwrap().to_string_lossy(), file_path.display());
+    summary.rehashed += 1;
+    let moves: Vec<_> = srcs.iter().zip(&new_paths).map(|(src, new)| (src.as_path(), Some(new.as_path()))).collect();
+    record_manifest(summary, args, &moves, None, None, Some(&new_hash), &warnings)
+}
+
+/// 整理済みの名前（拡張子と番号，接頭辞を除く）の中のハッシュ値の位置を返す（--rehash）．整理済みの名前でない場合はNoneを返す．
+/// 
+/// 既定の名前では，日時の後（日時が無い名前は先頭）の今の設定のハッシュ値の形をした最初の部分とする（split_original_nameと同じ）．
+fn find_hash(stem: &str, args: &Args) -> Option<std::ops::Range<usize>> {
+    match &args.template {
+        Some(template) => template.captures(stem, &args.date_format.clone().unwrap_or_default())?.hash_range,
+        None => {
+            if !is_organized_stem(stem, args) {
+                return None;
+            }
+            current_hash_range(stem, args)
+        },
+    }
+}
+
+/// 処理した一組のファイル（組になったファイルを含む）の元のパスと処理後のパスを記録する（--manifest）．
+/// 
+/// 処理後のパスがNoneのものは削除したファイル．警告は名前を決めた最初のファイルにだけ付ける．
+fn record_manifest(summary: &mut Summary, args: &Args, moves: &[(&path::Path, Option<&path::Path>)], date_time: Option<&[u8; 19]>,
+                   date_source: Option<&str>, hash: Option<&str>, warnings: &[String]) -> io::Result<()> {
+    if args.manifest.is_none() && !args.dry_run {
+        return Ok(());
+    }
+    let date = date_time.map(|date_time| {
+        let date_time = String::from_utf8_lossy(date_time);
+        format!("{}-{}-{}T{}", &date_time[0..4], &date_time[5..7], &date_time[8..10], &date_time[11..])
+    });
+    for (i, (original, new)) in moves.iter().enumerate() {
+        let size = match new {
+            Some(new) => Some(summary.files.size(new)?),
+            None => None,
+        };
+        summary.manifest.push(ManifestRow {
+            event: if args.rehash {
+                "rehash"
+            } else if args.content_store.is_some() {
+                "store"
+            } else if summary.undated_root.as_ref().is_some_and(|root| new.is_some_and(|new| new.starts_with(root))) {
+                "unsorted"
+            } else {
+                "organize"
+            },
+            original: original.to_path_buf(),
+            new: new.map(path::Path::to_path_buf),
+            date: date.clone(),
+            date_source: date_source.map(str::to_string),
+            hash: hash.map(str::to_string),
+            size,
+            warnings: if i == 0 { warnings.to_vec() } else { Vec::new() },
+        });
+    }
+    Ok(())
+}
+
+/// 変換前の元ファイルを削除する（--move-originalsを指定した場合は，同じフォルダの "originals" フォルダに移動する）．
+fn dispose_original(src: &path::Path, move_originals: bool, summary: &Summary) -> io::Result<()> {
+    if !move_originals {
+        summary.files.remove_file(src)?;
+        return summary.journal.delete(src);
+    }
+    move_into(src, &src.with_file_name(ORIGINALS_DIR_NAME), None, summary).map(|_| ())
+}
+
+/// srcを新しい名前でコピーし，コピーしたファイルの大きさとハッシュ値が元と一致するかを確かめる（--copy）．
+/// 
+/// 途中で止まっても新しい名前の壊れたファイルが残らないように，仮の名前でコピーしてから確かめ，一致した場合だけ新しい名前にする．
+/// 変換した画像はcontentsを書き出して，contentsと比べる．一致しなかった場合はコピーを削除して警告を表示し，falseを返す．
+/// crcはコピーする中身のCRC32（分かっている場合，ジャーナルに記録する）．
+fn copy_verified(src: &path::Path, new_path: &path::Path, contents: Option<&[u8]>, crc: Option<u32>, args: &Args, summary: &mut Summary,
+                 warnings: &mut Vec<String>) -> io::Result<bool> {
+    let temp = new_path.with_file_name(format!("{}{}", DEFERRED_TEMP_PREFIX, new_path.file_name().unwrap().to_string_lossy()));
+    let (len, digest) = match contents {
+        Some(contents) => {
+            summary.files.write(&temp, contents)?;
+            (contents.len() as u64, hash_bytes(contents, args.hash))
+        },
+        None => {
+            summary.files.copy(src, &temp)?;
+            (fs::metadata(src)?.len(), hash_file(src, args.hash)?)
+        },
+    };
+    if !summary.files.is_dry_run() && (fs::metadata(&temp)?.len() != len || hash_file(&temp, args.hash)? != digest) {
+        summary.files.remove_file(&temp)?;
+        warn(warnings, format!("The copy was removed, since its size or hash did not match the original file: {}", src.display()));
+        summary.copy_failures += 1;
+        return Ok(false);
+    }
+    summary.files.rename(&temp, new_path)?;
+    summary.journal.create(src, new_path, crc)?;
+    summary.copied += 1;
+    summary.copied_bytes += len;
+    Ok(true)
+}
+
+/// ファイルを指定したフォルダに移動して，移動先のパスを返す（フォルダが無ければ作り，同じ名前のファイルがあれば番号を付ける）．
+/// crcはsrcの中身のCRC32（分かっている場合）．
+fn move_into(src: &path::Path, dir: &path::Path, crc: Option<u32>, summary: &Summary) -> io::Result<path::PathBuf> {
+    summary.files.create_dir_all(dir)?;
+    let dest = dir.join(src.file_name().unwrap());
+    let stem = src.file_stem().unwrap().to_string_lossy();
+    let dest = summary.files.unique_paths(&[src.to_path_buf()], vec![dest], &stem).pop().unwrap();
+    summary.files.rename(src, &dest)?;
+    summary.journal.rename(src, &dest, crc)?;
+    Ok(dest)
+}
+
+/// サイズとCRC32が同じ残したファイルの中から，中身が全く同じものを探す（--dedupe）．
+/// CRC32が偶然一致しただけの別のファイルを重複とみなさないように，全体を比べる．
+fn find_kept_file(file_path: &path::Path, candidates: Option<&Vec<path::PathBuf>>, files: &fileops::FileOps) -> io::Result<Option<path::PathBuf>> {
+    for kept in candidates.into_iter().flatten() {
+        // 日付を印字した場合などは残したファイルの中身が変わっている（消えている）ことがある
+        if files.is_file(kept) && same_bytes(file_path, &files.real_path(kept))? {
+            return Ok(Some(kept.clone()));
+        }
+    }
+    Ok(None)
+}
+
+/// "_duplicates" フォルダの一覧に，移動した重複ファイルと残したファイルの組を追記する（タブ区切り）．
+fn write_dedupe_report(dest: &path::Path, kept: &path::Path, files: &fileops::FileOps) -> io::Result<()> {
+    files.append_line(&dest.with_file_name(DEDUPE_REPORT_NAME), &format!("{}\t{}", dest.display(), kept.display()))
+}
+
+/// 全てのリネーム先に，リネーム元と中身が同じファイルが既にあるかどうかを返す（--on-duplicate）．
+/// 
+/// 大文字・小文字を区別しないファイルシステムでは "IMG.JPG" と "img.jpg" が同じファイルになるので，
+/// 名前が大文字・小文字の違いしか無い場合は重複とみなさない（削除すると唯一のファイルが消えてしまう）．
+/// macOSのように正規化するファイルシステムでは "café.jpg" のNFCとNFDも同じファイルになるので，正規化してから比べる．
+fn is_duplicate(srcs: &[path::PathBuf], paths: &[path::PathBuf], files: &fileops::FileOps) -> io::Result<bool> {
+    for (src, path) in srcs.iter().zip(paths) {
+        let same_name = unicode::nfc(&src.file_name().unwrap().to_string_lossy()).eq_ignore_ascii_case(&unicode::nfc(&path.file_name().unwrap().to_string_lossy()));
+        if same_name || !files.is_file(path) || !same_contents(&files.real_path(src), &files.real_path(path))? {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// 二つのファイルの中身が同じかどうかを返す．
+/// サイズとCRC32を比べ，CRC32が一致した場合は偶然の一致でないことを全体を比べて確かめる．
+fn same_contents(a: &path::Path, b: &path::Path) -> io::Result<bool> {
+    if fs::metadata(a)?.len() != fs::metadata(b)?.len() || crc32_file(a)? != crc32_file(b)? {
+        return Ok(false);
+    }
+    same_bytes(a, b)
+}
+
+/// 二つのファイルが--hash-sourceの意味で同じものかどうかを，名前に入れる短くしたハッシュ値ではなく全体で確かめる（--content-store）．
+/// 
+/// ファイル全体の場合は中身を全て比べ，画素データなどの場合は短くしていないSHA-256を，ImageUniqueIDの場合はID全体を比べる．
+/// 拡張子で形式が分からない場合や画素データなどが読めない場合は，名前を付けた時と同じくファイル全体で比べる．
+fn same_identity(a: &path::Path, b: &path::Path, source: HashSource) -> io::Result<bool> {
+    if source == HashSource::Crc32 {
+        return same_contents(a, b);
+    }
+    let read = |path: &path::Path| -> io::Result<Option<(Vec<u8>, FileType)>> {
+        let file_type = match path.extension().and_then(|ext| filetype::from_extension(&ext.to_ascii_lowercase())) {
+            Some(file_type) => file_type,
+            None => return Ok(None),
+        };
+        let binary = if file_type.is_large() { read_head(path, LARGE_FILE_HEAD_LEN)? } else { fs::read(path)? };
+        Ok(Some((binary, file_type)))
+    };
+    let ((binary_a, type_a), (binary_b, type_b)) = match (read(a)?, read(b)?) {
+        (Some(read_a), Some(read_b)) => (read_a, read_b),
+        _ => return same_contents(a, b),
+    };
+    if source == HashSource::UniqueId {
+        return match (read_unique_id(&binary_a, type_a), read_unique_id(&binary_b, type_b)) {
+            (Some(id_a), Some(id_b)) => Ok(id_a == id_b),
+            _ => same_contents(a, b),
+        };
+    }
+    let mut warnings = Vec::new();
+    Ok(content_digest(a, &binary_a, type_a, HashAlgorithm::Sha256, source, &mut warnings)?
+        == content_digest(b, &binary_b, type_b, HashAlgorithm::Sha256, source, &mut warnings)?)
+}
+
+/// 二つのファイルの中身を先頭から全て比べる．
+fn same_bytes(a: &path::Path, b: &path::Path) -> io::Result<bool> {
+    if fs::metadata(a)?.len() != fs::metadata(b)?.len() {
+        return Ok(false);
+    }
+    let (mut file_a, mut file_b) = (fs::File::open(a)?, fs::File::open(b)?);
+    let (mut buf_a, mut buf_b) = (vec![0u8; HASH_BUF_LEN], vec![0u8; HASH_BUF_LEN]);
+    loop {
+        let n = file_a.read(&mut buf_a)?;
+        if n == 0 {
+            return Ok(true);
+        }
+        file_b.read_exact(&mut buf_b[..n])?;
+        if buf_a[..n] != buf_b[..n] {
+            return Ok(false);
+        }
+    }
+}
+
+/// ファイルの先頭からlenバイトまでを読み込む（ファイルがそれより短い場合は全体）．
+fn read_head(path: &path::Path, len: u64) -> io::Result<Vec<u8>> {
+    let mut head = Vec::new();
+    fs::File::open(path)?.take(len).read_to_end(&mut head)?;
+    Ok(head)
+}
+
+/// ファイル名に入れるハッシュ値の文字数（--hash，--hash-length，--hash-encoding）
+fn hash_len(args: &Args) -> usize {
+    let max_len = args.hash.max_len(args.hash_encoding);
+    args.hash_length.unwrap_or(args.hash.default_len(args.hash_encoding)).min(max_len) as usize
+}
+
+/// バイト列のハッシュ値を計算する（--hash）．
+fn hash_bytes(binary: &[u8], algorithm: HashAlgorithm) -> Vec<u8> {
+    let mut hasher = algorithm.hasher();
+    hasher.update(binary);
+    hasher.finalize()
+}
+
+/// ファイルを少しずつ読みながらハッシュ値を計算する（--hash）．
+fn hash_file(path: &path::Path, algorithm: HashAlgorithm) -> io::Result<Vec<u8>> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = algorithm.hasher();
+    let mut buf = vec![0u8; HASH_BUF_LEN];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hasher.finalize())
+}
+
+/// hash_fileと同じくハッシュ値を計算し，同時にファイル全体のCRC32も計算する（ジャーナル用に大きいファイルを読み直さないように）．
+fn hash_file_with_crc(path: &path::Path, algorithm: HashAlgorithm) -> io::Result<(Vec<u8>, u32)> {
+    if algorithm == HashAlgorithm::Crc32 {
+        let crc = crc32_file(path)?;
+        return Ok((crc.to_be_bytes().to_vec(), crc));
+    }
+    let mut file = fs::File::open(path)?;
+    let mut hasher = algorithm.hasher();
+    let mut crc = crc32fast::Hasher::new();
+    let mut buf = vec![0u8; HASH_BUF_LEN];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        crc.update(&buf[..n]);
+    }
+    Ok((hasher.finalize(), crc.finalize()))
+}
+
+/// ファイルを少しずつ読みながらCRC32を計算する．
+/// ファイル全体をメモリに載せないので，大きいファイルでもメモリ使用量はバッファ分だけで済む．
+fn crc32_file(path: &path::Path) -> io::Result<u32> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = crc32fast::Hasher::new();
+    let mut buf = vec![0u8; HASH_BUF_LEN];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hasher.finalize())
+}
+
+/// リネーム先のパスにファイルが存在しない場合はそのまま返す．
+/// 既に存在する場合は名前の本体stemの後（拡張子やIMG.jpg.xmpの ".jpg.xmp" などの接尾辞の前）に "-1", "-2", ... を付けて空いている名前を探す．
+/// 本体に "." を含む名前（秒未満の日時や "%Y.%m.%d" の日付など）でも番号の位置がずれないように，本体は呼び出し元が渡す．
+/// リネーム元のファイル自身（Unicode正規化の違いだけの名前を含む）は空いているものとして扱う（再実行時に番号がずれないように）．
+/// 
+/// 例えば，foo.jpgとfoo.jpegが同じ内容だった場合に，拡張子を統一すると
+/// リネーム先が被って上書きされてしまうのでそれを防ぐ．
+/// Live Photoのように複数のファイルを同じ名前にする場合は，全てのファイルに同じ番号を付ける．
+fn unique_paths(srcs: &[path::PathBuf], paths: Vec<path::PathBuf>, stem: &str) -> Vec<path::PathBuf> {
+    unique_paths_by(srcs, paths, stem, |path| path.exists())
+}
+
+/// unique_pathsと同じく空いているリネーム先のパスを返す（パスが使われているかどうかはexistsで確かめる，--dry-run用）．
+fn unique_paths_by<F: Fn(&path::Path) -> bool>(srcs: &[path::PathBuf], paths: Vec<path::PathBuf>, stem: &str, exists: F) -> Vec<path::PathBuf> {
+    let is_free = |candidates: &[path::PathBuf]| {
+        candidates.iter().zip(srcs).all(|(candidate, src)| unicode::is_same_path(candidate, src) || !exists(candidate))
+    };
+    if is_free(&paths) {
+        return paths;
+    }
+
+    let mut n = 1;
+    loop {
+        let candidates: Vec<path::PathBuf> = paths.iter().map(|path| {
+            // 本体で始まらない名前は，最後の "." の前に番号を付ける
+            let file_name = path.file_name().unwrap().to_string_lossy().into_owned();
+            let name = match file_name.strip_prefix(stem) {
+                Some(suffix) => format!("{}-{}{}", stem, n, suffix),
+                None => match file_name.rsplit_once('.') {
+                    Some((stem, ext)) => format!("{}-{}.{}", stem, n, ext),
+                    None => format!("{}-{}", file_name, n),
+                },
+            };
+            path.with_file_name(name)
+        }).collect();
+        if is_free(&candidates) {
+            return candidates;
+        }
+        n += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn print_date_keeps_the_motion_photo_video_and_xmp() {
+        let mut jpeg = Vec::new();
+        let img = image::DynamicImage::ImageRgb8(image::RgbImage::from_pixel(90, 60, image::Rgb([40, 120, 200])));
+        img.write_to(&mut io::Cursor::new(&mut jpeg), image::ImageOutputFormat::Jpeg(90)).unwrap();
+        let xml = b"<rdf:Description tiff:Orientation=\"6\" GCamera:MotionPhoto=\"1\" GCamera:MicroVideoOffset=\"24\"/>";
+        let payload = [xmp::IDENTIFIER, xml].concat();
+        let app1 = [&[0xFF, jpeg::APP1][..], &(payload.len() as u16 + 2).to_be_bytes(), &payload].concat();
+        let video = b"\x00\x00\x00\x18ftypmp42\x00\x00\x00\x00isommp42";
+        let binary = [exif::insert_after_app0(&jpeg, &app1).unwrap(), video.to_vec()].concat();
+
+        let path = std::env::temp_dir().join(format!("photo-organizer-test-{}-motion.jpg", process::id()));
+        fs::write(&path, &binary).unwrap();
+        let mut warnings = Vec::new();
+        // -kを指定しなくても，動画の位置を書いたXMPは残す
+        assert!(print_date(&path, &binary, "2023-01-23", false, &mut warnings));
+        let stamped = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(warnings.is_empty());
+        assert_ne!(stamped, binary);
+        assert!(motion_photo::is_motion_photo(&stamped));
+        assert_eq!(motion_photo::get_trailer(&stamped), Some(&video[..]));
+        let stamped_xml = xmp::get_xmp(&stamped).unwrap();
+        assert_eq!(stamped_xml, xml.map(|c| if c == b'6' { b'1' } else { c }));
+    }
+
+    #[test]
+    fn unique_paths_number_goes_after_the_stem() {
+        let dir = path::Path::new("/photos");
+        let taken = ["2023-01-23_143027.00.png", "2023.01.23_1430.png", "IMG.jpg", "IMG.jpg.xmp"].map(|name| dir.join(name));
+        let exists = |path: &path::Path| taken.iter().any(|taken| taken == path);
+        let unique = |names: &[&str], stem: &str| -> Vec<String> {
+            let srcs: Vec<path::PathBuf> = names.iter().map(|name| dir.join(format!("src-{}", name))).collect();
+            let paths = names.iter().map(|name| dir.join(name)).collect();
+            unique_paths_by(&srcs, paths, stem, exists).iter().map(|path| path.file_name().unwrap().to_string_lossy().into_owned()).collect()
+        };
+        assert_eq!(unique(&["2023-01-23_143027.00.png"], "2023-01-23_143027.00"), ["2023-01-23_143027.00-1.png"]);
+        assert_eq!(unique(&["2023.01.23_1430.png"], "2023.01.23_1430"), ["2023.01.23_1430-1.png"]);
+        // 組のファイルには同じ番号を付ける
+        assert_eq!(unique(&["IMG.jpg", "IMG.jpg.xmp"], "IMG"), ["IMG-1.jpg", "IMG-1.jpg.xmp"]);
+        assert_eq!(unique(&["free.png"], "free"), ["free.png"]);
+    }
+
+    #[test]
+    fn name_stems_strip_the_number() {
+        assert_eq!(name_stems(path::Path::new("2023.01.23_1430-1.png")), ["2023.01.23_1430-1", "2023.01.23_1430"]);
+    }
+
+    #[test]
+    fn to_system_time_is_the_inverse_of_from_system_time() {
+        // 夏時間のあるタイムゾーンでも，冬と夏のどちらも同じ現地時刻に戻る
+        for date_time in [b"2023:01:23 14:30:27", b"2023:07:01 00:00:00"] {
+            let time = to_system_time(date_time, TimeBase::Local).unwrap();
+            assert_eq!(datetime::from_system_time(time), Some(*date_time));
+        }
+    }
+
+    /// 末尾に4バイト足して，CRC32をtargetにする（CRC32は線形なので，足す32ビットについての連立方程式を解く）．
+    fn forge_crc32(prefix: &[u8], target: u32) -> Vec<u8> {
+        let crc_with = |tail: u32| crc32fast::hash(&[prefix, &tail.to_le_bytes()].concat());
+        let base = crc_with(0);
+        // 各行は（ビットを立てた時のCRC32の変化, 立てたビット）
+        let mut rows: Vec<(u32, u32)> = (0..32).map(|i| (crc_with(1 << i) ^ base, 1 << i)).collect();
+        let want = target ^ base;
+        for bit in 0..32 {
+            let pivot = (bit..32).find(|&i| rows[i].0 & (1 << bit) != 0).expect("singular");
+            rows.swap(bit, pivot);
+            let (delta, mask) = rows[bit];
+            for (i, row) in rows.iter_mut().enumerate() {
+                if i != bit && row.0 & (1 << bit) != 0 {
+                    *row = (row.0 ^ delta, row.1 ^ mask);
+                }
+            }
+        }
+        // 消去した後は各行の変化が1ビットだけなので，targetとの差のビットに対応する行を合わせればよい
+        let tail = rows.iter().enumerate().filter(|&(bit, _)| want & (1 << bit) != 0).fold(0, |tail, (_, &(_, mask))| tail ^ mask);
+        [prefix, &tail.to_le_bytes()].concat()
+    }
+
+    #[test]
+    fn crc32_collision_is_not_a_duplicate() {
+        let dir = std::env::temp_dir().join(format!("photo-organizer-test-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let a = forge_crc32(b"\xFF\xD8 first photo", 0x1234_5678);
+        let b = forge_crc32(b"\xFF\xD8 other photo", 0x1234_5678);
+        assert_ne!(a, b);
+        assert_eq!(crc32fast::hash(&a), crc32fast::hash(&b));
+
+        // 同じ分に撮った別の写真は，同じハッシュ値で同じ名前になる
+        let stem = format!("2023-01-23_1430_{:08x}", crc32fast::hash(&a));
+        let existing = dir.join(format!("{}.jpg", stem));
+        let src = dir.join("IMG_0002.jpg");
+        fs::write(&existing, &a).unwrap();
+        fs::write(&src, &b).unwrap();
+
+        let files = fileops::FileOps::default();
+        let srcs = [src.clone()];
+        assert!(!same_contents(&src, &existing).unwrap());
+        assert!(!same_identity(&src, &existing, HashSource::Crc32).unwrap());
+        assert!(!is_duplicate(&srcs, std::slice::from_ref(&existing), &files).unwrap());
+        assert_eq!(files.unique_paths(&srcs, vec![existing.clone()], &stem), [dir.join(format!("{}-1.jpg", stem))]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// テスト用の空のフォルダを作る（名前はテストごとに変える）．
+    fn temp_dir(name: &str) -> path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("photo-organizer-test-{}-main-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// 撮影日時（Exifの形式，Noneなら無し）とコメントだけを持つJPEGを書き，既定の名前（撮影日時とCRC32）を返す．
+    fn write_jpeg(path: &path::Path, date_time: Option<&[u8; 19]>, comment: &str) -> String {
+        let jpeg = [&[0xFF, 0xD8, 0xFF, jpeg::COM][..], &(comment.len() as u16 + 2).to_be_bytes(), comment.as_bytes(), &[0xFF, 0xD9]].concat();
+        let jpeg = match date_time {
+            Some(date_time) => exif::set_date_time_original(&jpeg, date_time).unwrap(),
+            None => jpeg,
+        };
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, &jpeg).unwrap();
+        let hash = format!("{:08x}", crc32fast::hash(&jpeg));
+        match date_time {
+            Some(date_time) => format!("{}_{}.jpg", format_date_time(*date_time, false), hash),
+            None => format!("{}.jpg", hash),
+        }
+    }
+
+    /// フォルダ内（サブフォルダを含む）のファイルを，フォルダからの相対パス（"/" 区切り）で並べて返す（ジャーナルは除く）．
+    fn list_files(dir: &path::Path) -> Vec<String> {
+        fn walk(dir: &path::Path, root: &path::Path, files: &mut Vec<String>) {
+            for entry in fs::read_dir(dir).unwrap() {
+                let path = entry.unwrap().path();
+                if path.is_dir() {
+                    walk(&path, root, files);
+                } else if path.file_name().unwrap() != journal::JOURNAL_FILE_NAME {
+                    let relative = path.strip_prefix(root).unwrap();
+                    files.push(relative.components().map(|c| c.as_os_str().to_string_lossy().into_owned()).collect::<Vec<_>>().join("/"));
+                }
+            }
+        }
+        let mut files = Vec::new();
+        walk(dir, dir, &mut files);
+        files.sort();
+        files
+    }
+
+    /// コマンドラインと同じ引数で，フォルダ内のファイルの名前を変える．
+    fn run(dir: &path::Path, options: &[&str]) -> Summary {
+        let args = Args::parse_from(["photo-organizer", "-p", dir.to_str().unwrap()].iter().chain(options));
+        let (dir_path, mut summary) = prepare(dir.to_path_buf(), &args).unwrap();
+        change_names(&dir_path, &args, args.prefix.as_deref(), &mut summary).unwrap();
+        summary
+    }
+
+    #[test]
+    fn layout_moves_files_into_date_folders() {
+        let dir = temp_dir("layout");
+        let a = write_jpeg(&dir.join("IMG_0001.jpg"), Some(b"2023:01:23 14:30:27"), "a");
+        let b = write_jpeg(&dir.join("IMG_0002.jpg"), Some(b"2022:12:31 23:59:00"), "b");
+        let c = write_jpeg(&dir.join("trip/IMG_0003.jpg"), Some(b"2023:01:05 08:00:00"), "c");
+
+        run(&dir, &["-r", "--layout", "%Y/%Y-%m"]);
+        let mut expected = [format!("2023/2023-01/{}", a), format!("2022/2022-12/{}", b), format!("2023/2023-01/{}", c)];
+        expected.sort();
+        assert_eq!(list_files(&dir), expected);
+        // 空になったフォルダは残す
+        assert!(dir.join("trip").is_dir());
+
+        // 移動したファイルは整理済みなので，もう一度実行しても変わらない
+        run(&dir, &["-r", "--layout", "%Y/%Y-%m"]);
+        assert_eq!(list_files(&dir), expected);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn copy_leaves_the_sources_untouched() {
+        let dir = temp_dir("copy-src");
+        let output = temp_dir("copy-dest");
+        let a = write_jpeg(&dir.join("IMG_0001.jpg"), Some(b"2023:01:23 14:30:27"), "a");
+        let b = write_jpeg(&dir.join("trip/IMG_0002.jpg"), Some(b"2022:12:31 23:59:00"), "b");
+        let sources = ["IMG_0001.jpg", "trip/IMG_0002.jpg"].map(|name| (name, fs::read(dir.join(name)).unwrap()));
+
+        let summary = run(&dir, &["-r", "--copy", "--dest", output.to_str().unwrap()]);
+        assert_eq!((summary.copied, summary.copy_failures), (2, 0));
+        // 元のフォルダにはジャーナルも作らず，元のファイルは名前も中身も変えない
+        assert!(!dir.join(journal::JOURNAL_FILE_NAME).exists());
+        assert_eq!(list_files(&dir), ["IMG_0001.jpg", "trip/IMG_0002.jpg"]);
+        for (name, contents) in &sources {
+            assert_eq!(&fs::read(dir.join(name)).unwrap(), contents, "{}", name);
+        }
+        assert_eq!(list_files(&output), [a.clone(), format!("trip/{}", b)]);
+        assert_eq!(fs::read(output.join(&a)).unwrap(), sources[0].1);
+        assert_eq!(fs::read(output.join("trip").join(&b)).unwrap(), sources[1].1);
+
+        // もう一度実行しても，同じ中身のコピーは作らない
+        let summary = run(&dir, &["-r", "--copy", "--dest", output.to_str().unwrap()]);
+        assert_eq!((summary.copied, summary.duplicates), (0, 2));
+        assert_eq!(list_files(&output), [a, format!("trip/{}", b)]);
+        fs::remove_dir_all(&dir).unwrap();
+        fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[test]
+    fn output_keeps_the_source_folders() {
+        let dir = temp_dir("output-src");
+        let output = temp_dir("output-dest").join("renamed");
+        let a = write_jpeg(&dir.join("IMG_0001.jpg"), Some(b"2023:01:23 14:30:27"), "a");
+        let b = write_jpeg(&dir.join("2019 Italy/IMG_1.jpg"), Some(b"2019:08:10 09:15:00"), "b");
+        let contents = fs::read(dir.join("2019 Italy/IMG_1.jpg")).unwrap();
+
+        run(&dir, &["-r", "--output", output.to_str().unwrap()]);
+        assert_eq!(list_files(&output), [format!("2019 Italy/{}", b), a]);
+        assert_eq!(fs::read(output.join("2019 Italy").join(&b)).unwrap(), contents);
+        assert!(list_files(&dir).is_empty());
+
+        // ジャーナルは出力先に作るので，出力先から元に戻せる
+        let undone = journal::undo(&output).unwrap();
+        assert_eq!(undone.restored, 2);
+        assert_eq!(list_files(&dir), ["2019 Italy/IMG_1.jpg", "IMG_0001.jpg"]);
+        fs::remove_dir_all(&dir).unwrap();
+        fs::remove_dir_all(output.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn undated_files_go_into_the_unsorted_folder() {
+        let dir = temp_dir("unsorted-layout");
+        let a = write_jpeg(&dir.join("IMG_0001.jpg"), Some(b"2023:01:23 14:30:27"), "a");
+        let b = write_jpeg(&dir.join("scan.jpg"), None, "b");
+        let summary = run(&dir, &["--layout", "%Y"]);
+        assert_eq!(summary.undated, 1);
+        assert_eq!(list_files(&dir), [format!("2023/{}", a), format!("_unsorted/{}", b)]);
+        fs::remove_dir_all(&dir).unwrap();
+
+        // --outputだけの場合は，--undated-dirの中に元のフォルダの構成を作る
+        let dir = temp_dir("unsorted-src");
+        let output = temp_dir("unsorted-dest");
+        let a = write_jpeg(&dir.join("trip/IMG_0001.jpg"), Some(b"2023:01:23 14:30:27"), "a");
+        let b = write_jpeg(&dir.join("trip/scan.jpg"), None, "b");
+        let summary = run(&dir, &["-r", "--output", output.to_str().unwrap(), "--undated-dir", "later"]);
+        assert_eq!(summary.undated, 1);
+        assert_eq!(list_files(&output), [format!("later/trip/{}", b), format!("trip/{}", a)]);
+        fs::remove_dir_all(&dir).unwrap();
+        fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[test]
+    fn content_store_keeps_files_with_the_same_hash_apart() {
+        let dir = temp_dir("store-src");
+        let root = temp_dir("store");
+        let options = ["--content-store", root.to_str().unwrap(), "--hash", "sha256", "--hash-length", "20"];
+        let stored_path = |name: &str| {
+            let hash: String = hash_bytes(&fs::read(dir.join(name)).unwrap(), HashAlgorithm::Sha256).iter().map(|b| format!("{:02x}", b)).collect();
+            root.join(&hash[..2]).join(format!("{}.jpg", &hash[2..20]))
+        };
+        write_jpeg(&dir.join("IMG_0001.jpg"), Some(b"2023:01:23 14:30:27"), "a");
+        write_jpeg(&dir.join("IMG_0002.jpg"), Some(b"2023:01:23 14:31:00"), "b");
+        fs::copy(dir.join("IMG_0002.jpg"), dir.join("IMG_0002 copy.jpg")).unwrap();
+        // 1つ目のファイルと同じハッシュ値の名前で，中身が違うファイルが既に置いてある
+        let collision = stored_path("IMG_0001.jpg");
+        fs::create_dir_all(collision.parent().unwrap()).unwrap();
+        fs::write(&collision, "another file").unwrap();
+        let b = stored_path("IMG_0002.jpg");
+        let contents = fs::read(dir.join("IMG_0002.jpg")).unwrap();
+
+        let summary = run(&dir, &options);
+        assert_eq!((summary.stored, summary.already_stored), (1, 1));
+        // ハッシュ値の先頭2文字のフォルダに置く
+        assert_eq!(fs::read(&b).unwrap(), contents);
+        assert_eq!(fs::read_to_string(&collision).unwrap(), "another file");
+        // 中身が違うファイルと，同じ中身のファイルのうち後に処理した方は元のフォルダに残す
+        let left = list_files(&dir);
+        assert_eq!(left.len(), 2);
+        assert_eq!(left[0], "IMG_0001.jpg");
+        assert!(left[1].starts_with("IMG_0002"));
+        fs::remove_dir_all(&dir).unwrap();
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
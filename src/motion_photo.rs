@@ -0,0 +1,79 @@
+//! Motion Photo（Google/Samsungの動く写真）を扱うためのモジュール
+//! Motion PhotoはJPEGのEOIの後ろにMP4の動画を連結したもの
+
+use crate::jpeg;
+
+/// Motion Photoであることを示す文字列（XMPの属性やSamsungの独自マーカ）
+const SIGNATURES: [&[u8]; 3] = [
+    b"GCamera:MotionPhoto",
+    b"GCamera:MicroVideo",
+    b"MotionPhoto_Data",
+];
+
+/// Motion Photoかどうかを返す．
+pub fn is_motion_photo(jpeg_binary: &[u8]) -> bool {
+    SIGNATURES.iter().any(|sig| jpeg_binary.windows(sig.len()).any(|w| w == *sig))
+}
+
+/// 主画像のEOIより後ろに連結されている動画などのデータを返す．
+/// 
+/// XMPに書かれている動画のオフセットはファイル末尾からの距離なので，
+/// このデータをそのまま付け直せば再エンコード後の画像でも動画を参照できる．
+pub fn get_trailer(jpeg_binary: &[u8]) -> Option<&[u8]> {
+    let end = jpeg::find_eoi(jpeg_binary)?;
+    let trailer = &jpeg_binary[end..];
+    if trailer.is_empty() {
+        None
+    } else {
+        Some(trailer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// XMPのAPP1セグメント（マーカから）を作る．
+    fn xmp_app1(xml: &[u8]) -> Vec<u8> {
+        let payload = [crate::xmp::IDENTIFIER, xml].concat();
+        [&[0xFF, jpeg::APP1][..], &(payload.len() as u16 + 2).to_be_bytes(), &payload].concat()
+    }
+
+    /// XMPと1つのスキャンだけのJPEGの後ろに，trailerを連結したものを作る．
+    fn jpeg_with_trailer(xml: &[u8], trailer: &[u8]) -> Vec<u8> {
+        let sos = [0xFF, jpeg::SOS, 0x00, 0x08, 0x01, 0x01, 0x00, 0x00, 0x3F, 0x00];
+        [&[0xFF, 0xD8][..], &xmp_app1(xml), &sos, &[0x12, 0x34, 0xFF, 0x00, 0x56], &[0xFF, jpeg::EOI], trailer].concat()
+    }
+
+    const VIDEO: &[u8] = b"\x00\x00\x00\x18ftypmp42\x00\x00\x00\x00isommp42";
+
+    #[test]
+    fn pixel_motion_photo_has_its_video_as_the_trailer() {
+        let xml = b"<rdf:Description GCamera:MotionPhoto=\"1\" GCamera:MotionPhotoVersion=\"1\"/>";
+        let binary = jpeg_with_trailer(xml, VIDEO);
+        assert!(is_motion_photo(&binary));
+        assert_eq!(get_trailer(&binary), Some(VIDEO));
+    }
+
+    #[test]
+    fn old_micro_video_is_a_motion_photo() {
+        let binary = jpeg_with_trailer(b"<rdf:Description GCamera:MicroVideo=\"1\" GCamera:MicroVideoOffset=\"24\"/>", VIDEO);
+        assert!(is_motion_photo(&binary));
+        assert_eq!(get_trailer(&binary), Some(VIDEO));
+    }
+
+    #[test]
+    fn samsung_marker_in_the_trailer_is_a_motion_photo() {
+        let binary = jpeg_with_trailer(b"<rdf:Description/>", &[VIDEO, b"MotionPhoto_Data"].concat());
+        assert!(is_motion_photo(&binary));
+    }
+
+    #[test]
+    fn plain_jpeg_has_no_trailer() {
+        let binary = jpeg_with_trailer(b"<rdf:Description/>", b"");
+        assert!(!is_motion_photo(&binary));
+        assert_eq!(get_trailer(&binary), None);
+        // 0xFF 0xD9 の無い，途中で切れたファイル
+        assert_eq!(get_trailer(&binary[..binary.len() - 2]), None);
+    }
+}
@@ -0,0 +1,42 @@
+//! PNGのチャンクを読むためのモジュール
+//! PNG 1.5の拡張で追加されたeXIfチャンクからExifデータを取り出す
+
+/// PNGシグネチャ
+const SIGNATURE: [u8; 8] = [0x89, 0x50, 0x4E, 0x47, 0x0D, 0x0A, 0x1A, 0x0A];
+
+/// eXIfチャンクの中身（TIFFヘッダから始まるExifデータ）を返す．
+/// 
+/// 古いソフトウェアの中にはJPEGのAPP1と同じく先頭に "Exif\0\0" を付けて書き込むものがあるため，
+/// その場合は識別子を読み飛ばす．
+pub fn get_exif(png_binary: &[u8]) -> Option<&[u8]> {
+    if !png_binary.starts_with(&SIGNATURE) {
+        return None;
+    }
+
+    // チャンクは Length(4byte) + Type(4byte) + Data(Length byte) + CRC(4byte) の並び
+    let mut i = SIGNATURE.len();
+    while i + 8 <= png_binary.len() {
+        let mut tmp = [0u8; 4];
+        tmp.copy_from_slice(&png_binary[i..(i + 4)]);
+        let length = u32::from_be_bytes(tmp) as usize;  // チャンク長は必ずビッグエンディアン
+        let chunk_type = &png_binary[(i + 4)..(i + 8)];
+
+        let data_start = i + 8;
+        let data_end = data_start.checked_add(length)?;
+        if data_end > png_binary.len() {
+            return None;  // ファイルが途中で切れている
+        }
+
+        match chunk_type {
+            b"eXIf" => {
+                let data = &png_binary[data_start..data_end];
+                return Some( data.strip_prefix(b"Exif\0\0").unwrap_or(data) );
+            },
+            b"IEND" => break,
+            _ => (),
+        }
+        i = data_end + 4;  // CRCを飛ばして次のチャンクへ
+    }
+
+    None
+}
@@ -0,0 +1,40 @@
+//! Fujifilm RAFファイルを読むためのモジュール
+//! RAFは独自のヘッダの後にExif付きのJPEGを埋め込んでいるので，そのJPEGを取り出す
+
+/// RAFファイル先頭の識別子
+pub const MAGIC: &[u8] = b"FUJIFILMCCD-RAW ";
+
+/// ヘッダ内における埋め込みJPEGのオフセットと長さが書かれている位置
+const OFFSET_JPEG_OFFSET: usize = 84;
+const OFFSET_JPEG_LENGTH: usize = 88;
+
+/// 埋め込まれたJPEGのバイナリを返す．
+/// 
+/// ヘッダのオフセットが壊れている場合は，SOIマーカ（FF D8 FF）を探す．
+/// ファイルの先頭部分だけを読み込んでいる場合は，JPEGの途中までのスライスになる．
+pub fn get_jpeg(raf_binary: &[u8]) -> Option<&[u8]> {
+    if !raf_binary.starts_with(MAGIC) {
+        return None;
+    }
+
+    // オフセットと長さはビッグエンディアン
+    let read_u32 = |i: usize| -> Option<usize> {
+        let mut tmp = [0u8; 4];
+        tmp.copy_from_slice(raf_binary.get(i..(i + 4))?);
+        Some( u32::from_be_bytes(tmp) as usize )
+    };
+    let from_header = read_u32(OFFSET_JPEG_OFFSET).zip(read_u32(OFFSET_JPEG_LENGTH))
+        .and_then(|(offset, length)| {
+            let end = offset.checked_add(length)?.min(raf_binary.len());
+            raf_binary.get(offset..end)
+        })
+        .filter(|jpeg| jpeg.starts_with(&[0xFF, 0xD8, 0xFF]));
+
+    match from_header {
+        Some(jpeg) => Some(jpeg),
+        None => {
+            let start = raf_binary.windows(3).position(|w| w == [0xFF, 0xD8, 0xFF])?;
+            Some(&raf_binary[start..])
+        },
+    }
+}
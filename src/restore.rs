@@ -0,0 +1,353 @@
+//! --manifestで書き出した一覧を使って，ファイルを元の名前に戻す処理（restoreサブコマンド）
+//!
+//! 一覧に記録した処理後のパスにファイルが無い場合は，後で移動したものとしてフォルダ内から
+//! ファイル名に含まれるハッシュ値（とファイルサイズ）で探し，見つからなければ拡張属性に記録した元の名前（--xattr-original-name）で探す．
+//! ジャーナル（--undo）とは別に使える．
+
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::fileops::FileOps;
+use crate::journal;
+use crate::json;
+use crate::xattr;
+
+/// 一覧の1行（元に戻すのに使う列だけ）
+struct Row {
+    original: PathBuf,
+    /// 処理後のパス（削除した場合はNone）
+    new: Option<PathBuf>,
+    hash: Option<String>,
+    size: Option<u64>,
+}
+
+/// 元に戻した結果の集計（最後に表示する）
+#[derive(Default)]
+pub struct RestoreSummary {
+    /// 元の名前に戻した（--dry-runの場合は戻す）ファイルの数
+    pub restored: usize,
+    /// 元の名前が別のファイルに使われていたので飛ばしたファイルの数
+    pub conflicts: usize,
+    /// 見つからなかった（またはハッシュ値で探した候補が複数あった）ファイルの数
+    pub not_found: usize,
+    /// 削除したので元に戻せないファイルの数
+    pub deleted: usize,
+    /// 移動に失敗したので飛ばしたファイルの数
+    pub skipped: usize,
+}
+
+/// 一覧に記録したファイルを元の名前に戻す（dry_runの場合は戻す予定を表示するだけ）．
+///
+/// 一覧のパスは書き出した時と同じように，今いるフォルダからの相対パスとして扱う．
+/// 処理後のパスにファイルがあればそのまま元のパスに戻し，無ければdirの中をハッシュ値で探して，見つかったフォルダの中で元の名前にする．
+/// 元の名前が別のファイルに使われている場合は，上書きしないように飛ばす．
+/// 移動に失敗したファイルは飛ばして，残りのファイルを戻し続ける．
+pub fn restore(manifest_path: &Path, dir: &Path, dry_run: bool) -> io::Result<RestoreSummary> {
+    let contents = fs::read_to_string(manifest_path)?;
+    let is_json = manifest_path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("json"));
+    let rows = if is_json { parse_json(&contents) } else { parse_csv(&contents) }
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("The manifest could not be read: {}", manifest_path.display())))?;
+    // 元に戻した操作もジャーナルに記録して，--undoでもう一度戻せるようにする
+    let journal = if dry_run { journal::Journal::default() } else { journal::Journal::open(dir)? };
+    // 別のドライブから戻すファイルは，名前を変えるだけでは戻せないのでコピーしてから削除する
+    let file_ops = FileOps::default();
+
+    let mut summary = RestoreSummary::default();
+    let mut files = None;
+    let mut used = HashSet::new();
+    let mut moves = Vec::new();
+    for row in &rows {
+        let new = match &row.new {
+            Some(new) => new,
+            None => {
+                summary.deleted += 1;
+                continue;
+            },
+        };
+        if *new == row.original {
+            continue;  // そのまま残したファイル
+        }
+        let file_name = match row.original.file_name() {
+            Some(file_name) => file_name,
+            None => continue,
+        };
+        let (current, target) = if new.is_file() && !used.contains(new) {
+            (new.clone(), row.original.clone())
+        } else {
+            // フォルダ内のファイルは，ハッシュ値で探す必要がある場合だけ一度読む
+            if files.is_none() {
+                files = Some(list_files(dir)?);
+            }
+            let candidates: Vec<&PathBuf> = files.iter().flatten()
+                .filter(|path| !used.contains(*path) && matches_row(path, row))
+                .collect();
+            // ハッシュ値で一つに決まらない場合は，--xattr-original-nameで記録した元の名前で探す
+            let candidates = match candidates.len() {
+                1 => candidates,
+                _ => {
+                    let by_name: Vec<&PathBuf> = files.iter().flatten()
+                        .filter(|path| !used.contains(*path) && has_original_name(path, file_name, row.size))
+                        .collect();
+                    if by_name.len() == 1 { by_name } else { candidates }
+                },
+            };
+            match candidates[..] {
+                [found] => (found.clone(), found.with_file_name(file_name)),
+                // 前に元に戻した場合など，既に元の名前になっているものは何もしない
+                [] if row.original.is_file() && row.size.is_none_or(|size| fs::metadata(&row.original).is_ok_and(|metadata| metadata.len() == size)) => continue,
+                [] => {
+                    println!("Warning: Skipped, since the file could not be found: {}", new.display());
+                    summary.not_found += 1;
+                    continue;
+                },
+                _ => {
+                    println!("Warning: Skipped, since {} files have the same hash: {}", candidates.len(), new.display());
+                    summary.not_found += 1;
+                    continue;
+                },
+            }
+        };
+        used.insert(current.clone());
+        moves.push((current, target));
+    }
+
+    // 元の名前が，後で元に戻すファイルに使われている場合もあるので，戻せるものが無くなるまで繰り返す
+    // --dry-runでは実際には動かさないので，空いた名前と埋まった名前を覚えておく
+    let mut vacated = HashSet::new();
+    let mut occupied = HashSet::new();
+    loop {
+        let mut pending = Vec::new();
+        let restored = summary.restored;
+        for (current, target) in moves {
+            if occupied.contains(&target) || (target.exists() && !vacated.contains(&target)) {
+                pending.push((current, target));
+                continue;
+            }
+            if dry_run {
+                println!("Would rename: {} -> {}", current.display(), target.display());
+            } else {
+                let moved = match target.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+                    Some(parent) => file_ops.create_dir_all(parent),
+                    None => Ok(()),
+                }.and_then(|()| file_ops.move_file(&current, &target));
+                if let Err(e) = moved {
+                    println!("Warning: Skipped, since an error occurred ({}): {}", e, current.display());
+                    summary.skipped += 1;
+                    continue;
+                }
+                journal.rename(&current, &target, None)?;
+                println!("Renamed: {} -> {}", current.display(), target.display());
+            }
+            vacated.insert(current);
+            vacated.remove(&target);
+            occupied.insert(target);
+            summary.restored += 1;
+        }
+        moves = pending;
+        if moves.is_empty() || summary.restored == restored {
+            break;
+        }
+    }
+    for (current, target) in &moves {
+        println!("Warning: Skipped, since \"{}\" already exists: {}", target.display(), current.display());
+        summary.conflicts += 1;
+    }
+    Ok(summary)
+}
+
+/// 拡張属性に記録した元の名前がfile_nameで，サイズが一覧と同じ（一覧にサイズが無い場合は問わない）かどうかを返す．
+fn has_original_name(path: &Path, file_name: &OsStr, size: Option<u64>) -> bool {
+    xattr::get_original_name(path).ok().flatten().is_some_and(|name| name == file_name)
+        && size.is_none_or(|size| fs::metadata(path).is_ok_and(|metadata| metadata.len() == size))
+}
+
+/// フォルダ内（サブフォルダを含む）のファイルを返す（ジャーナルなどの "." で始まるファイルは除く）．
+fn list_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.file_name().is_some_and(|name| name.to_string_lossy().starts_with('.')) {
+            continue;
+        }
+        if path.is_dir() {
+            files.append(&mut list_files(&path)?);
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(files)
+}
+
+/// ファイル名にハッシュ値が含まれていて，ファイルサイズが一致するかどうかを返す．
+///
+/// ハッシュ値は "_" の後か名前の先頭にあり，その後は名前の終わりか "_"（元の名前），"-"（重複を避ける番号）が続く．
+fn matches_row(path: &Path, row: &Row) -> bool {
+    let hash = match row.hash.as_deref().filter(|hash| !hash.is_empty()) {
+        Some(hash) => hash,
+        None => return false,
+    };
+    let file_name = path.file_name().unwrap().to_string_lossy();
+    let stem = file_name.split('.').next().unwrap();
+    let has_hash = stem.match_indices(hash).any(|(i, _)| {
+        let rest = &stem[i + hash.len()..];
+        (i == 0 || stem[..i].ends_with('_')) && (rest.is_empty() || rest.starts_with(['_', '-']))
+    });
+    has_hash && row.size.is_none_or(|size| fs::metadata(path).is_ok_and(|metadata| metadata.len() == size))
+}
+
+/// CSVの一覧を読む（1行目の見出しで列を決める）．
+fn parse_csv(contents: &str) -> Option<Vec<Row>> {
+    let records = split_csv(contents);
+    let (header, records) = records.split_first()?;
+    let column = |name: &str| header.iter().position(|field| field == name);
+    let (original, new, hash, size) = (column("original")?, column("new")?, column("hash"), column("size"));
+    let field = |record: &[String], i: usize| record.get(i).filter(|value| !value.is_empty()).cloned();
+    records.iter()
+        .filter(|record| record.iter().any(|value| !value.is_empty()))
+        .map(|record| Some(Row {
+            original: PathBuf::from(field(record, original)?),
+            new: field(record, new).map(PathBuf::from),
+            hash: hash.and_then(|i| field(record, i)),
+            size: size.and_then(|i| field(record, i)).map(|size| size.parse()).transpose().ok()?,
+        }))
+        .collect()
+}
+
+/// CSVを行と列に分ける（引用符で囲んだ値の中のカンマや改行，"" も扱う）．
+fn split_csv(contents: &str) -> Vec<Vec<String>> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut chars = contents.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if in_quotes && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            },
+            '"' => in_quotes = !in_quotes,
+            ',' if !in_quotes => record.push(std::mem::take(&mut field)),
+            '\r' if !in_quotes => (),
+            '\n' if !in_quotes => {
+                record.push(std::mem::take(&mut field));
+                records.push(std::mem::take(&mut record));
+            },
+            c => field.push(c),
+        }
+    }
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        records.push(record);
+    }
+    records
+}
+
+/// JSONの一覧（1行に1つのオブジェクトを並べた配列）を読む．
+fn parse_json(contents: &str) -> Option<Vec<Row>> {
+    let contents = contents.trim().strip_prefix('[')?.strip_suffix(']')?;
+    contents.lines()
+        .map(|line| line.trim().trim_end_matches(','))
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let mut fields = json::parse_object(line)?;
+            Some(Row {
+                original: PathBuf::from(fields.remove("original")?),
+                new: fields.remove("new").map(PathBuf::from),
+                hash: fields.remove("hash"),
+                size: fields.remove("size").map(|size| size.parse()).transpose().ok()?,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// テスト用の空のフォルダを作る（名前はテストごとに変える）．
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("photo-organizer-test-{}-restore-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// --manifestと同じ列のCSVを書く．
+    fn write_csv(path: &Path, rows: &[(&Path, &Path, &str, usize)]) {
+        let mut csv = String::from("event,original,new,date,date_source,hash,size,warnings\n");
+        for (original, new, hash, size) in rows {
+            csv += &format!("organize,{},{},2023-01-23T14:30:27,DateTimeOriginal,{},{},\n", original.display(), new.display(), hash, size);
+        }
+        fs::write(path, csv).unwrap();
+    }
+
+    #[test]
+    fn manifest_restores_renamed_and_moved_files() {
+        let dir = temp_dir("round-trip");
+        let originals = [dir.join("IMG_0001.jpg"), dir.join("a, \"quoted\" 写真.jpg")];
+        let renamed = [dir.join("2023/2023-01-23_1430_0ba50ed7.jpg"), dir.join("2023/2023-01-23_1431_206cc7d9.jpg")];
+        fs::create_dir_all(dir.join("2023")).unwrap();
+        for (original, new) in originals.iter().zip(&renamed) {
+            fs::write(new, original.to_str().unwrap()).unwrap();
+        }
+        // 2つ目のファイルは一覧を書き出した後で別のフォルダに移したので，ハッシュ値で探す
+        fs::create_dir_all(dir.join("sorted")).unwrap();
+        let moved = dir.join("sorted/2023-01-23_1431_206cc7d9.jpg");
+        fs::rename(&renamed[1], &moved).unwrap();
+
+        let rows = [
+            json::string(&originals[0].to_string_lossy()), json::string(&renamed[0].to_string_lossy()),
+            json::string(&originals[1].to_string_lossy()), json::string(&renamed[1].to_string_lossy()),
+        ];
+        let manifest = dir.join(".manifest.json");
+        fs::write(&manifest, format!(
+            "[\n  {{\"event\": \"organize\", \"original\": {}, \"new\": {}, \"hash\": \"0ba50ed7\", \"size\": {}, \"warnings\": []}},\n  \
+             {{\"event\": \"organize\", \"original\": {}, \"new\": {}, \"hash\": \"206cc7d9\", \"size\": {}, \"warnings\": []}}\n]\n",
+            rows[0], rows[1], originals[0].to_str().unwrap().len(), rows[2], rows[3], originals[1].to_str().unwrap().len(),
+        )).unwrap();
+
+        let summary = restore(&manifest, &dir, false).unwrap();
+        assert_eq!((summary.restored, summary.conflicts, summary.not_found), (2, 0, 0));
+        assert_eq!(fs::read_to_string(&originals[0]).unwrap(), originals[0].to_str().unwrap());
+        assert!(!renamed[0].exists() && !moved.exists());
+        // 移した先のフォルダの中で元の名前にする
+        assert_eq!(fs::read_to_string(dir.join("sorted/a, \"quoted\" 写真.jpg")).unwrap(), originals[1].to_str().unwrap());
+
+        // 同じ一覧でもう一度戻しても何もしない
+        let summary = restore(&manifest, &dir, false).unwrap();
+        assert_eq!((summary.restored, summary.not_found), (0, 1));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn missing_files_are_not_found() {
+        let dir = temp_dir("missing");
+        let manifest = dir.join(".manifest.csv");
+        write_csv(&manifest, &[(&dir.join("IMG_0001.jpg"), &dir.join("2023-01-23_1430_0ba50ed7.jpg"), "0ba50ed7", 4)]);
+
+        let summary = restore(&manifest, &dir, false).unwrap();
+        assert_eq!((summary.restored, summary.conflicts, summary.not_found), (0, 0, 1));
+        assert!(!dir.join("IMG_0001.jpg").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn taken_names_are_not_overwritten() {
+        let dir = temp_dir("taken");
+        let (original, new) = (dir.join("IMG_0001.jpg"), dir.join("2023-01-23_1430_0ba50ed7.jpg"));
+        fs::write(&new, "renamed").unwrap();
+        fs::write(&original, "another file").unwrap();
+        let manifest = dir.join(".manifest.csv");
+        write_csv(&manifest, &[(&original, &new, "0ba50ed7", 7)]);
+
+        let summary = restore(&manifest, &dir, false).unwrap();
+        assert_eq!((summary.restored, summary.conflicts, summary.not_found), (0, 1, 0));
+        assert_eq!(fs::read_to_string(&original).unwrap(), "another file");
+        assert_eq!(fs::read_to_string(&new).unwrap(), "renamed");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,35 @@
+//! RIFFコンテナのチャンクを読むためのモジュール
+//! WebPのEXIFチャンクからExifデータを取り出す
+
+/// EXIFチャンクの中身（TIFFヘッダから始まるExifデータ）を返す．
+/// 
+/// PNGのeXIfチャンクと同じく，先頭に "Exif\0\0" が付いている場合は読み飛ばす．
+pub fn get_exif(webp_binary: &[u8]) -> Option<&[u8]> {
+    // RIFFヘッダ: "RIFF" + ファイルサイズ(4byte) + "WEBP"
+    if webp_binary.len() < 12 || &webp_binary[..4] != b"RIFF" || &webp_binary[8..12] != b"WEBP" {
+        return None;
+    }
+
+    // チャンクは FourCC(4byte) + Size(4byte) + Data(Size byte) + パディング の並び
+    let mut i = 12;
+    while i + 8 <= webp_binary.len() {
+        let fourcc = &webp_binary[i..(i + 4)];
+        let mut tmp = [0u8; 4];
+        tmp.copy_from_slice(&webp_binary[(i + 4)..(i + 8)]);
+        let size = u32::from_le_bytes(tmp) as usize;  // RIFFのサイズは必ずリトルエンディアン
+
+        let data_start = i + 8;
+        let data_end = data_start.checked_add(size)?;
+        if data_end > webp_binary.len() {
+            return None;  // ファイルが途中で切れている
+        }
+
+        if fourcc == b"EXIF" {
+            let data = &webp_binary[data_start..data_end];
+            return Some( data.strip_prefix(b"Exif\0\0").unwrap_or(data) );
+        }
+        i = data_end + (size & 1);  // チャンクは偶数バイト境界に揃えられている
+    }
+
+    None
+}
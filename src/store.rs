@@ -0,0 +1,120 @@
+//! ハッシュ値だけで名前を付けたファイルを，gitのオブジェクトのようにハッシュ値の先頭2文字のフォルダに分けて置く保管場所（--content-store）
+//!
+//! ROOT/ab/cdef0123....jpg のように置くので，あるハッシュ値のファイルが既にあるかどうかは，
+//! 保管場所全体ではなくそのフォルダ（全体の1/256程度）を一度読むだけで分かる．
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// フォルダ名に使うハッシュ値の先頭の文字数
+const SHARD_LEN: usize = 2;
+
+/// 保管場所
+pub struct ContentStore {
+    root: PathBuf,
+    /// 読んだフォルダごとの，ハッシュ値（フォルダ名を除いた部分）と置いてあるファイルのパス（Live Photoの動画など，組のファイルも含む）
+    shards: HashMap<String, HashMap<String, Vec<PathBuf>>>,
+}
+
+impl ContentStore {
+    /// 保管場所を開く（無ければ作る．dry_runの場合は作らずに，空の保管場所として扱う）．
+    ///
+    /// ジャーナルに記録するパスが，処理したフォルダからの相対パスにならないように絶対パスにする．
+    pub fn open(root: &Path, dry_run: bool) -> io::Result<ContentStore> {
+        if !dry_run {
+            fs::create_dir_all(root)?;
+        }
+        let root = fs::canonicalize(root).or_else(|_| crate::canonicalize_missing(root))?;
+        Ok(ContentStore { root, shards: HashMap::new() })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// ハッシュ値がhashで拡張子がextのファイルを置くパス
+    pub fn path(&self, hash: &str, ext: &str) -> PathBuf {
+        let (shard, rest) = hash.split_at(SHARD_LEN);
+        self.root.join(shard).join(format!("{}.{}", rest, ext))
+    }
+
+    /// ハッシュ値がhashのファイルのパスを返す（拡張子は問わない，無ければ空）．
+    ///
+    /// フォルダは最初に調べる時に一度だけ読み，その後は覚えておいた内容で調べる．
+    pub fn find(&mut self, hash: &str) -> io::Result<Vec<PathBuf>> {
+        let (shard, rest) = hash.split_at(SHARD_LEN);
+        if !self.shards.contains_key(shard) {
+            let entries = read_shard(&self.root.join(shard))?;
+            self.shards.insert(shard.to_string(), entries);
+        }
+        Ok(self.shards[shard].get(rest).cloned().unwrap_or_default())
+    }
+
+    /// pathに置いたことを記録する（同じ実行の中で，同じハッシュ値のファイルを見つけられるように）．
+    pub fn insert(&mut self, hash: &str, path: &Path) {
+        let (shard, rest) = hash.split_at(SHARD_LEN);
+        self.shards.entry(shard.to_string()).or_default().entry(rest.to_string()).or_default().push(path.to_path_buf());
+    }
+}
+
+/// フォルダ内のファイルを，最初の "." より前（ハッシュ値）で引けるようにする（フォルダが無ければ空）．
+fn read_shard(dir: &Path) -> io::Result<HashMap<String, Vec<PathBuf>>> {
+    let mut entries: HashMap<String, Vec<PathBuf>> = HashMap::new();
+    let read_dir = match fs::read_dir(dir) {
+        Ok(read_dir) => read_dir,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(entries),
+        Err(e) => return Err(e),
+    };
+    for entry in read_dir {
+        let path = entry?.path();
+        let file_name = path.file_name().unwrap().to_string_lossy().into_owned();
+        // サイドカー（.jpg.xmpなど）は画像ではないので除く
+        let (stem, ext) = file_name.split_once('.').unwrap_or((&file_name, ""));
+        if !file_name.starts_with('.') && !ext.contains('.') {
+            entries.entry(stem.to_string()).or_default().push(path);
+        }
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// テスト用の空のフォルダを作る（名前はテストごとに変える）．
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("photo-organizer-test-{}-store-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn files_are_sharded_by_the_hash_prefix() {
+        let root = temp_dir("shard");
+        let mut store = ContentStore::open(&root, false).unwrap();
+        let path = store.path("0ba50ed7c3", "jpg");
+        assert_eq!(path, store.root().join("0b").join("a50ed7c3.jpg"));
+        assert!(store.find("0ba50ed7c3").unwrap().is_empty());
+
+        // 組のファイルは同じハッシュ値で引けるが，サイドカーや "." で始まるファイルは引けない
+        fs::create_dir_all(store.root().join("0b")).unwrap();
+        for name in ["a50ed7c3.heic", "a50ed7c3.mov", "a50ed7c3.heic.xmp", ".a50ed7c3.jpg", "a50ed7c4.jpg"] {
+            fs::write(store.root().join("0b").join(name), name).unwrap();
+        }
+        let mut store = ContentStore::open(&root, false).unwrap();
+        let mut found = store.find("0ba50ed7c3").unwrap();
+        found.sort();
+        assert_eq!(found, [store.root().join("0b/a50ed7c3.heic"), store.root().join("0b/a50ed7c3.mov")]);
+
+        // フォルダは一度だけ読み，その後に置いたファイルはinsertで覚える
+        fs::write(&path, "jpg").unwrap();
+        assert_eq!(store.find("0ba50ed7c3").unwrap().len(), 2);
+        store.insert("0ba50ed7c3", &path);
+        assert_eq!(store.find("0ba50ed7c3").unwrap().len(), 3);
+        assert!(store.find("0bffffffff").unwrap().is_empty());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
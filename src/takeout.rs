@@ -0,0 +1,56 @@
+//! Google Takeoutで書き出された写真のJSONファイルを扱うためのモジュール
+//! IMG_0042.jpgの撮影日時は，IMG_0042.jpg.jsonのphotoTakenTime.timestamp（UNIX時間の文字列）に記録されている
+
+use std::path::{Path, PathBuf};
+
+/// 画像のファイル名の後ろに付く，Takeoutのファイル名の接尾辞（新しいTakeoutではsupplemental-metadataが付く）
+const JSON_SUFFIXES: [&str; 2] = [".json", ".supplemental-metadata.json"];
+
+/// 画像の隣にあるTakeoutのJSONファイルのパスを返す．
+pub fn find_json(path: &Path) -> Option<PathBuf> {
+    let file_name = path.file_name()?.to_str()?;
+    JSON_SUFFIXES.iter()
+        .map(|suffix| path.with_file_name(format!("{}{}", file_name, suffix)))
+        .find(|json_path| json_path.is_file())
+}
+
+/// 空白を読み飛ばす．
+fn skip_whitespace(s: &[u8], mut i: usize) -> usize {
+    while s.get(i).is_some_and(|c| c.is_ascii_whitespace()) {
+        i += 1;
+    }
+    i
+}
+
+/// s[i]から始まる "key": の直後のインデックスを返す．
+fn after_key(s: &[u8], i: usize) -> Option<usize> {
+    let i = skip_whitespace(s, i);
+    if s.get(i) != Some(&b':') {
+        return None;
+    }
+    Some(skip_whitespace(s, i + 1))
+}
+
+/// 撮影日時（photoTakenTime.timestamp）をUNIX時間で返す．
+/// 
+/// JSONパーサを使うほどでもないので，必要なキーだけを探して読む．
+/// JSONが壊れている場合やキーが見つからない場合はNoneを返す．
+pub fn get_photo_taken_time(json: &[u8]) -> Option<i64> {
+    const KEY: &[u8] = b"\"photoTakenTime\"";
+    let start = json.windows(KEY.len()).position(|w| w == KEY)? + KEY.len();
+    let start = after_key(json, start)?;
+    if json.get(start) != Some(&b'{') {
+        return None;
+    }
+    // photoTakenTimeのオブジェクトの中だけを探す（formattedの文字列に "}" が入ることはない）
+    let end = start + json[start..].iter().position(|c| *c == b'}')?;
+    let object = &json[start..end];
+
+    const TIMESTAMP_KEY: &[u8] = b"\"timestamp\"";
+    let i = object.windows(TIMESTAMP_KEY.len()).position(|w| w == TIMESTAMP_KEY)? + TIMESTAMP_KEY.len();
+    let i = after_key(object, i)?;
+    // Takeoutでは文字列として書かれているが，数値の場合も受け付ける
+    let value = object[i..].strip_prefix(b"\"").unwrap_or(&object[i..]);
+    let len = value.iter().position(|c| !c.is_ascii_digit()).unwrap_or(value.len());
+    std::str::from_utf8(&value[..len]).ok()?.parse().ok()
+}
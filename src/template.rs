@@ -0,0 +1,388 @@
+//! ファイル名のテンプレート（--template）を扱うためのモジュール
+//! "IMG_{date}_{hash}" のような文字列を読み，要素の値を埋め込んで拡張子を除いたファイル名を作る
+
+use std::ops::Range;
+
+use crate::datetime::DateFormat;
+
+/// テンプレートで使える要素の名前（エラーの表示用）
+const TOKENS: &str = "{date}, {time}, {hash}, {hashalg}, {orig}, {model}, {location}, {prefix}, {ext}";
+
+/// ファイル名に使えない文字（Windowsで使えないものも含める）
+const FORBIDDEN_CHARS: &[char] = &['/', '\\', ':', '*', '?', '"', '<', '>', '|'];
+
+/// 値が無い要素を取り除く時に，一緒に取り除く区切り文字
+const SEPARATORS: &[char] = &['_', '-', ' ', '.'];
+
+/// テンプレートの要素
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Part {
+    /// そのまま使う文字列（"{{" と "}}" は "{" と "}" にしたもの）
+    Text(String),
+    /// 撮影日（YYYY-MM-DD，--date-formatの日付の部分）
+    Date,
+    /// 撮影時刻（HHMM，--precisionの指定によってはHHMMSSやHHMMSS.xx，--date-formatの時刻の部分）
+    Time,
+    /// ハッシュ値（--hash-source uniqueidの場合はImageUniqueID）
+    Hash,
+    /// ハッシュ値のアルゴリズムの名前（後でどのアルゴリズムで付けた名前か分かるようにする，画素データの場合は "-pixels"，メタデータを除いた場合は "-stripped" を付ける）
+    HashAlg,
+    /// 元のファイル名（拡張子を除く）
+    Orig,
+    /// 機種名
+    Model,
+    /// 撮影場所（--gps-nameの形式）
+    Location,
+    /// 撮影者などを表す接頭辞（--prefix，またはフォルダの ".photo-organizer-prefix" の内容）
+    Prefix,
+    /// 拡張子（"." を含まない）
+    Ext,
+}
+
+/// ファイル名のテンプレート
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+/// テンプレートに埋め込む値．値が無いものはNone．
+pub struct Values<'a> {
+    pub date: Option<&'a str>,
+    pub time: Option<&'a str>,
+    pub hash: &'a str,
+    pub hash_alg: &'a str,
+    pub orig: &'a str,
+    pub model: Option<&'a str>,
+    pub location: Option<&'a str>,
+    pub prefix: Option<&'a str>,
+    pub ext: &'a str,
+}
+
+/// テンプレートで付けた名前から読み取った値（verifyサブコマンドで使う）．テンプレートに無い要素はNone．
+pub struct Captures<'a> {
+    pub date: Option<&'a str>,
+    pub time: Option<&'a str>,
+    pub hash: Option<&'a str>,
+    /// ハッシュ値の位置（--rehashでハッシュ値の部分だけを付け直すのに使う）
+    pub hash_range: Option<Range<usize>>,
+    pub hash_alg: Option<&'a str>,
+}
+
+impl Template {
+    /// テンプレートの文字列を読む（clapのvalue_parserとして使う）．
+    /// 
+    /// "{" と "}" そのものは "{{" と "}}" と書く．知らない要素や閉じていない括弧，
+    /// ファイル名に使えない文字（"/" など）がある場合は，どこが悪いかを書いたエラーを返す．
+    pub fn parse(s: &str) -> Result<Template, String> {
+        let mut parts = Vec::new();
+        let mut text = String::new();
+        let mut chars = s.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.as_str().starts_with('{') => {
+                    chars.next();
+                    text.push('{');
+                },
+                '}' if chars.as_str().starts_with('}') => {
+                    chars.next();
+                    text.push('}');
+                },
+                '{' => {
+                    let (name, rest) = chars.as_str().split_once('}')
+                        .ok_or_else(|| format!("\"{{\" is not closed (write \"{{{{\" for the character itself). Available tokens: {}", TOKENS))?;
+                    let part = match name {
+                        "date"     => Part::Date,
+                        "time"     => Part::Time,
+                        "hash"     => Part::Hash,
+                        "hashalg"  => Part::HashAlg,
+                        "orig"     => Part::Orig,
+                        "model"    => Part::Model,
+                        "location" => Part::Location,
+                        "prefix"   => Part::Prefix,
+                        "ext"      => Part::Ext,
+                        _ => return Err(format!("Unknown token \"{{{}}}\". Available tokens: {}", name, TOKENS)),
+                    };
+                    if !text.is_empty() {
+                        parts.push(Part::Text(std::mem::take(&mut text)));
+                    }
+                    parts.push(part);
+                    chars = rest.chars();
+                },
+                '}' => return Err("\"}\" is not opened (write \"}}\" for the character itself).".to_string()),
+                c => {
+                    check_char(c)?;
+                    text.push(c);
+                },
+            }
+        }
+        if !text.is_empty() {
+            parts.push(Part::Text(text));
+        }
+        if parts.is_empty() {
+            return Err("The template is empty.".to_string());
+        }
+        Ok(Template { parts })
+    }
+
+    /// 機種名を含むかどうか（--model-nameを指定しなくても機種名を読む）．
+    pub fn has_model(&self) -> bool {
+        self.parts.contains(&Part::Model)
+    }
+
+    /// 撮影場所を含むかどうか（--gps-nameを指定しなくても位置情報を読む）．
+    pub fn has_location(&self) -> bool {
+        self.parts.contains(&Part::Location)
+    }
+
+    /// 接頭辞を含むかどうか（含まない場合は，--prefixの接頭辞をテンプレートで作った名前の前に付ける）．
+    pub fn has_prefix(&self) -> bool {
+        self.parts.contains(&Part::Prefix)
+    }
+
+    /// ファイル名（拡張子と，重複を避けるための "-1" などの番号を除く）がこのテンプレートで付けた名前の形をしているかどうかを返す（--skip-organized）．
+    /// 
+    /// 要素ごとに値として有り得る文字列かどうかだけを確認する（撮影日時やハッシュ値が正しいかどうかは確認しない）．
+    /// 撮影日時の無いファイルなど，renderで値が無い要素を取り除いた名前も一致する．撮影日時はformat（--date-format）の形かどうかを確認する．
+    pub fn matches(&self, stem: &str, format: &DateFormat) -> bool {
+        self.captures(stem, format).is_some()
+    }
+
+    /// ファイル名（拡張子と番号を除く）がこのテンプレートの形をしている場合は，撮影日時やハッシュ値の部分を返す．
+    /// 
+    /// 値が無いことのある要素を取り除いた形も，shapesの順に試す．
+    pub fn captures<'a>(&self, stem: &'a str, format: &DateFormat) -> Option<Captures<'a>> {
+        self.shapes().iter().find_map(|parts| {
+            let values = match_parts(parts, stem, format)?;
+            let find = |part: Part| values.iter().find(|(p, _)| **p == part).map(|&(_, value)| value);
+            // 要素の値は名前の先頭から順に並んでいるので，前の要素の長さを足せば位置が分かる
+            let hash_range = values.iter().position(|(part, _)| **part == Part::Hash).map(|i| {
+                let start = values[..i].iter().map(|(_, value)| value.len()).sum();
+                start..start + values[i].1.len()
+            });
+            Some(Captures {
+                date: find(Part::Date),
+                time: find(Part::Time),
+                hash: find(Part::Hash),
+                hash_range,
+                hash_alg: find(Part::HashAlg),
+            })
+        })
+    }
+
+    /// renderが作ることのある名前の形を，要素の列で返す（値が無いことのある要素を取り除いた形も含む）．
+    /// 
+    /// 取り除く要素が少ない形から，同じ数なら撮影日時を残す形から順に並べる
+    /// （時刻の数字は機種名としても有り得るので，機種名などが無い名前を撮影時刻が無い名前と取り違えないようにする）．
+    fn shapes(&self) -> Vec<Vec<Part>> {
+        let mut optional: Vec<&Part> = Vec::new();
+        for part in self.parts.iter().filter(|part| part.is_optional()) {
+            if !optional.contains(&part) {
+                optional.push(part);
+            }
+        }
+        let bit = |part: Part| optional.iter().position(|p| **p == part).map_or(0, |i| 1u32 << i);
+        let (date, time) = (bit(Part::Date), bit(Part::Time));
+        // 撮影日が無い場合は撮影時刻も無い
+        let mut masks: Vec<u32> = (0..1 << optional.len()).filter(|mask| mask & date == 0 || mask & time == time).collect();
+        masks.sort_by_key(|mask| (mask.count_ones(), (mask & (date | time)).count_ones()));
+        masks.into_iter().map(|missing| {
+            without_parts(&self.parts, |part| optional.iter().position(|p| *p == part).is_some_and(|i| missing & (1 << i) != 0))
+        }).collect()
+    }
+
+    /// 値を埋め込んだファイル名（拡張子を除く）を返す．
+    /// 
+    /// 値が無い要素は，without_partsで隣の区切り文字と一緒に取り除く．何も残らない場合はハッシュ値を返す．
+    pub fn render(&self, values: &Values) -> String {
+        let name: String = without_parts(&self.parts, |part| part.value(values).is_none()).iter()
+            .filter_map(|part| part.value(values))
+            .collect();
+        if name.is_empty() {
+            values.hash.to_string()
+        } else {
+            name
+        }
+    }
+}
+
+impl Part {
+    /// 要素の値を返す．値が無い場合（空の場合も含む）はNone．
+    fn value<'a>(&'a self, values: &Values<'a>) -> Option<&'a str> {
+        let value = match self {
+            Part::Text(text) => Some(text.as_str()),
+            Part::Date     => values.date,
+            Part::Time     => values.time,
+            Part::Hash     => Some(values.hash),
+            Part::HashAlg  => Some(values.hash_alg),
+            Part::Orig     => Some(values.orig),
+            Part::Model    => values.model,
+            Part::Location => values.location,
+            Part::Prefix   => values.prefix,
+            Part::Ext      => Some(values.ext),
+        };
+        value.filter(|value| !value.is_empty())
+    }
+
+    /// 値が無いことがある要素かどうか（renderで取り除かれることがある）．
+    fn is_optional(&self) -> bool {
+        matches!(self, Part::Date | Part::Time | Part::Model | Part::Location | Part::Prefix)
+    }
+}
+
+/// is_missingがtrueの要素の値が無い場合に，renderが作る名前の形を要素の列で返す．
+/// 
+/// 値が無い要素は，"{date}_{hash}" で撮影日時が無い場合に "_" が残らないように，
+/// 直後（直後が無ければ直前）の区切り文字（"_"，"-"，" "，"."）を一つ一緒に取り除く．
+/// "{date}T{time}" の "T" のように，値が無い要素に挟まれた文字列も取り除き，
+/// 最後に先頭と末尾に残った区切り文字を取り除く（値そのものの区切り文字は取り除かない）．
+fn without_parts(parts: &[Part], is_missing: impl Fn(&Part) -> bool) -> Vec<Part> {
+    let mut pieces: Vec<Option<Part>> = parts.iter().map(|part| Some(part.clone()).filter(|part| !is_missing(part))).collect();
+    if pieces.iter().all(Option::is_some) {
+        return parts.to_vec();
+    }
+
+    // 値が無い要素に挟まれた文字列を取り除き，連続した値が無い要素を一つにまとめる
+    for i in 1..pieces.len().saturating_sub(1) {
+        if pieces[i - 1].is_none() && pieces[i + 1].is_none() {
+            pieces[i] = None;
+        }
+    }
+    pieces.dedup_by(|a, b| a.is_none() && b.is_none());
+
+    for i in 0..pieces.len() {
+        if pieces[i].is_some() {
+            continue;
+        }
+        if let Some(Some(Part::Text(next))) = pieces.get_mut(i + 1) {
+            if next.starts_with(SEPARATORS) {
+                next.remove(0);
+                continue;
+            }
+        }
+        if let Some(Some(Part::Text(prev))) = i.checked_sub(1).map(|i| &mut pieces[i]) {
+            if prev.ends_with(SEPARATORS) {
+                prev.pop();
+            }
+        }
+    }
+
+    let mut parts: Vec<Part> = pieces.into_iter().flatten().filter(|part| *part != Part::Text(String::new())).collect();
+    while let Some(Part::Text(text)) = parts.first_mut() {
+        *text = text.trim_start_matches(SEPARATORS).to_string();
+        if !text.is_empty() {
+            break;
+        }
+        parts.remove(0);
+    }
+    while let Some(Part::Text(text)) = parts.last_mut() {
+        *text = text.trim_end_matches(SEPARATORS).to_string();
+        if !text.is_empty() {
+            break;
+        }
+        parts.pop();
+    }
+    parts
+}
+
+/// ファイル名に使える文字かどうかを確かめ，使えない場合はエラーを返す（--prefixの確認にも使う）．
+pub fn check_char(c: char) -> Result<(), String> {
+    if FORBIDDEN_CHARS.contains(&c) || c.is_control() {
+        Err(format!("\"{}\" cannot be used in file names.", c.escape_debug()))
+    } else {
+        Ok(())
+    }
+}
+
+/// テンプレートの要素の列が文字列全体に一致する場合は，要素ごとの値を返す（そのまま使う文字列も含む）．
+/// 
+/// 要素がどこまで続くかは分からないので，短い方から順に試す．
+fn match_parts<'p, 's>(parts: &'p [Part], s: &'s str, format: &DateFormat) -> Option<Vec<(&'p Part, &'s str)>> {
+    let (part, rest) = match parts.split_first() {
+        Some(split) => split,
+        None => return s.is_empty().then(Vec::new),
+    };
+    match part {
+        Part::Text(text) => {
+            let mut values = match_parts(rest, s.strip_prefix(text.as_str())?, format)?;
+            values.insert(0, (part, &s[..text.len()]));
+            Some(values)
+        },
+        _ => (1..=s.len())
+            .filter(|&len| s.is_char_boundary(len) && matches_value(part, &s[..len], format))
+            .find_map(|len| {
+                let mut values = match_parts(rest, &s[len..], format)?;
+                values.insert(0, (part, &s[..len]));
+                Some(values)
+            }),
+    }
+}
+
+/// 要素の値として有り得る文字列かどうかを返す．
+fn matches_value(part: &Part, value: &str, format: &DateFormat) -> bool {
+    match part {
+        Part::Text(text) => value == text,
+        Part::Date => format.is_date(value),
+        Part::Time => format.is_time(value),
+        Part::Hash => is_hash(value),
+        Part::HashAlg => value == "uniqueid"
+            || ["crc32", "sha256", "blake3", "xxh3"].contains(&value.trim_end_matches("-pixels").trim_end_matches("-stripped")),
+        Part::Orig => true,
+        Part::Model => value.chars().all(|c| c.is_ascii_alphanumeric() || "-.+".contains(c)),
+        Part::Location => value.chars().all(|c| c.is_ascii_alphanumeric() || c == '.'),
+        Part::Prefix => value.chars().all(|c| check_char(c).is_ok()),
+        Part::Ext => value.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit()),
+    }
+}
+
+/// ファイル名に入れるハッシュ値（4～64文字の16進数・36進数・62進数，または16文字の英数字のImageUniqueID）の形をしているかどうかを返す．
+/// 
+/// --hashや--hash-length，--hash-encodingを変える前に付けた名前も一致するように，どの長さと表記も受け付ける．
+pub fn is_hash(value: &str) -> bool {
+    (4..=64).contains(&value.len()) && value.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rendered_names_are_captured_with_or_without_optional_values() {
+        let format = DateFormat::default();
+        let templates = ["IMG_{date}T{time}_{hash}", "{date}_{time}_{model}_{hash}", "{prefix}-{date} {location}.{hash}", "{hash}_{model}", "{orig}_{date}_{hashalg}_{hash}"];
+        for template in templates.map(|s| Template::parse(s).unwrap()) {
+            // 撮影日時・機種名・撮影場所・接頭辞の，有る無しの全ての組み合わせ
+            for present in 0..1 << 4 {
+                let has = |bit: u32| present & (1 << bit) != 0;
+                let values = Values {
+                    date: has(0).then_some("2023-01-23"),
+                    time: has(0).then_some("143027"),
+                    hash: "0ba50ed7",
+                    hash_alg: "crc32",
+                    orig: "DSC_0001",
+                    model: has(1).then_some("ILCE-7M3"),
+                    location: has(2).then_some("35.68N139.77E"),
+                    prefix: has(3).then_some("taro"),
+                    ext: "jpg",
+                };
+                let name = template.render(&values);
+                let captures = template.captures(&name, &format).unwrap_or_else(|| panic!("{:?} did not match {:?}", name, template));
+                assert_eq!(captures.hash, Some("0ba50ed7"), "{}", name);
+                assert_eq!(&name[captures.hash_range.unwrap()], "0ba50ed7");
+                if template.parts.contains(&Part::Date) {
+                    assert_eq!(captures.date, values.date, "{}", name);
+                }
+                if template.parts.contains(&Part::Time) {
+                    assert_eq!(captures.time, values.time, "{}", name);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn undated_name_is_captured() {
+        let template = Template::parse("IMG_{date}T{time}_{hash}").unwrap();
+        let captures = template.captures("IMG_0ba50ed7", &DateFormat::default()).unwrap();
+        assert_eq!((captures.date, captures.time, captures.hash), (None, None, Some("0ba50ed7")));
+        assert!(!template.matches("IMG_0ba50ed7_extra", &DateFormat::default()));
+    }
+}
@@ -0,0 +1,103 @@
+//! ファイル名のUnicode正規化（NFC，NFD）を扱うためのモジュール
+//!
+//! macOS（HFS+）は "é" のような文字を分解した形（NFD）で，LinuxやWindowsは合成した形（NFC）で名前を持つことが多いので，
+//! 同じ名前でもバイト列が違うことがある．正規化はunicode-normalizationクレートで行う．
+
+use std::fs;
+use std::path::Path;
+
+use unicode_normalization::UnicodeNormalization;
+
+/// NFC（合成した形）にする．
+pub fn nfc(s: &str) -> String {
+    if s.is_ascii() {
+        return s.to_string();
+    }
+    s.nfc().collect()
+}
+
+/// NFD（分解した形）にする．
+pub fn nfd(s: &str) -> String {
+    if s.is_ascii() {
+        return s.to_string();
+    }
+    s.nfd().collect()
+}
+
+/// 正規化すると同じ文字列になるかどうかを返す．
+pub fn is_equivalent(a: &str, b: &str) -> bool {
+    a == b || (!a.is_ascii() && !b.is_ascii() && a.nfd().eq(b.nfd()))
+}
+
+/// 同じフォルダの中の，正規化すると同じ名前になるパスかどうかを返す．
+pub fn is_same_path(a: &Path, b: &Path) -> bool {
+    a == b || (a.parent() == b.parent() && match (a.file_name(), b.file_name()) {
+        (Some(a), Some(b)) => is_equivalent(&a.to_string_lossy(), &b.to_string_lossy()),
+        _ => false,
+    })
+}
+
+/// そのパスのフォルダに，正規化すると同じ名前になる別の形のファイルやフォルダがあるかどうかを返す．
+///
+/// 正規化しないファイルシステム（Linuxなど）では "café.jpg" のNFCとNFDは別のファイルになるが，
+/// 見た目が同じ名前が二つできてしまうので，使われている名前として扱う．ASCIIだけの名前はフォルダを読まずにfalseを返す．
+pub fn has_equivalent_entry(path: &Path) -> bool {
+    let (dir, name) = match (path.parent(), path.file_name()) {
+        (Some(dir), Some(name)) if !name.to_string_lossy().is_ascii() => (dir, name.to_string_lossy()),
+        _ => return false,
+    };
+    let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return false,
+    };
+    entries.flatten().any(|entry| is_equivalent(&entry.file_name().to_string_lossy(), &name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_answers() {
+        // （入力，NFC，NFD）
+        let cases = [
+            ("e\u{301}", "\u{E9}", "e\u{301}"),
+            ("\u{E9}", "\u{E9}", "e\u{301}"),
+            // 一文字への分解（ANGSTROM SIGN，OHM SIGN）
+            ("\u{212B}", "\u{C5}", "A\u{30A}"),
+            ("\u{2126}", "\u{3A9}", "\u{3A9}"),
+            // 結合文字の並べ替え（結合クラス220の下の点が230の曲折アクセントより先）
+            ("a\u{302}\u{323}", "\u{1EAD}", "a\u{323}\u{302}"),
+            ("\u{1E0D}\u{307}", "\u{1E0D}\u{307}", "d\u{323}\u{307}"),
+            // 先頭の結合文字は合成しない
+            ("\u{301}e", "\u{301}e", "\u{301}e"),
+            // 濁点・半濁点（macOSで作ったファイル名）
+            ("\u{304B}\u{3099}", "\u{304C}", "\u{304B}\u{3099}"),
+            ("\u{30CF}\u{309A}\u{30B9}\u{30DB}\u{309A}\u{30FC}\u{30C8}", "\u{30D1}\u{30B9}\u{30DD}\u{30FC}\u{30C8}", "\u{30CF}\u{309A}\u{30B9}\u{30DB}\u{309A}\u{30FC}\u{30C8}"),
+            // 合成の除外（U+0958は分解するが合成し直さない）
+            ("\u{958}", "\u{915}\u{93C}", "\u{915}\u{93C}"),
+            ("\u{915}\u{93C}", "\u{915}\u{93C}", "\u{915}\u{93C}"),
+            // 分解すると二つになり，合成しない（U+0344）
+            ("\u{344}", "\u{308}\u{301}", "\u{308}\u{301}"),
+            ("IMG_0001.jpg", "IMG_0001.jpg", "IMG_0001.jpg"),
+        ];
+        for (s, expected_nfc, expected_nfd) in cases {
+            assert_eq!(nfc(s), expected_nfc, "NFC of {:?}", s.escape_unicode().to_string());
+            assert_eq!(nfd(s), expected_nfd, "NFD of {:?}", s.escape_unicode().to_string());
+        }
+    }
+
+    #[test]
+    fn same_path_in_nfc_and_nfd() {
+        let nfc_path = Path::new("/photos/caf\u{E9}/\u{30D1}\u{30EA}.jpg");
+        let nfd_path = Path::new("/photos/caf\u{E9}/\u{30CF}\u{309A}\u{30EA}.jpg");
+        assert!(is_same_path(nfc_path, nfd_path));
+        assert!(is_same_path(nfd_path, nfc_path));
+        assert!(is_equivalent("caf\u{E9}.jpg", "cafe\u{301}.jpg"));
+        assert!(!is_equivalent("caf\u{E9}.jpg", "cafe.jpg"));
+        // 別のフォルダや，正規化しても違う名前は別のパス
+        assert!(!is_same_path(nfc_path, Path::new("/photos/cafe\u{301}/\u{30CF}\u{309A}\u{30EA}.jpg")));
+        assert!(!is_same_path(nfc_path, Path::new("/photos/caf\u{E9}/\u{30CF}\u{30EA}.jpg")));
+    }
+}
@@ -0,0 +1,172 @@
+//! ファイルの拡張属性（xattr）にリネーム前のファイル名を記録するためのモジュール（--xattr-original-name）
+//!
+//! 画像の中身は書き換えないので，ハッシュ値は変わらない．LinuxとmacOSは拡張属性，Windows（NTFS）は代替データストリームに書く．
+
+use std::ffi::{OsStr, OsString};
+use std::io;
+use std::path::Path;
+
+/// リネーム前のファイル名を記録する属性の名前
+pub const ORIGINAL_NAME: &str = "user.photo_organizer.original_name";
+
+/// 拡張属性に対応していないファイルシステムの場合のエラーかどうかを返す（一度だけ警告するために使う）．
+pub fn is_unsupported(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::Unsupported
+}
+
+/// リネーム前のファイル名を記録する．
+pub fn set_original_name(path: &Path, name: &OsStr) -> io::Result<()> {
+    set(path, ORIGINAL_NAME, &to_bytes(name))
+}
+
+/// 記録したリネーム前のファイル名を返す（記録していない場合はNone）．
+pub fn get_original_name(path: &Path) -> io::Result<Option<OsString>> {
+    Ok(get(path, ORIGINAL_NAME)?.map(from_bytes))
+}
+
+#[cfg(unix)]
+fn to_bytes(name: &OsStr) -> Vec<u8> {
+    use std::os::unix::ffi::OsStrExt;
+    name.as_bytes().to_vec()
+}
+
+#[cfg(unix)]
+fn from_bytes(bytes: Vec<u8>) -> OsString {
+    use std::os::unix::ffi::OsStringExt;
+    OsString::from_vec(bytes)
+}
+
+/// Unix系以外ではUTF-8で記録する．
+#[cfg(not(unix))]
+fn to_bytes(name: &OsStr) -> Vec<u8> {
+    name.to_string_lossy().into_owned().into_bytes()
+}
+
+#[cfg(not(unix))]
+fn from_bytes(bytes: Vec<u8>) -> OsString {
+    String::from_utf8_lossy(&bytes).into_owned().into()
+}
+
+/// パスと属性の名前をCの文字列にする．
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn c_strings(path: &Path, name: &str) -> io::Result<(std::ffi::CString, std::ffi::CString)> {
+    use std::os::unix::ffi::OsStrExt;
+    let invalid = |_| io::Error::new(io::ErrorKind::InvalidInput, "The path contains a NUL character.");
+    Ok((std::ffi::CString::new(path.as_os_str().as_bytes()).map_err(invalid)?, std::ffi::CString::new(name).map_err(invalid)?))
+}
+
+/// 直前のシステムコールのエラーを返す（拡張属性に対応していない場合はUnsupportedにする）．
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn last_error() -> io::Error {
+    let e = io::Error::last_os_error();
+    match e.raw_os_error() {
+        Some(libc::ENOTSUP) => io::Error::new(io::ErrorKind::Unsupported, e),
+        _ => e,
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set(path: &Path, name: &str, value: &[u8]) -> io::Result<()> {
+    let (path, name) = c_strings(path, name)?;
+    // SAFETY: pathとnameはNUL終端の文字列で，valueはvalue.len()バイト読める
+    let res = unsafe { libc::setxattr(path.as_ptr(), name.as_ptr(), value.as_ptr().cast(), value.len(), 0) };
+    if res == 0 { Ok(()) } else { Err(last_error()) }
+}
+
+#[cfg(target_os = "linux")]
+fn get(path: &Path, name: &str) -> io::Result<Option<Vec<u8>>> {
+    let (path, name) = c_strings(path, name)?;
+    // 長さを調べてから読む（間に書き換えられて長くなった場合はERANGEになるので，もう一度調べる）
+    loop {
+        // SAFETY: 長さを調べるだけなので，書き込み先はNULでよい
+        let len = unsafe { libc::getxattr(path.as_ptr(), name.as_ptr(), std::ptr::null_mut(), 0) };
+        if len < 0 {
+            return match io::Error::last_os_error().raw_os_error() {
+                Some(libc::ENODATA) => Ok(None),
+                _ => Err(last_error()),
+            };
+        }
+        let mut value = vec![0u8; len as usize];
+        // SAFETY: valueはvalue.len()バイト書き込める
+        let len = unsafe { libc::getxattr(path.as_ptr(), name.as_ptr(), value.as_mut_ptr().cast(), value.len()) };
+        if len >= 0 {
+            value.truncate(len as usize);
+            return Ok(Some(value));
+        }
+        if io::Error::last_os_error().raw_os_error() != Some(libc::ERANGE) {
+            return Err(last_error());
+        }
+    }
+}
+
+/// macOSの関数は，リソースフォーク用の位置とオプションの引数が多い．
+#[cfg(target_os = "macos")]
+fn set(path: &Path, name: &str, value: &[u8]) -> io::Result<()> {
+    let (path, name) = c_strings(path, name)?;
+    // SAFETY: pathとnameはNUL終端の文字列で，valueはvalue.len()バイト読める
+    let res = unsafe { libc::setxattr(path.as_ptr(), name.as_ptr(), value.as_ptr().cast(), value.len(), 0, 0) };
+    if res == 0 { Ok(()) } else { Err(last_error()) }
+}
+
+#[cfg(target_os = "macos")]
+fn get(path: &Path, name: &str) -> io::Result<Option<Vec<u8>>> {
+    let (path, name) = c_strings(path, name)?;
+    loop {
+        // SAFETY: 長さを調べるだけなので，書き込み先はNULでよい
+        let len = unsafe { libc::getxattr(path.as_ptr(), name.as_ptr(), std::ptr::null_mut(), 0, 0, 0) };
+        if len < 0 {
+            return match io::Error::last_os_error().raw_os_error() {
+                Some(libc::ENOATTR) => Ok(None),
+                _ => Err(last_error()),
+            };
+        }
+        let mut value = vec![0u8; len as usize];
+        // SAFETY: valueはvalue.len()バイト書き込める
+        let len = unsafe { libc::getxattr(path.as_ptr(), name.as_ptr(), value.as_mut_ptr().cast(), value.len(), 0, 0) };
+        if len >= 0 {
+            value.truncate(len as usize);
+            return Ok(Some(value));
+        }
+        if io::Error::last_os_error().raw_os_error() != Some(libc::ERANGE) {
+            return Err(last_error());
+        }
+    }
+}
+
+/// Windowsでは "ファイル名:属性の名前" の代替データストリームに書く（NTFS以外ではエラーになる）．
+#[cfg(windows)]
+fn stream_path(path: &Path, name: &str) -> std::path::PathBuf {
+    let mut stream = path.as_os_str().to_os_string();
+    stream.push(":");
+    stream.push(name.trim_start_matches("user."));
+    stream.into()
+}
+
+#[cfg(windows)]
+fn set(path: &Path, name: &str, value: &[u8]) -> io::Result<()> {
+    std::fs::write(stream_path(path, name), value).map_err(|e| match e.raw_os_error() {
+        // ERROR_INVALID_NAME（FATなど，代替データストリームに対応していない）
+        Some(123) => io::Error::new(io::ErrorKind::Unsupported, e),
+        _ => e,
+    })
+}
+
+#[cfg(windows)]
+fn get(path: &Path, name: &str) -> io::Result<Option<Vec<u8>>> {
+    match std::fs::read(stream_path(path, name)) {
+        Ok(value) => Ok(Some(value)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound || e.raw_os_error() == Some(123) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// その他のOSでは記録できない．
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+fn set(_path: &Path, _name: &str, _value: &[u8]) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "Extended attributes are not supported on this system."))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+fn get(_path: &Path, _name: &str) -> io::Result<Option<Vec<u8>>> {
+    Ok(None)
+}
@@ -0,0 +1,131 @@
+//! XMP（Adobeの拡張可能なメタデータ）を扱うためのモジュール
+//! JPEGではAPP1セグメントに "http://ns.adobe.com/xap/1.0/\0" に続けてXMLで格納されている
+
+use crate::datetime;
+use crate::jpeg;
+
+/// XMPのAPP1セグメントの識別子
+pub const IDENTIFIER: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+
+/// 撮影日時として使うプロパティ（優先順）
+const DATE_PROPERTIES: [&str; 3] = [
+    "exif:DateTimeOriginal",
+    "photoshop:DateCreated",
+    "xmp:CreateDate",
+];
+
+/// XMPパケットに書かれた回転情報（tiff:Orientation）を1（回転なし）に書き換える．
+/// 
+/// 属性（tiff:Orientation="6"）と要素（<tiff:Orientation>6</tiff:Orientation>）のどちらの書き方にも対応する．
+/// 値は1桁なので，書き換えてもセグメント長は変わらない．
+pub fn clear_orientation(xmp: &mut [u8]) {
+    for prefix in [&b"tiff:Orientation=\""[..], b"tiff:Orientation='", b"<tiff:Orientation>"] {
+        let mut i = 0;
+        while let Some(pos) = xmp[i..].windows(prefix.len()).position(|w| w == prefix) {
+            let value = i + pos + prefix.len();
+            if xmp.get(value).is_some_and(|c| (b'1'..=b'8').contains(c)) {
+                xmp[value] = b'1';
+            }
+            i = value;
+        }
+    }
+}
+
+/// XMPを格納したAPP1セグメント（マーカから）かどうかを返す．
+pub fn is_xmp_app1(segment: &[u8]) -> bool {
+    segment.get(4..(4 + IDENTIFIER.len())) == Some(IDENTIFIER)
+}
+
+/// JPEG画像のXMPのAPP1セグメントから，XMPパケット（識別子より後ろのXML）を返す．
+pub fn get_xmp(jpeg_binary: &[u8]) -> Option<&[u8]> {
+    jpeg::segments(jpeg_binary)
+        .take_while(|s| s.marker != jpeg::SOS)
+        .filter(|s| s.marker == jpeg::APP1)
+        .find_map(|s| jpeg_binary[(s.start + 4)..s.end].strip_prefix(IDENTIFIER))
+}
+
+/// XMPパケットからプロパティの値を返す．
+/// 
+/// 属性（xmp:CreateDate="..."）と要素（<xmp:CreateDate>...</xmp:CreateDate>）のどちらの書き方にも対応する．
+fn get_property<'a>(xmp: &'a [u8], name: &str) -> Option<&'a [u8]> {
+    let name = name.as_bytes();
+    let mut i = 0;
+    while let Some(pos) = xmp[i..].windows(name.len()).position(|w| w == name) {
+        let start = i + pos;
+        let after = &xmp[(start + name.len())..];
+        match (xmp[..start].last(), after.first()) {
+            // 属性
+            (Some(c), Some(b'=')) if c.is_ascii_whitespace() => {
+                let quote = *after.get(1)?;
+                if quote == b'"' || quote == b'\'' {
+                    let value = &after[2..];
+                    return Some(&value[..value.iter().position(|c| *c == quote)?]);
+                }
+            },
+            // 要素
+            (Some(b'<'), Some(b'>')) => {
+                let value = &after[1..];
+                return Some(&value[..value.iter().position(|c| *c == b'<')?]);
+            },
+            _ => (),
+        }
+        i = start + name.len();
+    }
+    None
+}
+
+/// XMPパケットに書かれた撮影日時をExifの日時形式で返す．
+/// 
+/// XMPの日時はISO 8601形式で，秒や時刻が省略されている場合は0で補う．
+/// タイムゾーンが付いている場合はExifと同じように無視して，書かれている時刻をそのまま使う．
+pub fn get_date_time(xmp: &[u8]) -> Option<[u8; 19]> {
+    DATE_PROPERTIES.iter().find_map(|name| {
+        let value = std::str::from_utf8(get_property(xmp, name)?).ok()?.trim();
+        let date = value.get(..10)?;  // YYYY-MM-DD
+        let time = value.get(10..)?.trim_start_matches(['T', ' ']);
+        let time = &time[..time.find(['+', '-', 'Z']).unwrap_or(time.len())];  // タイムゾーンを除く
+        let time = match time.len() {
+            0 => String::from("00:00:00"),
+            5 => format!("{}:00", time),  // HH:MM
+            _ => time.to_string(),
+        };
+        datetime::from_iso8601(&format!("{}T{}", date, time))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn create_date_with_fractional_seconds_and_time_zone() {
+        let dates = [
+            ("2023-01-23T14:30:27.42+09:00", b"2023:01:23 14:30:27"),
+            ("2023-01-23T14:30:27.123-05:00", b"2023:01:23 14:30:27"),
+            ("2023-01-23T14:30:27Z", b"2023:01:23 14:30:27"),
+            ("2023-01-23T14:30+09:00", b"2023:01:23 14:30:00"),
+            ("2023-01-23", b"2023:01:23 00:00:00"),
+        ];
+        for (value, expected) in dates {
+            let element = format!("<rdf:Description><xmp:CreateDate>{}</xmp:CreateDate></rdf:Description>", value);
+            assert_eq!(get_date_time(element.as_bytes()).as_ref(), Some(expected), "{}", value);
+            let attribute = format!("<rdf:Description xmp:CreateDate='{}'/>", value);
+            assert_eq!(get_date_time(attribute.as_bytes()).as_ref(), Some(expected), "{}", value);
+        }
+    }
+
+    #[test]
+    fn date_time_original_is_preferred() {
+        let xmp = b"<rdf:Description xmp:CreateDate=\"2023-01-24T09:00:00.5+09:00\" exif:DateTimeOriginal=\"2023-01-23T14:30:27\"/>";
+        assert_eq!(get_date_time(xmp), Some(*b"2023:01:23 14:30:27"));
+    }
+
+    #[test]
+    fn xmp_is_read_from_jpeg() {
+        let packet = b"<x:xmpmeta><rdf:Description xmp:CreateDate=\"2023-01-23T14:30:27.42+09:00\"/></x:xmpmeta>";
+        let segment = [IDENTIFIER, packet].concat();
+        let jpeg = [&[0xFF, 0xD8, 0xFF, 0xE1][..], &(segment.len() as u16 + 2).to_be_bytes(), &segment, &[0xFF, 0xD9]].concat();
+        assert_eq!(get_xmp(&jpeg), Some(&packet[..]));
+        assert_eq!(get_date_time(get_xmp(&jpeg).unwrap()), Some(*b"2023:01:23 14:30:27"));
+    }
+}